@@ -0,0 +1,76 @@
+//! Record what we're building, so that a boot log or a panic screen tells
+//! us exactly which kernel produced it.  We write `build_info.rs` into
+//! `OUT_DIR`, and `src/build_info.rs` includes it.
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Run a command and return the first line of its output, or "unknown" if
+/// it fails.  Building from a tarball without `git` is perfectly fine.
+fn command_output(program: &str, args: &[&str]) -> String {
+    Command::new(program).args(args).output().ok()
+        .and_then(|out| if out.status.success() { Some(out.stdout) } else { None })
+        .and_then(|stdout| String::from_utf8(stdout).ok())
+        .and_then(|text| text.lines().next().map(|line| line.trim().to_owned()))
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// Convert seconds since the epoch to an ISO 8601 UTC timestamp.  We use
+/// Howard Hinnant's `civil_from_days` rather than pull in a date crate.
+fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day,
+            rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+fn main() {
+    let commit = command_output("git", &["describe", "--always", "--dirty"]);
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let rustc_version = command_output(&rustc, &["--version"]);
+
+    // Honor `SOURCE_DATE_EPOCH` so that reproducible builds stay
+    // reproducible.
+    let secs = env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs()).unwrap_or(0)
+        });
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            if key.starts_with("CARGO_FEATURE_") {
+                Some(key["CARGO_FEATURE_".len()..].to_lowercase().replace("_", "-"))
+            } else {
+                None
+            }
+        })
+        .collect();
+    features.sort();
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let path = Path::new(&out_dir).join("build_info.rs");
+    let mut f = File::create(&path).unwrap();
+    writeln!(f, "pub const VERSION: &'static str = {:?};",
+             env::var("CARGO_PKG_VERSION").unwrap()).unwrap();
+    writeln!(f, "pub const COMMIT: &'static str = {:?};", commit).unwrap();
+    writeln!(f, "pub const TIMESTAMP: &'static str = {:?};",
+             format_timestamp(secs)).unwrap();
+    writeln!(f, "pub const RUSTC: &'static str = {:?};", rustc_version).unwrap();
+    writeln!(f, "pub const FEATURES: &'static [&'static str] = &{:?};",
+             features).unwrap();
+}
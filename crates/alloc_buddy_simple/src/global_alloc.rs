@@ -0,0 +1,155 @@
+//! A `GlobalAlloc` for modern Rust, built with the `global-alloc` feature.
+//! Unlike `use-as-rust-allocator`, this doesn't need any unstable
+//! features:
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static HEAP: LockedHeap = LockedHeap::empty();
+//!
+//! unsafe { HEAP.init(heap_base, heap_size); }
+//! ```
+//!
+//! We bring our own tiny spinlock, rather than depend on a `spin` crate
+//! which only offers a `const fn` constructor on nightly.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::hint;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use heap::Heap;
+
+/// A `Heap` behind a spinlock, which can serve as the global allocator.
+pub struct LockedHeap {
+    locked: AtomicBool,
+    heap: UnsafeCell<Option<Heap>>,
+}
+
+// We only touch `heap` while holding `locked`.
+unsafe impl Sync for LockedHeap {}
+
+impl LockedHeap {
+    /// A heap with no memory, which fails every allocation until `init`
+    /// is called.
+    pub const fn empty() -> LockedHeap {
+        LockedHeap {
+            locked: AtomicBool::new(false),
+            heap: UnsafeCell::new(None),
+        }
+    }
+
+    /// Give the heap its memory.  The requirements on `heap_base` and
+    /// `heap_size` are the same as for `Heap::new`.
+    pub unsafe fn init(&self, heap_base: *mut u8, heap_size: usize) {
+        self.with_heap(|heap| *heap = Some(Heap::new(heap_base, heap_size)));
+    }
+
+    /// Lock the heap and run `f` on it.  Don't allocate from inside `f`,
+    /// or we'll deadlock.
+    pub fn with_heap<R, F: FnOnce(&mut Option<Heap>) -> R>(&self, f: F) -> R {
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire,
+                                                Ordering::Relaxed).is_err() {
+            while self.locked.load(Ordering::Relaxed) {
+                hint::spin_loop();
+            }
+        }
+        let result = f(unsafe { &mut *self.heap.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_heap(|heap| match *heap {
+            Some(ref mut heap) => heap.allocate(layout.size(), layout.align()),
+            None => ptr::null_mut(),
+        })
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.with_heap(|heap| match *heap {
+            Some(ref mut heap) => heap.allocate_zeroed(layout.size(), layout.align()),
+            None => ptr::null_mut(),
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.with_heap(|heap| {
+            heap.as_mut()
+                .expect("Trying to deallocate before heap is initialized")
+                .deallocate(ptr, layout.size(), layout.align())
+        })
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize)
+                      -> *mut u8
+    {
+        let resized = self.with_heap(|heap| {
+            heap.as_mut()
+                .expect("Trying to reallocate before heap is initialized")
+                .reallocate_in_place(ptr, layout.size(), new_size, layout.align())
+        });
+        if resized {
+            return ptr;
+        }
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::ptr;
+
+    extern "C" {
+        /// We need this to allocate aligned memory for our heap.
+        fn memalign(alignment: usize, size: usize) -> *mut u8;
+
+        // Release our memory.
+        fn free(ptr: *mut u8);
+    }
+
+    #[test]
+    fn test_empty_heap_fails() {
+        let heap = LockedHeap::empty();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        assert_eq!(ptr::null_mut(), unsafe { heap.alloc(layout) });
+    }
+
+    #[test]
+    fn test_alloc_and_dealloc() {
+        unsafe {
+            let heap_size = 256;
+            let mem = memalign(4096, heap_size);
+            ptr::write_bytes(mem, 0xAA, heap_size);
+            let heap = LockedHeap::empty();
+            heap.init(mem, heap_size);
+
+            let small = Layout::from_size_align(8, 8).unwrap();
+            let block = heap.alloc(small);
+            assert_eq!(mem, block);
+
+            let big = Layout::from_size_align(64, 64).unwrap();
+            let zeroed = heap.alloc_zeroed(big);
+            assert_eq!(mem.offset(64), zeroed);
+            assert!((0..64).all(|i| *zeroed.offset(i) == 0));
+
+            heap.dealloc(block, small);
+            heap.dealloc(zeroed, big);
+            let whole = Layout::from_size_align(256, 256).unwrap();
+            assert_eq!(mem, heap.alloc(whole));
+
+            free(mem);
+        }
+    }
+}
@@ -12,10 +12,12 @@
 //! block size.
 
 use core::cmp::{max, min};
+use core::fmt;
 use core::mem::size_of;
 use core::ptr;
 
 use math::PowersOf2;
+use stats::AllocStats;
 
 const MIN_HEAP_ALIGN: usize = 4096;
 
@@ -26,34 +28,162 @@ pub struct FreeBlock {
     /// The next block in the free list, or NULL if this is the final
     /// block.
     next: *mut FreeBlock,
+
+    /// How far into this block we know the memory is zero.  Everything
+    /// from the end of this header up to `zeroed` bytes from the start of
+    /// the block is zero.  A block is completely clean when this equals
+    /// the block size, and we know nothing when it's no more than the
+    /// size of the header.
+    zeroed: usize,
 }
 
 impl FreeBlock {
     /// Construct a `FreeBlock` header pointing at `next`.
-    fn new(next: *mut FreeBlock) -> FreeBlock {
-        FreeBlock { next: next }
+    fn new(next: *mut FreeBlock, zeroed: usize) -> FreeBlock {
+        FreeBlock { next: next, zeroed: zeroed }
+    }
+}
+
+/// The size of the header at the start of each free block, which is never
+/// zero while the block is free.
+const HEADER_SIZE: usize = 16;
+
+/// What `allocate_with_header` stores just before the memory it returns,
+/// so that `deallocate_with_header` can find the block again.
+struct AllocHeader {
+    /// The `size` we passed to `allocate`, including the header.
+    size: usize,
+    /// The `align` we passed to `allocate`.
+    align: usize,
+}
+
+/// The default minimum block size used by `Heap::new`.  Every allocation
+/// takes up at least this much space.
+pub const MIN_BLOCK_SIZE: usize = 16;
+
+/// The maximum number of block sizes (or "orders") that a heap may have.
+/// With the default `MIN_BLOCK_SIZE`, this allows heaps of up to 8 TiB.
+pub const MAX_ORDERS: usize = 40;
+
+/// The maximum number of usage watermarks a heap can watch.
+pub const MAX_WATERMARKS: usize = 4;
+
+/// The maximum number of separate memory regions a heap can manage.
+pub const MAX_REGIONS: usize = 8;
+
+/// Called when a heap has no block big enough for an allocation.  Returns
+/// the base and size of a new region for `Heap::add_region`, which should
+/// be at least `size` bytes and aligned on `align`, or `None` if there's
+/// no more memory to be had.  We always ask for a power of 2.
+pub type GrowHandler = fn(size: usize, align: usize) -> Option<(*mut u8, usize)>;
+
+/// Why an allocation failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocError {
+    /// `align` wasn't a power of 2, or it was bigger than `MIN_HEAP_ALIGN`
+    /// in a heap whose minimum block size is too big to trim blocks.
+    UnsupportedAlignment,
+    /// No block we have, or could get from our grow handler, is big
+    /// enough.
+    TooLarge,
+    /// We have big enough blocks, but none of them are free.
+    OutOfMemory,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            AllocError::UnsupportedAlignment => "unsupported alignment",
+            AllocError::TooLarge => "allocation too large for heap",
+            AllocError::OutOfMemory => "out of memory",
+        })
+    }
+}
+
+/// A contiguous range of memory belonging to a heap.  We carve each region
+/// into blocks independently, so blocks never span two regions.
+#[derive(Clone, Copy)]
+struct Region {
+    /// The start of the region.  This must be aligned on a
+    /// `MIN_HEAP_ALIGN` boundary.
+    base: *mut u8,
+    /// The size of the region, which is a multiple of our minimum block
+    /// size, but not necessarily a power of 2.
+    size: usize,
+}
+
+impl Region {
+    /// Does this region contain `addr`?
+    fn contains(&self, addr: usize) -> bool {
+        let base = self.base as usize;
+        base <= addr && addr < base + self.size
+    }
+
+    /// Is `align` stricter than the alignment of our base?  If so, our
+    /// blocks big enough to be aligned on `align` aren't necessarily
+    /// aligned, and allocations from us need trimming.
+    fn needs_trim(&self, align: usize) -> bool {
+        align.is_power_of_2() && align > MIN_HEAP_ALIGN &&
+            self.base as usize & (align - 1) != 0
     }
 }
 
 /// The interface to a heap.  This data structure is stored _outside_ the
 /// heap somewhere, because every single byte of our heap is potentially
 /// available for allocation.
-pub struct Heap<'a> {
-    /// The base address of our heap.  This must be aligned on a
-    /// `MIN_HEAP_ALIGN` boundary.
-    heap_base: *mut u8,
+pub struct Heap {
+    /// The memory we manage.  Only the first `region_count` are used.
+    regions: [Region; MAX_REGIONS],
+    region_count: usize,
 
-    /// The space available in our heap.  This must be a power of 2.
+    /// The total space available in all our regions.
     heap_size: usize,
 
     /// The free lists for our heap.  The list at `free_lists[0]` contains
-    /// the smallest block size we can allocate, and the list at the end
-    /// can only contain a single free block the size of our entire heap,
-    /// and only when no memory is allocated.
-    free_lists: &'a mut [*mut FreeBlock],
-
-    /// Our minimum block size.  This is calculated based on `heap_size`
-    /// and the length of the provided `free_lists` array, and it must be
+    /// the smallest block size we can allocate, and the list at
+    /// `free_lists[order_count-1]` contains the biggest.  With a single
+    /// power-of-2 region, that's one block the size of our entire heap,
+    /// and only when no memory is allocated.  Lists beyond `order_count`
+    /// are never used.
+    free_lists: [*mut FreeBlock; MAX_ORDERS],
+
+    /// The number of blocks on each free list.  We keep these up to date
+    /// as we go, so that callers can watch fragmentation without walking
+    /// the free lists.
+    free_counts: [usize; MAX_ORDERS],
+
+    /// The number of blocks on each free list which aren't completely
+    /// zeroed, so that `scrub` can skip clean lists without walking them.
+    dirty_counts: [usize; MAX_ORDERS],
+
+    /// How many `allocate_zeroed` calls we've handled, and how many of
+    /// them found a block which `scrub` had already cleaned.
+    zeroed_requests: usize,
+    zeroed_hits: usize,
+
+    /// Statistics about the allocations we've handled.
+    stats: AllocStats,
+
+    /// The total size of the blocks which are currently allocated.
+    in_use: usize,
+
+    /// Levels of `in_use` that somebody wants to hear about, in increasing
+    /// order.  Only the first `watermark_count` are used.
+    watermarks: [usize; MAX_WATERMARKS],
+    watermark_count: usize,
+
+    /// How many watermarks we were above when `take_watermark_change` last
+    /// looked.
+    reported_level: usize,
+
+    /// Who to ask for more memory when we run out.
+    grow_handler: Option<GrowHandler>,
+
+    /// The number of different block sizes we support, which is also the
+    /// number of entries of `free_lists` that we actually use.
+    order_count: usize,
+
+    /// Our minimum block size.  This must be a power of 2, and it must be
     /// big enough to contain a `FreeBlock` header object.
     min_block_size: usize,
 
@@ -64,86 +194,156 @@ pub struct Heap<'a> {
 }
 
 // A Heap struct is the sole owner of the memory it manages
-unsafe impl<'a> Send for Heap<'a> {}
-
-impl<'a> Heap<'a> {
-    /// Create a new heap.  `heap_base` must be aligned on a
-    /// `MIN_HEAP_ALIGN` boundary, `heap_size` must be a power of 2, and
-    /// `heap_size / 2.pow(free_lists.len()-1)` must be greater than or
-    /// equal to `size_of::<FreeBlock>()`.  Passing in invalid parameters
-    /// may do horrible things.
-    pub unsafe fn new(
+unsafe impl Send for Heap {}
+
+impl Heap {
+    /// Create a new heap using the default `MIN_BLOCK_SIZE`.  `heap_base`
+    /// must be aligned on a `MIN_HEAP_ALIGN` boundary, and `heap_size`
+    /// must be a power of 2.  Passing in invalid parameters may do
+    /// horrible things.
+    pub unsafe fn new(heap_base: *mut u8, heap_size: usize) -> Heap {
+        Heap::with_min_block_size(heap_base, heap_size, MIN_BLOCK_SIZE)
+    }
+
+    /// Create a new heap with a custom minimum block size.  In addition to
+    /// the requirements of `new`, `min_block_size` must be a power of 2,
+    /// it must be at least `size_of::<FreeBlock>()`, and `heap_size /
+    /// min_block_size` must not exceed `2.pow(MAX_ORDERS-1)`.
+    pub unsafe fn with_min_block_size(
         heap_base: *mut u8,
         heap_size: usize,
-        free_lists: &mut [*mut FreeBlock])
+        min_block_size: usize)
         -> Heap
     {
         // The heap base must not be null.
         assert!(heap_base != ptr::null_mut());
 
-        // We must have at least one free list.
-        assert!(free_lists.len() > 0);
-
-        // Calculate our minimum block size based on the number of free
-        // lists we have available.
-        let min_block_size = heap_size >> (free_lists.len()-1);
-
         // The heap must be aligned on a 4K bounday.
         assert_eq!(heap_base as usize & (MIN_HEAP_ALIGN-1), 0);
 
-        // The heap must be big enough to contain at least one block.
-        assert!(heap_size >= min_block_size);
+        // The heap size must be a power of 2.  See:
+        // http://graphics.stanford.edu/~seander/bithacks.html#DetermineIfPowerOf2
+        assert!(heap_size.is_power_of_2());
+
+        // Our minimum block size must be a power of 2, too.
+        assert!(min_block_size.is_power_of_2());
 
         // The smallest possible heap block must be big enough to contain
         // the block header.
         assert!(min_block_size >= size_of::<FreeBlock>());
+        debug_assert_eq!(HEADER_SIZE, size_of::<FreeBlock>());
 
-        // The heap size must be a power of 2.  See:
-        // http://graphics.stanford.edu/~seander/bithacks.html#DetermineIfPowerOf2
-        assert!(heap_size.is_power_of_2());
+        // The heap must be big enough to contain at least one block.
+        assert!(heap_size >= min_block_size);
 
-        // We must have one free list per possible heap block size.
-        assert_eq!(min_block_size *
-                   (2u32.pow(free_lists.len() as u32 - 1)) as usize,
-                   heap_size);
+        // We need one free list per possible heap block size, and we only
+        // have room for `MAX_ORDERS` of them.
+        let order_count = (heap_size.log2() - min_block_size.log2()) as usize + 1;
+        assert!(order_count <= MAX_ORDERS);
 
-        // Zero out our free list pointers.
-        for ptr in free_lists.iter_mut() {
-            *ptr = ptr::null_mut();
-        }
-
-        // Store all the info about our heap in our struct.
+        // Store all the info about our heap in our struct.  `add_region`
+        // fills in the details of our memory.
+        let empty = Region { base: ptr::null_mut(), size: 0 };
         let mut result = Heap {
-            heap_base: heap_base,
-            heap_size: heap_size,
-            free_lists: free_lists,
+            regions: [empty; MAX_REGIONS],
+            region_count: 0,
+            heap_size: 0,
+            free_lists: [ptr::null_mut(); MAX_ORDERS],
+            free_counts: [0; MAX_ORDERS],
+            dirty_counts: [0; MAX_ORDERS],
+            zeroed_requests: 0,
+            zeroed_hits: 0,
+            stats: AllocStats::new(),
+            in_use: 0,
+            watermarks: [0; MAX_WATERMARKS],
+            watermark_count: 0,
+            reported_level: 0,
+            grow_handler: None,
+            order_count: 0,
             min_block_size: min_block_size,
             min_block_size_log2: min_block_size.log2(),
         };
+        result.add_region(heap_base, heap_size)
+            .expect("Failed to add root heap region");
 
-        // Insert the entire heap onto the appropriate free list as a
-        // single block.
-        let order = result.allocation_order(heap_size, 1)
-            .expect("Failed to calculate order for root heap block");
-        result.free_list_insert(order, heap_base);
-        
         // Return our newly-created heap.
         result
     }
 
+    /// Give us another region of memory to manage, starting at `base`,
+    /// which must be aligned on a `MIN_HEAP_ALIGN` boundary.  Unlike the
+    /// memory passed to `new`, `size` needn't be a power of 2, but we
+    /// ignore anything past the last whole minimum-size block.  The region
+    /// must not overlap any of our other regions.
+    pub unsafe fn add_region(&mut self, base: *mut u8, size: usize)
+        -> Result<(), &'static str>
+    {
+        let size = size & !(self.min_block_size - 1);
+        if base.is_null() || base as usize & (MIN_HEAP_ALIGN - 1) != 0 {
+            return Err("heap region is not aligned");
+        }
+        if size == 0 {
+            return Err("heap region is too small");
+        }
+        if self.region_count >= MAX_REGIONS {
+            return Err("too many heap regions");
+        }
+        let start = base as usize;
+        let end = start + size;
+        if self.regions[..self.region_count].iter()
+            .any(|r| (r.base as usize) < end && start < r.base as usize + r.size)
+        {
+            return Err("heap regions overlap");
+        }
+        self.regions[self.region_count] = Region { base: base, size: size };
+        self.region_count += 1;
+        self.heap_size += size;
+
+        // Carve the region into the biggest power-of-2 blocks we can,
+        // largest first, so that each block is aligned on its own size
+        // relative to `base`.  We don't know what's in them.
+        let biggest = self.order_size(MAX_ORDERS - 1);
+        let mut offset = 0;
+        while offset < size {
+            let block_size = min(1 << (size - offset).log2(), biggest);
+            let order = (block_size.log2() - self.min_block_size_log2) as usize;
+            self.order_count = max(self.order_count, order + 1);
+            self.free_list_insert(order, base.offset(offset as isize), 0);
+            offset += block_size;
+        }
+        Ok(())
+    }
+
+    /// The region containing `addr`.
+    fn region_of(&self, addr: *mut u8) -> Option<Region> {
+        self.regions[..self.region_count].iter()
+            .find(|r| r.contains(addr as usize))
+            .map(|&r| r)
+    }
+
     /// Figure out what size block we'll need to fulfill an allocation
     /// request.  This is deterministic, and it does not depend on what
     /// we've already allocated.  In particular, it's important to be able
     /// to calculate the same `allocation_size` when freeing memory as we
     /// did when allocating it, or everything will break horribly.
-    pub fn allocation_size(&self, mut size: usize, align: usize) -> Option<usize> {
+    ///
+    /// The exception is alignments bigger than `MIN_HEAP_ALIGN`, which
+    /// need `allocate_trimmed` as soon as one of our regions isn't
+    /// aligned that well.  Those don't use a single block, so we return
+    /// `None` for them.
+    pub fn allocation_size(&self, size: usize, align: usize) -> Option<usize> {
+        // Our blocks are only aligned as well as our regions.
+        if self.needs_trim(align) { return None; }
+        self.block_size(size, align)
+    }
+
+    /// The size of block that `allocation_size` would give us if all our
+    /// regions were aligned on `align`.  This is what a block allocated
+    /// from an aligned region is, whatever other regions we have.
+    fn block_size(&self, mut size: usize, align: usize) -> Option<usize> {
         // Sorry, we don't support weird alignments.
         if !align.is_power_of_2() { return None; }
 
-        // We can't align any more precisely than our heap base alignment
-        // without getting much too clever, so don't bother.
-        if align > MIN_HEAP_ALIGN { return None; }
-
         // We're automatically aligned to `size` because of how our heap is
         // sub-divided, but if we need a larger alignment, we can only do
         // it be allocating more memory.
@@ -155,46 +355,226 @@ impl<'a> Heap<'a> {
         // Round up to the next power of two.
         size = size.next_power_of_2();
 
-        // We can't allocate a block bigger than our heap.
-        if size > self.heap_size { return None; }
+        // We can't allocate a block bigger than our biggest block.
+        if size > self.order_size(self.order_count - 1) { return None; }
 
         Some(size)
     }
 
+    /// Is `align` stricter than the alignment of any of our regions?  If
+    /// so, we can't count on blocks big enough to be aligned on `align`
+    /// actually being aligned, so we need to cut an aligned piece out of a
+    /// bigger block.  Freeing memory depends on the region it's in, not on
+    /// this, because we may have added regions since we allocated it.
+    fn needs_trim(&self, align: usize) -> bool {
+        self.regions[..self.region_count].iter().any(|r| r.needs_trim(align))
+    }
+
     /// The "order" of an allocation is how many times we need to double
     /// `min_block_size` in order to get a large enough block, as well as
     /// the index we use into `free_lists`.
     pub fn allocation_order(&self, size: usize, align: usize) -> Option<usize> {
-        self.allocation_size(size, align).map(|s| {
-            (s.log2() - self.min_block_size_log2) as usize
-        })
+        self.allocation_size(size, align).map(|s| self.size_order(s))
+    }
+
+    /// Like `allocation_order`, but for `block_size`.
+    fn block_order(&self, size: usize, align: usize) -> Option<usize> {
+        self.block_size(size, align).map(|s| self.size_order(s))
+    }
+
+    /// The order of blocks of `size`, which must be a power of 2.
+    fn size_order(&self, size: usize) -> usize {
+        (size.log2() - self.min_block_size_log2) as usize
     }
 
     /// The size of the blocks we allocate for a given order.
-    fn order_size(&self, order: usize) -> usize {
+    pub fn order_size(&self, order: usize) -> usize {
         1 << (self.min_block_size_log2 as usize + order)
     }
 
-    /// Pop a block off the appropriate free list.
-    unsafe fn free_list_pop(&mut self, order: usize) -> Option<*mut u8> {
+    /// The number of different block sizes in this heap.  Orders from 0
+    /// up to (but not including) this value are valid.
+    pub fn order_count(&self) -> usize {
+        self.order_count
+    }
+
+    /// The number of free blocks of each order.  Entries beyond
+    /// `order_count` are always 0.  This is cheap, because we don't need
+    /// to walk the free lists.
+    pub fn free_blocks_per_order(&self) -> [usize; MAX_ORDERS] {
+        self.free_counts
+    }
+
+    /// The size of the largest block we could allocate right now, or 0 if
+    /// the heap is full.  Because our blocks are powers of 2, a request
+    /// for `size` bytes with alignment up to `size` will succeed if
+    /// `allocation_size(size, align)` is no more than this.  This takes
+    /// time proportional to the number of orders.
+    pub fn largest_free_block(&self) -> usize {
+        (0..self.order_count).rev()
+            .find(|&order| self.free_counts[order] > 0)
+            .map(|order| self.order_size(order))
+            .unwrap_or(0)
+    }
+
+    /// The number of free blocks of each order which `scrub` hasn't
+    /// finished zeroing.
+    pub fn dirty_blocks_per_order(&self) -> [usize; MAX_ORDERS] {
+        self.dirty_counts
+    }
+
+    /// How many calls to `allocate_zeroed` we've handled, and how many of
+    /// those were satisfied by memory that `scrub` had already zeroed.
+    pub fn zeroed_allocations(&self) -> (usize, usize) {
+        (self.zeroed_requests, self.zeroed_hits)
+    }
+
+    /// The size of our heap, in bytes, counting all our regions.
+    pub fn size(&self) -> usize {
+        self.heap_size
+    }
+
+    /// The number of bytes currently allocated, counting whole blocks.
+    pub fn bytes_in_use(&self) -> usize {
+        self.in_use
+    }
+
+    /// Watch for `bytes_in_use` crossing each of `levels`, which must be
+    /// in increasing order.  See `take_watermark_change`.
+    pub fn set_watermarks(&mut self, levels: &[usize]) -> Result<(), &'static str> {
+        if levels.len() > MAX_WATERMARKS {
+            return Err("too many heap watermarks");
+        }
+        if levels.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err("heap watermarks must be in increasing order");
+        }
+        self.watermarks[..levels.len()].copy_from_slice(levels);
+        self.watermark_count = levels.len();
+        self.reported_level = self.watermark_level();
+        Ok(())
+    }
+
+    /// How many of our watermarks `bytes_in_use` has reached.
+    pub fn watermark_level(&self) -> usize {
+        self.watermarks[..self.watermark_count].iter()
+            .take_while(|&&level| self.in_use >= level)
+            .count()
+    }
+
+    /// If we've crossed any watermarks since we were last asked, return
+    /// the old and new values of `watermark_level`.  We don't call anybody
+    /// back ourselves, because our caller probably holds a lock on us.
+    pub fn take_watermark_change(&mut self) -> Option<(usize, usize)> {
+        let level = self.watermark_level();
+        if level == self.reported_level {
+            return None;
+        }
+        let old = self.reported_level;
+        self.reported_level = level;
+        Some((old, level))
+    }
+
+    /// Call `handler` when we can't satisfy an allocation, and add the
+    /// region it returns before trying again.  If the region still isn't
+    /// enough, we ask again, until the handler returns `None` or we have
+    /// `MAX_REGIONS` regions.  It runs in the middle of the allocation, so
+    /// it mustn't allocate from this heap itself.
+    pub fn set_grow_handler(&mut self, handler: Option<GrowHandler>) {
+        self.grow_handler = handler;
+    }
+
+    /// Ask our grow handler for more memory for an allocation of `size`
+    /// bytes aligned on `align`.  Returns true if we got some.
+    unsafe fn grow(&mut self, size: usize, align: usize) -> bool {
+        let handler = match self.grow_handler {
+            Some(handler) => handler,
+            None => return false,
+        };
+
+        // Don't bother asking for memory we could never use.
+        if self.region_count >= MAX_REGIONS {
+            return false;
+        }
+        let biggest = self.order_size(MAX_ORDERS - 1);
+        if !align.is_power_of_2() || size > biggest || align > biggest {
+            return false;
+        }
+
+        // Work out how big a region we need to hold a single block for
+        // this allocation, like `allocation_size` and `allocate_trimmed`
+        // would, but without limiting it to the blocks we have now.
+        let size = max(size, self.min_block_size).next_power_of_2();
+        let (wanted, wanted_align) = if self.needs_trim(align) {
+            ((size + align).next_power_of_2(), MIN_HEAP_ALIGN)
+        } else {
+            (max(size, align), max(align, MIN_HEAP_ALIGN))
+        };
+        if wanted > biggest {
+            return false;
+        }
+
+        match handler(wanted, wanted_align) {
+            Some((base, region_size)) => self.add_region(base, region_size).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Statistics about every allocation we've made so far.
+    pub fn stats(&self) -> &AllocStats {
+        &self.stats
+    }
+
+    /// Our minimum block size.
+    pub fn min_block_size(&self) -> usize {
+        self.min_block_size
+    }
+
+    /// Is a block of order `order`, which is zero up to `zeroed`, known
+    /// to be zero apart from its header?
+    fn is_clean(&self, order: usize, zeroed: usize) -> bool {
+        max(zeroed, HEADER_SIZE) >= self.order_size(order)
+    }
+
+    /// Update our counts for a block which is zero up to `zeroed`, and
+    /// which is joining (or leaving) the free list for `order`.
+    fn count_free(&mut self, order: usize, zeroed: usize, joining: bool) {
+        let dirty = !self.is_clean(order, zeroed);
+        if joining {
+            self.free_counts[order] += 1;
+            if dirty { self.dirty_counts[order] += 1; }
+        } else {
+            self.free_counts[order] -= 1;
+            if dirty { self.dirty_counts[order] -= 1; }
+        }
+    }
+
+    /// Pop a block off the appropriate free list, returning it and how
+    /// much of it we know is zero.
+    unsafe fn free_list_pop(&mut self, order: usize) -> Option<(*mut u8, usize)> {
         let candidate = self.free_lists[order];
         if candidate != ptr::null_mut() {
+            let zeroed = (*candidate).zeroed;
             self.free_lists[order] = (*candidate).next;
-            Some(candidate as *mut u8)
+            self.count_free(order, zeroed, false);
+            Some((candidate as *mut u8, zeroed))
         } else {
             None
         }
     }
 
-    /// Insert `block` of order `order` onto the appropriate free list.
-    unsafe fn free_list_insert(&mut self, order: usize, block: *mut u8) {
+    /// Insert `block` of order `order` onto the appropriate free list,
+    /// noting that it's zero up to `zeroed` bytes from its start.
+    unsafe fn free_list_insert(&mut self, order: usize, block: *mut u8,
+                               zeroed: usize) {
         let free_block_ptr = block as *mut FreeBlock;
-        *free_block_ptr = FreeBlock::new(self.free_lists[order]);
+        *free_block_ptr = FreeBlock::new(self.free_lists[order], zeroed);
         self.free_lists[order] = free_block_ptr;
+        self.count_free(order, zeroed, true);
     }
 
-    /// Attempt to remove a block from our free list, returning true
-    /// success, and false if the block wasn't on our free list.  This is
+    /// Attempt to remove a block from our free list, returning how much
+    /// of it we know is zero, or `None` if the block wasn't on our free
+    /// list.  This is
     /// the slowest part of a primitive buddy allocator, because it runs in
     /// O(log N) time where N is the number of blocks of a given size.
     ///
@@ -204,7 +584,7 @@ impl<'a> Heap<'a> {
     /// finding.
     unsafe fn free_list_remove(
         &mut self, order: usize, block: *mut u8)
-        -> bool
+        -> Option<usize>
     {
         let block_ptr = block as *mut FreeBlock;
 
@@ -220,8 +600,10 @@ impl<'a> Heap<'a> {
             if *checking == block_ptr {
                 // Yup, this is the one, so overwrite the value we used to
                 // get here with the next one in the sequence.
+                let zeroed = (*block_ptr).zeroed;
                 *checking = (*(*checking)).next;
-                return true;
+                self.count_free(order, zeroed, false);
+                return Some(zeroed);
             }
 
             // Haven't found it yet, so point `checking` at the address
@@ -229,13 +611,29 @@ impl<'a> Heap<'a> {
             // be able to reach back and overwrite it later if necessary.)
             checking = &mut ((*(*checking)).next);
         }
-        false
+        None
+    }
+
+    /// Find a completely zeroed block on the free list for `order`.
+    unsafe fn free_list_find_zeroed(&self, order: usize) -> Option<*mut u8> {
+        let mut checking = self.free_lists[order];
+        while checking != ptr::null_mut() {
+            if self.is_clean(order, (*checking).zeroed) {
+                return Some(checking as *mut u8);
+            }
+            checking = (*checking).next;
+        }
+        None
     }
 
-    /// Split a `block` of order `order` down into a block of order
-    /// `order_needed`, placing any unused chunks on the free list.
+    /// Split a `block` of order `order`, which is zero up to `zeroed`
+    /// bytes from its start, down into a block of order `order_needed`,
+    /// placing any unused chunks on the free list.  Returns how much of
+    /// the remaining block we know is zero.
     unsafe fn split_free_block(
-        &mut self, block: *mut u8, mut order: usize, order_needed: usize)
+        &mut self, block: *mut u8, mut order: usize, order_needed: usize,
+        mut zeroed: usize)
+        -> usize
     {
         // Get the size of our starting block.
         let mut size_to_split = self.order_size(order);
@@ -246,66 +644,288 @@ impl<'a> Heap<'a> {
             size_to_split >>= 1;
             order -= 1;
 
-            // Insert the "upper half" of the block into the free list.
+            // Insert the "upper half" of the block into the free list,
+            // along with whatever we knew about its contents.
             let split = block.offset(size_to_split as isize);
-            self.free_list_insert(order, split);
+            self.free_list_insert(order, split, zeroed.saturating_sub(size_to_split));
+            zeroed = min(zeroed, size_to_split);
         }
+        zeroed
     }
 
     /// Allocate a block of memory large enough to contain `size` bytes,
-    /// and aligned on `align`.  This will return NULL if the `align` is
-    /// greater than `MIN_HEAP_ALIGN`, if `align` is not a power of 2, or
-    /// if we can't find enough memory.
+    /// and aligned on `align`.  This will return NULL if `align` is not a
+    /// power of 2, or if we can't find enough memory.  Alignments bigger
+    /// than `MIN_HEAP_ALIGN` work, but unless our heap base happens to be
+    /// aligned that well, they temporarily need a free block of at least
+    /// `size + align` bytes.
     ///
     /// All allocated memory must be passed to `deallocate` with the same
     /// `size` and `align` parameter, or else horrible things will happen.
     pub unsafe fn allocate(&mut self, size: usize, align: usize) -> *mut u8
     {
-        // Figure out which order block we need.
-        if let Some(order_needed) = self.allocation_order(size, align) {
+        self.try_allocate(size, align).unwrap_or(ptr::null_mut())
+    }
 
-            // Start with the smallest acceptable block size, and search
-            // upwards until we reach blocks the size of the entire heap.
-            for order in order_needed..self.free_lists.len() {
+    /// Like `allocate`, but tells us why it failed.  The pointer is never
+    /// null.
+    pub unsafe fn try_allocate(&mut self, size: usize, align: usize)
+        -> Result<*mut u8, AllocError>
+    {
+        self.allocate_block(size, align, false).map(|(block, _)| block)
+    }
+
+    /// Like `allocate`, but the first `size` bytes of the memory are
+    /// zero.  We prefer blocks which `scrub` has already cleaned, and only
+    /// zero the parts of the block that we don't know are zero.
+    pub unsafe fn allocate_zeroed(&mut self, size: usize, align: usize) -> *mut u8
+    {
+        let (block, zeroed) = match self.allocate_block(size, align, true) {
+            Ok(allocation) => allocation,
+            Err(_) => return ptr::null_mut(),
+        };
+        let known = max(zeroed, HEADER_SIZE);
+        self.zeroed_requests += 1;
+        if known >= size {
+            self.zeroed_hits += 1;
+        }
 
-                // Do we have a block of this size?
-                if let Some(block) = self.free_list_pop(order) {
+        // The header is never zero, and neither is anything past the
+        // part we know about.
+        ptr::write_bytes(block, 0, min(HEADER_SIZE, size));
+        if size > known {
+            ptr::write_bytes(block.offset(known as isize), 0, size - known);
+        }
+        block
+    }
 
-                    // If the block is too big, break it up.  This leaves
-                    // the address unchanged, because we always allocate at
-                    // the head of a block.
-                    if order > order_needed {
-                        self.split_free_block(block, order, order_needed);
+    /// Find a block for `allocate` or `allocate_zeroed`, preferring
+    /// completely zeroed blocks if `want_zeroed`.  Returns the block and
+    /// how much of it we know is zero, not counting its first
+    /// `HEADER_SIZE` bytes.  If we don't have one, we keep asking our grow
+    /// handler for memory until it gives up, or we run out of regions.
+    unsafe fn allocate_block(&mut self, size: usize, align: usize,
+                             want_zeroed: bool)
+                             -> Result<(*mut u8, usize), AllocError>
+    {
+        loop {
+            match self.try_allocate_block(size, align, want_zeroed) {
+                Err(AllocError::UnsupportedAlignment) => {
+                    return Err(AllocError::UnsupportedAlignment);
+                }
+                Err(err) => {
+                    if !self.grow(size, align) {
+                        return Err(err);
                     }
+                }
+                found => return found,
+            }
+        }
+    }
 
-                    // We have an allocation, so quit now.
-                    return block;
+    /// Find a block in the memory we already have, for `allocate_block`.
+    unsafe fn try_allocate_block(&mut self, size: usize, align: usize,
+                                 want_zeroed: bool)
+                                 -> Result<(*mut u8, usize), AllocError>
+    {
+        // Sorry, we don't support weird alignments.
+        if !align.is_power_of_2() {
+            return Err(AllocError::UnsupportedAlignment);
+        }
+        if self.needs_trim(align) {
+            return self.allocate_trimmed(size, align);
+        }
+
+        // Figure out which order block we need.  Our alignment is fine, so
+        // if there's no such order, it's because our blocks are too small.
+        let order_needed = match self.allocation_order(size, align) {
+            Some(order) => order,
+            None => return Err(AllocError::TooLarge),
+        };
+        let (block, zeroed) = match self.take_block(order_needed, want_zeroed) {
+            Some(found) => found,
+            None => return Err(AllocError::OutOfMemory),
+        };
+
+        let granted = self.order_size(order_needed);
+        self.stats.record(size, order_needed, granted);
+        self.in_use += granted;
+        Ok((block, zeroed))
+    }
+
+    /// Take a block of order `order_needed` off our free lists, splitting
+    /// a bigger one if we need to.  Returns the block and how much of it
+    /// we know is zero.
+    unsafe fn take_block(&mut self, order_needed: usize, want_zeroed: bool)
+                         -> Option<(*mut u8, usize)>
+    {
+
+        // If we're asked for zeroed memory, look for a block that `scrub`
+        // has finished with.  Otherwise, start with the smallest
+        // acceptable block size, and search upwards until we reach blocks
+        // the size of the entire heap.
+        let mut found = None;
+        if want_zeroed {
+            for order in order_needed..self.order_count {
+                if let Some(block) = self.free_list_find_zeroed(order) {
+                    let zeroed = self.free_list_remove(order, block)
+                        .expect("zeroed block vanished from free list");
+                    found = Some((order, block, zeroed));
+                    break;
                 }
             }
+        }
+        if found.is_none() {
+            found = (order_needed..self.order_count).filter_map(|order| {
+                self.free_list_pop(order).map(|(block, zeroed)| (order, block, zeroed))
+            }).next();
+        }
 
-            // We couldn't find a large enough block for this allocation.
-            ptr::null_mut()
-        } else {
-            // We can't allocate a block with the specified size and
-            // alignment.
-            ptr::null_mut()
+        // We couldn't find a large enough block for this allocation.
+        let (order, block, mut zeroed) = match found {
+            Some(found) => found,
+            None => return None,
+        };
+
+        // If the block is too big, break it up.  This leaves the address
+        // unchanged, because we always allocate at the head of a block.
+        if order > order_needed {
+            zeroed = self.split_free_block(block, order, order_needed, zeroed);
         }
+        Some((block, zeroed))
+    }
+
+    /// Allocate `size` bytes aligned on `align`, for alignments stricter
+    /// than our heap base.  We take a block big enough to be sure of
+    /// containing an aligned piece, and give back what's before and after
+    /// it.  We don't know anything about what's in the piece.
+    unsafe fn allocate_trimmed(&mut self, size: usize, align: usize)
+                               -> Result<(*mut u8, usize), AllocError>
+    {
+        // Our pieces must start on a block boundary.  This is always true
+        // unless somebody asked for really big blocks.
+        if self.min_block_size > MIN_HEAP_ALIGN {
+            return Err(AllocError::UnsupportedAlignment);
+        }
+
+        // What we'll actually hold on to.  `stats` sees both this and the
+        // size we were asked for.
+        let held = match self.block_size(size, 1) {
+            Some(held) => held,
+            None => return Err(AllocError::TooLarge),
+        };
+        let outer_order = match held.checked_add(align)
+            .and_then(|outer_size| self.block_order(outer_size, 1))
+        {
+            Some(order) => order,
+            None => return Err(AllocError::TooLarge),
+        };
+        let (outer, _) = match self.take_block(outer_order, false) {
+            Some(found) => found,
+            None => return Err(AllocError::OutOfMemory),
+        };
+
+        // If the block came from a region which is aligned well enough,
+        // `deallocate` will expect an ordinary block, so give it one.
+        let region = self.region_of(outer).expect("allocated memory we don't own");
+        if !region.needs_trim(align) {
+            let order = self.block_order(held, align)
+                .expect("block fits in outer block but has no order");
+            self.split_free_block(outer, outer_order, order, 0);
+            let granted = self.order_size(order);
+            self.stats.record(size, order, granted);
+            self.in_use += granted;
+            return Ok((outer, 0));
+        }
+
+        let offset = (align - (outer as usize & (align - 1))) & (align - 1);
+        let block = outer.offset(offset as isize);
+        let outer_end = outer.offset(self.order_size(outer_order) as isize);
+        self.free_range(outer, block);
+        self.free_range(block.offset(held as isize), outer_end);
+
+        let order = self.size_order(held);
+        self.stats.record(size, order, held);
+        self.in_use += held;
+        Ok((block, 0))
+    }
+
+    /// Return `start..end` to our free lists, as the biggest blocks that
+    /// fit.  Both ends must lie on block boundaries.
+    unsafe fn free_range(&mut self, mut start: *mut u8, end: *mut u8) {
+        let region = self.region_of(start).expect("freeing memory we don't own");
+        while start < end {
+            let relative = start as usize - region.base as usize;
+            let mut order = 0;
+            while order + 1 < self.order_count {
+                let bigger = self.order_size(order + 1);
+                if relative & (bigger - 1) != 0 ||
+                    start as usize + bigger > end as usize
+                {
+                    break;
+                }
+                order += 1;
+            }
+            self.free_block(start, order);
+            start = start.offset(self.order_size(order) as isize);
+        }
+    }
+
+    /// Try to resize the block at `ptr`, allocated with `old_size` and
+    /// `align`, to hold `new_size` bytes without moving it.  This works if
+    /// the new size needs a block of the same order, or of a smaller one,
+    /// in which case we give the tail back to the free lists.  Returns
+    /// false, leaving the block alone, if it needs to grow.  On success,
+    /// the block must be deallocated with `new_size`.
+    pub unsafe fn reallocate_in_place(
+        &mut self, ptr: *mut u8, old_size: usize, new_size: usize,
+        align: usize)
+        -> bool
+    {
+        // Trimmed allocations aren't single blocks, so leave them alone.
+        let region = self.region_of(ptr).expect("Tried to resize invalid block");
+        if region.needs_trim(align) {
+            return false;
+        }
+        let old_order = self.block_order(old_size, align)
+            .expect("Tried to resize invalid block");
+        let new_order = match self.block_order(new_size, align) {
+            Some(order) if order <= old_order => order,
+            _ => return false,
+        };
+        if new_order < old_order {
+            // We know nothing about what's in the tail.
+            self.split_free_block(ptr, old_order, new_order, 0);
+            self.in_use -= self.order_size(old_order) - self.order_size(new_order);
+        }
+        true
     }
 
     /// Given a `block` with the specified `order`, find the "buddy" block,
     /// that is, the other half of the block we originally split it from,
     /// and also the block we could potentially merge it with.
     pub unsafe fn buddy(&self, order: usize, block: *mut u8) -> Option<*mut u8> {
-        let relative = (block as usize) - (self.heap_base as usize);
+        // Blocks of our biggest size never merge.
+        if order + 1 >= self.order_count {
+            return None;
+        }
+        let region = match self.region_of(block) {
+            Some(region) => region,
+            None => return None,
+        };
+        let relative = (block as usize) - (region.base as usize);
         let size = self.order_size(order);
-        if size >= self.heap_size {
-            // The main heap itself does not have a budy.
-            None
-        } else {
-            // Fun: We can find our buddy by xoring the right bit in our
-            // offset from the base of the heap.
-            Some(self.heap_base.offset((relative ^ size) as isize))
+
+        // If the merged block wouldn't fit in the region, we're one of the
+        // blocks `add_region` started with, and we have no buddy.  (This
+        // includes the main heap itself.)
+        if (relative & !size) + 2 * size > region.size {
+            return None;
         }
+
+        // Fun: We can find our buddy by xoring the right bit in our
+        // offset from the base of the region.
+        Some(region.base.offset((relative ^ size) as isize))
     }
 
     /// Deallocate a block allocated using `allocate`.  Note that the
@@ -314,33 +934,142 @@ impl<'a> Heap<'a> {
     pub unsafe fn deallocate(
         &mut self, ptr: *mut u8, old_size: usize, align: usize)
     {
-        let initial_order = self.allocation_order(old_size, align)
+        // Whether we trimmed this depends only on the region it's in.
+        let region = self.region_of(ptr).expect("Tried to dispose of invalid block");
+        if region.needs_trim(align) {
+            let size = self.block_size(old_size, 1)
+                .expect("Tried to dispose of invalid block");
+            self.in_use -= size;
+            self.free_range(ptr, ptr.offset(size as isize));
+            return;
+        }
+
+        let initial_order = self.block_order(old_size, align)
             .expect("Tried to dispose of invalid block");
+        self.in_use -= self.order_size(initial_order);
+        self.free_block(ptr, initial_order);
+    }
 
+    /// Put `ptr`, a block of order `initial_order`, back on our free
+    /// lists, merging it with its buddies as far as we can.
+    unsafe fn free_block(&mut self, ptr: *mut u8, initial_order: usize) {
         // The fun part: When deallocating a block, we also want to check
         // to see if its "buddy" is on the free list.  If the buddy block
         // is also free, we merge them and continue walking up.
         //
-        // `block` is the biggest merged block we have so far.
+        // `block` is the biggest merged block we have so far, and it's
+        // zero up to `zeroed`.  We know nothing about freshly freed memory.
         let mut block = ptr;
-        for order in initial_order..self.free_lists.len() {
+        let mut zeroed = 0;
+        for order in initial_order..self.order_count {
             // Would this block have a buddy?
             if let Some(buddy) = self.buddy(order, block) {
                 // Is this block's buddy free?
-                if self.free_list_remove(order, buddy) {
+                if let Some(buddy_zeroed) = self.free_list_remove(order, buddy) {
                     // Merge them!  The lower address of the two is the
                     // newly-merged block.  Then we want to try again.
-                    block = min(block, buddy);
+                    let (lower, lower_zeroed, upper, upper_zeroed) = if block < buddy {
+                        (block, zeroed, buddy, buddy_zeroed)
+                    } else {
+                        (buddy, buddy_zeroed, block, zeroed)
+                    };
+                    zeroed = self.merged_zeroed(order, lower_zeroed, upper,
+                                                upper_zeroed);
+                    block = lower;
                     continue;
                 }
             }
 
             // If we reach here, we didn't find a buddy block of this size,
             // so take what we've got and mark it as free.
-            self.free_list_insert(order, block);
+            self.free_list_insert(order, block, zeroed);
             return;
         }
     }
+
+    /// How far past the start of its block `allocate_with_header` puts
+    /// memory aligned on `align`.  This is big enough for an `AllocHeader`,
+    /// and a multiple of `align`.
+    fn header_offset(align: usize) -> usize {
+        max(align, size_of::<AllocHeader>())
+    }
+
+    /// Like `allocate`, but we store the size and alignment in a small
+    /// header before the returned memory, so that it can be freed with
+    /// `deallocate_with_header` without remembering them.  This is useful
+    /// behind a C-style `free(ptr)` interface.  The header costs at least
+    /// `size_of::<AllocHeader>()` bytes per allocation, or `align` bytes
+    /// if that's bigger.
+    pub unsafe fn allocate_with_header(&mut self, size: usize, align: usize)
+                                       -> *mut u8
+    {
+        let offset = Heap::header_offset(align);
+        let block_size = match size.checked_add(offset) {
+            Some(block_size) => block_size,
+            None => return ptr::null_mut(),
+        };
+        let block_align = max(align, size_of::<usize>());
+        let block = self.allocate(block_size, block_align);
+        if block.is_null() {
+            return block;
+        }
+        let result = block.offset(offset as isize);
+        let header = (result as *mut AllocHeader).offset(-1);
+        ptr::write(header, AllocHeader { size: block_size, align: block_align });
+        result
+    }
+
+    /// Deallocate memory allocated using `allocate_with_header`.  Passing
+    /// in anything else will corrupt our heap.
+    pub unsafe fn deallocate_with_header(&mut self, ptr: *mut u8) {
+        let header = ptr::read((ptr as *mut AllocHeader).offset(-1));
+        let offset = Heap::header_offset(header.align);
+        self.deallocate(ptr.offset(-(offset as isize)), header.size, header.align);
+    }
+
+    /// How much of a merged block is zero, given its halves of order
+    /// `order`.  If the lower half is completely zero, the zeroed part
+    /// carries on into the upper half, once we've cleared its header.
+    unsafe fn merged_zeroed(&self, order: usize, lower_zeroed: usize,
+                            upper: *mut u8, upper_zeroed: usize)
+                            -> usize
+    {
+        if self.is_clean(order, lower_zeroed) {
+            let size = self.order_size(order);
+            ptr::write_bytes(upper, 0, HEADER_SIZE);
+            size + max(upper_zeroed, HEADER_SIZE)
+        } else {
+            lower_zeroed
+        }
+    }
+
+    /// Zero up to `budget` bytes of free memory in advance, so that
+    /// `allocate_zeroed` doesn't have to.  We work on the largest dirty
+    /// block first, and carry on where we left off next time.  Returns the
+    /// number of bytes we zeroed, which is 0 when everything is clean.
+    /// `budget` must not be 0.
+    pub unsafe fn scrub(&mut self, budget: usize) -> usize {
+        let order = match (0..self.order_count).rev()
+            .find(|&order| self.dirty_counts[order] > 0)
+        {
+            Some(order) => order,
+            None => return 0,
+        };
+        let size = self.order_size(order);
+
+        let mut block = self.free_lists[order];
+        while self.is_clean(order, (*block).zeroed) {
+            block = (*block).next;
+        }
+        let start = max((*block).zeroed, HEADER_SIZE);
+        let end = min(start.saturating_add(budget), size);
+        ptr::write_bytes((block as *mut u8).offset(start as isize), 0, end - start);
+        (*block).zeroed = end;
+        if end == size {
+            self.dirty_counts[order] -= 1;
+        }
+        end - start
+    }
 }
 
 #[cfg(test)]
@@ -362,8 +1091,7 @@ mod test {
         unsafe {
             let heap_size = 256;
             let mem = memalign(4096, heap_size);
-            let mut free_lists: [*mut FreeBlock; 5] = [0 as *mut _; 5];
-            let heap = Heap::new(mem, heap_size, &mut free_lists);
+            let heap = Heap::new(mem, heap_size);
 
             // TEST NEEDED: Can't align beyond MIN_HEAP_ALIGN.
 
@@ -395,13 +1123,579 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_custom_min_block_size() {
+        unsafe {
+            let heap_size = 256;
+            let mem = memalign(4096, heap_size);
+            let mut heap = Heap::with_min_block_size(mem, heap_size, 64);
+
+            // Everything gets rounded up to at least 64 bytes.
+            assert_eq!(Some(64), heap.allocation_size(1, 1));
+            assert_eq!(Some(0), heap.allocation_order(64, 1));
+            assert_eq!(Some(2), heap.allocation_order(256, 1));
+
+            let block_64_0 = heap.allocate(8, 8);
+            assert_eq!(mem, block_64_0);
+            let block_64_1 = heap.allocate(8, 8);
+            assert_eq!(mem.offset(64), block_64_1);
+
+            heap.deallocate(block_64_0, 8, 8);
+            heap.deallocate(block_64_1, 8, 8);
+            assert_eq!(mem, heap.allocate(256, 256));
+
+            free(mem);
+        }
+    }
+
+    #[test]
+    fn test_free_blocks_per_order() {
+        unsafe {
+            let heap_size = 256;
+            let mem = memalign(4096, heap_size);
+            let mut heap = Heap::new(mem, heap_size);
+            assert_eq!(5, heap.order_count());
+
+            // We start with a single block the size of the heap.
+            assert_eq!([0, 0, 0, 0, 1],
+                       heap.free_blocks_per_order()[..5]);
+
+            // Allocating 16 bytes splits off one block of each smaller
+            // size.
+            let block_16_0 = heap.allocate(8, 8);
+            assert_eq!([1, 1, 1, 1, 0],
+                       heap.free_blocks_per_order()[..5]);
+            let block_16_1 = heap.allocate(8, 8);
+            assert_eq!([0, 1, 1, 1, 0],
+                       heap.free_blocks_per_order()[..5]);
+
+            // Freeing merges everything back together.
+            heap.deallocate(block_16_0, 8, 8);
+            assert_eq!([1, 1, 1, 1, 0],
+                       heap.free_blocks_per_order()[..5]);
+            heap.deallocate(block_16_1, 8, 8);
+            assert_eq!([0, 0, 0, 0, 1],
+                       heap.free_blocks_per_order()[..5]);
+
+            // Nothing ever shows up beyond `order_count`.
+            assert!(heap.free_blocks_per_order()[5..].iter().all(|&n| n == 0));
+
+            free(mem);
+        }
+    }
+
+    #[test]
+    fn test_largest_free_block() {
+        unsafe {
+            let heap_size = 256;
+            let mem = memalign(4096, heap_size);
+            let mut heap = Heap::new(mem, heap_size);
+            assert_eq!(256, heap.largest_free_block());
+
+            let block_16 = heap.allocate(8, 8);
+            assert_eq!(128, heap.largest_free_block());
+            let block_128 = heap.allocate(128, 8);
+            assert_eq!(64, heap.largest_free_block());
+            let block_64 = heap.allocate(64, 8);
+            assert_eq!(32, heap.largest_free_block());
+            let block_32 = heap.allocate(32, 8);
+            assert_eq!(16, heap.largest_free_block());
+            let block_16_1 = heap.allocate(16, 8);
+            assert_eq!(0, heap.largest_free_block());
+
+            heap.deallocate(block_16, 8, 8);
+            heap.deallocate(block_16_1, 16, 8);
+            assert_eq!(32, heap.largest_free_block());
+            heap.deallocate(block_32, 32, 8);
+            heap.deallocate(block_64, 64, 8);
+            heap.deallocate(block_128, 128, 8);
+            assert_eq!(256, heap.largest_free_block());
+
+            free(mem);
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        unsafe {
+            let heap_size = 256;
+            let mem = memalign(4096, heap_size);
+            let mut heap = Heap::new(mem, heap_size);
+
+            let block_16 = heap.allocate(10, 1);
+            let block_64 = heap.allocate(50, 1);
+            let too_big = heap.allocate(512, 1);
+            assert_eq!(ptr::null_mut(), too_big);
+
+            // Failed allocations aren't counted.
+            assert_eq!(2, heap.stats().total_requests());
+            assert_eq!(60, heap.stats().total_requested_bytes());
+            assert_eq!(80, heap.stats().total_granted_bytes());
+            assert_eq!(1, heap.stats().granted_by_order()[0]);
+            assert_eq!(1, heap.stats().granted_by_order()[2]);
+
+            heap.deallocate(block_16, 10, 1);
+            heap.deallocate(block_64, 50, 1);
+            free(mem);
+        }
+    }
+
+    /// Is `len` bytes at `ptr` all zero?
+    unsafe fn all_zero(ptr: *mut u8, len: usize) -> bool {
+        (0..len).all(|i| *ptr.offset(i as isize) == 0)
+    }
+
+    #[test]
+    fn test_allocate_zeroed() {
+        unsafe {
+            let heap_size = 256;
+            let mem = memalign(4096, heap_size);
+            ptr::write_bytes(mem, 0xAA, heap_size);
+            let mut heap = Heap::new(mem, heap_size);
+
+            // Nothing is clean yet, so we have to zero it ourselves.
+            let block = heap.allocate_zeroed(64, 8);
+            assert!(all_zero(block, 64));
+            assert_eq!((1, 0), heap.zeroed_allocations());
+
+            // Freed memory is dirty again.
+            ptr::write_bytes(block, 0xAA, 64);
+            heap.deallocate(block, 64, 8);
+            let block = heap.allocate_zeroed(64, 8);
+            assert!(all_zero(block, 64));
+            assert_eq!((2, 0), heap.zeroed_allocations());
+            heap.deallocate(block, 64, 8);
+
+            free(mem);
+        }
+    }
+
+    #[test]
+    fn test_scrub() {
+        unsafe {
+            let heap_size = 256;
+            let mem = memalign(4096, heap_size);
+            ptr::write_bytes(mem, 0xAA, heap_size);
+            let mut heap = Heap::new(mem, heap_size);
+            assert_eq!(1, heap.dirty_blocks_per_order()[4]);
+
+            // We scrub a bit at a time, skipping the header.
+            assert_eq!(100, heap.scrub(100));
+            assert_eq!(1, heap.dirty_blocks_per_order()[4]);
+            assert_eq!(140, heap.scrub(1000));
+            assert_eq!(0, heap.dirty_blocks_per_order()[4]);
+            assert_eq!(0, heap.scrub(1000));
+            assert!(all_zero(mem.offset(16), heap_size - 16));
+
+            // Splitting a clean block gives clean blocks, and we use them.
+            let block_64 = heap.allocate_zeroed(64, 8);
+            assert!(all_zero(block_64, 64));
+            assert_eq!((1, 1), heap.zeroed_allocations());
+            assert_eq!([0; 5], heap.dirty_blocks_per_order()[..5]);
+
+            // Dirty memory merges into a dirty block, which we can scrub
+            // again.
+            ptr::write_bytes(block_64, 0xAA, 64);
+            heap.deallocate(block_64, 64, 8);
+            assert_eq!(1, heap.dirty_blocks_per_order()[4]);
+            assert_eq!(240, heap.scrub(1000));
+            let block_256 = heap.allocate_zeroed(256, 8);
+            assert!(all_zero(block_256, 256));
+            assert_eq!((2, 2), heap.zeroed_allocations());
+            heap.deallocate(block_256, 256, 8);
+
+            free(mem);
+        }
+    }
+
+    #[test]
+    fn test_merge_keeps_zeroed_memory() {
+        unsafe {
+            let heap_size = 256;
+            let mem = memalign(4096, heap_size);
+            let mut heap = Heap::new(mem, heap_size);
+            while heap.scrub(1000) > 0 {}
+
+            // A minimum-size block is all header, so freeing it leaves
+            // everything clean.
+            let block_16 = heap.allocate(16, 8);
+            heap.deallocate(block_16, 16, 8);
+            assert_eq!([0; 5], heap.dirty_blocks_per_order()[..5]);
+
+            // A clean lower half carries on into a dirty upper half, as far
+            // as the upper half's header.
+            let lower = heap.allocate(128, 8);
+            let upper = heap.allocate(128, 8);
+            heap.deallocate(lower, 128, 8);
+            assert_eq!(112, heap.scrub(1000));
+            heap.deallocate(upper, 128, 8);
+            assert_eq!(1, heap.dirty_blocks_per_order()[4]);
+            assert_eq!(112, heap.scrub(1000));
+
+            free(mem);
+        }
+    }
+
+    #[test]
+    fn test_bytes_in_use_and_watermarks() {
+        unsafe {
+            let heap_size = 256;
+            let mem = memalign(4096, heap_size);
+            let mut heap = Heap::new(mem, heap_size);
+            assert_eq!(256, heap.size());
+            assert!(heap.set_watermarks(&[128, 64]).is_err());
+            heap.set_watermarks(&[128, 192]).unwrap();
+            assert_eq!(None, heap.take_watermark_change());
+
+            // We count whole blocks.
+            let block_128 = heap.allocate(100, 8);
+            assert_eq!(128, heap.bytes_in_use());
+            assert_eq!(Some((0, 1)), heap.take_watermark_change());
+            assert_eq!(None, heap.take_watermark_change());
+
+            let block_64 = heap.allocate(64, 8);
+            assert_eq!(192, heap.bytes_in_use());
+            assert_eq!(Some((1, 2)), heap.take_watermark_change());
+
+            // Crossing two at once is one change.
+            heap.deallocate(block_64, 64, 8);
+            heap.deallocate(block_128, 100, 8);
+            assert_eq!(0, heap.bytes_in_use());
+            assert_eq!(Some((2, 0)), heap.take_watermark_change());
+            let block_256 = heap.allocate(256, 8);
+            assert_eq!(Some((0, 2)), heap.take_watermark_change());
+            heap.deallocate(block_256, 256, 8);
+            assert_eq!(Some((2, 0)), heap.take_watermark_change());
+
+            free(mem);
+        }
+    }
+
+    #[test]
+    fn test_reallocate_in_place() {
+        unsafe {
+            let heap_size = 256;
+            let mem = memalign(4096, heap_size);
+            let mut heap = Heap::new(mem, heap_size);
+
+            // Anything within the same block size works.
+            let block = heap.allocate(100, 8);
+            assert!(heap.reallocate_in_place(block, 100, 128, 8));
+            assert!(heap.reallocate_in_place(block, 128, 65, 8));
+            assert_eq!(128, heap.bytes_in_use());
+
+            // Shrinking gives the tail back.
+            assert!(heap.reallocate_in_place(block, 65, 20, 8));
+            assert_eq!(32, heap.bytes_in_use());
+            assert_eq!([0, 1, 1, 1, 0], heap.free_blocks_per_order()[..5]);
+
+            // Growing into a bigger block doesn't work in place.
+            assert!(!heap.reallocate_in_place(block, 20, 33, 8));
+            assert_eq!(32, heap.bytes_in_use());
+
+            // Everything still merges back together.
+            heap.deallocate(block, 20, 8);
+            assert_eq!([0, 0, 0, 0, 1], heap.free_blocks_per_order()[..5]);
+
+            free(mem);
+        }
+    }
+
+    #[test]
+    fn test_allocate_with_header() {
+        unsafe {
+            let heap_size = 256;
+            let mem = memalign(4096, heap_size);
+            let mut heap = Heap::new(mem, heap_size);
+
+            // The header goes in front of the memory we return.
+            let small = heap.allocate_with_header(16, 1);
+            assert_eq!(mem.offset(16), small);
+            assert_eq!(32, heap.bytes_in_use());
+
+            // Big alignments push the memory forward by a whole `align`.
+            let aligned = heap.allocate_with_header(8, 64);
+            assert_eq!(mem.offset(192), aligned);
+            assert_eq!(160, heap.bytes_in_use());
+
+            // Requests which don't fit with their header fail.
+            assert_eq!(ptr::null_mut(), heap.allocate_with_header(256, 8));
+            assert_eq!(ptr::null_mut(), heap.allocate_with_header(!0, 8));
+
+            heap.deallocate_with_header(small);
+            heap.deallocate_with_header(aligned);
+            assert_eq!(0, heap.bytes_in_use());
+            assert_eq!(mem, heap.allocate(256, 256));
+
+            free(mem);
+        }
+    }
+
+    #[test]
+    fn test_big_alignments() {
+        unsafe {
+            // Put our heap 4K past a 64K boundary, so that it isn't aligned
+            // on 8K.
+            let mem = memalign(65536, 65536);
+            let base = mem.offset(4096);
+            let heap_size = 16384;
+            let mut heap = Heap::new(base, heap_size);
+            assert_eq!(None, heap.allocation_size(4096, 8192));
+
+            // We cut an aligned piece out of the whole heap, and give back
+            // what's on either side.
+            let block = heap.allocate(4096, 8192);
+            assert_eq!(base.offset(4096), block);
+            assert_eq!(4096, heap.bytes_in_use());
+            assert_eq!([1, 1, 0], heap.free_blocks_per_order()[8..11]);
+
+            // There's no aligned space left big enough for this.
+            assert_eq!(ptr::null_mut(), heap.allocate(8192, 8192));
+            assert!(!heap.reallocate_in_place(block, 4096, 16, 8192));
+
+            // Everything merges back together when we're done.
+            heap.deallocate(block, 4096, 8192);
+            assert_eq!(0, heap.bytes_in_use());
+            assert_eq!(1, heap.free_blocks_per_order()[10]);
+
+            // Pieces bigger than `align` work, too.
+            let block = heap.allocate(8192, 8192);
+            assert_eq!(base.offset(4096), block);
+            assert_eq!([2, 0, 0], heap.free_blocks_per_order()[8..11]);
+            heap.deallocate(block, 8192, 8192);
+            assert_eq!(1, heap.free_blocks_per_order()[10]);
+
+            // Stats see both the size we were asked for and the size of
+            // the piece we held on to.
+            let block = heap.allocate(4000, 8192);
+            heap.deallocate(block, 4000, 8192);
+            assert_eq!(4096 + 8192 + 4000, heap.stats().total_requested_bytes());
+            assert_eq!(4096 + 8192 + 4096, heap.stats().total_granted_bytes());
+
+            // If our heap happens to be aligned, we use ordinary blocks.
+            let mut aligned = Heap::new(mem, heap_size);
+            assert_eq!(Some(8192), aligned.allocation_size(4096, 8192));
+            assert_eq!(mem, aligned.allocate(4096, 8192));
+
+            free(mem);
+        }
+    }
+
+    #[test]
+    fn test_add_region() {
+        unsafe {
+            // Our first region needs to be a power of 2, but later ones
+            // can be any multiple of our block size.  Leave a gap between
+            // them, and trim off the odd bytes at the end.
+            let mem = memalign(4096, 16384);
+            let mut heap = Heap::new(mem, 256);
+            let second = mem.offset(4096);
+            assert_eq!(Ok(()), heap.add_region(second, 192 + 5));
+            assert_eq!(256 + 192, heap.size());
+            assert_eq!([0, 0, 1, 1, 1], heap.free_blocks_per_order()[0..5]);
+
+            // Overlapping or misaligned regions are rejected.
+            assert!(heap.add_region(mem.offset(4096 + 128), 4096).is_err());
+            assert!(heap.add_region(mem.offset(8192 - 4096), 8192).is_err());
+            assert!(heap.add_region(mem.offset(8192 + 16), 256).is_err());
+            assert!(heap.add_region(mem.offset(8192), 15).is_err());
+
+            // We allocate from whichever region has a block.
+            let a = heap.allocate(256, 16);
+            assert_eq!(mem, a);
+            let b = heap.allocate(128, 16);
+            assert_eq!(second, b);
+            let c = heap.allocate(64, 16);
+            assert_eq!(second.offset(128), c);
+            assert_eq!(ptr::null_mut(), heap.allocate(16, 16));
+
+            // The 64-byte block at the end of the second region has no
+            // buddy, so it doesn't merge with anything past its end.
+            heap.deallocate(c, 64, 16);
+            heap.deallocate(b, 128, 16);
+            assert_eq!([0, 0, 1, 1, 0], heap.free_blocks_per_order()[0..5]);
+
+            // Blocks inside a region still merge normally.
+            let d = heap.allocate(16, 16);
+            assert_eq!(second.offset(128), d);
+            heap.deallocate(d, 16, 16);
+            heap.deallocate(a, 256, 16);
+            assert_eq!([0, 0, 1, 1, 1], heap.free_blocks_per_order()[0..5]);
+            assert_eq!(0, heap.bytes_in_use());
+
+            // A region bigger than our heap gives us bigger blocks.
+            assert_eq!(Ok(()), heap.add_region(mem.offset(8192), 8192));
+            assert_eq!(mem.offset(8192), heap.allocate(8192, 4096));
+
+            free(mem);
+        }
+    }
+
+    #[test]
+    fn test_big_alignments_with_regions() {
+        unsafe {
+            // Start with an aligned heap, and allocate an ordinary block
+            // with a big alignment from it.
+            let mem = memalign(65536, 65536);
+            let mut heap = Heap::new(mem, 16384);
+            let block = heap.allocate(4096, 8192);
+            assert_eq!(mem, block);
+            assert_eq!(8192, heap.bytes_in_use());
+
+            // Adding a region which isn't aligned on 8K doesn't change how
+            // we free memory from the first region.
+            let misaligned = mem.offset(32768 + 4096);
+            assert_eq!(Ok(()), heap.add_region(misaligned, 16384));
+            heap.deallocate(block, 4096, 8192);
+            assert_eq!(0, heap.bytes_in_use());
+
+            // Big alignments now need trimming, but whichever region the
+            // memory comes from, it all goes back.
+            let a = heap.allocate(4096, 8192);
+            let b = heap.allocate(4096, 8192);
+            assert!(!a.is_null() && !b.is_null());
+            assert_eq!(0, a as usize & 8191);
+            assert_eq!(0, b as usize & 8191);
+            heap.deallocate(a, 4096, 8192);
+            heap.deallocate(b, 4096, 8192);
+            assert_eq!(0, heap.bytes_in_use());
+            assert_eq!([0, 0, 2], heap.free_blocks_per_order()[8..11]);
+
+            free(mem);
+        }
+    }
+
+    #[test]
+    fn test_try_allocate() {
+        unsafe {
+            let heap_size = 256;
+            let mem = memalign(4096, heap_size);
+            let mut heap = Heap::new(mem, heap_size);
+
+            assert_eq!(Err(AllocError::UnsupportedAlignment),
+                       heap.try_allocate(16, 3));
+            assert_eq!(Err(AllocError::TooLarge), heap.try_allocate(512, 16));
+            assert_eq!(Err(AllocError::TooLarge), heap.try_allocate(16, 512));
+
+            let block = heap.try_allocate(256, 16);
+            assert_eq!(Ok(mem), block);
+            assert_eq!(Err(AllocError::OutOfMemory), heap.try_allocate(16, 16));
+
+            heap.deallocate(mem, 256, 16);
+            free(mem);
+        }
+    }
+
+    /// The memory `grow_from_spare` hands out, 8K at a time, how many
+    /// regions it has left, and the last request it got.  Only
+    /// `test_grow_handler` uses these.
+    static mut SPARE: *mut u8 = 0 as *mut u8;
+    static mut SPARE_REGIONS: usize = 0;
+    static mut LAST_GROW: usize = 0;
+
+    fn grow_from_spare(size: usize, align: usize) -> Option<(*mut u8, usize)> {
+        assert_eq!(4096, align);
+        unsafe {
+            LAST_GROW = size;
+            if SPARE_REGIONS == 0 {
+                return None;
+            }
+            let spare = SPARE;
+            SPARE = SPARE.offset(8192);
+            SPARE_REGIONS -= 1;
+            Some((spare, 8192))
+        }
+    }
+
+    fn last_grow() -> usize {
+        unsafe { LAST_GROW }
+    }
+
+    #[test]
+    fn test_grow_handler() {
+        unsafe {
+            let mem = memalign(4096, 32768);
+            let mut heap = Heap::new(mem, 256);
+            heap.set_grow_handler(Some(grow_from_spare));
+
+            // We don't bother the handler when we have memory.
+            SPARE = mem.offset(8192);
+            SPARE_REGIONS = 2;
+            let a = heap.allocate(256, 16);
+            assert_eq!(mem, a);
+            assert_eq!(0, last_grow());
+
+            // When we run out, it gives us a new region, which can be
+            // bigger than anything we had before.
+            let b = heap.allocate(1000, 16);
+            assert_eq!(mem.offset(8192), b);
+            assert_eq!(1024, last_grow());
+            assert_eq!(256 + 8192, heap.size());
+
+            // We can grow again later.
+            let c = heap.allocate(8192, 16);
+            assert_eq!(mem.offset(16384), c);
+            assert_eq!(8192, last_grow());
+            assert_eq!(256 + 2 * 8192, heap.size());
+
+            // Once it runs out, so do we.
+            assert_eq!(ptr::null_mut(), heap.allocate(8192, 16));
+            assert_eq!(8192, last_grow());
+
+            heap.deallocate(c, 8192, 16);
+            heap.deallocate(b, 1000, 16);
+            heap.deallocate(a, 256, 16);
+            assert_eq!(0, heap.bytes_in_use());
+            free(mem);
+        }
+    }
+
+    /// The memory `grow_too_little` hands out, 256 bytes at a time, and
+    /// how many times it's been called.  Only `test_grow_too_little` uses
+    /// these.
+    static mut LITTLE: *mut u8 = 0 as *mut u8;
+    static mut LITTLE_CALLS: usize = 0;
+
+    fn grow_too_little(_size: usize, _align: usize) -> Option<(*mut u8, usize)> {
+        unsafe {
+            let little = LITTLE.offset(LITTLE_CALLS as isize * 4096);
+            LITTLE_CALLS += 1;
+            Some((little, 256))
+        }
+    }
+
+    #[test]
+    fn test_grow_too_little() {
+        unsafe {
+            let mem = memalign(4096, 4096 * MAX_REGIONS);
+            let mut heap = Heap::new(mem, 256);
+            heap.set_grow_handler(Some(grow_too_little));
+            LITTLE = mem.offset(4096);
+
+            // If the handler's regions are too small, we keep asking
+            // until we can't take any more.  Then we give up, but we keep
+            // what we were given.
+            assert_eq!(Err(AllocError::TooLarge), heap.try_allocate(1024, 16));
+            assert_eq!(MAX_REGIONS - 1, LITTLE_CALLS);
+            assert_eq!(256 * MAX_REGIONS, heap.size());
+
+            // Every region we were given is usable, and we don't call the
+            // handler again once we're full.
+            for _ in 0..MAX_REGIONS {
+                assert!(!heap.allocate(256, 16).is_null());
+            }
+            assert_eq!(ptr::null_mut(), heap.allocate(16, 16));
+            assert_eq!(MAX_REGIONS - 1, LITTLE_CALLS);
+            free(mem);
+        }
+    }
+
     #[test]
     fn test_buddy() {
         unsafe {
             let heap_size = 256;
             let mem = memalign(4096, heap_size);
-            let mut free_lists: [*mut FreeBlock; 5] = [0 as *mut _; 5];
-            let heap = Heap::new(mem, heap_size, &mut free_lists);
+            let heap = Heap::new(mem, heap_size);
 
             let block_16_0 = mem;
             let block_16_1 = mem.offset(16);
@@ -430,8 +1724,7 @@ mod test {
         unsafe {
             let heap_size = 256;
             let mem = memalign(4096, heap_size);
-            let mut free_lists: [*mut FreeBlock; 5] = [0 as *mut _; 5];
-            let mut heap = Heap::new(mem, heap_size, &mut free_lists);
+            let mut heap = Heap::new(mem, heap_size);
 
             let block_16_0 = heap.allocate(8, 8);
             assert_eq!(mem, block_16_0);
@@ -4,49 +4,443 @@
 
 use core::cmp::min;
 use core::ptr;
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
 use spin::Mutex;
 
 use heap::*;
+use stats::AllocStats;
+#[cfg(feature = "timing")]
+use timing::{AllocTiming, OrderTiming, ALLOC_TIMING_INIT};
 
-/// Either our global system heap, or `None` if it hasn't been allocated
-/// yet.
-static HEAP: Mutex<Option<Heap<'static>>> = Mutex::new(None);
+/// The maximum number of heaps we can manage.
+pub const MAX_HEAPS: usize = 4;
 
-pub unsafe fn initialize_allocator(
-    heap_base: *mut u8,
-    heap_size: usize,
-    free_lists: &'static mut [*mut FreeBlock])
+/// Identifies one of our heaps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeapId(usize);
+
+/// The general-purpose heap, which Rust's allocator uses.
+pub const GENERAL_HEAP: HeapId = HeapId(0);
+
+/// All our heaps.  Entries are `None` until they're set up.
+struct Heaps {
+    heaps: [Option<Heap>; MAX_HEAPS],
+    names: [&'static str; MAX_HEAPS],
+}
+
+static HEAPS: Mutex<Heaps> = Mutex::new(Heaps {
+    heaps: [None, None, None, None],
+    names: [""; MAX_HEAPS],
+});
+
+/// Set while `HEAPS` is locked.  If we panic inside the allocator, this
+/// stays set, which lets panic handlers avoid deadlocking on `HEAPS`.
+static HEAP_BUSY: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Called when a heap crosses one of its watermarks, with the heap and
+/// the old and new `Heap::watermark_level`.
+pub type WatermarkHandler = fn(HeapId, usize, usize);
+
+static WATERMARK_HANDLER: Mutex<Option<WatermarkHandler>> = Mutex::new(None);
+
+/// What `timed` is doing to a heap.
+#[derive(Clone, Copy)]
+enum Operation {
+    Allocate,
+    Deallocate,
+}
+
+/// Our allocation timings, and the clock we measure them with.
+#[cfg(feature = "timing")]
+struct Timing {
+    clock: Option<fn() -> u64>,
+    heaps: [AllocTiming; MAX_HEAPS],
+}
+
+#[cfg(feature = "timing")]
+static TIMING: Mutex<Timing> = Mutex::new(Timing {
+    clock: None,
+    heaps: [ALLOC_TIMING_INIT; MAX_HEAPS],
+});
+
+/// Run `f` on `heap`, which is the heap `id`, to allocate or deallocate a
+/// block for `size` and `align`.  If we've been given a clock, record how
+/// long `f` took.
+#[cfg(feature = "timing")]
+fn timed<R, F>(id: HeapId, heap: &mut Heap, operation: Operation, size: usize,
+               align: usize, f: F)
+    -> R
+    where F: FnOnce(&mut Heap) -> R
+{
+    let mut timing = TIMING.lock();
+    let (clock, order) = match (timing.clock, heap.allocation_order(size, align)) {
+        (Some(clock), Some(order)) => (clock, order),
+        _ => return f(heap),
+    };
+    let start = clock();
+    let result = f(heap);
+    let cycles = clock().wrapping_sub(start);
+    match operation {
+        Operation::Allocate => timing.heaps[id.0].record_allocate(order, cycles),
+        Operation::Deallocate => timing.heaps[id.0].record_deallocate(order, cycles),
+    }
+    result
+}
+
+/// Without the `timing` feature, just run `f`.
+#[cfg(not(feature = "timing"))]
+#[inline(always)]
+fn timed<R, F>(_id: HeapId, heap: &mut Heap, _operation: Operation,
+               _size: usize, _align: usize, f: F)
+    -> R
+    where F: FnOnce(&mut Heap) -> R
+{
+    f(heap)
+}
+
+/// Lock our heaps and run `f` on them.  Once we've unlocked them, we tell
+/// the watermark handler about any watermarks `f` made us cross.
+fn with_heaps<R, F: FnOnce(&mut Heaps) -> R>(f: F) -> R {
+    let mut changes = [None; MAX_HEAPS];
+    let result = {
+        let mut heaps = HEAPS.lock();
+        HEAP_BUSY.store(true, Ordering::SeqCst);
+        let result = f(&mut heaps);
+        for (change, heap) in changes.iter_mut().zip(heaps.heaps.iter_mut()) {
+            *change = heap.as_mut().and_then(|heap| heap.take_watermark_change());
+        }
+        HEAP_BUSY.store(false, Ordering::SeqCst);
+        result
+    };
+    if changes.iter().any(|change| change.is_some()) {
+        let handler = *WATERMARK_HANDLER.lock();
+        if let Some(handler) = handler {
+            for (index, change) in changes.iter().enumerate() {
+                if let Some((old, new)) = *change {
+                    handler(HeapId(index), old, new);
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Lock our heaps and run `f` on the general-purpose heap.
+fn with_heap<R, F: FnOnce(&mut Option<Heap>) -> R>(f: F) -> R {
+    with_heaps(|heaps| f(&mut heaps.heaps[GENERAL_HEAP.0]))
+}
+
+/// Set up our global system heap.  The requirements on `heap_base` and
+/// `heap_size` are the same as for `Heap::new`.
+pub unsafe fn initialize_allocator(heap_base: *mut u8, heap_size: usize) {
+    with_heaps(|heaps| {
+        heaps.heaps[GENERAL_HEAP.0] = Some(Heap::new(heap_base, heap_size));
+        heaps.names[GENERAL_HEAP.0] = "general";
+    });
+}
+
+/// Add a heap for memory with special placement requirements, such as
+/// ISA DMA buffers, which must lie below 16MB.  Rust's allocator never
+/// uses this heap; allocate from it with `allocate_from`.
+///
+/// The heap must lie entirely below `max_address`, or we return an
+/// error.  The requirements on `heap_base` and `heap_size` are the same as
+/// for `Heap::new`.
+pub unsafe fn add_heap(name: &'static str, heap_base: *mut u8,
+                       heap_size: usize, max_address: usize)
+    -> Result<HeapId, &'static str>
 {
-    let mut heap = HEAP.lock();
-    *heap = Some(Heap::new(heap_base, heap_size, free_lists));
+    if heap_base as usize + heap_size > max_address {
+        return Err("heap doesn't meet its placement constraint");
+    }
+    with_heaps(|heaps| {
+        match heaps.heaps.iter().position(|h| h.is_none()) {
+            Some(index) if index != GENERAL_HEAP.0 => {
+                heaps.heaps[index] = Some(Heap::new(heap_base, heap_size));
+                heaps.names[index] = name;
+                Ok(HeapId(index))
+            }
+            Some(_) => Err("must call initialize_allocator first"),
+            None => Err("too many heaps"),
+        }
+    })
+}
+
+/// Give the heap `id` another region of memory, such as a range of RAM
+/// reported by the bootloader.  The requirements are the same as for
+/// `Heap::add_region`, and we don't check the heap's placement constraint,
+/// so that's up to the caller.
+pub unsafe fn add_region_to(id: HeapId, base: *mut u8, size: usize)
+    -> Result<(), &'static str>
+{
+    with_heaps(|heaps| {
+        match heaps.heaps[id.0] {
+            Some(ref mut heap) => heap.add_region(base, size),
+            None => Err("no such heap"),
+        }
+    })
+}
+
+/// Call `handler` when the heap `id` runs out of memory, to ask for
+/// another region.  It runs with our heaps locked, so it mustn't allocate
+/// memory itself.
+pub fn set_grow_handler(id: HeapId, handler: GrowHandler) {
+    with_heaps(|heaps| {
+        heaps.heaps[id.0].as_mut()
+            .expect("Must call initialize_allocator before setting a grow handler")
+            .set_grow_handler(Some(handler));
+    })
+}
+
+/// Watch for the heap `id` crossing each of `levels` bytes in use, which
+/// must be in increasing order.
+pub fn set_watermarks(id: HeapId, levels: &[usize]) -> Result<(), &'static str> {
+    with_heaps(|heaps| {
+        match heaps.heaps[id.0] {
+            Some(ref mut heap) => heap.set_watermarks(levels),
+            None => Err("no such heap"),
+        }
+    })
+}
+
+/// Call `handler` whenever a heap crosses one of its watermarks.  It runs
+/// in whatever context made the allocation that crossed it, so it should
+/// just take note, and not allocate memory itself.
+pub fn set_watermark_handler(handler: WatermarkHandler) {
+    *WATERMARK_HANDLER.lock() = Some(handler);
+}
+
+/// The number of bytes in use in the heap `id`, and its size, or zeros if
+/// it doesn't exist.
+pub fn usage_in(id: HeapId) -> (usize, usize) {
+    with_heaps(|heaps| {
+        heaps.heaps[id.0].as_ref()
+            .map(|heap| (heap.bytes_in_use(), heap.size()))
+            .unwrap_or((0, 0))
+    })
+}
+
+/// Look up a heap by the name it was given in `add_heap`.
+pub fn find_heap(name: &str) -> Option<HeapId> {
+    with_heaps(|heaps| {
+        (0..MAX_HEAPS)
+            .find(|&i| heaps.heaps[i].is_some() && heaps.names[i] == name)
+            .map(HeapId)
+    })
+}
+
+/// Allocate `size` bytes from the heap `id`, returning null if we can't.
+pub unsafe fn allocate_from(id: HeapId, size: usize, align: usize)
+    -> *mut u8
+{
+    with_heaps(|heaps| {
+        match heaps.heaps[id.0] {
+            Some(ref mut heap) => {
+                timed(id, heap, Operation::Allocate, size, align,
+                      |heap| heap.allocate(size, align))
+            }
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+/// Like `allocate_from`, but tells us why it failed.  A heap which
+/// doesn't exist has no memory.
+pub unsafe fn try_allocate_from(id: HeapId, size: usize, align: usize)
+    -> Result<*mut u8, AllocError>
+{
+    with_heaps(|heaps| {
+        match heaps.heaps[id.0] {
+            Some(ref mut heap) => {
+                timed(id, heap, Operation::Allocate, size, align,
+                      |heap| heap.try_allocate(size, align))
+            }
+            None => Err(AllocError::OutOfMemory),
+        }
+    })
+}
+
+/// Like `allocate_from`, but the first `size` bytes are zero.  This is
+/// cheap if `scrub` has already zeroed some free memory.
+pub unsafe fn allocate_zeroed_from(id: HeapId, size: usize, align: usize)
+    -> *mut u8
+{
+    with_heaps(|heaps| {
+        match heaps.heaps[id.0] {
+            Some(ref mut heap) => {
+                timed(id, heap, Operation::Allocate, size, align,
+                      |heap| heap.allocate_zeroed(size, align))
+            }
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+/// Zero up to `budget` bytes of free memory in whichever of our heaps
+/// needs it, so that later calls to `allocate_zeroed_from` are cheap.
+/// This holds the heap lock while it works, so keep `budget` small.
+/// Returns the number of bytes zeroed, which is 0 once every heap is
+/// clean.
+pub fn scrub(budget: usize) -> usize {
+    with_heaps(|heaps| {
+        for heap in heaps.heaps.iter_mut().filter_map(|h| h.as_mut()) {
+            let zeroed = unsafe { heap.scrub(budget) };
+            if zeroed > 0 {
+                return zeroed;
+            }
+        }
+        0
+    })
+}
+
+/// How many calls to `allocate_zeroed_from` the heap `id` has handled,
+/// and how many found memory that `scrub` had already zeroed.
+pub fn zeroed_allocations_in(id: HeapId) -> (usize, usize) {
+    with_heaps(|heaps| {
+        heaps.heaps[id.0].as_ref()
+            .map(|heap| heap.zeroed_allocations())
+            .unwrap_or((0, 0))
+    })
+}
+
+/// Like `allocate_from`, but the memory can be returned with
+/// `deallocate_with_header_to` without knowing its size or alignment.
+pub unsafe fn allocate_with_header_from(id: HeapId, size: usize, align: usize)
+    -> *mut u8
+{
+    with_heaps(|heaps| {
+        match heaps.heaps[id.0] {
+            Some(ref mut heap) => heap.allocate_with_header(size, align),
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+/// Return memory allocated by `allocate_with_header_from` to the heap
+/// `id`.
+pub unsafe fn deallocate_with_header_to(id: HeapId, ptr: *mut u8) {
+    with_heaps(|heaps| {
+        heaps.heaps[id.0].as_mut()
+            .expect("Trying to deallocate to a heap that doesn't exist")
+            .deallocate_with_header(ptr)
+    })
+}
+
+/// Return memory allocated by `allocate_from` or `allocate_zeroed_from` to
+/// the heap `id`.  `size`
+/// and `align` must be the same as when it was allocated.
+pub unsafe fn deallocate_to(id: HeapId, ptr: *mut u8, size: usize,
+                            align: usize) {
+    with_heaps(|heaps| {
+        let heap = heaps.heaps[id.0].as_mut()
+            .expect("Trying to deallocate to a heap that doesn't exist");
+        timed(id, heap, Operation::Deallocate, size, align,
+              |heap| heap.deallocate(ptr, size, align))
+    })
+}
+
+/// The number of free blocks of each order in the heap `id`, or all zeros
+/// if it doesn't exist.
+pub fn free_blocks_per_order_in(id: HeapId) -> [usize; MAX_ORDERS] {
+    with_heaps(|heaps| {
+        heaps.heaps[id.0].as_ref()
+            .map(|heap| heap.free_blocks_per_order())
+            .unwrap_or([0; MAX_ORDERS])
+    })
+}
+
+/// The number of free blocks of each order in our global heap, or all
+/// zeros if it hasn't been set up.  This only holds the heap lock long
+/// enough to copy the counts.
+pub fn free_blocks_per_order() -> [usize; MAX_ORDERS] {
+    with_heap(|heap| {
+        heap.as_ref()
+            .map(|heap| heap.free_blocks_per_order())
+            .unwrap_or([0; MAX_ORDERS])
+    })
+}
+
+/// The size of the largest block our global heap could allocate right
+/// now, or 0 if it hasn't been set up.
+pub fn largest_free_block() -> usize {
+    with_heap(|heap| {
+        heap.as_ref().map(|heap| heap.largest_free_block()).unwrap_or(0)
+    })
+}
+
+/// A copy of the allocation statistics for our global heap, or `None` if
+/// it hasn't been set up.
+pub fn allocation_stats() -> Option<AllocStats> {
+    with_heap(|heap| heap.as_ref().map(|heap| *heap.stats()))
+}
+
+/// Start timing allocations using `clock`, which should count something
+/// fine-grained and cheap to read, such as TSC cycles.
+#[cfg(feature = "timing")]
+pub fn set_cycle_counter(clock: fn() -> u64) {
+    TIMING.lock().clock = Some(clock);
+}
+
+/// How long allocating and deallocating blocks of `order` in the heap
+/// `id` has taken.
+#[cfg(feature = "timing")]
+pub fn timing_in(id: HeapId, order: usize) -> (OrderTiming, OrderTiming) {
+    let timing = TIMING.lock();
+    let heap = &timing.heaps[id.0];
+    (*heap.allocate(order), *heap.deallocate(order))
+}
+
+/// Forget all our allocation timings.
+#[cfg(feature = "timing")]
+pub fn reset_timing() {
+    for heap in TIMING.lock().heaps.iter_mut() {
+        heap.reset();
+    }
+}
+
+/// Like `free_blocks_per_order`, but returns `None` instead of waiting if
+/// the heap is locked.  This is meant for panic handlers running with
+/// interrupts disabled on a single CPU, where the heap can only be locked
+/// if we panicked inside the allocator.
+pub fn try_free_blocks_per_order() -> Option<[usize; MAX_ORDERS]> {
+    if HEAP_BUSY.load(Ordering::SeqCst) {
+        None
+    } else {
+        Some(free_blocks_per_order())
+    }
 }
 
 #[no_mangle]
 pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
-    unsafe {
-        HEAP.lock().as_mut()
-            .expect("Must call initialize_allocator before allocating on heap")
-            .allocate(size, align)
-    }
+    with_heap(|heap| unsafe {
+        let heap = heap.as_mut()
+            .expect("Must call initialize_allocator before allocating on heap");
+        timed(GENERAL_HEAP, heap, Operation::Allocate, size, align,
+              |heap| heap.allocate(size, align))
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn __rust_deallocate(ptr: *mut u8, old_size: usize, align: usize) {
-    unsafe {
-        HEAP.lock().as_mut()
-            .expect("Trying to deallocate before heap is initialized")
-            .deallocate(ptr, old_size, align)
-    }
+    with_heap(|heap| unsafe {
+        let heap = heap.as_mut()
+            .expect("Trying to deallocate before heap is initialized");
+        timed(GENERAL_HEAP, heap, Operation::Deallocate, old_size, align,
+              |heap| heap.deallocate(ptr, old_size, align))
+    })
 }
 
 /// Attempt to resize an existing block of memory, preserving as much data
-/// as possible.  For now, we always just allocate new memory, copy data,
-/// and deallocate the old memory.
+/// as possible.  If we can't resize it in place, we allocate new memory,
+/// copy data, and deallocate the old memory.
 #[no_mangle]
 pub extern "C" fn __rust_reallocate(
     ptr: *mut u8, old_size: usize, size: usize, align: usize)
     -> *mut u8
 {
+    if __rust_reallocate_inplace(ptr, old_size, size, align) == size {
+        return ptr;
+    }
     let new_ptr = __rust_allocate(size, align);
     if new_ptr.is_null() {
         return new_ptr;
@@ -57,13 +451,20 @@ pub extern "C" fn __rust_reallocate(
     }
 }
 
-/// We do not support in-place reallocation, so just return `old_size`.
+/// Resize a block without moving it, if the new size fits in the same
+/// block (or a smaller one).  Returns `size` if we succeeded, or
+/// `old_size` if the block couldn't change.
 #[no_mangle]
 pub extern "C" fn __rust_reallocate_inplace(
-    _ptr: *mut u8, old_size: usize, _size: usize, _align: usize)
+    ptr: *mut u8, old_size: usize, size: usize, align: usize)
     -> usize
 {
-    old_size
+    let resized = with_heap(|heap| unsafe {
+        heap.as_mut()
+            .expect("Trying to reallocate before heap is initialized")
+            .reallocate_in_place(ptr, old_size, size, align)
+    });
+    if resized { size } else { old_size }
 }
 
 /// I have no idea what this actually does, but we're supposed to have one,
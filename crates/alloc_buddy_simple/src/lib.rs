@@ -17,10 +17,24 @@ extern crate spin;
 
 #[cfg(feature = "use-as-rust-allocator")]
 pub use integration::*;
-pub use heap::{Heap, FreeBlock};
+#[cfg(feature = "global-alloc")]
+pub use global_alloc::LockedHeap;
+pub use heap::{Heap, AllocError, FreeBlock, GrowHandler, MIN_BLOCK_SIZE,
+               MAX_ORDERS, MAX_REGIONS, MAX_WATERMARKS};
+pub use stats::{AllocStats, SIZE_CLASSES};
+#[cfg(feature = "timing")]
+pub use timing::{AllocTiming, OrderTiming, ALLOC_TIMING_INIT, ORDER_TIMING_INIT,
+                 TIMING_BUCKETS};
 
 mod math;
 mod heap;
+mod stats;
 
 #[cfg(feature = "use-as-rust-allocator")]
 mod integration;
+
+#[cfg(feature = "global-alloc")]
+mod global_alloc;
+
+#[cfg(feature = "timing")]
+mod timing;
@@ -0,0 +1,189 @@
+//! Statistics about the allocations a `Heap` has handled, so that we can
+//! see how much memory we lose to rounding up, and pick a better minimum
+//! block size for the workload we actually have.
+
+use core::cmp::max;
+
+use heap::MAX_ORDERS;
+use math::PowersOf2;
+
+/// The number of request size classes we track.  Class `k` holds requests
+/// which round up to `2^k` bytes, and the last class also holds anything
+/// bigger.
+pub const SIZE_CLASSES: usize = 32;
+
+/// How close to the minimum possible waste a suggested minimum block size
+/// must come, in percent.  We'd rather have a few percent more waste than
+/// lots of tiny block orders to search through.
+const SUGGESTION_TOLERANCE_PERCENT: usize = 5;
+
+/// Cumulative allocation statistics for a heap.
+pub struct AllocStats {
+    /// The number of requests in each size class.
+    requests: [usize; SIZE_CLASSES],
+    /// The total number of bytes requested in each size class.
+    requested_bytes: [usize; SIZE_CLASSES],
+    /// The number of blocks we've handed out of each order.
+    granted: [usize; MAX_ORDERS],
+    /// The total size of the blocks we've handed out.
+    granted_bytes: usize,
+}
+
+// We can't `#[derive]` these, because the standard library doesn't
+// implement `Clone` for arrays this large.
+impl Copy for AllocStats {}
+impl Clone for AllocStats {
+    fn clone(&self) -> AllocStats { *self }
+}
+
+/// The size class of a request for `size` bytes.
+fn size_class(size: usize) -> usize {
+    let class = size.next_power_of_2().log2() as usize;
+    if class < SIZE_CLASSES { class } else { SIZE_CLASSES - 1 }
+}
+
+impl AllocStats {
+    /// Create an empty set of statistics.
+    pub fn new() -> AllocStats {
+        AllocStats {
+            requests: [0; SIZE_CLASSES],
+            requested_bytes: [0; SIZE_CLASSES],
+            granted: [0; MAX_ORDERS],
+            granted_bytes: 0,
+        }
+    }
+
+    /// Record a request for `requested` bytes, which we satisfied with a
+    /// block of order `order` and size `granted`.
+    pub fn record(&mut self, requested: usize, order: usize, granted: usize) {
+        let class = size_class(requested);
+        self.requests[class] += 1;
+        self.requested_bytes[class] += requested;
+        self.granted[order] += 1;
+        self.granted_bytes += granted;
+    }
+
+    /// The number of requests in each size class.  Entry `k` counts
+    /// requests for `2^(k-1)+1` through `2^k` bytes.
+    pub fn requests_by_size_class(&self) -> &[usize; SIZE_CLASSES] {
+        &self.requests
+    }
+
+    /// The number of blocks we've handed out of each order.
+    pub fn granted_by_order(&self) -> &[usize; MAX_ORDERS] {
+        &self.granted
+    }
+
+    /// The total number of allocations we've recorded.
+    pub fn total_requests(&self) -> usize {
+        self.requests.iter().fold(0, |sum, &n| sum + n)
+    }
+
+    /// The total number of bytes requested.
+    pub fn total_requested_bytes(&self) -> usize {
+        self.requested_bytes.iter().fold(0, |sum, &n| sum + n)
+    }
+
+    /// The total size of all the blocks we handed out.
+    pub fn total_granted_bytes(&self) -> usize {
+        self.granted_bytes
+    }
+
+    /// Bytes lost to internal fragmentation: the difference between what
+    /// was asked for and what we handed out.
+    pub fn internal_fragmentation(&self) -> usize {
+        self.granted_bytes - self.total_requested_bytes()
+    }
+
+    /// Estimate the bytes we would have lost to internal fragmentation
+    /// with a minimum block size of `min_block_size`.  This ignores
+    /// alignment requests, which can only make things worse.
+    pub fn estimated_waste(&self, min_block_size: usize) -> usize {
+        let mut granted = 0;
+        for class in 0..SIZE_CLASSES {
+            let block = max(1 << class, min_block_size);
+            // The last class also holds requests bigger than `block`, so
+            // charge at least what they asked for.
+            granted += max(self.requests[class] * block,
+                           self.requested_bytes[class]);
+        }
+        granted - self.total_requested_bytes()
+    }
+
+    /// Suggest a minimum block size for this workload: the largest power
+    /// of 2 from `smallest` up to `largest` whose estimated waste is
+    /// within a few percent of the best we could do.  `smallest` should be
+    /// at least `size_of::<FreeBlock>()`.
+    pub fn suggest_min_block_size(&self, smallest: usize, largest: usize)
+        -> usize
+    {
+        let best = self.estimated_waste(smallest);
+        let limit = best + self.total_requested_bytes()
+            * SUGGESTION_TOLERANCE_PERCENT / 100;
+        let mut suggestion = smallest;
+        let mut candidate = smallest;
+        while candidate <= largest {
+            if self.estimated_waste(candidate) <= limit {
+                suggestion = candidate;
+            }
+            candidate *= 2;
+        }
+        suggestion
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record_and_waste() {
+        let mut stats = AllocStats::new();
+        stats.record(10, 0, 16);
+        stats.record(16, 0, 16);
+        stats.record(100, 3, 128);
+
+        assert_eq!(3, stats.total_requests());
+        assert_eq!(2, stats.requests_by_size_class()[4]);
+        assert_eq!(1, stats.requests_by_size_class()[7]);
+        assert_eq!(2, stats.granted_by_order()[0]);
+        assert_eq!(126, stats.total_requested_bytes());
+        assert_eq!(160, stats.total_granted_bytes());
+        assert_eq!(34, stats.internal_fragmentation());
+
+        // Our estimate matches reality for the block size we used, and
+        // gets worse with bigger blocks.
+        assert_eq!(34, stats.estimated_waste(16));
+        assert_eq!((64 - 10) + (64 - 16) + (128 - 100),
+                   stats.estimated_waste(64));
+    }
+
+    #[test]
+    fn test_waste_with_huge_requests() {
+        // This lands in our last size class, but it's bigger than that
+        // class's block size.
+        let huge = (1 << (SIZE_CLASSES - 1)) + 1;
+        let mut stats = AllocStats::new();
+        stats.record(huge, 0, 2 * (huge - 1));
+        stats.record(10, 0, 16);
+        assert_eq!(1, stats.requests_by_size_class()[SIZE_CLASSES - 1]);
+        assert_eq!(6, stats.estimated_waste(16));
+    }
+
+    #[test]
+    fn test_suggest_min_block_size() {
+        // Lots of 100-byte allocations: blocks up to 128 bytes cost
+        // nothing extra.
+        let mut stats = AllocStats::new();
+        for _ in 0..10 {
+            stats.record(100, 3, 128);
+        }
+        assert_eq!(128, stats.suggest_min_block_size(16, 4096));
+
+        // Add lots of tiny ones, and we want small blocks again.
+        for _ in 0..100 {
+            stats.record(8, 0, 16);
+        }
+        assert_eq!(16, stats.suggest_min_block_size(16, 4096));
+    }
+}
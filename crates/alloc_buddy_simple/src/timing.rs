@@ -0,0 +1,196 @@
+//! How long allocations take, so that we can measure the cost of
+//! searching the free lists and merging buddies instead of guessing.  This
+//! is only built with the `timing` feature.
+//!
+//! We don't know how to read a clock ourselves, so we just record whatever
+//! numbers our caller gives us.  In a kernel, these will usually be TSC
+//! cycles.
+
+use core::cmp::{max, min};
+
+use heap::MAX_ORDERS;
+
+/// The number of histogram buckets we keep.  Bucket `k` counts times from
+/// `2^k` up to (but not including) `2^(k+1)` cycles, except that bucket 0
+/// also counts 0, and the last bucket counts everything bigger.
+pub const TIMING_BUCKETS: usize = 24;
+
+/// Timings for one kind of operation.
+#[derive(Clone, Copy, Debug)]
+pub struct OrderTiming {
+    /// How many times we've been called.
+    count: usize,
+    /// The total time we've taken.
+    total: u64,
+    /// The fastest and slowest times we've seen.
+    min: u64,
+    max: u64,
+    /// A rough histogram of our times, for percentiles.
+    buckets: [u32; TIMING_BUCKETS],
+}
+
+/// An `OrderTiming` which hasn't recorded anything yet.
+pub const ORDER_TIMING_INIT: OrderTiming = OrderTiming {
+    count: 0,
+    total: 0,
+    min: !0,
+    max: 0,
+    buckets: [0; TIMING_BUCKETS],
+};
+
+/// The bucket we count a time of `cycles` in.
+fn bucket(cycles: u64) -> usize {
+    let log2 = 63 - (cycles | 1).leading_zeros() as usize;
+    min(log2, TIMING_BUCKETS - 1)
+}
+
+impl OrderTiming {
+    /// Record a call which took `cycles`.
+    pub fn record(&mut self, cycles: u64) {
+        self.count += 1;
+        self.total = self.total.saturating_add(cycles);
+        self.min = min(self.min, cycles);
+        self.max = max(self.max, cycles);
+        let count = &mut self.buckets[bucket(cycles)];
+        *count = count.saturating_add(1);
+    }
+
+    /// The number of calls we've recorded.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The fastest call we've seen, or `None` if we haven't seen any.
+    pub fn min(&self) -> Option<u64> {
+        if self.count > 0 { Some(self.min) } else { None }
+    }
+
+    /// The slowest call we've seen.
+    pub fn max(&self) -> Option<u64> {
+        if self.count > 0 { Some(self.max) } else { None }
+    }
+
+    /// The average time per call.
+    pub fn average(&self) -> Option<u64> {
+        if self.count > 0 { Some(self.total / self.count as u64) } else { None }
+    }
+
+    /// An upper bound on the time taken by `percent` percent of our calls.
+    /// This is only as precise as our histogram, so it's usually a power
+    /// of 2 minus one, but it's never more than `max`.
+    pub fn percentile(&self, percent: usize) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let wanted = (self.count * percent + 99) / 100;
+        let mut seen = 0;
+        for (k, &count) in self.buckets.iter().enumerate() {
+            seen += count as usize;
+            if seen >= wanted && k < TIMING_BUCKETS - 1 {
+                return Some(min((2 << k) - 1, self.max));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+/// Timings for allocating and deallocating blocks of each order.
+pub struct AllocTiming {
+    allocate: [OrderTiming; MAX_ORDERS],
+    deallocate: [OrderTiming; MAX_ORDERS],
+}
+
+// We can't `#[derive]` these, because the standard library doesn't
+// implement `Clone` for arrays this large.
+impl Copy for AllocTiming {}
+impl Clone for AllocTiming {
+    fn clone(&self) -> AllocTiming { *self }
+}
+
+/// An `AllocTiming` which hasn't recorded anything yet.  This is about
+/// 10KB, so it's best kept in a `static` rather than on the stack.
+pub const ALLOC_TIMING_INIT: AllocTiming = AllocTiming {
+    allocate: [ORDER_TIMING_INIT; MAX_ORDERS],
+    deallocate: [ORDER_TIMING_INIT; MAX_ORDERS],
+};
+
+impl AllocTiming {
+    /// Record an allocation of a block of `order` which took `cycles`.
+    pub fn record_allocate(&mut self, order: usize, cycles: u64) {
+        self.allocate[order].record(cycles);
+    }
+
+    /// Record a deallocation of a block of `order` which took `cycles`.
+    pub fn record_deallocate(&mut self, order: usize, cycles: u64) {
+        self.deallocate[order].record(cycles);
+    }
+
+    /// How long allocating blocks of `order` has taken.
+    pub fn allocate(&self, order: usize) -> &OrderTiming {
+        &self.allocate[order]
+    }
+
+    /// How long deallocating blocks of `order` has taken.
+    pub fn deallocate(&self, order: usize) -> &OrderTiming {
+        &self.deallocate[order]
+    }
+
+    /// Forget everything we've recorded.
+    pub fn reset(&mut self) {
+        *self = ALLOC_TIMING_INIT;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_empty() {
+        let timing = ORDER_TIMING_INIT;
+        assert_eq!(0, timing.count());
+        assert_eq!(None, timing.min());
+        assert_eq!(None, timing.average());
+        assert_eq!(None, timing.percentile(99));
+    }
+
+    #[test]
+    fn test_record() {
+        let mut timing = ORDER_TIMING_INIT;
+        for _ in 0..98 {
+            timing.record(100);
+        }
+        timing.record(1000);
+        timing.record(5000);
+        assert_eq!(100, timing.count());
+        assert_eq!(Some(100), timing.min());
+        assert_eq!(Some(5000), timing.max());
+        assert_eq!(Some(158), timing.average());
+
+        // 100 is in the 64..128 bucket, and 1000 in the 512..1024 one.
+        assert_eq!(Some(127), timing.percentile(50));
+        assert_eq!(Some(1023), timing.percentile(99));
+        assert_eq!(Some(5000), timing.percentile(100));
+    }
+
+    #[test]
+    fn test_huge_times() {
+        let mut timing = ORDER_TIMING_INIT;
+        timing.record(0);
+        timing.record(!0);
+        assert_eq!(Some(1), timing.percentile(50));
+        assert_eq!(Some(!0), timing.percentile(100));
+    }
+
+    #[test]
+    fn test_alloc_timing() {
+        let mut timing = ALLOC_TIMING_INIT;
+        timing.record_allocate(3, 10);
+        timing.record_deallocate(3, 20);
+        assert_eq!(1, timing.allocate(3).count());
+        assert_eq!(Some(20), timing.deallocate(3).max());
+        assert_eq!(0, timing.allocate(2).count());
+        timing.reset();
+        assert_eq!(0, timing.allocate(3).count());
+    }
+}
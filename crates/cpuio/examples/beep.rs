@@ -0,0 +1,41 @@
+//! Beep the PC speaker, using PIT channel 2 as a square wave generator.
+//! Run this as root, on a machine (or emulator) which has a speaker.
+//!
+//! See http://wiki.osdev.org/PC_Speaker for details.
+
+extern crate cpuio;
+
+use std::env;
+use std::thread;
+use std::time::Duration;
+
+use cpuio::Port;
+use cpuio::userspace::request_ports;
+
+/// The frequency of the PIT's input clock.
+const PIT_HZ: u32 = 1193182;
+
+fn main() {
+    let hz: u32 = env::args().nth(1)
+        .map(|arg| arg.parse().expect("frequency must be a number"))
+        .unwrap_or(440);
+    assert!(hz >= 19 && hz <= PIT_HZ, "frequency out of range");
+
+    request_ports(0x42, 2).expect("can't access PIT ports (are you root?)");
+    request_ports(0x61, 1).expect("can't access speaker port");
+    let mut channel2: Port<u8> = unsafe { Port::new(0x42) };
+    let mut command: Port<u8> = unsafe { Port::new(0x43) };
+    let mut speaker: Port<u8> = unsafe { Port::new(0x61) };
+
+    // Channel 2, low byte then high byte, mode 3 (square wave).
+    let divisor = PIT_HZ / hz;
+    command.write(0xB6);
+    channel2.write(divisor as u8);
+    channel2.write((divisor >> 8) as u8);
+
+    // Connect the speaker to channel 2, beep, and disconnect it again.
+    let original = speaker.read();
+    speaker.write(original | 0x03);
+    thread::sleep(Duration::from_millis(500));
+    speaker.write(original & !0x03);
+}
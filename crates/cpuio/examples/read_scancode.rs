@@ -1,13 +1,15 @@
 //! An example program showing how to read a single scancode from a PS/2
-//! keyboard.
+//! keyboard.  Run this as root.
 
 extern crate cpuio;
 
 use cpuio::Port;
+use cpuio::userspace::request_ports;
 
 fn main() {
+    // Without this, reading the port would fail with a SIGSEGV.
+    request_ports(0x60, 1).expect("can't access keyboard port (are you root?)");
+
     let mut keyboard: Port<u8> = unsafe { Port::new(0x60) };
-    // If you run this as an ordinary user in user space it will fail with
-    // a SIGSEGV.
     println!("scancode: {}", keyboard.read());
 }
@@ -0,0 +1,76 @@
+//! Read the date and time from the CMOS real-time clock.  Run this as
+//! root.
+//!
+//! See http://wiki.osdev.org/CMOS for the register layout.
+
+extern crate cpuio;
+
+use cpuio::Port;
+use cpuio::userspace::request_ports;
+
+/// The CMOS index and data ports.
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    fn read(&mut self, register: u8) -> u8 {
+        // Keep bit 7 clear, so we don't touch the NMI-disable bit.
+        self.index.write(register & 0x7F);
+        self.data.read()
+    }
+
+    /// Is the clock in the middle of updating its registers?
+    fn update_in_progress(&mut self) -> bool {
+        self.read(0x0A) & 0x80 != 0
+    }
+
+    /// Read all the time registers at once.
+    fn read_time(&mut self) -> [u8; 6] {
+        while self.update_in_progress() {}
+        [self.read(0x09), self.read(0x08), self.read(0x07),
+         self.read(0x04), self.read(0x02), self.read(0x00)]
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+fn main() {
+    request_ports(0x70, 2).expect("can't access CMOS ports (are you root?)");
+    let mut cmos = Cmos {
+        index: unsafe { Port::new(0x70) },
+        data: unsafe { Port::new(0x71) },
+    };
+
+    // Read twice until we get the same answer, in case an update started
+    // between our check and our reads.
+    let mut time = cmos.read_time();
+    loop {
+        let again = cmos.read_time();
+        if again == time { break; }
+        time = again;
+    }
+
+    // Status register B tells us whether the clock uses BCD, and whether
+    // it's in 12-hour mode.
+    let status_b = cmos.read(0x0B);
+    let bcd = status_b & 0x04 == 0;
+    let pm = status_b & 0x02 == 0 && time[3] & 0x80 != 0;
+    time[3] &= 0x7F;
+    if bcd {
+        for value in time.iter_mut() {
+            *value = from_bcd(*value);
+        }
+    }
+    if pm {
+        time[3] = (time[3] % 12) + 12;
+    }
+
+    // We assume the 21st century, rather than trust the century register,
+    // which isn't always present.
+    println!("20{:02}-{:02}-{:02} {:02}:{:02}:{:02} (RTC time, usually UTC)",
+             time[0], time[1], time[2], time[3], time[4], time[5]);
+}
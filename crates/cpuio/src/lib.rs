@@ -1,17 +1,36 @@
 //! CPU-level input/output instructions, including `inb`, `outb`, etc., and
 //! a high level Rust wrapper.
 
-#![feature(llvm_asm, const_fn)]
-#![no_std]
+// The `mock` feature doesn't use any inline assembly, so we can test
+// drivers with it on a current compiler.
+#![cfg_attr(not(feature = "mock"), feature(asm, const_fn))]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
+extern crate core;
 
 use core::marker::PhantomData;
 
-#[cfg(any(target_arch="x86", target_arch="x86_64"))]
+#[cfg(all(any(target_arch="x86", target_arch="x86_64"),
+          not(feature = "mock")))]
 pub use x86::{inb, outb, inw, outw, inl, outl};
 
-#[cfg(any(target_arch="x86", target_arch="x86_64"))]
+#[cfg(all(any(target_arch="x86", target_arch="x86_64"),
+          not(feature = "mock")))]
 mod x86;
 
+#[cfg(feature = "mock")]
+pub use mock::{inb, outb, inw, outw, inl, outl};
+
+#[cfg(feature = "trace-io")]
+pub mod trace;
+
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod userspace;
+
+#[cfg(feature = "mock")]
+pub mod mock;
+
 
 /// This trait is defined for any type which can be read or written over a
 /// port.  The processor supports I/O with `u8`, `u16` and `u32`.  The
@@ -26,17 +45,23 @@ pub trait InOut {
 }
 
 impl InOut for u8 {
+    #[inline(always)]
     unsafe fn port_in(port: u16) -> u8 { inb(port) }
+    #[inline(always)]
     unsafe fn port_out(port: u16, value: u8) { outb(value, port); }
 }
 
 impl InOut for u16 {
+    #[inline(always)]
     unsafe fn port_in(port: u16) -> u16 { inw(port) }
+    #[inline(always)]
     unsafe fn port_out(port: u16, value: u16) { outw(value, port); }
 }
 
 impl InOut for u32 {
+    #[inline(always)]
     unsafe fn port_in(port: u16) -> u32 { inl(port) }
+    #[inline(always)]
     unsafe fn port_out(port: u16, value: u32) { outl(value, port); }
 }
 
@@ -45,6 +70,9 @@ impl InOut for u32 {
 /// This version of `Port` has safe `read` and `write` functions, and it's
 /// appropriate for communicating with hardware that can't violate Rust's
 /// safety guarantees.
+///
+/// With the `trace-io` feature, `read` and `write` are always inlined, so
+/// that traced accesses point at the code which called them.
 #[derive(Debug)]
 pub struct Port<T: InOut> {
     // Port address.
@@ -64,11 +92,13 @@ impl<T: InOut> Port<T> {
     /// Read data from the port.  This is nominally safe, because you
     /// shouldn't be able to get hold of a port object unless somebody
     /// thinks it's safe to give you one.
+    #[cfg_attr(feature = "trace-io", inline(always))]
     pub fn read(&mut self) -> T {
         unsafe { T::port_in(self.port) }
     }
 
     /// Write data to the port.
+    #[cfg_attr(feature = "trace-io", inline(always))]
     pub fn write(&mut self, value: T) {
         unsafe { T::port_out(self.port, value); }
     }
@@ -93,12 +123,120 @@ impl<T: InOut> UnsafePort<T> {
     }
 
     /// Read data from the port.
+    #[cfg_attr(feature = "trace-io", inline(always))]
     pub unsafe fn read(&mut self) -> T {
         T::port_in(self.port)
     }
 
     /// Write data to the port.
+    #[cfg_attr(feature = "trace-io", inline(always))]
     pub unsafe fn write(&mut self, value: T) {
         T::port_out(self.port, value);
     }
 }
+
+/// A port address which is known at compile time.  Implement this for a
+/// zero-sized marker type, or use the `port_address!` macro, and then pass
+/// the marker type to `StaticPort` or `StaticUnsafePort`.
+pub trait PortAddress {
+    /// The address of this port.
+    fn port() -> u16;
+}
+
+/// Declare a zero-sized type implementing `PortAddress`:
+///
+/// ```ignore
+/// port_address!(pub LineStatus = 0x3F8 + 5);
+/// ```
+#[macro_export]
+macro_rules! port_address {
+    (@impl $name:ident = $port:expr) => {
+        impl $crate::PortAddress for $name {
+            #[inline(always)]
+            fn port() -> u16 { $port }
+        }
+    };
+    (pub $name:ident = $port:expr) => {
+        #[derive(Debug)]
+        pub struct $name;
+        port_address!(@impl $name = $port);
+    };
+    ($name:ident = $port:expr) => {
+        #[derive(Debug)]
+        struct $name;
+        port_address!(@impl $name = $port);
+    };
+}
+
+/// An I/O port whose address is part of its type, for use in register
+/// maps which are fixed at compile time:
+///
+/// ```ignore
+/// port_address!(LineStatus = 0x3F8 + 5);
+/// type LineStatusPort = StaticPort<u8, LineStatus>;
+/// ```
+///
+/// Unlike `Port`, this is a zero-sized type, so a struct full of these
+/// takes up no space at all, and every access compiles down to a constant
+/// port number.  Reads and writes are safe, with the same caveats as
+/// `Port`.
+#[derive(Debug)]
+pub struct StaticPort<T: InOut, P: PortAddress> {
+    phantom: PhantomData<(T, P)>,
+}
+
+impl<T: InOut, P: PortAddress> StaticPort<T, P> {
+    /// Create a new I/O port.
+    pub const unsafe fn new() -> StaticPort<T, P> {
+        StaticPort { phantom: PhantomData }
+    }
+
+    /// The address of this port.
+    #[inline(always)]
+    pub fn port() -> u16 {
+        P::port()
+    }
+
+    /// Read data from the port.
+    #[inline(always)]
+    pub fn read(&mut self) -> T {
+        unsafe { T::port_in(P::port()) }
+    }
+
+    /// Write data to the port.
+    #[inline(always)]
+    pub fn write(&mut self, value: T) {
+        unsafe { T::port_out(P::port(), value); }
+    }
+}
+
+/// The `UnsafePort` equivalent of `StaticPort`.
+#[derive(Debug)]
+pub struct StaticUnsafePort<T: InOut, P: PortAddress> {
+    phantom: PhantomData<(T, P)>,
+}
+
+impl<T: InOut, P: PortAddress> StaticUnsafePort<T, P> {
+    /// Create a new I/O port.
+    pub const unsafe fn new() -> StaticUnsafePort<T, P> {
+        StaticUnsafePort { phantom: PhantomData }
+    }
+
+    /// The address of this port.
+    #[inline(always)]
+    pub fn port() -> u16 {
+        P::port()
+    }
+
+    /// Read data from the port.
+    #[inline(always)]
+    pub unsafe fn read(&mut self) -> T {
+        T::port_in(P::port())
+    }
+
+    /// Write data to the port.
+    #[inline(always)]
+    pub unsafe fn write(&mut self, value: T) {
+        T::port_out(P::port(), value);
+    }
+}
@@ -0,0 +1,225 @@
+//! A fake port I/O backend for testing drivers on the host, enabled by
+//! the `mock` feature.
+//!
+//! With this feature turned on, `Port`, `UnsafePort` and friends don't
+//! execute real `in` and `out` instructions.  Instead, every access goes
+//! to a per-thread device model, which you set up from your test:
+//!
+//! - `expect_reads` queues up values to return from a port, in order.
+//! - `set_value` sets the value a port returns once its queue is empty.
+//! - `attach` connects a `Device` to a range of ports, for hardware which
+//!   needs to react to writes.
+//!
+//! Every access is recorded, and `accesses` or `writes_to` return them so
+//! that tests can check exactly what a driver did.  Because the model is
+//! thread-local, tests running in parallel don't interfere with each
+//! other.
+//!
+//! The free functions `inb`, `outb`, etc. use the device model, too.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::vec::Vec;
+use std::boxed::Box;
+
+/// A single port read or write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    /// The port which was accessed.
+    pub port: u16,
+    /// The value read or written, zero-extended.
+    pub value: u32,
+    /// The size of the access in bytes: 1, 2 or 4.
+    pub size: u8,
+    /// Was this a write?
+    pub write: bool,
+}
+
+/// A simulated piece of hardware.
+pub trait Device {
+    /// Handle a read of `size` bytes from `port`.
+    fn read(&mut self, port: u16, size: u8) -> u32;
+
+    /// Handle a write of `size` bytes to `port`.
+    fn write(&mut self, port: u16, size: u8, value: u32);
+}
+
+/// Everything we know about a single port.
+#[derive(Default)]
+struct PortState {
+    /// Values to return from reads, before falling back to `value`.
+    queued: VecDeque<u32>,
+    /// The value to return when `queued` is empty.
+    value: u32,
+}
+
+/// Our complete device model.
+#[derive(Default)]
+struct Model {
+    ports: HashMap<u16, PortState>,
+    /// Devices, and the first and last ports they handle.
+    devices: Vec<((u16, u16), Box<Device>)>,
+    log: Vec<Access>,
+}
+
+thread_local! {
+    static MODEL: RefCell<Model> = RefCell::new(Model::default());
+}
+
+/// Forget all devices, scripted values and recorded accesses.
+pub fn reset() {
+    MODEL.with(|model| *model.borrow_mut() = Model::default());
+}
+
+/// Return each of `values`, in order, from the next reads of `port`.
+pub fn expect_reads(port: u16, values: &[u32]) {
+    MODEL.with(|model| {
+        let mut model = model.borrow_mut();
+        model.ports.entry(port).or_insert_with(Default::default)
+            .queued.extend(values);
+    });
+}
+
+/// Return `value` from reads of `port` once any queued values have been
+/// used up.  Ports default to 0.
+pub fn set_value(port: u16, value: u32) {
+    MODEL.with(|model| {
+        let mut model = model.borrow_mut();
+        model.ports.entry(port).or_insert_with(Default::default).value = value;
+    });
+}
+
+/// Send all accesses to ports `first` through `last`, inclusive, to
+/// `device`.  Devices take priority over `expect_reads` and `set_value`.
+pub fn attach<D: Device + 'static>(first: u16, last: u16, device: D) {
+    MODEL.with(|model| {
+        model.borrow_mut().devices.push(((first, last), Box::new(device)));
+    });
+}
+
+/// Every access made so far, in order.
+pub fn accesses() -> Vec<Access> {
+    MODEL.with(|model| model.borrow().log.clone())
+}
+
+/// The values written to `port` so far, in order.
+pub fn writes_to(port: u16) -> Vec<u32> {
+    MODEL.with(|model| {
+        model.borrow().log.iter()
+            .filter(|a| a.write && a.port == port)
+            .map(|a| a.value)
+            .collect()
+    })
+}
+
+/// Simulate a read.
+fn port_in(port: u16, size: u8) -> u32 {
+    MODEL.with(|model| {
+        let mut model = model.borrow_mut();
+        let device = model.devices.iter_mut()
+            .find(|&&mut ((first, last), _)| first <= port && port <= last);
+        let value = match device {
+            Some(&mut (_, ref mut device)) => device.read(port, size),
+            None => {
+                let state =
+                    model.ports.entry(port).or_insert_with(Default::default);
+                state.queued.pop_front().unwrap_or(state.value)
+            }
+        };
+        model.log.push(Access {
+            port: port,
+            value: value,
+            size: size,
+            write: false,
+        });
+        value
+    })
+}
+
+/// Simulate a write.
+fn port_out(port: u16, size: u8, value: u32) {
+    MODEL.with(|model| {
+        let mut model = model.borrow_mut();
+        model.log.push(Access {
+            port: port,
+            value: value,
+            size: size,
+            write: true,
+        });
+        let device = model.devices.iter_mut()
+            .find(|&&mut ((first, last), _)| first <= port && port <= last);
+        if let Some(&mut (_, ref mut device)) = device {
+            device.write(port, size, value);
+        }
+    });
+}
+
+/// Simulate `cpuio::inb`.
+pub unsafe fn inb(port: u16) -> u8 { port_in(port, 1) as u8 }
+
+/// Simulate `cpuio::outb`.
+pub unsafe fn outb(value: u8, port: u16) { port_out(port, 1, value as u32) }
+
+/// Simulate `cpuio::inw`.
+pub unsafe fn inw(port: u16) -> u16 { port_in(port, 2) as u16 }
+
+/// Simulate `cpuio::outw`.
+pub unsafe fn outw(value: u16, port: u16) { port_out(port, 2, value as u32) }
+
+/// Simulate `cpuio::inl`.
+pub unsafe fn inl(port: u16) -> u32 { port_in(port, 4) }
+
+/// Simulate `cpuio::outl`.
+pub unsafe fn outl(value: u32, port: u16) { port_out(port, 4, value) }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Port;
+
+    #[test]
+    fn test_scripted_reads() {
+        reset();
+        expect_reads(0x64, &[0x02, 0x02, 0x00]);
+        set_value(0x60, 0xFA);
+
+        let mut status: Port<u8> = unsafe { Port::new(0x64) };
+        let mut data: Port<u8> = unsafe { Port::new(0x60) };
+        let mut polls = 0;
+        while status.read() & 0x02 != 0 { polls += 1; }
+        assert_eq!(2, polls);
+        assert_eq!(0, status.read());
+        assert_eq!(0xFA, data.read());
+        assert_eq!(0xFA, data.read());
+    }
+
+    #[test]
+    fn test_writes_are_recorded() {
+        reset();
+        let mut port: Port<u16> = unsafe { Port::new(0x1F0) };
+        port.write(0x1234);
+        port.write(0x5678);
+        assert_eq!(vec![0x1234, 0x5678], writes_to(0x1F0));
+        assert_eq!(Access { port: 0x1F0, value: 0x1234, size: 2, write: true },
+                   accesses()[0]);
+    }
+
+    /// A latch that returns the last value written to it, plus one.
+    struct Latch(u32);
+
+    impl Device for Latch {
+        fn read(&mut self, _port: u16, _size: u8) -> u32 { self.0 + 1 }
+        fn write(&mut self, _port: u16, _size: u8, value: u32) {
+            self.0 = value;
+        }
+    }
+
+    #[test]
+    fn test_device() {
+        reset();
+        attach(0x70, 0x71, Latch(0));
+        let mut port: Port<u8> = unsafe { Port::new(0x71) };
+        port.write(41);
+        assert_eq!(42, port.read());
+    }
+}
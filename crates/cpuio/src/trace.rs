@@ -0,0 +1,121 @@
+//! Optional tracing of port I/O, enabled by the `trace-io` feature.
+//!
+//! When a driver misbehaves, the quickest way to find out why is often to
+//! compare the exact sequence of `in` and `out` instructions it performs
+//! against a trace from a known-good driver (or from an emulator).  With
+//! this feature turned on, every access made through this crate is passed
+//! to a hook function installed with `set_hook`, optionally restricted to
+//! certain ports using `trace_ports`.
+//!
+//! Each access records the address of the code which made it.  To turn
+//! that into a source location, run `addr2line -e` on the binary.
+
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_BOOL_INIT,
+                         ATOMIC_USIZE_INIT, Ordering};
+
+/// A single port read or write.
+#[derive(Debug)]
+pub struct Access {
+    /// The port which was accessed.
+    pub port: u16,
+    /// The value read or written, zero-extended.
+    pub value: u32,
+    /// The size of the access, in bytes.
+    pub size: u8,
+    /// Was this a write?
+    pub write: bool,
+    /// The address of the code which performed the access.  Port reads
+    /// and writes are always inlined, so this points into the function
+    /// which called them.
+    pub address: usize,
+}
+
+/// A function which will be called for each traced access.  The hook may
+/// perform port I/O of its own (to write to a serial port, say); those
+/// accesses won't be traced.
+pub type Hook = fn(&Access);
+
+/// The maximum number of port ranges we can filter on.
+pub const MAX_RANGES: usize = 8;
+
+/// Our hook, stored as a `usize` so we can update it atomically.  Zero
+/// means no hook.
+static HOOK: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Set while we're running the hook, so that we don't trace the hook's
+/// own I/O.
+static IN_HOOK: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Port ranges to trace, packed as `first << 16 | last`.  Only the first
+/// `RANGE_COUNT` entries are valid, and if there are none, we trace
+/// everything.
+static RANGES: [AtomicUsize; MAX_RANGES] = [
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+];
+static RANGE_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Install a hook function to receive traced accesses, or remove the
+/// current one by passing `None`.
+pub fn set_hook(hook: Option<Hook>) {
+    let raw = match hook {
+        Some(f) => f as usize,
+        None => 0,
+    };
+    HOOK.store(raw, Ordering::SeqCst);
+}
+
+/// Only trace ports from `first` through `last`, inclusive.  This may be
+/// called up to `MAX_RANGES` times to trace several ranges.  Until it's
+/// called, all ports are traced.
+pub fn trace_ports(first: u16, last: u16) -> Result<(), &'static str> {
+    let count = RANGE_COUNT.load(Ordering::SeqCst);
+    if count >= MAX_RANGES {
+        return Err("too many traced port ranges");
+    }
+    RANGES[count].store((first as usize) << 16 | last as usize,
+                        Ordering::SeqCst);
+    RANGE_COUNT.store(count + 1, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Go back to tracing all ports.
+pub fn trace_all_ports() {
+    RANGE_COUNT.store(0, Ordering::SeqCst);
+}
+
+/// Should we trace accesses to `port`?
+fn is_traced(port: u16) -> bool {
+    let count = RANGE_COUNT.load(Ordering::SeqCst);
+    if count == 0 {
+        return true;
+    }
+    RANGES[..count].iter().any(|range| {
+        let packed = range.load(Ordering::Relaxed);
+        let (first, last) = ((packed >> 16) as u16, packed as u16);
+        first <= port && port <= last
+    })
+}
+
+/// Report an access to our hook, if we have one and it's interested.
+/// This is only public so that our inlined I/O functions can call it.
+#[doc(hidden)]
+pub fn record(port: u16, value: u32, size: u8, write: bool, address: usize) {
+    let raw = HOOK.load(Ordering::SeqCst);
+    if raw == 0 || !is_traced(port) {
+        return;
+    }
+    if IN_HOOK.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let hook: Hook = unsafe { mem::transmute(raw) };
+    hook(&Access {
+        port: port,
+        value: value,
+        size: size,
+        write: write,
+        address: address,
+    });
+    IN_HOOK.store(false, Ordering::SeqCst);
+}
@@ -0,0 +1,47 @@
+//! Getting permission to do port I/O from a Linux user-space process.
+//!
+//! Normally, an `in` or `out` instruction in user space kills the process
+//! with `SIGSEGV`.  But a process with `CAP_SYS_RAWIO` (in practice, one
+//! running as root) can ask the kernel to let it use specific ports, which
+//! makes it possible to poke at hardware without writing a kernel.  This
+//! is handy for experimenting with a driver's port-level logic before
+//! moving it into a kernel, and it's how our examples work.
+
+use std::io;
+use std::os::raw::{c_int, c_ulong};
+
+extern "C" {
+    fn ioperm(from: c_ulong, num: c_ulong, turn_on: c_int) -> c_int;
+    fn iopl(level: c_int) -> c_int;
+}
+
+/// Allow this process to access the `count` ports starting at `from`.
+/// Linux only supports this for ports below 0x400; use `set_iopl` for
+/// anything higher.
+pub fn request_ports(from: u16, count: u16) -> io::Result<()> {
+    set_ports(from, count, true)
+}
+
+/// Take away access to ports granted by `request_ports`.
+pub fn release_ports(from: u16, count: u16) -> io::Result<()> {
+    set_ports(from, count, false)
+}
+
+fn set_ports(from: u16, count: u16, enable: bool) -> io::Result<()> {
+    let result = unsafe {
+        ioperm(from as c_ulong, count as c_ulong, enable as c_int)
+    };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+/// Change this process's I/O privilege level.  Level 3 allows access to
+/// all 65536 ports, and also lets the process disable interrupts, which
+/// is even more dangerous than it sounds.  Level 0 is the default.
+pub fn set_iopl(level: u8) -> io::Result<()> {
+    if level > 3 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "I/O privilege level must be 0 to 3"));
+    }
+    let result = unsafe { iopl(level as c_int) };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
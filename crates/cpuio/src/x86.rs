@@ -1,39 +1,80 @@
 //! Rust wrappers around the x86-family I/O instructions.
 
+#[cfg(feature = "trace-io")]
+use trace;
+
+/// The address of the code we've been inlined into, for `trace::record`.
+#[cfg(all(feature = "trace-io", target_arch = "x86_64"))]
+#[inline(always)]
+fn caller_address() -> usize {
+    let address: usize;
+    unsafe { asm!("lea 0(%rip), $0" : "=r"(address) ::: "volatile"); }
+    address
+}
+
+/// The address of the code we've been inlined into, for `trace::record`.
+/// There's no `%rip`-relative addressing on 32-bit x86, so we `call` the
+/// next instruction and pop our own return address.
+#[cfg(all(feature = "trace-io", target_arch = "x86"))]
+#[inline(always)]
+fn caller_address() -> usize {
+    let address: usize;
+    unsafe { asm!("call 1f; 1: pop $0" : "=r"(address) ::: "volatile"); }
+    address
+}
+
 /// Read a `u8`-sized value from `port`.
+#[inline(always)]
 pub unsafe fn inb(port: u16) -> u8 {
     // The registers for the `in` and `out` instructions are always the
     // same: `a` for value, and `d` for the port address.
     let result: u8;
-    llvm_asm!("inb %dx, %al" : "={al}"(result) : "{dx}"(port) :: "volatile");
+    asm!("inb %dx, %al" : "={al}"(result) : "{dx}"(port) :: "volatile");
+    #[cfg(feature = "trace-io")]
+    trace::record(port, result as u32, 1, false, caller_address());
     result
 }
 
 /// Write a `u8`-sized `value` to `port`.
+#[inline(always)]
 pub unsafe fn outb(value: u8, port: u16) {
-    llvm_asm!("outb %al, %dx" :: "{dx}"(port), "{al}"(value) :: "volatile");
+    #[cfg(feature = "trace-io")]
+    trace::record(port, value as u32, 1, true, caller_address());
+    asm!("outb %al, %dx" :: "{dx}"(port), "{al}"(value) :: "volatile");
 }
 
 /// Read a `u16`-sized value from `port`.
+#[inline(always)]
 pub unsafe fn inw(port: u16) -> u16 {
     let result: u16;
-    llvm_asm!("inw %dx, %ax" : "={ax}"(result) : "{dx}"(port) :: "volatile");
+    asm!("inw %dx, %ax" : "={ax}"(result) : "{dx}"(port) :: "volatile");
+    #[cfg(feature = "trace-io")]
+    trace::record(port, result as u32, 2, false, caller_address());
     result
 }
 
 /// Write a `u8`-sized `value` to `port`.
+#[inline(always)]
 pub unsafe fn outw(value: u16, port: u16) {
-    llvm_asm!("outw %ax, %dx" :: "{dx}"(port), "{ax}"(value) :: "volatile");
+    #[cfg(feature = "trace-io")]
+    trace::record(port, value as u32, 2, true, caller_address());
+    asm!("outw %ax, %dx" :: "{dx}"(port), "{ax}"(value) :: "volatile");
 }
 
 /// Read a `u32`-sized value from `port`.
+#[inline(always)]
 pub unsafe fn inl(port: u16) -> u32 {
     let result: u32;
-    llvm_asm!("inl %dx, %eax" : "={eax}"(result) : "{dx}"(port) :: "volatile");
+    asm!("inl %dx, %eax" : "={eax}"(result) : "{dx}"(port) :: "volatile");
+    #[cfg(feature = "trace-io")]
+    trace::record(port, result, 4, false, caller_address());
     result
 }
 
 /// Write a `u32`-sized `value` to `port`.
+#[inline(always)]
 pub unsafe fn outl(value: u32, port: u16) {
-    llvm_asm!("outl %eax, %dx" :: "{dx}"(port), "{eax}"(value) :: "volatile");
+    #[cfg(feature = "trace-io")]
+    trace::record(port, value, 4, true, caller_address());
+    asm!("outl %eax, %dx" :: "{dx}"(port), "{eax}"(value) :: "volatile");
 }
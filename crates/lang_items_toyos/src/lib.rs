@@ -0,0 +1,78 @@
+//! Minor functions that Rust really expects to be defined by our compiler
+//! or something, but which we need to provide manually because we're on
+//! bare metal.
+//!
+//! We only provide one implementation of each lang item, but what a panic
+//! actually does is up to the crate using us: see `set_panic_hook` and
+//! `set_panic_output`.
+
+#![feature(lang_items, oom)]
+#![no_std]
+
+extern crate alloc;
+
+use core::fmt;
+use core::mem::transmute;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+/// A function which takes over completely when we panic.
+pub type PanicHook = fn(fmt::Arguments, &str, u32) -> !;
+
+/// A function which writes out formatted text.
+pub type PanicOutput = fn(fmt::Arguments);
+
+/// Our `PanicHook`, or 0 if none has been set.  We store function
+/// pointers as `usize` so that we can update them atomically.
+static PANIC_HOOK: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Our `PanicOutput`, or 0 if none has been set.
+static PANIC_OUTPUT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Call `hook` whenever we panic, instead of our default handling.
+pub fn set_panic_hook(hook: PanicHook) {
+    PANIC_HOOK.store(hook as usize, Ordering::SeqCst);
+}
+
+/// Use our default panic handling, but write the panic message using
+/// `output`.
+pub fn set_panic_output(output: PanicOutput) {
+    PANIC_OUTPUT.store(output as usize, Ordering::SeqCst);
+}
+
+/// Panic when we run out of memory, instead of aborting without a word.
+pub fn install_oom_handler() {
+    alloc::oom::set_oom_handler(oom);
+}
+
+fn oom() -> ! {
+    panic!("out of memory")
+}
+
+#[lang = "eh_personality"]
+extern "C" fn eh_personality() {
+}
+
+#[lang = "panic_fmt"]
+extern "C" fn panic_fmt(args: fmt::Arguments, file: &str, line: u32) -> ! {
+    match PANIC_HOOK.load(Ordering::SeqCst) {
+        0 => {}
+        hook => {
+            let hook: PanicHook = unsafe { transmute(hook) };
+            hook(args, file, line)
+        }
+    }
+    match PANIC_OUTPUT.load(Ordering::SeqCst) {
+        0 => {}
+        output => {
+            let output: PanicOutput = unsafe { transmute(output) };
+            output(format_args!("PANIC: {}:{}: {}\n", file, line, args));
+        }
+    }
+    loop {}
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn _Unwind_Resume() -> ! {
+    panic!("_Unwind_Resume called, but we don't support unwinding")
+}
@@ -30,6 +30,10 @@ const CMD_INIT: u8 = 0x11;
 /// Command sent to acknowledge an interrupt.
 const CMD_END_OF_INTERRUPT: u8 = 0x20;
 
+/// Command sent to make the next read of the command port return the
+/// in-service register.
+const CMD_READ_ISR: u8 = 0x0B;
+
 // The mode in which we want to run our PICs.
 const MODE_8086: u8 = 0x01;
 
@@ -58,6 +62,25 @@ impl Pic {
     unsafe fn end_of_interrupt(&mut self) {
         self.command.write(CMD_END_OF_INTERRUPT);
     }
+
+    /// Read our in-service register, which has a bit set for each of our
+    /// interrupts which the CPU is handling but hasn't acknowledged yet.
+    unsafe fn in_service(&mut self) -> u8 {
+        self.command.write(CMD_READ_ISR);
+        self.command.read()
+    }
+
+    /// Mask or unmask one of our interrupts.  The caller must make sure
+    /// that we actually handle `interrupt_id`.
+    unsafe fn set_masked(&mut self, interrupt_id: u8, masked: bool) {
+        let bit = 1 << (interrupt_id - self.offset);
+        let mask = self.data.read();
+        if masked {
+            self.data.write(mask | bit);
+        } else {
+            self.data.write(mask & !bit);
+        }
+    }
 }
 
 /// A pair of chained PIC controllers.  This is the standard setup on x86.
@@ -140,6 +163,26 @@ impl ChainedPics {
         self.pics.iter().any(|p| p.handles_interrupt(interrupt_id))
     }
 
+    /// Stop delivering the specified interrupt.  Does nothing if the
+    /// interrupt doesn't belong to one of our PICs.
+    pub unsafe fn mask(&mut self, interrupt_id: u8) {
+        for pic in self.pics.iter_mut() {
+            if pic.handles_interrupt(interrupt_id) {
+                pic.set_masked(interrupt_id, true);
+            }
+        }
+    }
+
+    /// Resume delivering the specified interrupt.  Does nothing if the
+    /// interrupt doesn't belong to one of our PICs.
+    pub unsafe fn unmask(&mut self, interrupt_id: u8) {
+        for pic in self.pics.iter_mut() {
+            if pic.handles_interrupt(interrupt_id) {
+                pic.set_masked(interrupt_id, false);
+            }
+        }
+    }
+
     /// Figure out which (if any) PICs in our chain need to know about this
     /// interrupt.  This is tricky, because all interrupts from `pics[1]`
     /// get chained through `pics[0]`.
@@ -151,4 +194,27 @@ impl ChainedPics {
             self.pics[0].end_of_interrupt();
         }
     }
+    /// Is this a spurious interrupt?  If an interrupt request goes away
+    /// before the CPU acknowledges it, the PIC reports its lowest-priority
+    /// interrupt (IRQ 7 or 15) without marking it as in service.  These
+    /// must not be acknowledged with `notify_end_of_interrupt`; use
+    /// `notify_end_of_spurious_interrupt` instead.
+    pub unsafe fn is_spurious(&mut self, interrupt_id: u8) -> bool {
+        for pic in self.pics.iter_mut() {
+            if interrupt_id == pic.offset + 7 {
+                return pic.in_service() & 0x80 == 0;
+            }
+        }
+        false
+    }
+
+    /// Acknowledge a spurious interrupt.  `pics[1]` doesn't expect to hear
+    /// about it, but `pics[0]` really did get an interrupt on the line
+    /// `pics[1]` is chained through.
+    pub unsafe fn notify_end_of_spurious_interrupt(&mut self,
+                                                   interrupt_id: u8) {
+        if self.pics[1].handles_interrupt(interrupt_id) {
+            self.pics[0].end_of_interrupt();
+        }
+    }
 }
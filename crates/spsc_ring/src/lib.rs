@@ -0,0 +1,291 @@
+//! A fixed-capacity, single-producer, single-consumer ring buffer.
+//!
+//! The producer is typically an interrupt handler, and the consumer is
+//! ordinary kernel code.  Neither side takes a lock: the producer only
+//! ever writes `tail`, the consumer only ever writes `head`, and each
+//! publishes its progress with a release store that the other side reads
+//! with an acquire load.  So the producer can safely push while the
+//! consumer is in the middle of a `pop` that it interrupted.
+//!
+//! `head` and `tail` count every item ever popped and pushed, and wrap
+//! around at `usize::MAX`.  Their difference is the number of items in
+//! the buffer, so we can use every slot without needing an extra flag to
+//! tell "full" from "empty".  For the slot index `count & (capacity - 1)`
+//! to stay continuous across that wraparound, the capacity must be a power
+//! of 2, so we only implement `Array` for arrays of those sizes.
+//!
+//! Items must be `Copy`.  This lets us keep them in an ordinary array,
+//! which we can build in a `static`, and means we never need to drop
+//! anything left in the buffer.
+
+#![no_std]
+
+#![cfg_attr(feature = "const-fn", feature(const_fn))]
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// An array we can use as the storage for a `RingBuffer`.  This is only
+/// implemented for arrays whose length is a power of 2.
+///
+/// # Safety
+///
+/// `capacity` must be a power of 2, and `Self` must really be an array of
+/// that many `Item`s.
+pub unsafe trait Array {
+    /// The type of item in the array.
+    type Item;
+
+    /// The length of the array.
+    fn capacity() -> usize;
+}
+
+macro_rules! impl_array {
+    ($($len:expr)*) => {
+        $(
+            unsafe impl<T> Array for [T; $len] {
+                type Item = T;
+                fn capacity() -> usize { $len }
+            }
+        )*
+    }
+}
+
+impl_array!(1 2 4 8 16 32 64 128 256 512 1024 2048 4096);
+
+/// A queue holding up to `A::capacity()` items, stored in an array of
+/// type `A`.
+pub struct RingBuffer<A: Array> where A::Item: Copy {
+    /// Our storage.  Slots from `head` up to `tail` hold items.
+    slots: UnsafeCell<A>,
+    /// The number of items ever popped.  Only written by the consumer.
+    head: AtomicUsize,
+    /// The number of items ever pushed.  Only written by the producer.
+    tail: AtomicUsize,
+}
+
+// We hand out items across threads (or from interrupt handlers), so the
+// items must be `Send`.  The producer/consumer discipline is up to the
+// caller.
+unsafe impl<A: Array> Sync for RingBuffer<A> where A::Item: Copy + Send {}
+
+impl<A: Array> RingBuffer<A> where A::Item: Copy {
+    /// Create a new, empty ring buffer, using `slots` as storage.  Their
+    /// contents don't matter.  With the `const-fn` feature, this can be
+    /// used to initialize a `static`.
+    pub const fn new(slots: A) -> RingBuffer<A> {
+        RingBuffer {
+            slots: UnsafeCell::new(slots),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// The maximum number of items we can hold.
+    pub fn capacity(&self) -> usize {
+        A::capacity()
+    }
+
+    /// A pointer to the slot for the item numbered `count`.
+    fn slot(&self, count: usize) -> *mut A::Item {
+        let index = count & (A::capacity() - 1);
+        unsafe { (self.slots.get() as *mut A::Item).offset(index as isize) }
+    }
+
+    /// The number of items currently in the buffer.  If the other side is
+    /// running concurrently, this may be out of date by the time you look
+    /// at it.
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+
+    /// Is the buffer empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Is the buffer full?
+    pub fn is_full(&self) -> bool {
+        self.len() == A::capacity()
+    }
+
+    /// Add `item` to the buffer, or give it back if the buffer is full.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that no other code calls `push` at the
+    /// same time.  On a single CPU, that usually means only calling it
+    /// from one interrupt handler.
+    pub unsafe fn push(&self, item: A::Item) -> Result<(), A::Item> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == A::capacity() {
+            return Err(item);
+        }
+        ptr::write(self.slot(tail), item);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Remove the oldest item from the buffer, if there is one.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that no other code calls `pop` at the
+    /// same time.
+    pub unsafe fn pop(&self) -> Option<A::Item> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let item = ptr::read(self.slot(head));
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(item)
+    }
+
+    /// Split a buffer we own into a `Producer` and a `Consumer`, which
+    /// can be handed to different threads, and which don't need `unsafe`
+    /// because there can only be one of each.
+    pub fn split<'a>(&'a mut self) -> (Producer<'a, A>, Consumer<'a, A>) {
+        let ring: &'a Self = self;
+        (Producer { ring: ring }, Consumer { ring: ring })
+    }
+}
+
+/// The pushing half of a `RingBuffer`.  See `RingBuffer::split`.
+pub struct Producer<'a, A: Array + 'a> where A::Item: Copy {
+    ring: &'a RingBuffer<A>,
+}
+
+unsafe impl<'a, A: Array> Send for Producer<'a, A> where A::Item: Copy + Send {}
+
+impl<'a, A: Array> Producer<'a, A> where A::Item: Copy {
+    /// Add `item` to the buffer, or give it back if the buffer is full.
+    pub fn push(&mut self, item: A::Item) -> Result<(), A::Item> {
+        unsafe { self.ring.push(item) }
+    }
+
+    /// Is the buffer full?
+    pub fn is_full(&self) -> bool {
+        self.ring.is_full()
+    }
+}
+
+/// The popping half of a `RingBuffer`.  See `RingBuffer::split`.
+pub struct Consumer<'a, A: Array + 'a> where A::Item: Copy {
+    ring: &'a RingBuffer<A>,
+}
+
+unsafe impl<'a, A: Array> Send for Consumer<'a, A> where A::Item: Copy + Send {}
+
+impl<'a, A: Array> Consumer<'a, A> where A::Item: Copy {
+    /// Remove the oldest item from the buffer, if there is one.
+    pub fn pop(&mut self) -> Option<A::Item> {
+        unsafe { self.ring.pop() }
+    }
+
+    /// Is the buffer empty?
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::vec::Vec;
+    use super::*;
+
+    #[test]
+    fn test_push_and_pop() {
+        let ring = RingBuffer::new([0u8; 4]);
+        assert_eq!(4, ring.capacity());
+        assert!(ring.is_empty());
+        unsafe {
+            assert_eq!(None, ring.pop());
+            for i in 0..4 {
+                assert_eq!(Ok(()), ring.push(i));
+            }
+            assert!(ring.is_full());
+            assert_eq!(Err(4), ring.push(4));
+            assert_eq!(Some(0), ring.pop());
+            assert_eq!(Ok(()), ring.push(4));
+            for i in 1..5 {
+                assert_eq!(Some(i), ring.pop());
+            }
+            assert_eq!(None, ring.pop());
+        }
+    }
+
+    #[test]
+    fn test_counters_wrap() {
+        let ring = RingBuffer::new([0usize; 4]);
+        ring.head.store(!0 - 1, Ordering::SeqCst);
+        ring.tail.store(!0 - 1, Ordering::SeqCst);
+        unsafe {
+            for i in 0..4 {
+                assert_eq!(Ok(()), ring.push(i));
+            }
+            assert_eq!(4, ring.len());
+            assert_eq!(Err(4), ring.push(4));
+            for i in 0..4 {
+                assert_eq!(Some(i), ring.pop());
+            }
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn test_static() {
+        static RING: RingBuffer<[u32; 8]> = RingBuffer::new([0; 8]);
+        unsafe {
+            RING.push(42).unwrap();
+            assert_eq!(Some(42), RING.pop());
+        }
+    }
+
+    /// Run a real producer and consumer against each other, with a small
+    /// buffer so that both the full and empty cases get plenty of
+    /// exercise.
+    #[test]
+    fn test_threads() {
+        const COUNT: usize = 200000;
+        let mut ring = RingBuffer::new([0usize; 8]);
+        {
+            let (mut producer, mut consumer) = ring.split();
+            thread::scope(|s| {
+                s.spawn(move || {
+                    for i in 0..COUNT {
+                        let mut item = i;
+                        while let Err(rejected) = producer.push(item) {
+                            item = rejected;
+                            thread::yield_now();
+                        }
+                    }
+                });
+                let received = s.spawn(move || {
+                    let mut received = Vec::with_capacity(COUNT);
+                    while received.len() < COUNT {
+                        match consumer.pop() {
+                            Some(item) => received.push(item),
+                            None => thread::yield_now(),
+                        }
+                    }
+                    received
+                }).join().unwrap();
+                for (i, &item) in received.iter().enumerate() {
+                    assert_eq!(i, item);
+                }
+            });
+        }
+        assert!(ring.is_empty());
+    }
+}
@@ -1,6 +1,9 @@
 // Export our platform-specific modules.
 #[cfg(target_arch="x86_64")]
-pub use self::x86_64::{vga, interrupts, serial, pci};
+pub use self::x86_64::{vga, interrupts, serial, pci, paging, cpu, multiboot,
+                       backtrace, reset, sb16, timer, vbe, mem,
+                       virtio_console, acpi, latency, vectors,
+                       kernel_layout, keyboard, keymap};
 
 // Implementations for x86_64.
 #[cfg(target_arch="x86_64")]
@@ -0,0 +1,202 @@
+//! Just enough ACPI to switch the machine off.
+//!
+//! To enter the S5 ("soft off") sleep state, we write the S5 sleep type and
+//! the sleep enable bit to the PM1 control registers, whose ports we find in
+//! the FADT.  The sleep type lives in the `\_S5` package in the DSDT, which
+//! is AML bytecode.  Rather than interpret AML, we look for the bytes of
+//! `Name (_S5, Package () { ... })`, which is how every firmware we know of
+//! defines it.  See http://wiki.osdev.org/Shutdown.
+//!
+//! We don't attempt S3 (suspend to RAM): on resume, the firmware jumps to
+//! our waking vector in real mode, and we have no trampoline to get back
+//! to long mode from there.
+
+use core::slice;
+use cpuio;
+
+use arch::x86_64::interrupts;
+use arch::x86_64::multiboot;
+
+/// We can only read tables in the memory we've identity mapped.
+const IDENTITY_MAPPED: usize = 1 << 30;
+
+/// The size of the header shared by all system description tables.
+const HEADER_SIZE: usize = 36;
+
+/// Offsets of the FADT fields we use.
+const FADT_DSDT: usize = 40;
+const FADT_SMI_CMD: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_PM1A_CNT_BLK: usize = 64;
+const FADT_PM1B_CNT_BLK: usize = 68;
+const FADT_X_DSDT: usize = 140;
+
+/// Bits of the PM1 control registers.
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_EN: u16 = 1 << 13;
+
+/// AML opcodes we need to recognize.
+const AML_NAME: u8 = 0x08;
+const AML_PACKAGE: u8 = 0x12;
+const AML_ZERO: u8 = 0x00;
+const AML_ONE: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_ROOT_PREFIX: u8 = b'\\';
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    read_u32(bytes, offset) as u64 | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+/// Get the system description table at physical address `addr`, and check
+/// its checksum.
+fn table(addr: u64) -> Result<&'static [u8], &'static str> {
+    let addr = addr as usize;
+    if addr == 0 || addr + HEADER_SIZE > IDENTITY_MAPPED {
+        return Err("ACPI table isn't in identity-mapped memory");
+    }
+    let header = unsafe { slice::from_raw_parts(addr as *const u8, HEADER_SIZE) };
+    let len = read_u32(header, 4) as usize;
+    if len < HEADER_SIZE || addr + len > IDENTITY_MAPPED {
+        return Err("ACPI table has a bad length");
+    }
+    let bytes = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+    if bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+        return Err("ACPI table has a bad checksum");
+    }
+    Ok(bytes)
+}
+
+/// Find the table with `signature`, via the RSDT or XSDT.
+fn find_table(signature: &[u8]) -> Result<&'static [u8], &'static str> {
+    let rsdp = try!(multiboot::info().and_then(|i| i.acpi_rsdp())
+                    .ok_or("boot loader didn't give us the ACPI RSDP"));
+    if rsdp.len() < 20 || &rsdp[0..8] != b"RSD PTR " {
+        return Err("bad ACPI RSDP");
+    }
+    // ACPI 2.0 and later have a 64-bit XSDT, which we prefer.
+    let (sdt, entry_size) = if rsdp[15] >= 2 && rsdp.len() >= 32 &&
+        read_u64(rsdp, 24) != 0
+    {
+        (try!(table(read_u64(rsdp, 24))), 8)
+    } else {
+        (try!(table(read_u32(rsdp, 16) as u64)), 4)
+    };
+
+    let mut offset = HEADER_SIZE;
+    while offset + entry_size <= sdt.len() {
+        let addr = if entry_size == 8 {
+            read_u64(sdt, offset)
+        } else {
+            read_u32(sdt, offset) as u64
+        };
+        // Skip tables we can't read, in case it's not the one we want.
+        if let Ok(found) = table(addr) {
+            if &found[0..4] == signature {
+                return Ok(found);
+            }
+        }
+        offset += entry_size;
+    }
+    Err("ACPI table not found")
+}
+
+/// Read a small integer from an AML package, advancing `pos`.
+fn aml_integer(aml: &[u8], pos: &mut usize) -> Option<u16> {
+    match aml.get(*pos) {
+        Some(&AML_ZERO) => { *pos += 1; Some(0) }
+        Some(&AML_ONE) => { *pos += 1; Some(1) }
+        Some(&AML_BYTE_PREFIX) => {
+            *pos += 2;
+            aml.get(*pos - 1).map(|&b| b as u16)
+        }
+        _ => None,
+    }
+}
+
+/// Find the `SLP_TYPa` and `SLP_TYPb` values for S5 in the DSDT.
+fn s5_sleep_types(dsdt: &[u8]) -> Result<(u16, u16), &'static str> {
+    let aml = &dsdt[HEADER_SIZE..];
+    for i in 2..aml.len().saturating_sub(6) {
+        if &aml[i..i + 4] != b"_S5_" || aml[i + 4] != AML_PACKAGE {
+            continue;
+        }
+        let named = aml[i - 1] == AML_NAME ||
+            (aml[i - 1] == AML_ROOT_PREFIX && aml[i - 2] == AML_NAME);
+        if !named {
+            continue;
+        }
+
+        // Skip the package length, whose top two bits say how many more
+        // bytes it has, and the element count.
+        let mut pos = i + 5;
+        pos += 1 + (aml[pos] >> 6) as usize;
+        pos += 1;
+        let a = aml_integer(aml, &mut pos);
+        let b = aml_integer(aml, &mut pos);
+        return match (a, b) {
+            (Some(a), Some(b)) => Ok((a, b)),
+            _ => Err("can't understand the \\_S5 package"),
+        };
+    }
+    Err("no \\_S5 package in the DSDT")
+}
+
+/// Switch ACPI mode on, if the firmware left it off.
+unsafe fn enable_acpi(fadt: &[u8], pm1a: u16) -> Result<(), &'static str> {
+    if cpuio::inw(pm1a) & SCI_EN != 0 {
+        return Ok(());
+    }
+    let smi_cmd = read_u32(fadt, FADT_SMI_CMD) as u16;
+    let acpi_enable = fadt[FADT_ACPI_ENABLE];
+    if smi_cmd == 0 || acpi_enable == 0 {
+        return Err("ACPI is off, and the firmware won't turn it on");
+    }
+    cpuio::outb(acpi_enable, smi_cmd);
+    // The spec allows the firmware a few seconds.
+    for _ in 0..300 {
+        if cpuio::inw(pm1a) & SCI_EN != 0 {
+            return Ok(());
+        }
+        interrupts::io_delay_us(10 * 1000);
+    }
+    Err("timed out enabling ACPI")
+}
+
+/// Switch the machine off.  Returns an error if we can't.
+pub fn power_off() -> Result<(), &'static str> {
+    let fadt = try!(find_table(b"FACP"));
+    if fadt.len() < FADT_PM1B_CNT_BLK + 4 {
+        return Err("FADT is too short");
+    }
+    let dsdt_addr = if fadt.len() >= FADT_X_DSDT + 8 && read_u64(fadt, FADT_X_DSDT) != 0 {
+        read_u64(fadt, FADT_X_DSDT)
+    } else {
+        read_u32(fadt, FADT_DSDT) as u64
+    };
+    let (slp_typa, slp_typb) = try!(s5_sleep_types(try!(table(dsdt_addr))));
+    let pm1a = read_u32(fadt, FADT_PM1A_CNT_BLK) as u16;
+    let pm1b = read_u32(fadt, FADT_PM1B_CNT_BLK) as u16;
+    if pm1a == 0 {
+        return Err("FADT has no PM1a control block");
+    }
+
+    try!(unsafe { enable_acpi(fadt, pm1a) });
+    interrupts::without_interrupts(|| unsafe {
+        cpuio::outw(slp_typa << SLP_TYP_SHIFT | SLP_EN, pm1a);
+        if pm1b != 0 {
+            cpuio::outw(slp_typb << SLP_TYP_SHIFT | SLP_EN, pm1b);
+        }
+        // Give the hardware a moment to act on our request.
+        interrupts::io_delay_us(100 * 1000);
+    });
+    Err("the machine is still on")
+}
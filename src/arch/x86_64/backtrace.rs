@@ -0,0 +1,42 @@
+//! Stack backtraces, found by walking the chain of saved frame pointers.
+//!
+//! This only works because our target spec tells LLVM to keep frame
+//! pointers around.  We're careful not to follow `rbp` outside our kernel
+//! stack, because we're typically called when things have already gone
+//! wrong.
+
+use arch::kernel_layout;
+
+/// The most frames we'll report.
+pub const MAX_FRAMES: usize = 32;
+
+/// Call `f` with the return address of each frame on the stack, starting
+/// with our caller's caller.
+#[inline(never)]
+pub fn walk<F: FnMut(usize)>(mut f: F) {
+    let stack = kernel_layout::stack();
+    let (bottom, top) = (stack.start, stack.end);
+
+    let mut rbp: usize;
+    unsafe { asm!("mov %rbp, $0" : "=r"(rbp) ::: "volatile"); }
+
+    for _ in 0..MAX_FRAMES {
+        if rbp < bottom || rbp + 16 > top || rbp & 7 != 0 {
+            break;
+        }
+        // Each frame starts with the caller's `rbp`, followed by our
+        // return address.
+        let next = unsafe { *(rbp as *const usize) };
+        let ret = unsafe { *((rbp + 8) as *const usize) };
+        if ret == 0 {
+            break;
+        }
+        f(ret);
+
+        // The stack grows down, so callers' frames are always higher.
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
@@ -7,6 +7,13 @@ global start
 global gdt64_code_offset
 global HEAP_BOTTOM
 global HEAP_TOP
+global DMA_HEAP_BOTTOM
+global DMA_HEAP_TOP
+global p1_table
+global mmio_p2_table
+global mmio_p1_table
+global stack_bottom
+global stack_top
 
 extern long_mode_start
 
@@ -16,6 +23,11 @@ bits 32
 start:
         mov esp, stack_top               ; Use our temporary stack.
 
+        ;; Save the multiboot information pointer in edi, where it will
+        ;; become the first argument to rust_main.  None of our setup code
+        ;; below touches edi.
+        mov edi, ebx
+
         ;; Sanity-check our system.
         call test_multiboot
         call test_cpuid
@@ -96,8 +108,9 @@ test_long_mode:
         mov al, "L"
         jmp error
 
-;;; Configure p4_table and p3_table to map a single, huge 1GB page that
-;;; has the same virtual and physical addresses, located at 0x0.
+;;; Identity map the first 1GB of memory.  We use 2MB pages for most of
+;;; it, but the first 2MB is mapped using 4K pages from p1_table, so that
+;;; Rust code can unmap the null page and protect the kernel's code.
 setup_page_tables:
         ;; Point first entry in P4 at P3, setting appropriate flag
         ;; bits in the unused portions of the pointer.
@@ -105,8 +118,47 @@ setup_page_tables:
         or eax, 0b11                      ; Present & writable.
         mov [p4_table], eax
 
-        ;; Map first entry in P3 to 0, with flag bits set.
-        mov dword [p3_table], 0b10000011  ; Present & writable & huge.
+        ;; Point first entry in P3 at P2.
+        mov eax, p2_table
+        or eax, 0b11                      ; Present & writable.
+        mov [p3_table], eax
+
+        ;; Point second entry in P3 at a P2 with a single, empty P1.  This
+        ;; gives Rust a 2MB window at 1GB where it can map devices.
+        mov eax, mmio_p2_table
+        or eax, 0b11                      ; Present & writable.
+        mov [p3_table + 8], eax
+        mov eax, mmio_p1_table
+        or eax, 0b11                      ; Present & writable.
+        mov [mmio_p2_table], eax
+
+        ;; Point first entry in P2 at P1, which maps the first 2MB.
+        mov eax, p1_table
+        or eax, 0b11                      ; Present & writable.
+        mov [p2_table], eax
+
+        ;; Map the remaining P2 entries to 2MB huge pages.
+        mov ecx, 1
+.map_p2_table:
+        mov eax, 0x200000                 ; 2MB
+        mul ecx                           ; Start address of ecx-th page.
+        or eax, 0b10000011                ; Present & writable & huge.
+        mov [p2_table + ecx * 8], eax
+        inc ecx
+        cmp ecx, 512
+        jne .map_p2_table
+
+        ;; Map each P1 entry to a 4K page.
+        mov ecx, 0
+.map_p1_table:
+        mov eax, ecx
+        shl eax, 12                       ; Start address of ecx-th page.
+        or eax, 0b11                      ; Present & writable.
+        mov [p1_table + ecx * 8], eax
+        inc ecx
+        cmp ecx, 512
+        jne .map_p1_table
+
         ret
 
 ;;; Turn on paging.
@@ -145,6 +197,22 @@ p4_table:
 p3_table:
         resb 4096
 
+;;; P2 page table, mapping the first 1GB of memory.
+p2_table:
+        resb 4096
+
+;;; P1 page table, mapping the first 2MB of memory using 4K pages.
+p1_table:
+        resb 4096
+
+;;; P2 and P1 page tables for the device mapping window at 1GB.  The P1
+;;; table and the rest of the P2 table start out empty, and are filled in
+;;; by `paging.rs`.
+mmio_p2_table:
+        resb 4096
+mmio_p1_table:
+        resb 4096
+
 ;;; Our kernel stack.  We want to make this large enough so that we don't
 ;;; need to worry about overflowing it until we figure out how to set up
 ;;; a guard page and print errors on page faults.
@@ -157,6 +225,15 @@ HEAP_BOTTOM:
         resb 4*1024*1024
 HEAP_TOP:
 
+;;; A small heap for ISA DMA buffers, which must lie below 16MB.  We're
+;;; loaded at 1MB, so anything in our .bss qualifies, and `heap.rs`
+;;; double-checks.  ISA DMA transfers can't cross a 64KB boundary, so we
+;;; align this to 64KB, which means no block up to that size crosses one.
+align 65536
+DMA_HEAP_BOTTOM:
+        resb 256*1024
+DMA_HEAP_TOP:
+
 ;;; Global Description Table.  Used to set segmentation to the restricted
 ;;; values needed for 64-bit mode.
 section .rodata
@@ -0,0 +1,225 @@
+//! Identifying our CPU using `cpuid`, and reading its sensors.
+
+use core::fmt;
+use core::str;
+use x86::cpuid::{cpuid1, cpuid2};
+use x86::msr::{rdmsr, IA32_APERF, IA32_MPERF, IA32_THERM_STATUS,
+               MSR_TEMPERATURE_TARGET};
+
+use arch::x86_64::interrupts;
+
+/// Names of the feature bits in `cpuid` leaf 1, EDX.
+static FEATURES_EDX: &'static [(u32, &'static str)] = &[
+    (0, "fpu"), (4, "tsc"), (5, "msr"), (6, "pae"), (8, "cx8"),
+    (9, "apic"), (11, "sep"), (12, "mtrr"), (13, "pge"), (15, "cmov"),
+    (16, "pat"), (19, "clflush"), (23, "mmx"), (24, "fxsr"), (25, "sse"),
+    (26, "sse2"), (28, "htt"),
+];
+
+/// Names of the feature bits in `cpuid` leaf 1, ECX.
+static FEATURES_ECX: &'static [(u32, &'static str)] = &[
+    (0, "sse3"), (1, "pclmulqdq"), (5, "vmx"), (9, "ssse3"), (13, "cx16"),
+    (19, "sse4.1"), (20, "sse4.2"), (21, "x2apic"), (22, "movbe"),
+    (23, "popcnt"), (24, "tsc-deadline"), (25, "aes"), (26, "xsave"),
+    (28, "avx"), (30, "rdrand"), (31, "hypervisor"),
+];
+
+/// Basic information about our CPU.
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    features_edx: u32,
+    features_ecx: u32,
+}
+
+impl CpuInfo {
+    /// Query the CPU.
+    pub fn read() -> CpuInfo {
+        let leaf0 = cpuid1(0);
+        let mut vendor = [0; 12];
+        for (i, &reg) in [leaf0.ebx, leaf0.edx, leaf0.ecx].iter().enumerate() {
+            copy_u32(&mut vendor[i*4..], reg);
+        }
+
+        let leaf1 = cpuid1(1);
+        let mut family = (leaf1.eax >> 8) & 0xF;
+        let mut model = (leaf1.eax >> 4) & 0xF;
+        if family == 0xF {
+            family += (leaf1.eax >> 20) & 0xFF;
+        }
+        if family == 0x6 || family >= 0xF {
+            model += ((leaf1.eax >> 16) & 0xF) << 4;
+        }
+
+        // The brand string lives in extended leaves 0x80000002-4.
+        let mut brand = [0; 48];
+        if cpuid1(0x80000000).eax >= 0x80000004 {
+            for leaf in 0..3 {
+                let r = cpuid2(0x80000002 + leaf, 0);
+                for (i, &reg) in [r.eax, r.ebx, r.ecx, r.edx].iter().enumerate() {
+                    copy_u32(&mut brand[(leaf as usize)*16 + i*4..], reg);
+                }
+            }
+        }
+
+        CpuInfo {
+            vendor: vendor,
+            brand: brand,
+            family: family,
+            model: model,
+            stepping: leaf1.eax & 0xF,
+            features_edx: leaf1.edx,
+            features_ecx: leaf1.ecx,
+        }
+    }
+
+    /// The CPU vendor, such as "GenuineIntel".
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("(unknown)")
+    }
+
+    /// The marketing name of the CPU, if it has one.
+    pub fn brand(&self) -> &str {
+        str::from_utf8(&self.brand).unwrap_or("")
+            .trim_matches(|c| c == ' ' || c == '\0')
+    }
+
+    /// A displayable list of the features we know the names of.
+    pub fn features(&self) -> Features {
+        Features { info: self }
+    }
+}
+
+/// Store `value` into the first four bytes of `dest` in little-endian
+/// order, which is how `cpuid` strings are laid out.
+fn copy_u32(dest: &mut [u8], value: u32) {
+    for i in 0..4 {
+        dest[i] = (value >> (8 * i)) as u8;
+    }
+}
+
+/// A list of CPU features which can be displayed.
+pub struct Features<'a> {
+    info: &'a CpuInfo,
+}
+
+impl<'a> fmt::Display for Features<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let edx = FEATURES_EDX.iter().map(|f| (self.info.features_edx, f));
+        let ecx = FEATURES_ECX.iter().map(|f| (self.info.features_ecx, f));
+        let mut first = true;
+        for (bits, &(bit, name)) in edx.chain(ecx) {
+            if bits & (1 << bit) != 0 {
+                if !first { try!(write!(f, " ")); }
+                try!(write!(f, "{}", name));
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the cycle counter.
+pub fn rdtsc() -> u64 {
+    let (high, low): (u32, u32);
+    unsafe {
+        asm!("rdtsc" : "={edx}"(high), "={eax}"(low) ::: "volatile");
+    }
+    (high as u64) << 32 | low as u64
+}
+
+/// The thermal and power management leaf of `cpuid`.
+const CPUID_POWER: u32 = 0x06;
+
+/// In `CPUID_POWER`: EAX has a digital temperature sensor, and ECX has
+/// APERF and MPERF.
+const POWER_DTS: u32 = 1 << 0;
+const POWER_APERF_MPERF: u32 = 1 << 0;
+
+/// The `cpuid` leaf with the base frequency in MHz, in EAX.
+const CPUID_FREQUENCY: u32 = 0x16;
+
+/// Bits of `IA32_THERM_STATUS`: whether the reading is valid, and how many
+/// degrees below the maximum junction temperature we are.
+const THERM_READING_VALID: u64 = 1 << 31;
+const THERM_READING_SHIFT: u64 = 16;
+const THERM_READING_MASK: u64 = 0x7F;
+
+/// What we assume the maximum junction temperature is, if the CPU won't
+/// tell us.
+const DEFAULT_TJ_MAX: u32 = 100;
+
+/// How long we measure APERF and MPERF for.
+const FREQUENCY_SAMPLE_US: usize = 50 * 1000;
+
+/// Does the CPU have `cpuid` leaf `leaf`?
+fn has_leaf(leaf: u32) -> bool {
+    cpuid1(0).eax >= leaf
+}
+
+/// Is this an Intel CPU?  The thermal MSRs we read are Intel's.
+fn is_intel() -> bool {
+    CpuInfo::read().vendor() == "GenuineIntel"
+}
+
+/// The temperature of this core in degrees Celsius, if it has a sensor we
+/// can read.
+pub fn temperature() -> Option<u32> {
+    if !is_intel() || !has_leaf(CPUID_POWER) ||
+        cpuid1(CPUID_POWER).eax & POWER_DTS == 0
+    {
+        return None;
+    }
+    let status = unsafe { rdmsr(IA32_THERM_STATUS) };
+    if status & THERM_READING_VALID == 0 {
+        return None;
+    }
+    let below_max = ((status >> THERM_READING_SHIFT) & THERM_READING_MASK) as u32;
+
+    // Only some CPUs tell us their maximum, and reading it on the others
+    // would fault.  Family 6 from Nehalem (model 0x1A) onwards have it.
+    let info = CpuInfo::read();
+    let tj_max = if info.family == 6 && info.model >= 0x1A {
+        match ((unsafe { rdmsr(MSR_TEMPERATURE_TARGET) } >> 16) & 0xFF) as u32 {
+            0 => DEFAULT_TJ_MAX,
+            t => t,
+        }
+    } else {
+        DEFAULT_TJ_MAX
+    };
+    Some(tj_max.saturating_sub(below_max))
+}
+
+/// The CPU's base frequency in MHz, if it will tell us.
+pub fn base_frequency_mhz() -> Option<u32> {
+    if has_leaf(CPUID_FREQUENCY) {
+        match cpuid1(CPUID_FREQUENCY).eax & 0xFFFF {
+            0 => None,
+            mhz => Some(mhz),
+        }
+    } else {
+        None
+    }
+}
+
+/// How fast we've really been running, as a percentage of the base
+/// frequency, measured over a short busy wait.  Turbo boost makes this
+/// more than 100, and throttling makes it less.  Returns `None` if the CPU
+/// lacks APERF and MPERF.
+pub fn effective_frequency_percent() -> Option<u64> {
+    if !has_leaf(CPUID_POWER) || cpuid1(CPUID_POWER).ecx & POWER_APERF_MPERF == 0 {
+        return None;
+    }
+    let (aperf, mperf) = unsafe { (rdmsr(IA32_APERF), rdmsr(IA32_MPERF)) };
+    interrupts::io_delay_us(FREQUENCY_SAMPLE_US);
+    let (aperf2, mperf2) = unsafe { (rdmsr(IA32_APERF), rdmsr(IA32_MPERF)) };
+    let (actual, reference) = (aperf2.wrapping_sub(aperf), mperf2.wrapping_sub(mperf));
+    if reference == 0 {
+        None
+    } else {
+        Some(actual * 100 / reference)
+    }
+}
@@ -0,0 +1,95 @@
+//! The 8042 "keyboard controller", which these days is usually emulated by
+//! the chipset or the hypervisor.
+//!
+//! The controller has a data port and a status port.  Before we read data,
+//! we must check that the output buffer is full, or we'll get whatever
+//! stale byte is lying around.  Before we write a command or data, we must
+//! check that the input buffer is empty, or the controller may drop it.
+//! Real controllers can be slow, and some emulated ones never answer, so we
+//! only wait a bounded time for either.
+//!
+//! See http://wiki.osdev.org/%228042%22_PS/2_Controller.
+
+use cpuio;
+
+use arch::x86_64::interrupts;
+
+/// Our ports.  The status and command registers share a port.
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+
+/// Bits of the status register.
+const OUTPUT_FULL: u8 = 1 << 0;
+const INPUT_FULL: u8 = 1 << 1;
+/// The byte in the output buffer came from the second (mouse) port.
+const AUX_DATA: u8 = 1 << 5;
+
+/// How many times we poll the status register before giving up, and how
+/// long we wait between polls.  Together, about 10ms.
+const TIMEOUT_POLLS: usize = 1000;
+const POLL_DELAY_US: usize = 10;
+
+/// Read the status register.
+pub fn status() -> u8 {
+    unsafe { cpuio::inb(STATUS) }
+}
+
+/// Wait until `status() & mask == want`.
+fn wait_for(mask: u8, want: u8, err: &'static str) -> Result<(), &'static str> {
+    for _ in 0..TIMEOUT_POLLS {
+        if status() & mask == want {
+            return Ok(());
+        }
+        interrupts::io_delay_us(POLL_DELAY_US);
+    }
+    Err(err)
+}
+
+/// Wait until the controller is ready to accept a command or data.
+pub fn wait_for_input_empty() -> Result<(), &'static str> {
+    wait_for(INPUT_FULL, 0, "timed out waiting for 8042 to accept input")
+}
+
+/// Wait until the controller has a byte for us.
+pub fn wait_for_output_full() -> Result<(), &'static str> {
+    wait_for(OUTPUT_FULL, OUTPUT_FULL, "timed out waiting for 8042 output")
+}
+
+/// Send a command to the controller itself.
+pub fn send_command(command: u8) -> Result<(), &'static str> {
+    try!(wait_for_input_empty());
+    unsafe { cpuio::outb(command, COMMAND); }
+    Ok(())
+}
+
+/// Send a byte to the first (keyboard) device, or to the controller as the
+/// argument of a command.
+pub fn write_data(byte: u8) -> Result<(), &'static str> {
+    try!(wait_for_input_empty());
+    unsafe { cpuio::outb(byte, DATA); }
+    Ok(())
+}
+
+/// Wait for a byte from the controller, such as a reply to a command.
+pub fn read_data() -> Result<u8, &'static str> {
+    try!(wait_for_output_full());
+    Ok(unsafe { cpuio::inb(DATA) })
+}
+
+/// Read a byte from the keyboard, if the controller has one for us right
+/// now.  This is for interrupt handlers, so it doesn't wait.  If the byte
+/// is from the mouse port, we throw it away, since we don't have a mouse
+/// driver and a full output buffer blocks the keyboard.
+pub fn poll_keyboard() -> Option<u8> {
+    let status = status();
+    if status & OUTPUT_FULL == 0 {
+        return None;
+    }
+    let byte = unsafe { cpuio::inb(DATA) };
+    if status & AUX_DATA != 0 {
+        None
+    } else {
+        Some(byte)
+    }
+}
@@ -2,6 +2,7 @@
 
 global report_interrupt
 global interrupt_handlers
+global interrupt_handlers_count
 
 extern rust_interrupt_handler
 
@@ -139,7 +140,16 @@ int_entry_error 17               ; Error code is always 0?
 int_entry_dummy_error 18
 ;;; 19 SIMD Floating-Point Exception
 int_entry_dummy_error 19
+;;; 20 Virtualization Exception
+int_entry_dummy_error 20
+;;; 21 Control-Protection Exception
+int_entry_error 21
+;;; 28 Hypervisor Injection Exception
+int_entry_dummy_error 28
+;;; 29 VMM Communication Exception
+int_entry_error 29
 ;;; 30 Security Exception
+int_entry_error 30
 
 ;;; Fill in cutom handlers 32 through 255.
 int_entry_dummy_error 32
@@ -153,6 +163,11 @@ int_entry_dummy_error i
 int_shared:
         push_caller_saved
 
+        ;; Rust code assumes the direction flag is clear, as the ABI
+        ;; requires, but we may have interrupted `memmove` copying
+        ;; backwards.  `iretq` restores the old flags for us.
+        cld
+
         mov rdi, rsp            ; Pass pointer to interrupt data.
         call rust_interrupt_handler
 
@@ -193,20 +208,26 @@ interrupt_handlers:
         dq int_entry_17
         dq int_entry_18
         dq int_entry_19
+        dq int_entry_20
+        dq int_entry_21
         dq 0
         dq 0
         dq 0
         dq 0
         dq 0
         dq 0
-        dq 0
-        dq 0
-        dq 0
-        dq 0
-        dq 0                    ; int_entry_30
+        dq int_entry_28
+        dq int_entry_29
+        dq int_entry_30
         dq 0
 %assign i 32
 %rep    224
         dq int_entry_%+i
 %assign i i+1
 %endrep
+interrupt_handlers_end:
+
+;;; The number of entries in `interrupt_handlers`, so that Rust doesn't have
+;;; to take our word for it.
+interrupt_handlers_count:
+        dq (interrupt_handlers_end - interrupt_handlers) / 8
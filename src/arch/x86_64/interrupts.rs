@@ -8,14 +8,27 @@
 /// some general advice on setting up interrupts and an entertaining saga
 /// of frustration.
 
+use core::fmt::{self, Write};
 use core::mem::size_of;
-use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use cpuio;
 use pic8259_simple::ChainedPics;
 use spin::Mutex;
 use x86;
 use x86::irq::IdtEntry;
 
+use arch::x86_64::cpu;
+use arch::x86_64::kernel_layout;
 use arch::x86_64::keyboard;
+use arch::x86_64::latency;
+use arch::x86_64::paging;
+use arch::x86_64::sb16;
+use arch::x86_64::serial;
+use arch::x86_64::timer;
+use arch::x86_64::vectors;
+use kassert;
+use status_bar;
 
 
 //=========================================================================
@@ -34,8 +47,42 @@ extern {
     /// A primitive interrupt-reporting function.
     fn report_interrupt();
 
-    /// Interrupt handlers which call back to rust_interrupt_handler.
-    static interrupt_handlers: [*const u8; IDT_ENTRY_COUNT];
+    /// Interrupt handlers which call back to rust_interrupt_handler, or
+    /// null for vectors we don't handle.  This is really an array of
+    /// `interrupt_handlers_count` entries, but we only take the address
+    /// of it; use `handler_table` instead.
+    static interrupt_handlers: *const u8;
+
+    /// The number of entries in `interrupt_handlers`.
+    static interrupt_handlers_count: u64;
+}
+
+/// The handler table from `interrupt_handlers.asm`, with the length it
+/// says it has.
+fn handler_table() -> &'static [*const u8] {
+    unsafe {
+        slice::from_raw_parts(&interrupt_handlers as *const *const u8,
+                              interrupt_handlers_count as usize)
+    }
+}
+
+/// The assembly handler for `vector`, if it has one.  This is the only
+/// way to index `handler_table`, so a table that's shorter than we expect
+/// can't send us off into the weeds.
+fn asm_handler(vector: usize) -> Option<*const u8> {
+    match handler_table().get(vector) {
+        Some(&handler) if !handler.is_null() => Some(handler),
+        _ => None,
+    }
+}
+
+/// Make sure the assembly table and our IDT agree on how many vectors
+/// there are.
+fn check_handler_table() {
+    let count = handler_table().len();
+    assert!(count == IDT_ENTRY_COUNT,
+            "interrupt_handlers.asm has {} entries, but the IDT has {}",
+            count, IDT_ENTRY_COUNT);
 }
 
 /// Various data available on our stack when handling an interrupt.
@@ -56,6 +103,31 @@ pub struct InterruptContext {
     _pad_1: u32,
     error_code: u32,
     _pad_2: u32,
+    // Pushed by the CPU itself when the interrupt occurred.
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+
+impl InterruptContext {
+    /// Write the registers we saved, for crash reports.
+    pub fn write_registers<W: Write>(&self, w: &mut W) -> fmt::Result {
+        let registers = [
+            ("rax", self.rax), ("rcx", self.rcx), ("rdx", self.rdx),
+            ("rsi", self.rsi), ("rdi", self.rdi), ("r8", self.r8),
+            ("r9", self.r9), ("r10", self.r10), ("r11", self.r11),
+            ("rip", self.rip), ("rsp", self.rsp), ("rflags", self.rflags),
+            ("cs", self.cs), ("ss", self.ss),
+        ];
+        for (i, &(name, value)) in registers.iter().enumerate() {
+            try!(write!(w, "{:>6}={:016x}", name, value));
+            try!(w.write_str(if i % 3 == 2 { "\n" } else { "  " }));
+        }
+        w.write_str("\n")
+    }
 }
 
 
@@ -67,51 +139,225 @@ pub struct InterruptContext {
 static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(0x20, 0x28) });
 
-/// Print our information about a CPU exception, and loop.
-fn cpu_exception_handler(ctx: &InterruptContext) {
+/// The context of the CPU exception we're dying from, if any.  This
+/// points into the stack, but it stays valid because we never return
+/// from a CPU exception.
+static EXCEPTION_CONTEXT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The registers saved by the CPU exception which caused us to panic, if
+/// that's what happened.
+pub fn exception_context() -> Option<&'static InterruptContext> {
+    match EXCEPTION_CONTEXT.load(Ordering::SeqCst) {
+        0 => None,
+        addr => Some(unsafe { &*(addr as *const InterruptContext) }),
+    }
+}
 
-    // Print general information provided by x86::irq.
-    println!("{}, error 0x{:x}",
-             x86::irq::EXCEPTIONS[ctx.int_id as usize],
-             ctx.error_code);
+/// The names of the 32 vectors the CPU reserves for exceptions.
+/// `x86::irq::EXCEPTIONS` only covers the first 15.
+const EXCEPTION_NAMES: [&'static str; 32] = [
+    "#DE Divide Error",
+    "#DB Debug",
+    "NMI Non-Maskable Interrupt",
+    "#BP Breakpoint",
+    "#OF Overflow",
+    "#BR BOUND Range Exceeded",
+    "#UD Invalid Opcode",
+    "#NM Device Not Available",
+    "#DF Double Fault",
+    "Coprocessor Segment Overrun",
+    "#TS Invalid TSS",
+    "#NP Segment Not Present",
+    "#SS Stack-Segment Fault",
+    "#GP General Protection",
+    "#PF Page Fault",
+    "Reserved exception 15",
+    "#MF x87 Floating-Point Error",
+    "#AC Alignment Check",
+    "#MC Machine Check",
+    "#XM SIMD Floating-Point Exception",
+    "#VE Virtualization Exception",
+    "#CP Control Protection Exception",
+    "Reserved exception 22",
+    "Reserved exception 23",
+    "Reserved exception 24",
+    "Reserved exception 25",
+    "Reserved exception 26",
+    "Reserved exception 27",
+    "#HV Hypervisor Injection Exception",
+    "#VC VMM Communication Exception",
+    "#SX Security Exception",
+    "Reserved exception 31",
+];
+
+/// Describe a CPU exception as best we can, and panic.  Returning would
+/// just run the faulting instruction again.
+fn cpu_exception_handler(ctx: &InterruptContext) -> ! {
+    EXCEPTION_CONTEXT.store(ctx as *const _ as usize, Ordering::SeqCst);
+
+    let name = EXCEPTION_NAMES[ctx.int_id as usize];
+    let error_code = ctx.error_code;
 
     // Provide detailed information about our error code if we know how to
     // parse it.
     match ctx.int_id {
         14 => {
-            let err = x86::irq::PageFaultError::from_bits(ctx.error_code);
-            println!("{:?}", err);
+            let err = x86::irq::PageFaultError::from_bits(error_code);
+            let addr = unsafe { x86::controlregs::cr2() } as usize;
+            let rip = ctx.rip;
+            if paging::is_null_page(addr) {
+                panic!("NULL pointer dereference at RIP=0x{:x} (address 0x{:x}), {:?}",
+                       rip, addr, err);
+            } else {
+                panic!("Page fault at 0x{:x}, RIP=0x{:x}, {:?}", addr, rip, err);
+            }
         }
-        _ => {}
+        _ => panic!("{}, error 0x{:x}", name, error_code),
     }
+}
+
+/// The total number of interrupts we've handled.
+static INTERRUPTS: AtomicUsize = ATOMIC_USIZE_INIT;
 
-    loop {}
+/// The number of interrupts we've handled since boot.
+pub fn interrupt_count() -> usize {
+    INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Busy-wait for roughly `us` microseconds.  Each write to the POST
+/// diagnostic port 0x80 takes about a microsecond on PC-compatible
+/// hardware, which is crude but works even with interrupts disabled,
+/// unlike anything based on the timer.
+pub fn io_delay_us(us: usize) {
+    let mut port: cpuio::Port<u8> = unsafe { cpuio::Port::new(0x80) };
+    for _ in 0..us {
+        port.write(0);
+    }
+}
+
+/// Allow the PIC to deliver the hardware interrupt `int_id`, which the
+/// BIOS may have left masked.
+pub unsafe fn unmask_irq(int_id: u8) {
+    PICS.lock().unmask(int_id);
+}
+
+/// An all-zero row of `UNKNOWN_INTERRUPTS`.  Atomics can't be copied, so
+/// we have to spell out every entry.
+const UNSEEN: [AtomicUsize; 16] = [
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT, ATOMIC_USIZE_INIT,
+];
+
+/// How many times we've seen each interrupt that we don't know how to
+/// handle, indexed by `[int_id / 16][int_id % 16]`.
+static UNKNOWN_INTERRUPTS: [[AtomicUsize; 16]; IDT_ENTRY_COUNT / 16] = [
+    UNSEEN, UNSEEN, UNSEEN, UNSEEN, UNSEEN, UNSEEN, UNSEEN, UNSEEN,
+    UNSEEN, UNSEEN, UNSEEN, UNSEEN, UNSEEN, UNSEEN, UNSEEN, UNSEEN,
+];
+
+/// The sum of `UNKNOWN_INTERRUPTS`, so that the main loop can cheaply
+/// check whether there's anything new to report.
+static UNKNOWN_INTERRUPT_TOTAL: AtomicUsize = ATOMIC_USIZE_INIT;
+static REPORTED_UNKNOWN_INTERRUPT_TOTAL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// How many spurious PIC interrupts we've seen.
+static SPURIOUS_INTERRUPTS: AtomicUsize = ATOMIC_USIZE_INIT;
+static REPORTED_SPURIOUS_INTERRUPTS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The counts from `UNKNOWN_INTERRUPTS` which we've already logged.
+static REPORTED_UNKNOWN_INTERRUPTS: Mutex<[usize; IDT_ENTRY_COUNT]> =
+    Mutex::new([0; IDT_ENTRY_COUNT]);
+
+/// Deal with an interrupt that nobody has claimed.  Rather than hanging
+/// the machine, we count it, and if it came from the PIC, mask that line
+/// so it can't bother us again.  `report_unknown_interrupts` logs it
+/// later.
+fn unknown_interrupt(int_id: u8) {
+    let id = int_id as usize;
+    UNKNOWN_INTERRUPTS[id / 16][id % 16].fetch_add(1, Ordering::SeqCst);
+    UNKNOWN_INTERRUPT_TOTAL.fetch_add(1, Ordering::SeqCst);
+
+    let mut pics = PICS.lock();
+    if pics.handles_interrupt(int_id) {
+        unsafe { pics.mask(int_id); }
+    }
+}
+
+/// Log any unknown or spurious interrupts we've seen since we were last
+/// called.  Call this from the main loop, not from an interrupt handler.
+pub fn report_unknown_interrupts() {
+    let spurious = SPURIOUS_INTERRUPTS.load(Ordering::SeqCst);
+    let reported_spurious =
+        REPORTED_SPURIOUS_INTERRUPTS.swap(spurious, Ordering::SeqCst);
+    if reported_spurious != spurious {
+        log_rate_limited!(5, 1000, "Spurious PIC interrupts: {}", spurious);
+    }
+
+    let total = UNKNOWN_INTERRUPT_TOTAL.load(Ordering::SeqCst);
+    let reported_total =
+        REPORTED_UNKNOWN_INTERRUPT_TOTAL.swap(total, Ordering::SeqCst);
+    if reported_total == total {
+        return;
+    }
+    let mut reported = REPORTED_UNKNOWN_INTERRUPTS.lock();
+    let counts = UNKNOWN_INTERRUPTS.iter().flat_map(|row| row.iter());
+    for (id, count) in counts.enumerate() {
+        let count = count.load(Ordering::SeqCst);
+        if count == reported[id] {
+            continue;
+        }
+        log_rate_limited!(5, 1000, "UNKNOWN INTERRUPT #{} (seen {} times)",
+                          id, count);
+        let from_pic = without_interrupts(|| {
+            PICS.lock().handles_interrupt(id as u8)
+        });
+        if reported[id] == 0 && from_pic {
+            log_rate_limited!(5, 1000, "Masked PIC interrupt #{}", id);
+        }
+        reported[id] = count;
+    }
 }
 
 /// Called from our assembly-language interrupt handlers to dispatch an
 /// interrupt.
 #[no_mangle]
 pub unsafe extern "C" fn rust_interrupt_handler(ctx: &InterruptContext) {
-    match ctx.int_id {
-        0x00...0x0F => cpu_exception_handler(ctx),
-        0x20 => { /* Timer. */ }
-        0x21 => {
-            if let Some(input) = keyboard::read_char() {
-                if input == '\r' {
-                    println!("");
-                } else {
-                    print!("{}", input);
-                }
-            }
+    let start = cpu::rdtsc();
+    INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+
+    // A spurious IRQ 7 or 15 isn't a real request, so don't run a handler
+    // or mask the line for it, and only acknowledge it as far as the PICs
+    // expect.
+    {
+        let mut pics = PICS.lock();
+        if pics.is_spurious(ctx.int_id as u8) {
+            pics.notify_end_of_spurious_interrupt(ctx.int_id as u8);
+            SPURIOUS_INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+            return;
         }
-        0x80 => println!("Not actually Linux, sorry."),
-        _ => {
-            println!("UNKNOWN INTERRUPT #{}", ctx.int_id);
-            loop {}
+    }
+
+    match ctx.int_id {
+        // Breakpoints are how `kassert!` reports failures.
+        0x03 if kassert::handle_breakpoint(ctx.rax, ctx.rdx) => {}
+        0x00...0x1F => cpu_exception_handler(ctx),
+        0x20 => {
+            timer::handle_interrupt();
+            status_bar::tick();
         }
+        0x21 => keyboard::handle_interrupt(),
+        id if id == sb16::INTERRUPT as u32 => sb16::handle_interrupt(),
+        id if id == serial::INTERRUPT as u32 => serial::handle_interrupt(),
+        id if id == vectors::syscall() as u32 =>
+            println!("Not actually Linux, sorry."),
+        id if vectors::dispatch(id as u8) => {}
+        _ => unknown_interrupt(ctx.int_id as u8),
     }
 
     PICS.lock().notify_end_of_interrupt(ctx.int_id as u8);
+    latency::record_handler(ctx.int_id as u8, start);
 }
 
 
@@ -127,14 +373,15 @@ struct Idt {
 impl Idt {
     /// Initialize interrupt handling.
     pub unsafe fn initialize(&mut self) {
+        check_handler_table();
         self.add_handlers();
         self.load();
     }
 
     /// Fill in our IDT with our handlers.
     fn add_handlers(&mut self) {
-        for (index, &handler) in interrupt_handlers.iter().enumerate() {
-            if handler != ptr::null() {
+        for index in 0..IDT_ENTRY_COUNT {
+            if let Some(handler) = asm_handler(index) {
                 self.table[index] = IdtEntry::new(gdt64_code_offset, handler);
             }
         }
@@ -155,6 +402,143 @@ static IDT: Mutex<Idt> = Mutex::new(Idt {
     table: [missing_handler(); IDT_ENTRY_COUNT]
 });
 
+/// The kinds of IDT gate we support.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GateKind {
+    /// Interrupts are disabled while the handler runs.
+    Interrupt,
+    /// Interrupts stay enabled while the handler runs.
+    Trap,
+}
+
+/// How an IDT gate behaves.
+#[derive(Clone, Copy, Debug)]
+pub struct GateOptions {
+    pub kind: GateKind,
+    /// The least privileged ring which may invoke this gate with `int`.
+    /// Use 3 to allow system calls from user mode.
+    pub dpl: u8,
+    /// Which interrupt stack table entry to switch to, or 0 to stay on the
+    /// current stack.
+    pub ist: u8,
+}
+
+/// The options we use for every gate at boot.
+pub const DEFAULT_GATE: GateOptions = GateOptions {
+    kind: GateKind::Interrupt,
+    dpl: 0,
+    ist: 0,
+};
+
+/// IDT flag bits.
+const GATE_PRESENT: u8 = 0x80;
+const GATE_TYPE_INTERRUPT: u8 = 0x0E;
+const GATE_TYPE_TRAP: u8 = 0x0F;
+
+impl GateOptions {
+    /// The IDT `flags` byte for these options.
+    fn flags(&self) -> u8 {
+        let typ = match self.kind {
+            GateKind::Interrupt => GATE_TYPE_INTERRUPT,
+            GateKind::Trap => GATE_TYPE_TRAP,
+        };
+        GATE_PRESENT | self.dpl << 5 | typ
+    }
+}
+
+/// Make sure `handler` is somewhere the CPU could sensibly jump to.  An
+/// IDT entry pointing into data, or at a non-canonical address, would
+/// fault on every interrupt, and the fault would be hard to track down.
+fn check_handler(handler: *const u8) -> Result<(), &'static str> {
+    let addr = handler as usize;
+    // Canonical addresses have bits 47 through 63 all equal.
+    let high = addr >> 47;
+    if high != 0 && high != (1 << 17) - 1 {
+        return Err("handler address is not canonical");
+    }
+    if !kernel_layout::text().contains(addr) {
+        return Err("handler is not in the kernel's code");
+    }
+    Ok(())
+}
+
+/// Point IDT entry `vector` at `handler`, which must be an assembly
+/// routine ending in `iretq`.  Use `default_handler` to get back our
+/// standard handler, which calls `rust_interrupt_handler`.  The change
+/// takes effect immediately.
+pub unsafe fn set_gate(vector: u8, handler: *const u8, options: GateOptions)
+    -> Result<(), &'static str>
+{
+    try!(check_handler(handler));
+    if options.dpl > 3 {
+        return Err("DPL must be between 0 and 3");
+    }
+    if options.ist != 0 {
+        // We'd need a TSS with an interrupt stack table first.
+        return Err("interrupt stacks aren't supported");
+    }
+
+    let mut entry = IdtEntry::new(gdt64_code_offset, handler);
+    entry.flags = options.flags();
+    // The CPU reads the IDT directly, so make sure it can't see a
+    // half-written entry.
+    without_interrupts(|| {
+        IDT.lock().table[vector as usize] = entry;
+    });
+    Ok(())
+}
+
+/// Change the options of IDT entry `vector`, keeping its handler.  For
+/// example, use this to make `int 0x80` callable from user mode.
+pub unsafe fn set_gate_options(vector: u8, options: GateOptions)
+    -> Result<(), &'static str>
+{
+    let handler = try!(current_handler(vector).ok_or("no handler installed"));
+    set_gate(vector, handler, options)
+}
+
+/// The handler currently installed for `vector`, if any.
+fn current_handler(vector: u8) -> Option<*const u8> {
+    let entry = IDT.lock().table[vector as usize];
+    if entry.flags & GATE_PRESENT == 0 {
+        None
+    } else {
+        Some((entry.base_hi << 16 | entry.base_lo as u64) as *const u8)
+    }
+}
+
+/// Our standard handler for `vector`, from `interrupt_handlers.asm`.
+pub fn default_handler(vector: u8) -> Option<*const u8> {
+    asm_handler(vector as usize)
+}
+
+
+//=========================================================================
+//  Disabling interrupts
+
+/// Are interrupts currently enabled?
+fn interrupts_enabled() -> bool {
+    let flags: u64;
+    unsafe { asm!("pushfq; popq $0" : "=r"(flags) ::: "volatile"); }
+    flags & (1 << 9) != 0
+}
+
+/// Run `f` with interrupts disabled, so that it can safely take locks
+/// which are also used by interrupt handlers.  Interrupts are restored to
+/// their previous state afterwards.  We time how long interrupts stay
+/// off, for `latency`.
+pub fn without_interrupts<R, F: FnOnce() -> R>(f: F) -> R {
+    let enabled = interrupts_enabled();
+    if enabled { unsafe { x86::irq::disable(); } }
+    let start = cpu::rdtsc();
+    let result = f();
+    if enabled {
+        latency::record_disabled(start);
+        unsafe { x86::irq::enable(); }
+    }
+    result
+}
+
 
 //=========================================================================
 //  Initialization
@@ -162,8 +546,14 @@ static IDT: Mutex<Idt> = Mutex::new(Idt {
 /// Use the `int` instruction to manually trigger an interrupt without
 /// actually using `sti` to enable interrupts.  This is highly recommended by
 /// http://jvns.ca/blog/2013/12/04/day-37-how-a-keyboard-works/
+///
+/// `int` needs a constant vector, so we can only test the default system
+/// call gate.
 #[allow(dead_code)]
 pub unsafe fn test_interrupt() {
+    if vectors::syscall() != vectors::DEFAULT_SYSCALL {
+        return;
+    }
     println!("Triggering interrupt.");
     int!(0x80);
     println!("Interrupt returned!");
@@ -171,6 +561,12 @@ pub unsafe fn test_interrupt() {
 
 /// Platform-independent initialization.
 pub unsafe fn initialize() {
+    vectors::initialize(&[
+        (0x20, "timer"),
+        (0x21, "keyboard"),
+        (serial::INTERRUPT, "serial"),
+        (sb16::INTERRUPT, "sb16"),
+    ]);
     PICS.lock().initialize();
     IDT.lock().initialize();
 
@@ -214,7 +610,7 @@ impl IdtEntryExt for IdtEntry {
             base_lo: ((handler as u64) & 0xFFFF) as u16,
             sel: gdt_code_selector,
             res0: 0,
-            flags: 0b100_01110,
+            flags: DEFAULT_GATE.flags(),
             base_hi: (handler as u64) >> 16,
             res1: 0,
         }
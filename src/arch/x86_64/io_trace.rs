@@ -0,0 +1,88 @@
+//! Port I/O tracing, available when we're built with `--features
+//! trace-io`.
+//!
+//! Boot with `iotrace=0x20-0x21,0x60` on the kernel command line to log
+//! every access to those ports on COM1.  A bare `iotrace` logs every port,
+//! which is a lot of output.  Each line ends with the address of the code
+//! which made the access; `addr2line -e` on the kernel binary will tell
+//! you where that is.
+
+use core::fmt::{self, Write};
+use cpuio;
+use cpuio::trace::{self, Access};
+
+use arch::x86_64::multiboot;
+use util;
+
+/// COM1's data and line status registers.
+const COM1_DATA: u16 = 0x3F8;
+const COM1_LINE_STATUS: u16 = 0x3FD;
+
+/// A writer which talks straight to COM1.  Our hook can be called while
+/// somebody else holds `serial::COM1` (or any other lock), so we can't use
+/// the normal console.  `cpuio` won't trace our own I/O, so this doesn't
+/// recurse.
+struct RawSerial;
+
+impl Write for RawSerial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            unsafe {
+                // Wait until the transmit buffer is empty.
+                while cpuio::inb(COM1_LINE_STATUS) & 0x20 == 0 {}
+                cpuio::outb(byte, COM1_DATA);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Log a single access.
+fn log_access(access: &Access) {
+    let (direction, suffix) = match (access.write, access.size) {
+        (false, 1) => ("in", "b"),
+        (false, 2) => ("in", "w"),
+        (false, _) => ("in", "l"),
+        (true, 1) => ("out", "b"),
+        (true, 2) => ("out", "w"),
+        (true, _) => ("out", "l"),
+    };
+    let _ = write!(RawSerial, "io: {}{} {:#06x} {:#x} (at {:#x})\r\n",
+                   direction, suffix, access.port, access.value,
+                   access.address);
+}
+
+/// Parse a port range of the form `first-last` or `port`.
+fn parse_range(s: &str) -> Option<(u16, u16)> {
+    let mut parts = s.splitn(2, '-');
+    let first = parts.next().and_then(util::parse_number);
+    let last = match parts.next() {
+        Some(last) => util::parse_number(last),
+        None => first,
+    };
+    match (first, last) {
+        (Some(first), Some(last)) if first <= last && last <= 0xFFFF =>
+            Some((first as u16, last as u16)),
+        _ => None,
+    }
+}
+
+/// Start tracing if `iotrace` was passed on the kernel command line.
+pub fn initialize() {
+    let ports = match multiboot::info().and_then(|i| i.option("iotrace")) {
+        Some(ports) => ports,
+        None => return,
+    };
+    for range in ports.split(',').filter(|r| !r.is_empty()) {
+        match parse_range(range) {
+            Some((first, last)) => {
+                if let Err(err) = trace::trace_ports(first, last) {
+                    println!("iotrace: {}", err);
+                }
+            }
+            None => println!("iotrace: can't parse port range: {}", range),
+        }
+    }
+    println!("Tracing port I/O to COM1.");
+    trace::set_hook(Some(log_access));
+}
@@ -0,0 +1,84 @@
+//! The ISA DMA controller (an emulated Intel 8237), which lets old
+//! devices like the Sound Blaster read and write memory on their own.
+//! We only support the 8-bit channels 0 to 3.
+//!
+//! See http://wiki.osdev.org/ISA_DMA for the gory details.
+
+use cpuio::Port;
+
+/// ISA DMA can only reach the first 16MB of physical memory.
+pub const LIMIT: usize = 16 * 1024 * 1024;
+
+/// A single transfer can't cross a 64KB boundary, because the page
+/// register doesn't increment.
+const BOUNDARY: usize = 64 * 1024;
+
+/// Which way data flows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// The device reads from memory.
+    ToDevice,
+    /// The device writes to memory.
+    FromDevice,
+}
+
+/// Mode register bits.
+const MODE_SINGLE: u8 = 0x40;
+const MODE_READ_MEMORY: u8 = 0x08;
+const MODE_WRITE_MEMORY: u8 = 0x04;
+
+/// Set this bit in the single-channel mask register to mask a channel.
+const MASK_ON: u8 = 0x04;
+
+/// The address, count and page ports for each channel.  The page
+/// registers are in a strange order for historical reasons.
+const ADDRESS_PORTS: [u16; 4] = [0x00, 0x02, 0x04, 0x06];
+const COUNT_PORTS: [u16; 4] = [0x01, 0x03, 0x05, 0x07];
+const PAGE_PORTS: [u16; 4] = [0x87, 0x83, 0x81, 0x82];
+
+/// Program `channel` to transfer `len` bytes at the physical address
+/// `buffer`, and unmask it.  The transfer starts when the device asks
+/// for it.
+///
+/// This is unsafe because the device will access `buffer` at some
+/// unknown time after we return.
+pub unsafe fn start(channel: u8, buffer: usize, len: usize,
+                    direction: Direction) -> Result<(), &'static str> {
+    if channel >= 4 {
+        return Err("only 8-bit DMA channels 0-3 are supported");
+    }
+    if len == 0 || len > BOUNDARY {
+        return Err("DMA transfers must be 1 to 65536 bytes");
+    }
+    if buffer + len > LIMIT {
+        return Err("DMA buffer must lie below 16MB");
+    }
+    if buffer / BOUNDARY != (buffer + len - 1) / BOUNDARY {
+        return Err("DMA buffer must not cross a 64KB boundary");
+    }
+
+    let mut mask: Port<u8> = Port::new(0x0A);
+    let mut mode: Port<u8> = Port::new(0x0B);
+    let mut flip_flop: Port<u8> = Port::new(0x0C);
+    let mut address: Port<u8> = Port::new(ADDRESS_PORTS[channel as usize]);
+    let mut count: Port<u8> = Port::new(COUNT_PORTS[channel as usize]);
+    let mut page: Port<u8> = Port::new(PAGE_PORTS[channel as usize]);
+
+    let direction_bits = match direction {
+        Direction::ToDevice => MODE_READ_MEMORY,
+        Direction::FromDevice => MODE_WRITE_MEMORY,
+    };
+
+    mask.write(MASK_ON | channel);
+    // Any write resets the flip-flop, so the next byte is the low byte.
+    flip_flop.write(0);
+    mode.write(MODE_SINGLE | direction_bits | channel);
+    address.write(buffer as u8);
+    address.write((buffer >> 8) as u8);
+    page.write((buffer >> 16) as u8);
+    flip_flop.write(0);
+    count.write((len - 1) as u8);
+    count.write(((len - 1) >> 8) as u8);
+    mask.write(channel);
+    Ok(())
+}
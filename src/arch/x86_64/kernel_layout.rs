@@ -0,0 +1,134 @@
+//! Where the pieces of our kernel ended up in memory, according to the
+//! symbols defined by `linker.ld` and `boot.asm`.
+//!
+//! The linker only gives us these as the addresses of fake variables, so
+//! this is the one place we declare them.  Everybody else gets a `Region`.
+
+use core::fmt;
+use core::slice;
+
+extern {
+    // Declared in `linker.ld`.  We declare each of these as a single
+    // variable of type `u8`, because that's how we get it to link, but we
+    // only ever want the addresses.
+    static kernel_start: u8;
+    static kernel_end: u8;
+    static kernel_text_start: u8;
+    static kernel_text_end: u8;
+    static kernel_rodata_start: u8;
+    static kernel_rodata_end: u8;
+    static kernel_data_start: u8;
+    static kernel_data_end: u8;
+    static kernel_bss_start: u8;
+    static kernel_bss_end: u8;
+
+    // Declared in `boot.asm`, inside `.bss`.  The tops are "one beyond"
+    // the end, so storing things there would be Very Bad.
+    static stack_bottom: u8;
+    static stack_top: u8;
+    static HEAP_BOTTOM: u8;
+    static HEAP_TOP: u8;
+    static DMA_HEAP_BOTTOM: u8;
+    static DMA_HEAP_TOP: u8;
+}
+
+/// A range of addresses used by the kernel image.  We identity map
+/// memory, so these are physical addresses, too.
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    /// A short name for this region, such as ".text".
+    pub name: &'static str,
+    /// The first address in the region.
+    pub start: usize,
+    /// One beyond the last address in the region.
+    pub end: usize,
+}
+
+impl Region {
+    fn new(name: &'static str, start: &'static u8, end: &'static u8) -> Region {
+        Region {
+            name: name,
+            start: start as *const u8 as usize,
+            end: end as *const u8 as usize,
+        }
+    }
+
+    /// The size of this region, in bytes.
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Is `addr` inside this region?
+    pub fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Does this region share any addresses with `start..end`?
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+
+    /// The contents of this region.  Only use this on regions which
+    /// nobody else is writing to, such as `text`.
+    pub unsafe fn as_slice(&self) -> &'static [u8] {
+        slice::from_raw_parts(self.start as *const u8, self.size())
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:8} 0x{:06x}-0x{:06x} {:>6} KB",
+               self.name, self.start, self.end, (self.size() + 1023) / 1024)
+    }
+}
+
+/// The whole kernel image, from the multiboot header to the end of
+/// `.bss`.  Memory in here is never free for other uses.
+pub fn kernel() -> Region {
+    unsafe { Region::new("kernel", &kernel_start, &kernel_end) }
+}
+
+/// Our code.
+pub fn text() -> Region {
+    unsafe { Region::new(".text", &kernel_text_start, &kernel_text_end) }
+}
+
+/// Our read-only data, including the checksum of `.text`.
+pub fn rodata() -> Region {
+    unsafe { Region::new(".rodata", &kernel_rodata_start, &kernel_rodata_end) }
+}
+
+/// Our initialized, writable data.
+pub fn data() -> Region {
+    unsafe { Region::new(".data", &kernel_data_start, &kernel_data_end) }
+}
+
+/// Our zero-initialized data, which includes the stack and heaps below.
+pub fn bss() -> Region {
+    unsafe { Region::new(".bss", &kernel_bss_start, &kernel_bss_end) }
+}
+
+/// Our kernel stack.
+pub fn stack() -> Region {
+    unsafe { Region::new("stack", &stack_bottom, &stack_top) }
+}
+
+/// The memory set aside for our main heap.
+pub fn heap() -> Region {
+    unsafe { Region::new("heap", &HEAP_BOTTOM, &HEAP_TOP) }
+}
+
+/// The memory set aside for ISA DMA buffers.
+pub fn dma_heap() -> Region {
+    unsafe { Region::new("dma heap", &DMA_HEAP_BOTTOM, &DMA_HEAP_TOP) }
+}
+
+/// Our sections, in address order.
+pub fn sections() -> [Region; 4] {
+    [text(), rodata(), data(), bss()]
+}
+
+/// Our sections, followed by the interesting parts of `.bss`.
+pub fn regions() -> [Region; 7] {
+    [text(), rodata(), data(), bss(), stack(), heap(), dma_heap()]
+}
@@ -2,9 +2,16 @@
 //! PS/2-specific driver, and a high-level portable driver.
 //!
 //! Scancode table available at http://wiki.osdev.org/Keyboard#Scan_Code_Set_1
+//!
+//! Which character a key types depends on the layout in `keymap`, chosen
+//! with `keymap=` on the kernel command line.
 
 use spin::Mutex;
-use cpuio;
+use spsc_ring::RingBuffer;
+
+use arch::x86_64::i8042;
+use arch::x86_64::keymap::{self, Action, Keymap};
+use arch::x86_64::multiboot;
 
 /// A pair of keys which appear on both the left and right sides of the
 /// keyboard, such as "left shift" and "right shift".
@@ -49,21 +56,11 @@ impl Modifiers {
         }
     }
 
-    /// Given the current modifier state, should we convert letters to
-    /// uppercase?
-    fn use_uppercase_letters(&self) -> bool {
-        self.shift.is_pressed() ^ self.caps_lock
-    }
-
-    /// Apply all of our modifiers to an ASCII character, and return a new
-    /// ASCII character.
-    fn apply_to(&self, ascii: u8) -> u8 {
-        if b'a' <= ascii && ascii <= b'z' {
-            if self.use_uppercase_letters() {
-                return ascii - b'a' + b'A';
-            }
-        }
-        ascii
+    /// What the key `code` does in `keymap`, given our current state.
+    /// The right alt key is AltGr.
+    fn apply_to(&self, keymap: &Keymap, code: u8) -> Option<Action> {
+        keymap.action(code, self.shift.is_pressed(), self.caps_lock,
+                      self.alt.right)
     }
 
     /// Given a keyboard scancode, update our current modifier state.
@@ -84,64 +81,264 @@ impl Modifiers {
             _ => {},
         }
     }
+
+    /// Update our modifier state for a scancode which followed an `0xE0`
+    /// prefix.  The right-hand control and alt keys live here.  We
+    /// deliberately ignore the "fake shift" codes `0xE0 0x2A` and `0xE0
+    /// 0xAA`, which some keyboards wrap around the navigation keys.
+    fn update_extended(&mut self, scancode: u8) {
+        match scancode {
+            0x1D => self.control.right = true,
+            0x38 => self.alt.right = true,
+            0x9D => self.control.right = false,
+            0xB8 => self.alt.right = false,
+            _ => {},
+        }
+    }
+}
+
+/// A key press, decoded as far as we know how.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    /// A key which produces a character.
+    Char(char),
+    /// Page Up, and whether shift was held down at the time.
+    PageUp { shift: bool },
+    /// Page Down, and whether shift was held down at the time.
+    PageDown { shift: bool },
+}
+
+/// Whether a key went down, is auto-repeating, or came back up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyState {
+    Pressed,
+    Repeated,
+    Released,
 }
 
-/// Our keyboard state, including our I/O port, our currently pressed
-/// modifiers, etc.
+/// Something that happened to a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: Key,
+    pub state: KeyState,
+}
+
+/// Which kinds of events `read_key` should return.  Presses are always
+/// delivered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// Deliver the keyboard's auto-repeat as `KeyState::Repeated` events.
+    pub repeats: bool,
+    /// Deliver key releases as `KeyState::Released` events.
+    pub releases: bool,
+}
+
+/// What a line-oriented shell wants: held-down keys repeat, and releases
+/// don't matter.  This is the default.
+pub const SHELL_OPTIONS: Options = Options { repeats: true, releases: false };
+
+/// Everything, for things like games which track which keys are held.
+pub const ALL_EVENTS: Options = Options { repeats: true, releases: true };
+
+/// Scancode set 1 prefix byte for the "extended" keys added by the
+/// 101-key keyboard.
+const EXTENDED_PREFIX: u8 = 0xE0;
+
+/// Scancode set 1 sets this bit in the scancode of a key release.
+const RELEASE_BIT: u8 = 0x80;
+
+/// Our keyboard state, including our currently pressed modifiers, etc.
+/// We read scancodes via `i8042`, because there's a huge amount of
+/// emulation going on at the hardware level to allow us to pretend to be
+/// an early-80s IBM PC, and we need to check the controller's status
+/// before trusting its data port.
 struct State {
-    /// The PS/2 serial IO port for the keyboard.  There's a huge amount of
-    /// emulation going on at the hardware level to allow us to pretend to
-    /// be an early-80s IBM PC.
-    ///
-    /// We could read the standard keyboard port directly using
-    /// `inb(0x60)`, but it's nicer if we wrap it up in an `cpuio::Port`
-    /// object.
-    port: cpuio::Port<u8>,
-
-    /// We also need to keep track of which modifier keys have been pressed
+    /// We need to keep track of which modifier keys have been pressed
     /// and released.
     modifiers: Modifiers,
+
+    /// Was the last scancode an `EXTENDED_PREFIX`?
+    extended: bool,
+
+    /// Which keys are currently held down, indexed by scancode, with
+    /// extended keys in the upper half.  The keyboard's auto-repeat just
+    /// sends the same scancode again, so this is how we recognize it.
+    down: [bool; 256],
+
+    /// Which events we pass on.
+    options: Options,
+
+    /// Our keyboard layout.
+    keymap: &'static Keymap,
+
+    /// The accent from a dead key, waiting for the next character.
+    dead: Option<char>,
 }
 
+/// Scancodes which our interrupt handler has read, waiting for `read_key`.
+/// The interrupt handler is the only producer, and `read_key`, which
+/// holds `STATE`, is the only consumer.
+static SCANCODES: RingBuffer<[u8; 64]> = RingBuffer::new([0; 64]);
+
 /// Our global keyboard state, protected by a mutex.
 static STATE: Mutex<State> = Mutex::new(State {
-    port: unsafe { cpuio::Port::new(0x60) },
     modifiers: Modifiers::new(),
+    extended: false,
+    down: [false; 256],
+    options: SHELL_OPTIONS,
+    keymap: &keymap::US,
+    dead: None,
 });
 
-/// Try to convert a scancode to an ASCII character.  If we don't recognize
-/// it, just return `None`.
-fn find_ascii(scancode: u8) -> Option<u8> {
-    let idx = scancode as usize;
-    match scancode {
-        0x01 ... 0x0E => Some(b"\x1B1234567890-=\0x02"[idx-0x01]),
-        0x0F ... 0x1C => Some(b"\tqwertyuiop[]\r"[idx-0x0F]),
-        0x1E ... 0x28 => Some(b"asdfghjkl;'"[idx-0x1E]),
-        0x2C ... 0x35 => Some(b"zxcvbnm,./"[idx-0x2C]),
-        0x39 => Some(b' '),
+/// Choose which kinds of key events `read_key` returns.
+pub fn set_options(options: Options) {
+    STATE.lock().options = options;
+}
+
+/// Switch to the keyboard layout called `name`.
+pub fn set_keymap(name: &str) -> Result<(), &'static str> {
+    let keymap = try!(keymap::find(name).ok_or("unknown keymap"));
+    let mut state = STATE.lock();
+    state.keymap = keymap;
+    state.dead = None;
+    Ok(())
+}
+
+/// The name of our current keyboard layout.
+pub fn keymap_name() -> &'static str {
+    STATE.lock().keymap.name
+}
+
+/// Pick our keyboard layout from `keymap=` on the kernel command line.
+/// Call this before enabling interrupts.
+pub fn initialize() {
+    if let Some(name) = multiboot::info().and_then(|i| i.option("keymap")) {
+        if let Err(err) = set_keymap(name) {
+            println!("keymap={}: {}", name, err);
+        }
+    }
+}
+
+/// The keys which type the same control character in every layout.
+fn find_control(code: u8) -> Option<char> {
+    match code {
+        0x01 => Some('\x1B'),
+        0x0E => Some('\x08'),
+        0x0F => Some('\t'),
+        0x1C => Some('\r'),
+        0x39 => Some(' '),
         _ => None,
     }
 }
 
-/// Try to read a single input character
-pub fn read_char() -> Option<char> {
-    let mut state = STATE.lock();
+/// Queue the scancode the controller has for us, if it really has one.
+/// Call this from the keyboard interrupt handler.  It doesn't take any
+/// locks, so it's fine if we interrupted `read_key`.  If nobody has
+/// called `read_key` for a while and our queue is full, we drop the
+/// scancode.
+pub fn handle_interrupt() {
+    if let Some(scancode) = i8042::poll_keyboard() {
+        let _ = unsafe { SCANCODES.push(scancode) };
+    }
+}
 
-    // Read a single scancode off our keyboard port.
-    let scancode = state.port.read();
+/// Read the next key event from the scancodes our interrupt handler has
+/// queued.  Scancodes which don't complete an event we know how to
+/// decode, or which are a kind of event that our `Options` filter out,
+/// are skipped.  Returns `None` once we run out of scancodes.
+pub fn read_key() -> Option<KeyEvent> {
+    let mut state = STATE.lock();
+    // We're the only consumer, because we hold `STATE`.
+    while let Some(scancode) = unsafe { SCANCODES.pop() } {
+        if let Some(event) = decode(&mut state, scancode) {
+            return Some(event);
+        }
+    }
+    None
+}
 
-    // Give our modifiers first crack at this.
-    state.modifiers.update(scancode);
+/// Decode a single scancode.  Returns `None` if it didn't complete an event
+/// we know how to decode, or if it was a kind of event that our `Options`
+/// filter out.
+fn decode(state: &mut State, scancode: u8) -> Option<KeyEvent> {
+    // Extended keys arrive as two bytes, so remember that we've seen the
+    // first one and wait for the next scancode.
+    if scancode == EXTENDED_PREFIX {
+        state.extended = true;
+        return None;
+    }
+    let extended = state.extended;
+    state.extended = false;
 
-    // Look up the ASCII keycode.
-    if let Some(ascii) = find_ascii(scancode) {
-        // The `as char` converts our ASCII data to Unicode, which is
-        // correct as long as we're only using 7-bit ASCII.
-        Some(state.modifiers.apply_to(ascii) as char)
+    // Figure out whether this is a press, a repeat or a release.
+    let code = scancode & !RELEASE_BIT;
+    let index = code as usize + if extended { 128 } else { 0 };
+    let key_state = if scancode & RELEASE_BIT != 0 {
+        state.down[index] = false;
+        KeyState::Released
+    } else if state.down[index] {
+        KeyState::Repeated
     } else {
-        // Either this was a modifier key, or it some key we don't know how
-        // to handle yet, or it's part of a multibyte scancode.  Just look
-        // innocent and pretend nothing happened.
-        None
+        state.down[index] = true;
+        KeyState::Pressed
+    };
+
+    // Give our modifiers first crack at this.  Repeats can't change
+    // anything, except to toggle caps lock over and over.
+    if key_state != KeyState::Repeated {
+        if extended {
+            state.modifiers.update_extended(scancode);
+        } else {
+            state.modifiers.update(scancode);
+        }
     }
+
+    let wanted = match key_state {
+        KeyState::Pressed => true,
+        KeyState::Repeated => state.options.repeats,
+        KeyState::Released => state.options.releases,
+    };
+    if !wanted { return None; }
+
+    let key = if extended {
+        let shift = state.modifiers.shift.is_pressed();
+        match code {
+            0x49 => Some(Key::PageUp { shift: shift }),
+            0x51 => Some(Key::PageDown { shift: shift }),
+            _ => None,
+        }
+    } else if key_state == KeyState::Released {
+        // Releases don't type anything, so don't let them use up a dead
+        // key.
+        find_control(code).or_else(|| {
+            match state.modifiers.apply_to(state.keymap, code) {
+                Some(Action::Char(c)) | Some(Action::Dead(c)) => Some(c),
+                None => None,
+            }
+        }).map(Key::Char)
+    } else {
+        let action = find_control(code).map(Action::Char)
+            .or_else(|| state.modifiers.apply_to(state.keymap, code));
+        match (action, state.dead.take()) {
+            // Remember the accent, and wait for the next key.
+            (Some(Action::Dead(accent)), None) => {
+                state.dead = Some(accent);
+                None
+            }
+            (Some(Action::Dead(c)), Some(accent)) |
+            (Some(Action::Char(c)), Some(accent)) =>
+                Some(Key::Char(keymap::compose(accent, c))),
+            (Some(Action::Char(c)), None) => Some(Key::Char(c)),
+            // Modifiers don't cancel a dead key.
+            (None, dead) => {
+                state.dead = dead;
+                None
+            }
+        }
+    };
+
+    // If we didn't get a key, either this was a modifier key, or it some
+    // key we don't know how to handle yet.  Just look innocent and pretend
+    // nothing happened.
+    key.map(|key| KeyEvent { key: key, state: key_state })
 }
@@ -0,0 +1,140 @@
+//! Keyboard layouts, which turn scancode set 1 key codes into characters.
+//!
+//! Each layout has three layers: normal, shifted, and AltGr (the right
+//! alt key).  Some keys in a layout may be "dead keys", which don't type
+//! anything by themselves, but put an accent on the next letter.
+
+/// The keys we look up in a layout, as rows of consecutive scancodes.
+/// Everything else is the same in every layout.
+const ROWS: [(u8, u8); 5] = [
+    (0x02, 0x0D),               // 1 through =
+    (0x10, 0x1B),               // Q through ]
+    (0x1E, 0x29),               // A through `
+    (0x2B, 0x35),               // \ through /
+    (0x56, 0x56),               // The extra key on ISO keyboards.
+];
+
+/// Marks a key which does nothing in a given layer.
+const NONE: char = '\0';
+
+/// One layer of a keyboard layout.  Each entry of `rows` has one
+/// character for each scancode in the corresponding entry of `ROWS`, or
+/// is empty if the whole row does nothing.
+type Layer = [&'static str; 5];
+
+/// A keyboard layout.
+pub struct Keymap {
+    /// The name we choose this layout by.
+    pub name: &'static str,
+    normal: Layer,
+    shift: Layer,
+    altgr: Layer,
+    /// Which of the characters in our layers are dead keys.
+    dead: &'static [char],
+}
+
+/// The US layout.  AltGr does nothing special here.
+pub static US: Keymap = Keymap {
+    name: "us",
+    normal: ["1234567890-=", "qwertyuiop[]", "asdfghjkl;'`", "\\zxcvbnm,./",
+             "\\"],
+    shift: ["!@#$%^&*()_+", "QWERTYUIOP{}", "ASDFGHJKL:\"~", "|ZXCVBNM<>?",
+            "|"],
+    altgr: ["", "", "", "", ""],
+    dead: &[],
+};
+
+/// The German layout, with the accent keys next to backspace and 1 as
+/// dead keys.
+pub static DE: Keymap = Keymap {
+    name: "de",
+    normal: ["1234567890ß´", "qwertzuiopü+", "asdfghjklöä^", "#yxcvbnm,.-",
+             "<"],
+    shift: ["!\"§$%&/()=?`", "QWERTZUIOPÜ*", "ASDFGHJKLÖÄ°", "'YXCVBNM;:_",
+            ">"],
+    altgr: ["\0²³\0\0\0{[]}\\\0", "@\0€\0\0\0\0\0\0\0\0~", "",
+            "\0\0\0\0\0\0\0µ\0\0\0", "|"],
+    dead: &['´', '`', '^'],
+};
+
+/// Every layout we know.
+pub static KEYMAPS: [&'static Keymap; 2] = [&US, &DE];
+
+/// Look up a layout by name.
+pub fn find(name: &str) -> Option<&'static Keymap> {
+    KEYMAPS.iter().find(|keymap| keymap.name == name).map(|&keymap| keymap)
+}
+
+/// What pressing a key does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Type a character.
+    Char(char),
+    /// Put this accent on the next character.
+    Dead(char),
+}
+
+/// The character for `code` in `layer`, if it has one.
+fn lookup(layer: &Layer, code: u8) -> Option<char> {
+    for (row, &(first, last)) in layer.iter().zip(ROWS.iter()) {
+        if first <= code && code <= last {
+            return row.chars().nth((code - first) as usize)
+                .and_then(|c| if c == NONE { None } else { Some(c) });
+        }
+    }
+    None
+}
+
+/// Is `c` a lowercase letter that caps lock should affect?  We only know
+/// about Latin-1, which covers our layouts.
+fn is_lowercase_letter(c: char) -> bool {
+    ('a' <= c && c <= 'z') || ('à' <= c && c <= 'þ' && c != '÷')
+}
+
+impl Keymap {
+    /// What the key `code` does, given the state of our modifiers.  If
+    /// AltGr doesn't do anything special for a key, it's ignored.
+    pub fn action(&self, code: u8, shift: bool, caps_lock: bool, altgr: bool)
+        -> Option<Action>
+    {
+        let c = if altgr {
+            lookup(&self.altgr, code)
+        } else {
+            None
+        };
+        let c = c.or_else(|| {
+            let normal = lookup(&self.normal, code);
+            let letter = normal.map_or(false, is_lowercase_letter);
+            if shift ^ (caps_lock && letter) {
+                lookup(&self.shift, code)
+            } else {
+                normal
+            }
+        });
+        c.map(|c| if self.dead.contains(&c) { Action::Dead(c) } else { Action::Char(c) })
+    }
+}
+
+/// Accented letters we can make with dead keys, as the accent, the
+/// letters it goes on, and the results.
+const COMPOSE: [(char, &'static str, &'static str); 3] = [
+    ('´', "aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+    ('`', "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    ('^', "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+];
+
+/// What to type when `c` follows the dead key `accent`.  Space, or the
+/// accent again, types the accent by itself, and letters which can't take
+/// the accent come out plain.
+pub fn compose(accent: char, c: char) -> char {
+    if c == ' ' || c == accent {
+        return accent;
+    }
+    COMPOSE.iter()
+        .find(|&&(a, _, _)| a == accent)
+        .and_then(|&(_, bases, results)| {
+            bases.chars().position(|b| b == c)
+                .and_then(|i| results.chars().nth(i))
+        })
+        .unwrap_or(c)
+}
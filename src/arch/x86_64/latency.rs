@@ -0,0 +1,148 @@
+//! How long do we run with interrupts disabled?
+//!
+//! While interrupts are off, we can't see keypresses or timer ticks, so
+//! the longest such stretch bounds how late we can be to respond to
+//! anything.  We don't have a scheduler, so these are the only
+//! non-preemptible sections we have.  We measure two kinds, using the
+//! cycle counter:
+//!
+//! - `without_interrupts` sections, which include everything the shell
+//!   does in response to serial input.
+//! - Interrupt handlers, which include everything the shell does in
+//!   response to keypresses.
+//!
+//! We convert cycles to microseconds by comparing the cycle counter with
+//! the timer, which is only rough for the first few seconds after boot.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+use arch::x86_64::cpu::rdtsc;
+use arch::x86_64::timer;
+
+/// Statistics for one kind of section.
+struct Section {
+    count: AtomicUsize,
+    total_cycles: AtomicUsize,
+    max_cycles: AtomicUsize,
+    /// For interrupt handlers, the vector which took `max_cycles`.
+    max_vector: AtomicUsize,
+}
+
+impl Section {
+    fn record(&self, cycles: usize, vector: usize) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_cycles.fetch_add(cycles, Ordering::Relaxed);
+        let mut max = self.max_cycles.load(Ordering::Relaxed);
+        while cycles > max {
+            let old = self.max_cycles.compare_and_swap(max, cycles, Ordering::Relaxed);
+            if old == max {
+                self.max_vector.store(vector, Ordering::Relaxed);
+                break;
+            }
+            max = old;
+        }
+    }
+
+    fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.total_cycles.store(0, Ordering::Relaxed);
+        self.max_cycles.store(0, Ordering::Relaxed);
+        self.max_vector.store(0, Ordering::Relaxed);
+    }
+}
+
+static DISABLED: Section = Section {
+    count: ATOMIC_USIZE_INIT,
+    total_cycles: ATOMIC_USIZE_INIT,
+    max_cycles: ATOMIC_USIZE_INIT,
+    max_vector: ATOMIC_USIZE_INIT,
+};
+
+static HANDLERS: Section = Section {
+    count: ATOMIC_USIZE_INIT,
+    total_cycles: ATOMIC_USIZE_INIT,
+    max_cycles: ATOMIC_USIZE_INIT,
+    max_vector: ATOMIC_USIZE_INIT,
+};
+
+/// The cycle counter when we started the timer.
+static TIMER_START_TSC: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Remember where the cycle counter was when the timer started, so that we
+/// can work out its rate later.  Call this just after `timer::initialize`.
+pub fn initialize() {
+    TIMER_START_TSC.store(rdtsc() as usize, Ordering::Relaxed);
+}
+
+/// Record a `without_interrupts` section which started at cycle `start`.
+pub fn record_disabled(start: u64) {
+    DISABLED.record(rdtsc().wrapping_sub(start) as usize, 0);
+}
+
+/// Record a handler for interrupt `vector` which started at cycle `start`.
+pub fn record_handler(vector: u8, start: u64) {
+    HANDLERS.record(rdtsc().wrapping_sub(start) as usize, vector as usize);
+}
+
+/// Forget everything we've measured so far.
+pub fn reset() {
+    DISABLED.reset();
+    HANDLERS.reset();
+}
+
+/// Our best guess at cycles per microsecond, if we've been running long
+/// enough to make one.
+fn cycles_per_us() -> Option<usize> {
+    let us = timer::uptime_us();
+    let cycles = (rdtsc() as usize).wrapping_sub(TIMER_START_TSC.load(Ordering::Relaxed));
+    if us == 0 || cycles < us {
+        None
+    } else {
+        Some(cycles / us)
+    }
+}
+
+/// Show a number of cycles, and microseconds if we know the rate.
+struct Cycles(usize, Option<usize>);
+
+impl fmt::Display for Cycles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.1 {
+            Some(rate) => write!(f, "{} cycles ({} us)", self.0, self.0 / rate),
+            None => write!(f, "{} cycles", self.0),
+        }
+    }
+}
+
+/// Displays what we've measured, for the `latency` command.
+pub struct Report;
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rate = cycles_per_us();
+        match rate {
+            Some(rate) => try!(writeln!(f, "CPU clock: about {} MHz", rate)),
+            None => try!(writeln!(f, "CPU clock: not known yet")),
+        }
+        let sections = [
+            ("Interrupts disabled", &DISABLED, false),
+            ("Interrupt handlers", &HANDLERS, true),
+        ];
+        for &(name, section, by_vector) in &sections {
+            let count = section.count.load(Ordering::Relaxed);
+            try!(writeln!(f, "{}: {} times", name, count));
+            if count == 0 {
+                continue;
+            }
+            let total = section.total_cycles.load(Ordering::Relaxed);
+            try!(writeln!(f, "  mean {}", Cycles(total / count, rate)));
+            try!(write!(f, "  max  {}", Cycles(section.max_cycles.load(Ordering::Relaxed), rate)));
+            if by_vector {
+                try!(write!(f, ", vector {:#x}", section.max_vector.load(Ordering::Relaxed)));
+            }
+            try!(writeln!(f, ""));
+        }
+        Ok(())
+    }
+}
@@ -18,6 +18,11 @@ section .text
 bits 64
 long_mode_start:
         call setup_SSE
+
+        ;; Pass the multiboot information pointer saved by boot.asm.  The
+        ;; upper half of rdi is undefined after switching modes, so clear
+        ;; it by writing edi to itself.
+        mov edi, edi
         call rust_main
 
         ;; Display "OKAY".
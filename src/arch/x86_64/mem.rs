@@ -0,0 +1,142 @@
+//! `memcpy`, `memmove`, `memset` and `memcmp`, which the compiler calls
+//! for us whenever it copies or clears memory.
+//!
+//! On CPUs with "enhanced `rep movsb`/`stosb`" (ERMS), a single `rep
+//! movsb` or `rep stosb` is the fastest way to copy or fill memory of any
+//! size, because the microcode moves whole cache lines at a time.  Without
+//! ERMS, we move 8 bytes at a time with `rep movsq` and `rep stosq`, and
+//! mop up the rest with the byte versions.  We can't use SSE here: our
+//! target disables it, and our interrupt handlers don't save the SSE
+//! registers.
+//!
+//! These get called long before we've looked at the CPU, so until
+//! `initialize` runs we stick to the version which works everywhere.
+
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use x86::cpuid::{cpuid1, cpuid2};
+
+/// The ERMS bit in `cpuid` leaf 7, EBX.
+const CPUID_ERMS: u32 = 1 << 9;
+
+/// Can we use `rep movsb` and `rep stosb` for everything?
+static ERMS: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Pick the fastest versions of our routines for this CPU.
+pub fn initialize() {
+    let erms = cpuid1(0).eax >= 7 && cpuid2(7, 0).ebx & CPUID_ERMS != 0;
+    ERMS.store(erms, Ordering::Relaxed);
+}
+
+/// A short description of the routines we're using, for the boot
+/// summary.
+pub fn description() -> &'static str {
+    if ERMS.load(Ordering::Relaxed) {
+        "rep movsb/stosb (ERMS)"
+    } else {
+        "rep movsq/stosq"
+    }
+}
+
+/// Copy `n` bytes forwards from `src` to `dest`.
+#[inline(always)]
+unsafe fn copy_forward(mut dest: *mut u8, mut src: *const u8, mut n: usize) {
+    if ERMS.load(Ordering::Relaxed) {
+        asm!("rep movsb"
+             : "+{rdi}"(dest), "+{rsi}"(src), "+{rcx}"(n)
+             :
+             : "memory"
+             : "volatile");
+    } else {
+        let mut words = n / 8;
+        asm!("rep movsq; movq %rdx, %rcx; rep movsb"
+             : "+{rdi}"(dest), "+{rsi}"(src), "+{rcx}"(words)
+             : "{rdx}"(n % 8)
+             : "memory"
+             : "volatile");
+    }
+}
+
+/// Copy `n` bytes backwards from `src` to `dest`, for overlapping
+/// `memmove` calls where `dest` is above `src`.  This is much slower than
+/// copying forwards, but it doesn't come up often.  Interrupts may arrive
+/// while the direction flag is set, so `int_shared` clears it before
+/// calling any Rust code.
+#[inline(always)]
+unsafe fn copy_backward(dest: *mut u8, src: *const u8, mut n: usize) {
+    let mut last_dest = dest.offset(n as isize - 1);
+    let mut last_src = src.offset(n as isize - 1);
+    asm!("std; rep movsb; cld"
+         : "+{rdi}"(last_dest), "+{rsi}"(last_src), "+{rcx}"(n)
+         :
+         : "memory"
+         : "volatile");
+}
+
+#[no_mangle]
+pub unsafe extern fn memcpy(dest: *mut u8, src: *const u8, n: usize)
+    -> *mut u8
+{
+    copy_forward(dest, src, n);
+    dest
+}
+
+#[no_mangle]
+pub unsafe extern fn memmove(dest: *mut u8, src: *const u8, n: usize)
+    -> *mut u8
+{
+    if (dest as usize) <= (src as usize) ||
+        (dest as usize) >= (src as usize) + n
+    {
+        copy_forward(dest, src, n);
+    } else if n > 0 {
+        copy_backward(dest, src, n);
+    }
+    dest
+}
+
+#[no_mangle]
+pub unsafe extern fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8 {
+    let mut dest = s;
+    if ERMS.load(Ordering::Relaxed) {
+        let mut count = n;
+        asm!("rep stosb"
+             : "+{rdi}"(dest), "+{rcx}"(count)
+             : "{al}"(c as u8)
+             : "memory"
+             : "volatile");
+    } else {
+        let pattern = (c as u8 as u64) * 0x0101010101010101;
+        let mut words = n / 8;
+        asm!("rep stosq; movq %rdx, %rcx; rep stosb"
+             : "+{rdi}"(dest), "+{rcx}"(words)
+             : "{rax}"(pattern), "{rdx}"(n % 8)
+             : "memory"
+             : "volatile");
+    }
+    s
+}
+
+#[no_mangle]
+pub unsafe extern fn memcmp(s1: *const u8, s2: *const u8, n: usize) -> i32 {
+    let mut i = 0;
+
+    // Skip over matching words quickly, if both sides are aligned.
+    if (s1 as usize | s2 as usize) % 8 == 0 {
+        while i + 8 <= n &&
+            *(s1.offset(i as isize) as *const u64) ==
+            *(s2.offset(i as isize) as *const u64)
+        {
+            i += 8;
+        }
+    }
+
+    while i < n {
+        let a = *s1.offset(i as isize);
+        let b = *s2.offset(i as isize);
+        if a != b {
+            return a as i32 - b as i32;
+        }
+        i += 1;
+    }
+    0
+}
@@ -1,6 +1,26 @@
+pub mod acpi;
+pub mod backtrace;
+pub mod i8042;
+pub mod kernel_layout;
 pub mod keyboard;
+pub mod keymap;
+pub mod latency;
 pub mod serial;
 pub mod pci;
+pub mod cpu;
+pub mod multiboot;
+pub mod mem;
+pub mod paging;
+pub mod reset;
+pub mod isa_dma;
+pub mod sb16;
+pub mod timer;
+pub mod vbe;
+pub mod vectors;
+pub mod virtio;
+pub mod virtio_console;
+#[cfg(feature = "trace-io")]
+pub mod io_trace;
 
 pub mod vga;
 pub mod interrupts;
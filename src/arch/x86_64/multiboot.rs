@@ -0,0 +1,236 @@
+//! Access to the Multiboot 2 information structure that GRUB passes to us
+//! at boot time.
+//!
+//! See http://nongnu.askapache.com/grub/phcoder/multiboot.pdf for the
+//! format.  We only parse the handful of tags we actually use.
+
+use core::mem::size_of;
+use core::slice;
+use core::str;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+/// Tag types we understand.
+const TAG_END: u32 = 0;
+const TAG_COMMAND_LINE: u32 = 1;
+const TAG_MODULE: u32 = 3;
+const TAG_BASIC_MEMORY: u32 = 4;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_ACPI_OLD_RSDP: u32 = 14;
+const TAG_ACPI_NEW_RSDP: u32 = 15;
+
+/// The memory map type for ordinary, usable RAM.
+const MEMORY_AVAILABLE: u32 = 1;
+
+/// The address of the multiboot information structure, or 0 if we don't
+/// have one.
+static INFO_ADDR: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The header of each multiboot tag.
+#[repr(C)]
+struct Tag {
+    typ: u32,
+    size: u32,
+}
+
+/// Iterator over multiboot tags.
+struct TagIter {
+    current: *const Tag,
+}
+
+impl Iterator for TagIter {
+    type Item = &'static Tag;
+
+    fn next(&mut self) -> Option<&'static Tag> {
+        let tag = unsafe { &*self.current };
+        if tag.typ == TAG_END { return None; }
+
+        // Tags are padded to 8-byte boundaries.
+        let next = (self.current as usize + tag.size as usize + 7) & !7;
+        self.current = next as *const Tag;
+        Some(tag)
+    }
+}
+
+/// A file loaded into memory by the boot loader, such as an initrd.
+pub struct Module {
+    /// The string following the file name on the `module2` line in
+    /// `grub.cfg`.  We use this as the module's name.
+    pub name: &'static str,
+    /// The contents of the file.
+    pub data: &'static [u8],
+}
+
+/// A region of physical memory reported by the boot loader.
+#[derive(Debug)]
+#[repr(C)]
+pub struct MemoryArea {
+    pub base: u64,
+    pub length: u64,
+    typ: u32,
+    _reserved: u32,
+}
+
+impl MemoryArea {
+    /// Is this region ordinary RAM that we can use?
+    pub fn is_available(&self) -> bool {
+        self.typ == MEMORY_AVAILABLE
+    }
+}
+
+/// Iterator over the entries of the memory map.
+pub struct MemoryAreaIter {
+    current: usize,
+    end: usize,
+    entry_size: usize,
+}
+
+impl Iterator for MemoryAreaIter {
+    type Item = &'static MemoryArea;
+
+    fn next(&mut self) -> Option<&'static MemoryArea> {
+        if self.current >= self.end { return None; }
+        let area = unsafe { &*(self.current as *const MemoryArea) };
+        self.current += self.entry_size;
+        Some(area)
+    }
+}
+
+/// Our multiboot information.
+pub struct BootInfo {
+    addr: usize,
+}
+
+impl BootInfo {
+    /// Iterate over all our tags.  The first tag follows an 8-byte header
+    /// containing the total size.
+    fn tags(&self) -> TagIter {
+        TagIter { current: (self.addr + 8) as *const Tag }
+    }
+
+    /// Find the first tag of the specified type.
+    fn find_tag(&self, typ: u32) -> Option<&'static Tag> {
+        self.tags().find(|t| t.typ == typ)
+    }
+
+    /// The kernel command line specified in `grub.cfg`, if any.
+    pub fn command_line(&self) -> Option<&'static str> {
+        self.find_tag(TAG_COMMAND_LINE).and_then(|tag| {
+            let start = tag as *const Tag as usize + size_of::<Tag>();
+            let len = tag.size as usize - size_of::<Tag>();
+            let bytes = unsafe { slice::from_raw_parts(start as *const u8, len) };
+            // Strip the trailing NUL.
+            let len = bytes.iter().position(|&b| b == 0).unwrap_or(len);
+            str::from_utf8(&bytes[..len]).ok()
+        })
+    }
+
+    /// Look up `name` on the kernel command line.  An option written as
+    /// `name=value` returns `Some("value")`, and a bare `name` returns
+    /// `Some("")`.
+    pub fn option(&self, name: &str) -> Option<&'static str> {
+        self.command_line().and_then(|cmdline| {
+            cmdline.split_whitespace().filter_map(|word| {
+                let mut parts = word.splitn(2, '=');
+                if parts.next() == Some(name) {
+                    Some(parts.next().unwrap_or(""))
+                } else {
+                    None
+                }
+            }).next()
+        })
+    }
+
+    /// All the modules loaded by the boot loader.
+    pub fn modules(&self) -> ModuleIter {
+        ModuleIter { tags: self.tags() }
+    }
+
+    /// Find the module with the specified name.
+    pub fn module(&self, name: &str) -> Option<Module> {
+        self.modules().find(|m| m.name == name)
+    }
+
+    /// The amount of lower and upper memory in KB, as reported by the
+    /// BIOS.
+    pub fn basic_memory(&self) -> Option<(u32, u32)> {
+        self.find_tag(TAG_BASIC_MEMORY).map(|tag| {
+            let fields = (tag as *const Tag as usize + size_of::<Tag>())
+                as *const u32;
+            unsafe { (*fields, *fields.offset(1)) }
+        })
+    }
+
+    /// The boot loader's copy of the ACPI RSDP, preferring the ACPI 2.0
+    /// version if there is one.
+    pub fn acpi_rsdp(&self) -> Option<&'static [u8]> {
+        self.find_tag(TAG_ACPI_NEW_RSDP)
+            .or_else(|| self.find_tag(TAG_ACPI_OLD_RSDP))
+            .map(|tag| {
+                let start = tag as *const Tag as usize + size_of::<Tag>();
+                let len = tag.size as usize - size_of::<Tag>();
+                unsafe { slice::from_raw_parts(start as *const u8, len) }
+            })
+    }
+
+    /// The BIOS memory map.
+    pub fn memory_areas(&self) -> Option<MemoryAreaIter> {
+        self.find_tag(TAG_MEMORY_MAP).map(|tag| {
+            let start = tag as *const Tag as usize;
+            let entry_size = unsafe { *((start + 8) as *const u32) };
+            MemoryAreaIter {
+                current: start + 16,
+                end: start + tag.size as usize,
+                entry_size: entry_size as usize,
+            }
+        })
+    }
+}
+
+/// Iterator over the modules loaded by the boot loader.
+pub struct ModuleIter {
+    tags: TagIter,
+}
+
+impl Iterator for ModuleIter {
+    type Item = Module;
+
+    fn next(&mut self) -> Option<Module> {
+        let tag = match self.tags.find(|t| t.typ == TAG_MODULE) {
+            Some(tag) => tag,
+            None => return None,
+        };
+        // The module tag holds 32-bit start and end addresses, followed
+        // by a NUL-terminated string.  Modules are loaded below 4GB, in
+        // memory we've identity mapped.
+        let fields = (tag as *const Tag as usize + size_of::<Tag>())
+            as *const u32;
+        let (start, end) = unsafe { (*fields, *fields.offset(1)) };
+        let name_start = fields as usize + 8;
+        let name_len = tag.size as usize - size_of::<Tag>() - 8;
+        let name = unsafe {
+            slice::from_raw_parts(name_start as *const u8, name_len)
+        };
+        let name_len = name.iter().position(|&b| b == 0).unwrap_or(name_len);
+        Some(Module {
+            name: str::from_utf8(&name[..name_len]).unwrap_or(""),
+            data: unsafe {
+                slice::from_raw_parts(start as usize as *const u8,
+                                      (end - start) as usize)
+            },
+        })
+    }
+}
+
+/// Record the address of the multiboot information passed to us by our
+/// boot loader.  This must be called before anything else in this module.
+pub unsafe fn initialize(addr: usize) {
+    INFO_ADDR.store(addr, Ordering::SeqCst);
+}
+
+/// Get our multiboot information, if we have any.
+pub fn info() -> Option<BootInfo> {
+    match INFO_ADDR.load(Ordering::SeqCst) {
+        0 => None,
+        addr => Some(BootInfo { addr: addr }),
+    }
+}
@@ -0,0 +1,208 @@
+//! Minimal page table management.
+//!
+//! `boot.asm` identity maps the first 1GB of memory before we ever get to
+//! Rust.  Most of that mapping uses 2MB pages, but the first 2MB is mapped
+//! using individual 4K pages from `p1_table`, which is where the kernel
+//! itself lives.  This module lets us adjust those 4K mappings.  It's not
+//! a general-purpose paging API yet.
+//!
+//! We also have a 2MB window at `MMIO_BASE`, set up empty by `boot.asm`,
+//! where we can map device memory (uncached) at addresses which don't
+//! depend on the identity mapping.  Above that window, the rest of the
+//! 1GB region is available for big mappings, such as framebuffers, using
+//! 2MB pages.
+
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use x86;
+
+use arch::kernel_layout::{self, Region};
+
+/// The size of the pages we manage.
+pub const PAGE_SIZE: usize = 4096;
+
+/// The size of the huge pages we use for big device mappings.
+pub const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// The number of entries in a page table.
+const ENTRY_COUNT: usize = 512;
+
+/// Page table entry flags.
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const WRITE_THROUGH: u64 = 1 << 3;
+const CACHE_DISABLE: u64 = 1 << 4;
+const HUGE_PAGE: u64 = 1 << 7;
+const NO_EXECUTE: u64 = 1 << 63;
+
+/// The "no execute enable" bit in the EFER MSR.
+const EFER_NXE: u64 = 1 << 11;
+
+extern {
+    /// The page table mapping the first 2MB of memory.  Declared in
+    /// `boot.asm`.
+    static mut p1_table: [u64; ENTRY_COUNT];
+
+    /// The page tables for our device mapping window.  Also declared in
+    /// `boot.asm`.  The first entry of `mmio_p2_table` points at
+    /// `mmio_p1_table`.
+    static mut mmio_p2_table: [u64; ENTRY_COUNT];
+    static mut mmio_p1_table: [u64; ENTRY_COUNT];
+}
+
+/// Look up the page table entry for the 4K page containing `addr`, if we
+/// have one.
+unsafe fn entry(addr: usize) -> Option<&'static mut u64> {
+    let index = addr / PAGE_SIZE;
+    if index < ENTRY_COUNT {
+        Some(&mut p1_table[index])
+    } else {
+        None
+    }
+}
+
+/// Mark the page containing `addr` as not present, so that any access to
+/// it will cause a page fault.
+pub unsafe fn unmap(addr: usize) {
+    *entry(addr).expect("no 4K mapping for address") &= !PRESENT;
+    x86::tlb::flush(addr);
+}
+
+/// Is `addr` inside the null page?
+pub fn is_null_page(addr: usize) -> bool {
+    addr < PAGE_SIZE
+}
+
+/// Set the flags in `set` and clear the flags in `clear` for every page
+/// overlapping `start..end`.  Returns the number of pages we couldn't
+/// update because they're not mapped by `p1_table`.
+unsafe fn update_flags(start: usize, end: usize, set: u64, clear: u64)
+    -> usize
+{
+    let mut skipped = 0;
+    let mut page = start & !(PAGE_SIZE - 1);
+    while page < end {
+        if let Some(e) = entry(page) {
+            *e = (*e | set) & !clear;
+            x86::tlb::flush(page);
+        } else {
+            skipped += 1;
+        }
+        page += PAGE_SIZE;
+    }
+    skipped
+}
+
+/// Does this CPU support no-execute pages?
+fn supports_no_execute() -> bool {
+    let extended = x86::cpuid::cpuid1(0x80000000);
+    if extended.eax < 0x80000001 { return false; }
+    x86::cpuid::cpuid1(0x80000001).edx & (1 << 20) != 0
+}
+
+/// Protect a section of the kernel with the specified flags, and report
+/// what we did.
+unsafe fn protect_section(section: Region, set: u64, clear: u64) {
+    let skipped = update_flags(section.start, section.end, set, clear);
+    println!("  {:8} 0x{:06x}-0x{:06x} {}{}{}",
+             section.name, section.start, section.end,
+             if clear & WRITABLE != 0 { "read-only" } else { "writable" },
+             if set & NO_EXECUTE != 0 { ", no-execute" } else { "" },
+             if skipped > 0 { " (partial: beyond 2MB)" } else { "" });
+}
+
+/// Map the kernel's code and read-only data as read-only, and, if the CPU
+/// supports it, mark everything except code as non-executable.  (Our
+/// boot code already turned on CR0.WP, so read-only pages are enforced
+/// even in kernel mode.)
+unsafe fn protect_kernel() {
+    let no_execute = if supports_no_execute() {
+        let efer = x86::msr::rdmsr(x86::msr::IA32_EFER);
+        x86::msr::wrmsr(x86::msr::IA32_EFER, efer | EFER_NXE);
+        NO_EXECUTE
+    } else {
+        0
+    };
+
+    println!("Protecting kernel pages:");
+    protect_section(kernel_layout::text(), 0, WRITABLE);
+    protect_section(kernel_layout::rodata(), no_execute, WRITABLE);
+    protect_section(kernel_layout::data(), no_execute, 0);
+    protect_section(kernel_layout::bss(), no_execute, 0);
+}
+
+/// The virtual address of our device mapping window.
+pub const MMIO_BASE: usize = 0x4000_0000;
+
+/// The next unused page in the device mapping window, relative to
+/// `MMIO_BASE`.
+static MMIO_NEXT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Map `size` bytes of device memory starting at the physical address
+/// `phys` into our device window, with caching disabled, and return the
+/// corresponding virtual address.  Mappings are permanent.
+pub unsafe fn map_mmio(phys: usize, size: usize) -> Result<usize, &'static str> {
+    let offset = phys & (PAGE_SIZE - 1);
+    let first_page = phys - offset;
+    let count = (offset + size + PAGE_SIZE - 1) / PAGE_SIZE;
+
+    let start = MMIO_NEXT.fetch_add(count, Ordering::SeqCst);
+    if start + count > ENTRY_COUNT {
+        MMIO_NEXT.fetch_sub(count, Ordering::SeqCst);
+        return Err("device mapping window is full");
+    }
+
+    let flags = PRESENT | WRITABLE | WRITE_THROUGH | CACHE_DISABLE |
+        no_execute_flag();
+    for i in 0..count {
+        mmio_p1_table[start + i] = (first_page + i * PAGE_SIZE) as u64 | flags;
+        x86::tlb::flush(MMIO_BASE + (start + i) * PAGE_SIZE);
+    }
+    Ok(MMIO_BASE + start * PAGE_SIZE + offset)
+}
+
+/// The next unused 2MB page after our device mapping window, as an index
+/// into `mmio_p2_table`.  Entry 0 is the window itself.
+static MMIO_LARGE_NEXT: AtomicUsize = AtomicUsize::new(1);
+
+/// Like `map_mmio`, but for regions too big for our device window, such
+/// as framebuffers.  We map whole 2MB pages, so this uses up address
+/// space quickly, and the caller gets write-through caching so that
+/// drawing isn't painfully slow.
+pub unsafe fn map_mmio_large(phys: usize, size: usize)
+    -> Result<usize, &'static str>
+{
+    let offset = phys & (HUGE_PAGE_SIZE - 1);
+    let first_page = phys - offset;
+    let count = (offset + size + HUGE_PAGE_SIZE - 1) / HUGE_PAGE_SIZE;
+
+    let start = MMIO_LARGE_NEXT.fetch_add(count, Ordering::SeqCst);
+    if start + count > ENTRY_COUNT {
+        MMIO_LARGE_NEXT.fetch_sub(count, Ordering::SeqCst);
+        return Err("no room to map large device region");
+    }
+
+    let flags = PRESENT | WRITABLE | WRITE_THROUGH | HUGE_PAGE |
+        no_execute_flag();
+    for i in 0..count {
+        mmio_p2_table[start + i] =
+            (first_page + i * HUGE_PAGE_SIZE) as u64 | flags;
+        x86::tlb::flush(MMIO_BASE + (start + i) * HUGE_PAGE_SIZE);
+    }
+    Ok(MMIO_BASE + start * HUGE_PAGE_SIZE + offset)
+}
+
+/// `NO_EXECUTE`, if we've turned it on, or 0 otherwise.  Setting the bit
+/// when it's not enabled would cause a page fault.
+fn no_execute_flag() -> u64 {
+    let efer = unsafe { x86::msr::rdmsr(x86::msr::IA32_EFER) };
+    if efer & EFER_NXE != 0 { NO_EXECUTE } else { 0 }
+}
+
+/// Set up our kernel's page protections.
+pub unsafe fn initialize() {
+    // Unmap page 0 so that NULL pointer dereferences fault immediately,
+    // instead of scribbling over the real-mode IVT and failing later.
+    unmap(0);
+
+    protect_kernel();
+}
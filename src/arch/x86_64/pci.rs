@@ -2,12 +2,20 @@
 //!
 //! As usual, this is heavily inspired by http://wiki.osdev.org/Pci
 
+use collections::vec::Vec;
 use core::fmt;
 use core::intrinsics::transmute;
 use core::iter::Iterator;
 use spin::Mutex;
 use cpuio;
 
+use arch::x86_64::interrupts;
+use arch::x86_64::vbe;
+use arch::x86_64::virtio_console;
+use map::HashMap;
+use regs::{self, RegisterMap};
+use sync::RwSpinlock;
+
 struct Pci {
     address: cpuio::Port<u32>,
     data: cpuio::Port<u32>,
@@ -31,6 +39,21 @@ impl Pci {
         self.data.read()
     }
 
+    /// Write a 32-bit aligned word to PCI Configuration Address Space.
+    /// Even more exciting than `read_config`.
+    unsafe fn write_config(&mut self, bus: u8, slot: u8, function: u8,
+                           offset: u8, value: u32)
+    {
+        let address: u32 =
+            0x80000000
+            | (bus as u32) << 16
+            | (slot as u32) << 11
+            | (function as u32) << 8
+            | (offset & 0b1111_1100) as u32;
+        self.address.write(address);
+        self.data.write(value);
+    }
+
     /// Check for a PCI device, and return information about it if present.
     unsafe fn probe(
         &mut self, bus: u8, slot: u8, function: u8)
@@ -50,14 +73,17 @@ impl Pci {
             vendor_id: config_0 as u16,
             device_id: (config_0 >> 16) as u16,
             revision_id: config_4 as u8,
+            prog_if: (config_4 >> 8) as u8,
             subclass: (config_4 >> 16) as u8,
+            class: (config_4 >> 24) as u8,
             class_code: DeviceClass::from_u8((config_4 >> 24) as u8),
+            header_type: (config_c >> 16) as u8 & 0x7F,
             multifunction: config_c & 0x800000 != 0,
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 #[allow(dead_code)]
 pub enum DeviceClass {
@@ -92,7 +118,7 @@ impl DeviceClass {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FunctionInfo {
     bus: u8,
     device: u8,
@@ -101,11 +127,99 @@ pub struct FunctionInfo {
     vendor_id: u16,
     device_id: u16,
     revision_id: u8,
+    prog_if: u8,
     subclass: u8,
+    class: u8,
     class_code: DeviceClass,
+    header_type: u8,
     multifunction: bool,
 }
 
+/// The header type of an ordinary device.
+const HEADER_TYPE_DEVICE: u8 = 0x00;
+
+/// The header type of a PCI-to-PCI bridge.
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+impl FunctionInfo {
+    /// The bus, device and function numbers of this function.
+    pub fn address(&self) -> (u8, u8, u8) {
+        (self.bus, self.device, self.function)
+    }
+
+    pub fn vendor_id(&self) -> u16 { self.vendor_id }
+    pub fn device_id(&self) -> u16 { self.device_id }
+    pub fn class_code(&self) -> DeviceClass { self.class_code }
+    pub fn subclass(&self) -> u8 { self.subclass }
+    pub fn revision_id(&self) -> u8 { self.revision_id }
+    pub fn prog_if(&self) -> u8 { self.prog_if }
+
+    /// The raw class code, which unlike `class_code` is meaningful even
+    /// for classes we don't know about.
+    pub fn class(&self) -> u8 { self.class }
+
+    /// The subsystem vendor and device IDs, which identify the board that
+    /// a chip is on.  Only ordinary devices have these.
+    pub fn subsystem(&self) -> Option<(u16, u16)> {
+        if self.header_type == HEADER_TYPE_DEVICE {
+            let word = self.read_config(0x2C);
+            Some((word as u16, (word >> 16) as u16))
+        } else {
+            None
+        }
+    }
+
+    /// If this is a PCI-to-PCI bridge, the number of the bus behind it.
+    pub fn secondary_bus(&self) -> Option<u8> {
+        if self.header_type == HEADER_TYPE_BRIDGE {
+            Some((self.read_config(0x18) >> 8) as u8)
+        } else {
+            None
+        }
+    }
+
+    /// The raw value of base address register `index`, from 0 to 5.
+    pub fn bar(&self, index: u8) -> u32 {
+        self.read_config(0x10 + index * 4)
+    }
+
+    /// Let the device respond to I/O and memory accesses, and access
+    /// memory itself, which devices using DMA need.
+    pub unsafe fn enable_bus_master(&self) {
+        let command = self.read_config_u16(COMMAND);
+        self.write_config_u16(COMMAND, command | COMMAND_IO | COMMAND_MEMORY |
+                              COMMAND_BUS_MASTER);
+    }
+
+    /// Read a 32-bit word from our configuration space.
+    fn read_config(&self, offset: u8) -> u32 {
+        unsafe {
+            PCI.lock().read_config(self.bus, self.device, self.function, offset)
+        }
+    }
+
+    /// Write a 32-bit word to our configuration space.
+    unsafe fn write_config(&self, offset: u8, value: u32) {
+        PCI.lock().write_config(self.bus, self.device, self.function,
+                                offset, value);
+    }
+
+    /// Read a 16-bit value from our configuration space.  `offset` must be
+    /// 2-byte aligned.
+    fn read_config_u16(&self, offset: u8) -> u16 {
+        (self.read_config(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    /// Write a 16-bit value to our configuration space, leaving the other
+    /// half of the word alone.  Note that this writes back the other half,
+    /// which will clear any write-1-to-clear status bits there.
+    unsafe fn write_config_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let word = self.read_config(offset) & !(0xFFFF << shift);
+        self.write_config(offset, word | (value as u32) << shift);
+    }
+}
+
 impl fmt::Display for FunctionInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}.{}.{}: {:04x} {:04x} {:?} {:02x}",
@@ -184,6 +298,26 @@ impl Iterator for FunctionIterator {
     }
 }
 
+/// Size of the PCI configuration space for a single function.
+pub const CONFIG_SPACE_SIZE: usize = 256;
+
+/// Read the entire configuration space of the specified function.
+pub fn config_space(bus: u8, device: u8, function: u8)
+    -> [u8; CONFIG_SPACE_SIZE]
+{
+    let mut result = [0; CONFIG_SPACE_SIZE];
+    let mut pci = PCI.lock();
+    for offset in (0..CONFIG_SPACE_SIZE / 4).map(|i| i * 4) {
+        let word = unsafe {
+            pci.read_config(bus, device, function, offset as u8)
+        };
+        for i in 0..4 {
+            result[offset + i] = (word >> (8 * i)) as u8;
+        }
+    }
+    result
+}
+
 /// Brute-force PCI bus probing.
 pub fn functions() -> FunctionIterator {
     FunctionIterator {
@@ -201,3 +335,414 @@ pub fn functions() -> FunctionIterator {
 // 0.1: 8086 7000 Intel 82371SB PIIX3 PCI-to-ISA Bridge (Triton II)
 // 0.2: 1013 00b8 Cirrus Logic CL-GD5446 64-bit VisualMedia Accelerator
 // 0.3: 8086 100e Intel 02000 Intel Pro 1000/MT
+
+
+//=========================================================================
+//  Capabilities, power management and reset
+
+/// The command register, and the bits which turn on decoding and DMA.
+const COMMAND: u8 = 0x04;
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+/// Bit in the status register indicating a capabilities list.
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// Where the capabilities list starts.
+const CAPABILITIES_POINTER: u8 = 0x34;
+
+/// Capability IDs that we know about.
+const CAP_POWER_MANAGEMENT: u8 = 0x01;
+const CAP_PCI_EXPRESS: u8 = 0x10;
+const CAP_ADVANCED_FEATURES: u8 = 0x13;
+
+/// Power management control/status register, relative to the capability.
+const PMCSR: u8 = 0x04;
+const PMCSR_STATE_MASK: u16 = 0b11;
+const PMCSR_NO_SOFT_RESET: u16 = 1 << 3;
+
+/// PCI Express device capabilities and control, relative to the
+/// capability.
+const PCIE_DEVICE_CAPABILITIES: u8 = 0x04;
+const PCIE_DEVICE_CONTROL: u8 = 0x08;
+const PCIE_CAP_FLR: u32 = 1 << 28;
+const PCIE_CONTROL_FLR: u16 = 1 << 15;
+
+/// Advanced features capability and control, relative to the capability.
+/// These are single bytes, but we access them as part of the word
+/// starting at the capability.
+const AF_CAP_FLR: u32 = 1 << (1 + 24);
+const AF_CONTROL: u8 = 0x04;
+const AF_CONTROL_FLR: u32 = 1;
+
+/// A PCI device power state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    D0 = 0,
+    D1 = 1,
+    D2 = 2,
+    D3Hot = 3,
+}
+
+impl FunctionInfo {
+    /// Find the offset of the capability with the specified ID.
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        let status = self.read_config_u16(0x06);
+        if status & STATUS_CAPABILITIES == 0 {
+            return None;
+        }
+        let mut offset = self.read_config(CAPABILITIES_POINTER) as u8 & !0b11;
+        // Guard against malformed, looping lists: there can't be more than
+        // 48 capabilities in 192 bytes.
+        for _ in 0..48 {
+            if offset < 0x40 {
+                return None;
+            }
+            let header = self.read_config(offset);
+            if header as u8 == id {
+                return Some(offset);
+            }
+            offset = (header >> 8) as u8 & !0b11;
+        }
+        None
+    }
+
+    /// Our current power state, if we support power management.
+    pub fn power_state(&self) -> Option<PowerState> {
+        self.find_capability(CAP_POWER_MANAGEMENT).map(|cap| {
+            match self.read_config_u16(cap + PMCSR) & PMCSR_STATE_MASK {
+                0 => PowerState::D0,
+                1 => PowerState::D1,
+                2 => PowerState::D2,
+                _ => PowerState::D3Hot,
+            }
+        })
+    }
+
+    /// Move this function to `state`.  Per the PCI PM spec, we then wait
+    /// 10ms, which is the longest transition time it allows.
+    pub unsafe fn set_power_state(&self, state: PowerState)
+        -> Result<(), &'static str>
+    {
+        let cap = try!(self.find_capability(CAP_POWER_MANAGEMENT)
+                       .ok_or("no power management capability"));
+        let pmcsr = self.read_config_u16(cap + PMCSR);
+        self.write_config_u16(cap + PMCSR,
+                              (pmcsr & !PMCSR_STATE_MASK) | state as u16);
+        interrupts::io_delay_us(10 * 1000);
+        if self.power_state() == Some(state) {
+            Ok(())
+        } else {
+            Err("device didn't change power state")
+        }
+    }
+
+    /// Make sure this function is fully powered up.  Devices are
+    /// sometimes left in D3 by a previous OS after a warm reboot.
+    pub unsafe fn power_up(&self) -> Result<(), &'static str> {
+        match self.power_state() {
+            None | Some(PowerState::D0) => Ok(()),
+            Some(_) => self.set_power_state(PowerState::D0),
+        }
+    }
+
+    /// Try to start a function-level reset, returning false if we don't
+    /// know how.
+    unsafe fn start_function_level_reset(&self) -> bool {
+        if let Some(cap) = self.find_capability(CAP_PCI_EXPRESS) {
+            if self.read_config(cap + PCIE_DEVICE_CAPABILITIES) & PCIE_CAP_FLR != 0 {
+                let control = self.read_config_u16(cap + PCIE_DEVICE_CONTROL);
+                self.write_config_u16(cap + PCIE_DEVICE_CONTROL,
+                                      control | PCIE_CONTROL_FLR);
+                return true;
+            }
+        }
+        if let Some(cap) = self.find_capability(CAP_ADVANCED_FEATURES) {
+            if self.read_config(cap) & AF_CAP_FLR != 0 {
+                let control = self.read_config(cap + AF_CONTROL);
+                self.write_config(cap + AF_CONTROL, control | AF_CONTROL_FLR);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Reset this function, using a function-level reset if it supports
+    /// one, or a trip through D3hot if that resets it.  The standard
+    /// configuration header (BARs, command register, etc.) is saved and
+    /// restored, so the device stays where we put it.
+    pub unsafe fn reset(&self) -> Result<(), &'static str> {
+        let mut header = [0u32; 16];
+        for (i, word) in header.iter_mut().enumerate() {
+            *word = self.read_config(i as u8 * 4);
+        }
+
+        if self.start_function_level_reset() {
+            // The spec gives the device 100ms to complete the reset.
+            interrupts::io_delay_us(100 * 1000);
+        } else {
+            let cap = try!(self.find_capability(CAP_POWER_MANAGEMENT)
+                           .ok_or("device doesn't support reset"));
+            if self.read_config_u16(cap + PMCSR) & PMCSR_NO_SOFT_RESET != 0 {
+                return Err("device doesn't support reset");
+            }
+            try!(self.set_power_state(PowerState::D3Hot));
+            try!(self.set_power_state(PowerState::D0));
+        }
+
+        // Restore everything after the command/status word, and then the
+        // command register itself, so that the BARs are in place before we
+        // re-enable decoding.
+        for (i, &word) in header.iter().enumerate().skip(4) {
+            self.write_config(i as u8 * 4, word);
+        }
+        self.write_config_u16(0x04, header[1] as u16);
+        Ok(())
+    }
+}
+
+
+//=========================================================================
+//  Drivers
+
+/// Which devices a driver is willing to handle.
+pub enum DeviceMatch {
+    /// Match a specific vendor and device ID.
+    Id { vendor: u16, device: u16 },
+    /// Match any device with the specified class and subclass.
+    Class { class: DeviceClass, subclass: u8 },
+}
+
+impl DeviceMatch {
+    /// Does this entry match `info`?
+    fn matches(&self, info: &FunctionInfo) -> bool {
+        match *self {
+            DeviceMatch::Id { vendor, device } =>
+                info.vendor_id == vendor && info.device_id == device,
+            DeviceMatch::Class { class, subclass } =>
+                info.class_code == class && info.subclass == subclass,
+        }
+    }
+}
+
+/// A PCI device driver.
+pub struct Driver {
+    /// A short name for this driver, used in diagnostic output.
+    pub name: &'static str,
+    /// The devices this driver supports.
+    pub matches: &'static [DeviceMatch],
+    /// Try to set up the specified device.  Returns an error if the
+    /// driver can't handle it after all.
+    pub probe: fn(&FunctionInfo) -> Result<(), &'static str>,
+    /// Let go of a device, which may already be gone, so that it can be
+    /// removed or probed again.  `None` if the driver can't let go, in
+    /// which case we just forget that it was bound.
+    pub remove: Option<fn(&FunctionInfo)>,
+    /// A description of the device's registers, found via BAR 0, for the
+    /// shell's `regs` command.
+    pub registers: Option<&'static RegisterMap>,
+}
+
+impl Driver {
+    /// Does this driver claim to support `info`?
+    fn supports(&self, info: &FunctionInfo) -> bool {
+        self.matches.iter().any(|m| m.matches(info))
+    }
+}
+
+/// All the PCI drivers built into our kernel.  To add a driver, define a
+/// `static` `Driver` in its module and list it here.
+static DRIVERS: &'static [&'static Driver] = &[
+    &vbe::DRIVER,
+    &virtio_console::DRIVER,
+];
+
+/// A driver which has been successfully attached to a function.
+#[derive(Clone)]
+pub struct Binding {
+    pub function: FunctionInfo,
+    pub driver: &'static Driver,
+}
+
+/// Our current driver bindings, by function address, or `None` if we
+/// haven't scanned the bus yet.
+static BINDINGS: RwSpinlock<Option<HashMap<(u8, u8, u8), Binding>>> =
+    RwSpinlock::new(None);
+
+/// Every function we found on our last scan of the bus, by address, or
+/// `None` if we haven't scanned it yet.
+static FUNCTIONS: RwSpinlock<Option<HashMap<(u8, u8, u8), FunctionInfo>>> =
+    RwSpinlock::new(None);
+
+/// Bind `function` to the first driver which supports it and whose `probe`
+/// function succeeds, if any.
+fn bind(function: &FunctionInfo) -> Option<Binding> {
+    let mut powered_up = false;
+    for &driver in DRIVERS.iter().filter(|d| d.supports(function)) {
+        // Make sure the device is awake before any driver looks at it.
+        if !powered_up {
+            if let Err(err) = unsafe { function.power_up() } {
+                let (bus, device, func) = function.address();
+                println!("pci: can't power up {}.{}.{}: {}",
+                         bus, device, func, err);
+            }
+            powered_up = true;
+        }
+        match (driver.probe)(function) {
+            Ok(()) => {
+                return Some(Binding {
+                    function: function.clone(),
+                    driver: driver,
+                });
+            }
+            Err(err) => {
+                let (bus, device, func) = function.address();
+                println!("pci: {} failed to probe {}.{}.{}: {}",
+                         driver.name, bus, device, func, err);
+            }
+        }
+    }
+    None
+}
+
+/// Scan the PCI bus and bind each function to the first driver which
+/// supports it and whose `probe` function succeeds.  Requires the heap.
+pub fn bind_drivers() {
+    let mut found = HashMap::new();
+    let mut bindings = HashMap::new();
+    for function in functions() {
+        if let Some(binding) = bind(&function) {
+            bindings.insert(function.address(), binding);
+        }
+        found.insert(function.address(), function);
+    }
+    *FUNCTIONS.write() = Some(found);
+    *BINDINGS.write() = Some(bindings);
+}
+
+/// Is `b` the same device as `a`, rather than something new at the same
+/// address?
+fn same_device(a: &FunctionInfo, b: &FunctionInfo) -> bool {
+    a.vendor_id == b.vendor_id && a.device_id == b.device_id &&
+        a.class == b.class && a.subclass == b.subclass
+}
+
+/// Scan the PCI bus again, for devices which have come or gone since the
+/// last scan.  Drivers let go of devices which have disappeared, and we
+/// bind drivers to new ones.  Returns the number of functions added and
+/// removed, or an error if `bind_drivers` hasn't done the first scan.
+pub fn rescan() -> Result<(usize, usize), &'static str> {
+    let mut found = HashMap::new();
+    for function in functions() {
+        found.insert(function.address(), function);
+    }
+
+    // Work out what changed, and let go of what's gone.
+    let (mut added, mut removed) = (Vec::new(), Vec::new());
+    {
+        let known = FUNCTIONS.read();
+        let known = try!(known.as_ref().ok_or("bus hasn't been scanned yet"));
+        for (address, old) in known.iter() {
+            match found.get(address) {
+                Some(new) if same_device(old, new) => {}
+                _ => removed.push(old.clone()),
+            }
+        }
+        for (address, new) in found.iter() {
+            match known.get(address) {
+                Some(old) if same_device(old, new) => {}
+                _ => added.push(new.clone()),
+            }
+        }
+    }
+    for function in &removed {
+        println!("pci: - {}", function);
+        // Don't hold our lock while the driver works.
+        let binding = BINDINGS.write().as_mut()
+            .and_then(|bindings| bindings.remove(&function.address()));
+        if let Some(binding) = binding {
+            binding.unbind();
+        }
+    }
+
+    *FUNCTIONS.write() = Some(found);
+    for function in &added {
+        println!("pci: + {}", function);
+        if let Some(binding) = bind(function) {
+            println!("pci: bound {}", binding.driver.name);
+            if let Some(ref mut bindings) = *BINDINGS.write() {
+                bindings.insert(function.address(), binding);
+            }
+        }
+    }
+    Ok((added.len(), removed.len()))
+}
+
+/// Find the function at `address`, if there is one.
+pub fn find_function(address: (u8, u8, u8)) -> Option<FunctionInfo> {
+    let (bus, device, function) = address;
+    unsafe { PCI.lock().probe(bus, device, function) }
+}
+
+impl Binding {
+    /// Ask our driver to let go of our device.
+    fn unbind(&self) {
+        match self.driver.remove {
+            Some(remove) => remove(&self.function),
+            None => println!("pci: {} can't let go of {}; forgetting it",
+                             self.driver.name, self.function),
+        }
+    }
+
+    /// Reset our device, and give our driver a chance to set it up again.
+    pub unsafe fn reset(&self) -> Result<(), &'static str> {
+        if let Some(remove) = self.driver.remove {
+            remove(&self.function);
+        }
+        try!(self.function.reset());
+        (self.driver.probe)(&self.function)
+    }
+}
+
+/// Reset the function at `address`.  If a driver is bound to it, the
+/// driver will be asked to probe it again.
+pub unsafe fn reset(address: (u8, u8, u8)) -> Result<(), &'static str> {
+    // Don't hold our lock while the driver works.
+    let binding = BINDINGS.read().as_ref()
+        .and_then(|bindings| bindings.get(&address)).cloned();
+    if let Some(binding) = binding {
+        return binding.reset();
+    }
+    let function = try!(find_function(address).ok_or("no such function"));
+    function.reset()
+}
+
+/// Find the register description and register base address of the
+/// device at `address`, if a driver which describes its registers is bound
+/// to it.
+pub fn bound_registers(address: (u8, u8, u8))
+    -> Result<(&'static RegisterMap, regs::Base), &'static str>
+{
+    let bindings = BINDINGS.read();
+    let binding = try!(bindings.as_ref().and_then(|b| b.get(&address))
+                       .ok_or("no driver bound to this function"));
+    let map = try!(binding.driver.registers
+                   .ok_or("driver doesn't describe its registers"));
+
+    let bar = binding.function.bar(0);
+    if bar & 1 != 0 {
+        Ok((map, regs::Base::Port((bar & !0b11) as u16)))
+    } else {
+        // We'd need to map these into our device window first.
+        Err("memory-mapped registers aren't supported yet")
+    }
+}
+
+/// Find the name of the driver bound to the function at `address`, if
+/// any.
+pub fn bound_driver(address: (u8, u8, u8)) -> Option<&'static str> {
+    BINDINGS.read().as_ref()
+        .and_then(|bindings| bindings.get(&address))
+        .map(|b| b.driver.name)
+}
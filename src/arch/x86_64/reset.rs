@@ -0,0 +1,171 @@
+//! Rebooting the machine, and figuring out why we were rebooted.
+//!
+//! There's no single reliable way to reset a PC, so we try the usual
+//! suspects in order: pulsing the reset line via the 8042 keyboard
+//! controller, the PCI reset control register at 0xCF9, and finally a
+//! triple fault, which no CPU can survive.
+//!
+//! Before each attempt, we leave a note in a spare CMOS byte saying which
+//! method we're trying.  CMOS survives a reset, so at the next boot we can
+//! tell a warm reboot of our own making from a cold boot (or from a reset
+//! we didn't initiate, such as the reset button).
+
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use cpuio;
+use x86;
+
+use arch::x86_64::i8042;
+use arch::x86_64::interrupts;
+
+/// CMOS index and data ports.  Setting the top bit of the index disables
+/// NMIs, which we do to avoid an NMI catching the RTC in a bad state.  We
+/// turn them back on afterwards.  The index port is write-only on most
+/// chipsets, so we can't save its old state, but nobody else disables NMIs.
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+const CMOS_NMI_DISABLE: u8 = 0x80;
+
+/// The CMOS byte where we record our reset method.  This is outside the
+/// range covered by the standard CMOS checksum (0x10-0x2D), and it's not
+/// used by any BIOS we know of, but it's still a bit of a gamble.
+const CMOS_RESET_FLAG: u8 = 0x3F;
+
+/// The high nibble we store in `CMOS_RESET_FLAG`, so that we don't mistake
+/// random CMOS contents for one of our notes.
+const RESET_FLAG_MAGIC: u8 = 0xA0;
+
+/// The 8042 keyboard controller command which pulses the reset line.
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+/// The PCI reset control register, and the values we write to it: first
+/// select a hard reset, then trigger it.
+const PCI_RESET_CONTROL: u16 = 0xCF9;
+const PCI_RESET_HARD: u8 = 0x02;
+const PCI_RESET_NOW: u8 = 0x04;
+
+/// Ways that we know how to reset the machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    KeyboardController = 1,
+    PciResetControl = 2,
+    TripleFault = 3,
+}
+
+impl Method {
+    fn from_u8(value: u8) -> Option<Method> {
+        match value {
+            1 => Some(Method::KeyboardController),
+            2 => Some(Method::PciResetControl),
+            3 => Some(Method::TripleFault),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Method::KeyboardController => "8042 keyboard controller",
+            Method::PciResetControl => "PCI reset control register",
+            Method::TripleFault => "triple fault",
+        }
+    }
+}
+
+/// Why the machine was last reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cause {
+    /// We found no note from a previous boot, so this was a power-on, or
+    /// some reset that we didn't cause.
+    ColdBoot,
+    /// We rebooted ourselves using `Method`.
+    Reboot(Method),
+}
+
+impl Cause {
+    /// A human-readable description of this cause.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Cause::ColdBoot => "cold boot (or external reset)",
+            Cause::Reboot(method) => method.name(),
+        }
+    }
+}
+
+/// The value of `CMOS_RESET_FLAG` we found at boot.  0 means we haven't
+/// checked yet.
+static BOOT_FLAG: AtomicUsize = ATOMIC_USIZE_INIT;
+
+unsafe fn read_cmos(index: u8) -> u8 {
+    cpuio::outb(CMOS_NMI_DISABLE | index, CMOS_INDEX);
+    let value = cpuio::inb(CMOS_DATA);
+    cpuio::outb(index, CMOS_INDEX);
+    value
+}
+
+unsafe fn write_cmos(index: u8, value: u8) {
+    cpuio::outb(CMOS_NMI_DISABLE | index, CMOS_INDEX);
+    cpuio::outb(value, CMOS_DATA);
+    cpuio::outb(index, CMOS_INDEX);
+}
+
+/// Check how we were reset, and clear our note so that a subsequent power
+/// cycle isn't mistaken for a reboot.  Call this once, early in boot.
+pub unsafe fn initialize() {
+    let flag = read_cmos(CMOS_RESET_FLAG);
+    write_cmos(CMOS_RESET_FLAG, 0);
+    // Store something non-zero even if the flag was 0, to record that
+    // we've checked.
+    BOOT_FLAG.store(0x100 | flag as usize, Ordering::SeqCst);
+}
+
+/// Why were we last reset?
+pub fn cause() -> Cause {
+    let flag = BOOT_FLAG.load(Ordering::SeqCst) as u8;
+    if flag & 0xF0 == RESET_FLAG_MAGIC {
+        if let Some(method) = Method::from_u8(flag & 0x0F) {
+            return Cause::Reboot(method);
+        }
+    }
+    Cause::ColdBoot
+}
+
+/// Try to reset the machine using `method`.  If this returns, it didn't
+/// work.
+unsafe fn try_reset(method: Method) {
+    write_cmos(CMOS_RESET_FLAG, RESET_FLAG_MAGIC | method as u8);
+    match method {
+        Method::KeyboardController => {
+            // If the controller is wedged, move on to the next method.
+            let _ = i8042::send_command(KBC_PULSE_RESET);
+        }
+        Method::PciResetControl => {
+            cpuio::outb(PCI_RESET_HARD, PCI_RESET_CONTROL);
+            cpuio::outb(PCI_RESET_HARD | PCI_RESET_NOW, PCI_RESET_CONTROL);
+        }
+        Method::TripleFault => {
+            // With an empty IDT, any interrupt causes a double fault, which
+            // can't be delivered either.
+            let pointer = x86::dtables::DescriptorTablePointer {
+                limit: 0,
+                base: 0,
+            };
+            x86::dtables::lidt(&pointer);
+            int!(3);
+        }
+    }
+    // Give the hardware a moment to act on our request.
+    interrupts::io_delay_us(50 * 1000);
+}
+
+/// Reset the machine.
+pub fn reboot() -> ! {
+    unsafe {
+        x86::irq::disable();
+        try_reset(Method::KeyboardController);
+        try_reset(Method::PciResetControl);
+        try_reset(Method::TripleFault);
+    }
+    // We should never get here, but if we do, at least stop cleanly.
+    loop {
+        unsafe { asm!("hlt" :::: "volatile"); }
+    }
+}
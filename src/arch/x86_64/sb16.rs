@@ -0,0 +1,188 @@
+//! A Sound Blaster 16 driver, which can play 8-bit PCM audio.  In QEMU,
+//! run with `-device sb16`.
+//!
+//! We play sound one chunk at a time: we copy a chunk into a buffer below
+//! 16MB, start an ISA DMA transfer from it, and when the card interrupts
+//! us to say it's done, we copy in the next chunk.  There's a tiny gap
+//! between chunks, which real drivers avoid with auto-initialized DMA and
+//! double buffering.
+//!
+//! See http://wiki.osdev.org/Sound_Blaster_16.
+
+use cpuio::Port;
+use spin::Mutex;
+
+use arch::x86_64::interrupts;
+use arch::x86_64::isa_dma::{self, Direction};
+use config;
+use dma::DmaVec;
+use wav::Sound;
+
+/// The card's default I/O base, IRQ and 8-bit DMA channel.
+const BASE: u16 = 0x220;
+const IRQ: u8 = 5;
+const DMA_CHANNEL: u8 = 1;
+
+/// The interrupt vector for our IRQ, given how we've set up the PICs.
+pub const INTERRUPT: u8 = 0x20 + IRQ;
+
+/// How much sound we transfer at once.  We align our DMA buffer to its
+/// size, so it never crosses a 64KB boundary.
+const CHUNK_SIZE: usize = 32 * 1024;
+
+/// DSP commands.
+const CMD_SET_OUTPUT_RATE: u8 = 0x41;
+const CMD_PLAY_8_BIT: u8 = 0xC0;
+const CMD_SPEAKER_ON: u8 = 0xD1;
+const CMD_SPEAKER_OFF: u8 = 0xD3;
+
+/// Mode bits for `CMD_PLAY_8_BIT`.
+const MODE_MONO: u8 = 0x00;
+const MODE_STEREO: u8 = 0x20;
+
+/// What the DSP says after a successful reset.
+const RESET_OK: u8 = 0xAA;
+
+/// The ports of the card's digital signal processor.
+struct Dsp {
+    reset: Port<u8>,
+    read: Port<u8>,
+    write: Port<u8>,
+    /// Reading this also acknowledges an 8-bit DMA interrupt.
+    read_status: Port<u8>,
+}
+
+impl Dsp {
+    /// Reset the DSP, and check that it's really there.
+    fn reset(&mut self) -> Result<(), &'static str> {
+        self.reset.write(1);
+        interrupts::io_delay_us(3);
+        self.reset.write(0);
+        for _ in 0..1000 {
+            if self.read_status.read() & 0x80 != 0 {
+                return if self.read.read() == RESET_OK {
+                    Ok(())
+                } else {
+                    Err("Sound Blaster DSP failed to reset")
+                };
+            }
+            interrupts::io_delay_us(1);
+        }
+        Err("no Sound Blaster found")
+    }
+
+    /// Send a byte to the DSP, once it's ready.
+    fn send(&mut self, value: u8) {
+        while self.write.read() & 0x80 != 0 {}
+        self.write.write(value);
+    }
+}
+
+/// A sound we're in the middle of playing.
+struct Playback {
+    data: &'static [u8],
+    position: usize,
+    mode: u8,
+}
+
+/// The state of our driver.
+struct State {
+    dsp: Dsp,
+    /// Our DMA buffer, once we've allocated it.
+    buffer: Option<DmaVec<u8>>,
+    playing: Option<Playback>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    dsp: Dsp {
+        reset: unsafe { Port::new(BASE + 0x6) },
+        read: unsafe { Port::new(BASE + 0xA) },
+        write: unsafe { Port::new(BASE + 0xC) },
+        read_status: unsafe { Port::new(BASE + 0xE) },
+    },
+    buffer: None,
+    playing: None,
+});
+
+impl State {
+    /// Copy the next chunk of our sound into the DMA buffer and start
+    /// playing it.  When we run out of sound, turn off the speaker.
+    fn play_next_chunk(&mut self) {
+        let (chunk_len, mode, buffer_addr) =
+            match (&mut self.playing, &mut self.buffer)
+        {
+            (&mut Some(ref mut playback), &mut Some(ref mut buffer)) => {
+                let rest = &playback.data[playback.position..];
+                let len = if rest.len() < CHUNK_SIZE { rest.len() } else { CHUNK_SIZE };
+                buffer[..len].copy_from_slice(&rest[..len]);
+                playback.position += len;
+                (len, playback.mode, buffer.physical_addr())
+            }
+            _ => return,
+        };
+        if chunk_len == 0 {
+            self.playing = None;
+            self.dsp.send(CMD_SPEAKER_OFF);
+            return;
+        }
+
+        unsafe {
+            isa_dma::start(DMA_CHANNEL, buffer_addr, chunk_len, Direction::ToDevice)
+                .expect("our DMA buffer should always be usable");
+        }
+        let count = chunk_len - 1;
+        self.dsp.send(CMD_PLAY_8_BIT);
+        self.dsp.send(mode);
+        self.dsp.send(count as u8);
+        self.dsp.send((count >> 8) as u8);
+    }
+}
+
+/// Start playing `sound`.  We return immediately, and the rest of the
+/// sound is fed to the card from our interrupt handler.
+pub fn play(sound: Sound) -> Result<(), &'static str> {
+    if !config::SOUND {
+        return Err("kernel built without sound support");
+    }
+    if sound.bits_per_sample != 8 {
+        return Err("only 8-bit sound is supported");
+    }
+    let mode = match sound.channels {
+        1 => MODE_MONO,
+        2 => MODE_STEREO,
+        _ => return Err("only mono and stereo sound are supported"),
+    };
+    if sound.rate > 0xFFFF {
+        return Err("sample rate too high");
+    }
+
+    let mut state = STATE.lock();
+    if state.playing.is_some() {
+        return Err("already playing");
+    }
+    try!(state.dsp.reset());
+
+    if state.buffer.is_none() {
+        state.buffer = Some(try!(DmaVec::zeroed(CHUNK_SIZE, CHUNK_SIZE)));
+    }
+    unsafe { interrupts::unmask_irq(INTERRUPT); }
+
+    state.dsp.send(CMD_SPEAKER_ON);
+    state.dsp.send(CMD_SET_OUTPUT_RATE);
+    state.dsp.send((sound.rate >> 8) as u8);
+    state.dsp.send(sound.rate as u8);
+    state.playing = Some(Playback {
+        data: sound.data,
+        position: 0,
+        mode: mode,
+    });
+    state.play_next_chunk();
+    Ok(())
+}
+
+/// Called from our interrupt handler when the card finishes a chunk.
+pub fn handle_interrupt() {
+    let mut state = STATE.lock();
+    state.dsp.read_status.read();
+    state.play_next_chunk();
+}
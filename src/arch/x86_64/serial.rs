@@ -1,12 +1,29 @@
 //! Basic serial port driver.
 //!
 //! As usual, inspired by http://wiki.osdev.org/Serial_Ports
+//!
+//! By default we run at 57,600 baud, 8N1, without flow control, which is
+//! what QEMU and most terminal programs expect.  Real hardware may need
+//! something else, so all of that can be changed at runtime with
+//! `ComPort::configure`.  If RTS/CTS flow control is on, we wait for the
+//! other end to raise CTS before sending each byte.  We don't use transmit
+//! interrupts.  COM1 takes an interrupt when data arrives, and queues it
+//! for `read_received`, so that we don't lose input while we're busy.  It
+//! can also take one whenever the modem status lines change, which is
+//! handy for seeing whether a cable is plugged in.
 
 use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_BOOL_INIT,
+                         ATOMIC_USIZE_INIT, Ordering};
 use spin::Mutex;
+use spsc_ring::RingBuffer;
 use cpuio;
 use self::Register::*;
 
+use arch::x86_64::interrupts;
+use regs::{self, Field, RegisterMap};
+use sync::SeqLock;
+
 /// Each COM port has 8 I/O registers associated with it, some of which are
 /// dual use.
 #[allow(dead_code)]
@@ -28,6 +45,155 @@ enum Register {
     Scratch = 7
 }
 
+/// The UART's clock, divided by 16.  The baud rate is this divided by
+/// the divisor we program.
+const MAX_BAUD: u32 = 115_200;
+
+/// Bits of the LineStatus register.
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_TRANSMIT_EMPTY: u8 = 0x20;
+
+/// Bits of the ModemStatus register.
+const MSR_CTS: u8 = 0x10;
+const MSR_DSR: u8 = 0x20;
+const MSR_RING: u8 = 0x40;
+const MSR_CARRIER: u8 = 0x80;
+
+/// The interrupt-enable bits for received data and modem status changes.
+const IER_RECEIVED_DATA: u8 = 0x01;
+const IER_MODEM_STATUS: u8 = 0x08;
+
+/// How many times we poll CTS before giving up and sending anyway.  A
+/// console which hangs because nobody is listening is worse than one which
+/// drops a few characters.
+const CTS_POLLS: usize = 100_000;
+
+/// Parity checking, as set in the LineControl register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// The parity bit is always 1.
+    Mark,
+    /// The parity bit is always 0.
+    Space,
+}
+
+impl Parity {
+    /// Our bits in the LineControl register.
+    fn line_control_bits(self) -> u8 {
+        match self {
+            Parity::None => 0x00,
+            Parity::Odd => 0x08,
+            Parity::Even => 0x18,
+            Parity::Mark => 0x28,
+            Parity::Space => 0x38,
+        }
+    }
+
+    /// The letter used for this parity in the usual "8N1" notation.
+    fn letter(self) -> char {
+        match self {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+            Parity::Mark => 'M',
+            Parity::Space => 'S',
+        }
+    }
+}
+
+/// How a serial port should talk to the other end of the cable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Bits per second.  This must divide 115,200 evenly.
+    pub baud: u32,
+    /// Bits in each character, from 5 to 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2.  With 5 data bits, 2 actually means 1.5.
+    pub stop_bits: u8,
+    /// Wait for CTS before sending?
+    pub flow_control: bool,
+}
+
+/// The configuration we start with.
+pub const DEFAULT_CONFIG: Config = Config {
+    baud: 57_600,
+    data_bits: 8,
+    parity: Parity::None,
+    stop_bits: 1,
+    flow_control: false,
+};
+
+impl Config {
+    /// The divisor and LineControl bits for this configuration, or an
+    /// error if the hardware can't do it.
+    fn registers(&self) -> Result<(u16, u8), &'static str> {
+        if self.baud == 0 || self.baud > MAX_BAUD || MAX_BAUD % self.baud != 0 {
+            return Err("baud rate must divide 115200");
+        }
+        if self.data_bits < 5 || self.data_bits > 8 {
+            return Err("data bits must be 5 to 8");
+        }
+        if self.stop_bits != 1 && self.stop_bits != 2 {
+            return Err("stop bits must be 1 or 2");
+        }
+        let mut line_control = self.data_bits - 5;
+        if self.stop_bits == 2 {
+            line_control |= 0x04;
+        }
+        line_control |= self.parity.line_control_bits();
+        Ok(((MAX_BAUD / self.baud) as u16, line_control))
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} baud, {}{}{}, {}", self.baud, self.data_bits,
+               self.parity.letter(), self.stop_bits,
+               if self.flow_control { "RTS/CTS" } else { "no flow control" })
+    }
+}
+
+/// The state of the modem status lines, which tell us about the other end
+/// of the cable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModemLines {
+    /// Clear To Send: the other end is ready for data.
+    pub cts: bool,
+    /// Data Set Ready: the other end is switched on.
+    pub dsr: bool,
+    /// Ring Indicator.
+    pub ring: bool,
+    /// Data Carrier Detect.
+    pub carrier: bool,
+}
+
+impl ModemLines {
+    fn from_register(msr: u8) -> ModemLines {
+        ModemLines {
+            cts: msr & MSR_CTS != 0,
+            dsr: msr & MSR_DSR != 0,
+            ring: msr & MSR_RING != 0,
+            carrier: msr & MSR_CARRIER != 0,
+        }
+    }
+}
+
+impl fmt::Display for ModemLines {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lines = [(self.cts, "CTS"), (self.dsr, "DSR"),
+                     (self.ring, "RI"), (self.carrier, "DCD")];
+        for (i, &(on, name)) in lines.iter().enumerate() {
+            if i > 0 { try!(write!(f, " ")); }
+            try!(write!(f, "{}{}", if on { "+" } else { "-" }, name));
+        }
+        Ok(())
+    }
+}
+
 /// A COM serial port.
 pub struct ComPort {
     /// COM ports are identified by the base address of their associated
@@ -35,6 +201,8 @@ pub struct ComPort {
     base_addr: u16,
     /// Has this port been initialized yet?
     initialized: bool,
+    /// How the port is currently set up.
+    config: Config,
 }
 
 impl ComPort {
@@ -44,20 +212,29 @@ impl ComPort {
     /// Initialization is finished by `lazy_initialize`, which should be
     /// called by all safe, public functions in this API.
     const unsafe fn new(base_addr: u16) -> ComPort {
-        ComPort { base_addr: base_addr, initialized: false }
+        ComPort {
+            base_addr: base_addr,
+            initialized: false,
+            config: DEFAULT_CONFIG,
+        }
     }
 
     /// Finish the runtime-only setup needed by this port.
     unsafe fn lazy_initialize(&mut self) {
         if self.initialized == true { return; }
         self.initialized = true;
+        if self.base_addr == COM1_BASE {
+            COM1_READY.store(true, Ordering::SeqCst);
+        }
 
         // Disable interrupts.
         self.port(InterruptEnableOrBaudMsb).write(0x00);
 
-        // Set baud and 8N1 mode.
-        self.set_baud_divisor(2); // 115,200 / 2
-        self.port(LineControl).write(0x03);
+        // Set baud, data bits, parity and stop bits.  Our default
+        // configuration is always valid.
+        let (divisor, line_control) = self.config.registers().unwrap();
+        self.port(LineControl).write(line_control);
+        self.set_baud_divisor(divisor);
 
         // Enable FIFOs with 14-byte threshhold.
         self.port(InterruptIdentAndFifo).write(0xC7);
@@ -95,6 +272,68 @@ impl ComPort {
         }
     }
 
+    /// How this port is currently set up.
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    /// Change the baud rate, character format or flow control.  Anything
+    /// still in the transmit FIFO is sent first, using the old settings.
+    pub fn configure(&mut self, config: Config) -> Result<(), &'static str> {
+        let (divisor, line_control) = try!(config.registers());
+        unsafe {
+            self.lazy_initialize();
+            while self.port(LineStatus).read() & LSR_TRANSMIT_EMPTY == 0 {}
+            self.port(LineControl).write(line_control);
+            self.set_baud_divisor(divisor);
+        }
+        self.config = config;
+        if self.base_addr == COM1_BASE {
+            // `raw_com1` may read this from an interrupt handler, which
+            // would spin forever if it interrupted us halfway through.
+            interrupts::without_interrupts(|| COM1_CONFIG.write(config));
+        }
+        Ok(())
+    }
+
+    /// Read the modem status lines.  This clears the "changed" bits in the
+    /// ModemStatus register.
+    pub fn modem_lines(&mut self) -> ModemLines {
+        unsafe {
+            self.lazy_initialize();
+            ModemLines::from_register(self.port(ModemStatus).read())
+        }
+    }
+
+    /// Ask for an interrupt whenever the modem status lines change, or
+    /// stop asking.  Only COM1 has an interrupt handler.
+    pub fn set_modem_interrupts(&mut self, enabled: bool) {
+        unsafe {
+            self.lazy_initialize();
+            // Clear any stale change before we start listening.
+            self.port(ModemStatus).read();
+            let others = self.port(InterruptEnableOrBaudMsb).read()
+                & !IER_MODEM_STATUS;
+            self.port(InterruptEnableOrBaudMsb)
+                .write(if enabled { others | IER_MODEM_STATUS } else { others });
+            if enabled {
+                interrupts::unmask_irq(INTERRUPT);
+            }
+        }
+    }
+
+    /// Read a byte from this serial port, if one is waiting.
+    pub fn read_byte(&mut self) -> Option<u8> {
+        unsafe {
+            self.lazy_initialize();
+            if (self.port(LineStatus).read() & LSR_DATA_READY) != 0 {
+                Some(self.port(DataOrBaudLsb).read())
+            } else {
+                None
+            }
+        }
+    }
+
     /// Can we safely transmit data on this serial port right now, or will
     /// we block?
     fn can_transmit(&mut self) -> bool {
@@ -103,8 +342,20 @@ impl ComPort {
             // TODO: Check to see what the meaning of this bit is. OSDev
             // calls it "is_transmit_empty", so maybe we actually want a
             // different bit.
-            (self.port(LineStatus).read() & 0x20) != 0
+            (self.port(LineStatus).read() & LSR_TRANSMIT_EMPTY) != 0
+        }
+    }
+
+    /// If we're using flow control, wait for the other end to raise CTS,
+    /// but not forever.
+    unsafe fn wait_for_clear_to_send(&mut self) {
+        if !self.config.flow_control { return; }
+        for _ in 0..CTS_POLLS {
+            if self.port(ModemStatus).read() & MSR_CTS != 0 {
+                return;
+            }
         }
+        CTS_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
     }
 }
 
@@ -119,6 +370,7 @@ impl fmt::Write for ComPort {
             for &b in s.as_bytes() {
                 // Loop until the port's available.
                 while !self.can_transmit() {}
+                self.wait_for_clear_to_send();
 
                 // Write our byte.
                 self.port(DataOrBaudLsb).write(b);
@@ -130,5 +382,212 @@ impl fmt::Write for ComPort {
 
 /// Our primary serial port.
 pub static COM1: Mutex<ComPort> = Mutex::new(unsafe {
-    ComPort::new(0x03F8)
+    ComPort::new(COM1_BASE)
 });
+
+/// Has COM1 been initialized?  `raw_com1` checks this, so that a panic
+/// doesn't reset a port somebody has reconfigured.
+static COM1_READY: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// A copy of COM1's configuration, so that `raw_com1` can honor it
+/// without taking the `COM1` lock.
+static COM1_CONFIG: SeqLock<Config> = SeqLock::new(DEFAULT_CONFIG);
+
+/// The interrupt vector for COM1, which is IRQ 4.
+pub const INTERRUPT: u8 = 0x24;
+
+/// Bytes our interrupt handler has read from COM1, waiting for
+/// `read_received`.  If they arrive faster than we read them, we drop
+/// them, just as the UART would.
+static RECEIVED: RingBuffer<[u8; 256]> = RingBuffer::new([0; 256]);
+
+/// Is the early serial console on?  See `early_initialize`.
+static EARLY: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// How many modem status changes we've been interrupted for.
+static MODEM_CHANGES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The ModemStatus register as of the last change, and how many changes
+/// `report_modem_changes` has already logged.
+static MODEM_STATUS: AtomicUsize = ATOMIC_USIZE_INIT;
+static REPORTED_MODEM_CHANGES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// How many times we gave up waiting for CTS and sent a byte anyway.
+static CTS_TIMEOUTS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Counts of interesting serial events, as `(modem status changes, CTS
+/// timeouts)`.
+pub fn stats() -> (usize, usize) {
+    (MODEM_CHANGES.load(Ordering::Relaxed), CTS_TIMEOUTS.load(Ordering::Relaxed))
+}
+
+/// Handle an interrupt from COM1.  The interrupted code may be holding
+/// `COM1`, so we talk to the registers directly, and we can't print
+/// anything, because that would need `COM1`.  We queue any data for
+/// `read_received`, and note modem status changes for
+/// `report_modem_changes`.
+pub fn handle_interrupt() {
+    let mut port = unsafe { raw_com1() };
+    // Bit 0 of IIR is clear while an interrupt is pending, and bits 1-3
+    // say which one.  Keep going until we've acknowledged them all.
+    loop {
+        let ident = unsafe { port.port(InterruptIdentAndFifo).read() };
+        if ident & 0x01 != 0 { break; }
+        match ident & 0x0E {
+            // Modem status change.  Reading MSR acknowledges it.
+            0x00 => {
+                let status = unsafe { port.port(ModemStatus).read() };
+                MODEM_STATUS.store(status as usize, Ordering::SeqCst);
+                MODEM_CHANGES.fetch_add(1, Ordering::SeqCst);
+            }
+            // Received data, or a timeout with data still in the FIFO.
+            // Emptying the FIFO acknowledges it.
+            0x04 | 0x0C => {
+                while let Some(byte) = port.read_byte() {
+                    // We're the only producer.
+                    let _ = unsafe { RECEIVED.push(byte) };
+                }
+            }
+            // A receive error.  Reading LSR acknowledges it.
+            0x06 => unsafe { port.port(LineStatus).read(); },
+            // The transmitter is empty, which we never ask about.  Reading
+            // IIR acknowledged it.
+            _ => {}
+        }
+    }
+}
+
+/// Read the next byte of input from COM1: anything our interrupt handler
+/// has queued, and then anything still waiting in the UART.  Call this
+/// with interrupts off, so that bytes stay in order.  Only the console
+/// reads input, so we're the only consumer.
+pub fn read_received() -> Option<u8> {
+    unsafe { RECEIVED.pop() }.or_else(|| COM1.lock().read_byte())
+}
+
+/// Start queuing COM1 input from our interrupt handler.  Call this once
+/// interrupts are set up.
+pub fn enable_receive_interrupts() {
+    let mut com1 = COM1.lock();
+    unsafe {
+        com1.lazy_initialize();
+        let enabled = com1.port(InterruptEnableOrBaudMsb).read();
+        com1.port(InterruptEnableOrBaudMsb).write(enabled | IER_RECEIVED_DATA);
+        interrupts::unmask_irq(INTERRUPT);
+    }
+}
+
+/// Log any modem status changes since we were last called.  Call this
+/// from the main loop, not from an interrupt handler.
+pub fn report_modem_changes() {
+    let changes = MODEM_CHANGES.load(Ordering::SeqCst);
+    if REPORTED_MODEM_CHANGES.swap(changes, Ordering::SeqCst) != changes {
+        let status = ModemLines::from_register(
+            MODEM_STATUS.load(Ordering::SeqCst) as u8);
+        log_rate_limited!(5, 1000, "COM1: modem status now {}", status);
+    }
+}
+
+/// Shorthand for building `REGISTERS`.
+const fn field(name: &'static str, shift: u8, width: u8) -> Field {
+    Field { name: name, shift: shift, width: width }
+}
+
+/// Descriptions of our 16550 UART registers, for the shell's `regs`
+/// command.  We assume the divisor latch is off, which is how we always
+/// leave it.
+pub static REGISTERS: RegisterMap = RegisterMap {
+    name: "16550 UART",
+    registers: &[
+        regs::Register { name: "RBR", offset: 0, size: 1,
+                         read_has_side_effects: true, fields: &[] },
+        regs::Register { name: "IER", offset: 1, size: 1,
+                         read_has_side_effects: false, fields: &[
+                             field("RDA", 0, 1), field("THRE", 1, 1),
+                             field("RLS", 2, 1), field("MS", 3, 1),
+                         ] },
+        // Reading IIR acknowledges a pending THRE interrupt.
+        regs::Register { name: "IIR", offset: 2, size: 1,
+                         read_has_side_effects: true, fields: &[
+                             field("NOPEND", 0, 1), field("ID", 1, 3),
+                             field("FIFO", 6, 2),
+                         ] },
+        regs::Register { name: "LCR", offset: 3, size: 1,
+                         read_has_side_effects: false, fields: &[
+                             field("WLS", 0, 2), field("STB", 2, 1),
+                             field("PEN", 3, 1), field("EPS", 4, 1),
+                             field("SP", 5, 1), field("BC", 6, 1),
+                             field("DLAB", 7, 1),
+                         ] },
+        regs::Register { name: "MCR", offset: 4, size: 1,
+                         read_has_side_effects: false, fields: &[
+                             field("DTR", 0, 1), field("RTS", 1, 1),
+                             field("OUT1", 2, 1), field("OUT2", 3, 1),
+                             field("LOOP", 4, 1),
+                         ] },
+        // Strictly speaking, reading LSR clears the error bits, but we
+        // poll it constantly anyway.
+        regs::Register { name: "LSR", offset: 5, size: 1,
+                         read_has_side_effects: false, fields: &[
+                             field("DR", 0, 1), field("OE", 1, 1),
+                             field("PE", 2, 1), field("FE", 3, 1),
+                             field("BI", 4, 1), field("THRE", 5, 1),
+                             field("TEMT", 6, 1), field("FIFOERR", 7, 1),
+                         ] },
+        // Reading MSR clears the delta bits.
+        regs::Register { name: "MSR", offset: 6, size: 1,
+                         read_has_side_effects: true, fields: &[
+                             field("DCTS", 0, 1), field("DDSR", 1, 1),
+                             field("TERI", 2, 1), field("DDCD", 3, 1),
+                             field("CTS", 4, 1), field("DSR", 5, 1),
+                             field("RI", 6, 1), field("DCD", 7, 1),
+                         ] },
+        regs::Register { name: "SCR", offset: 7, size: 1,
+                         read_has_side_effects: false, fields: &[] },
+    ],
+};
+
+/// The base I/O port of COM1.
+pub const COM1_BASE: u16 = 0x03F8;
+
+/// Set up COM1 as an early console, at `baud` if given, so that we can
+/// report progress before anything else works.  This needs no heap and no
+/// interrupts.  Call it first thing, while nobody else can be holding
+/// `COM1`.
+pub fn early_initialize(baud: Option<u32>) -> Result<(), &'static str> {
+    let mut config = DEFAULT_CONFIG;
+    if let Some(baud) = baud {
+        config.baud = baud;
+    }
+    try!(COM1.lock().configure(config));
+    EARLY.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Stop writing to the early console.
+pub fn early_disable() {
+    EARLY.store(false, Ordering::SeqCst);
+}
+
+/// Write a line to COM1 if the early console is on.  We don't take any
+/// locks, so this works even if we fault while somebody holds one, at the
+/// risk of interleaving with their output.  Use `early_println!`.
+pub fn early_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    if EARLY.load(Ordering::SeqCst) {
+        let mut port = unsafe { raw_com1() };
+        let _ = write!(port, "{}\n", args);
+    }
+}
+
+/// Get a second handle to COM1 which bypasses the `COM1` lock.  This is
+/// only for panic handlers and the `kassert!` breakpoint handler, which
+/// can't wait for a lock that may never be released.  It uses COM1's
+/// current configuration, including flow control.
+pub unsafe fn raw_com1() -> ComPort {
+    let mut port = ComPort::new(COM1_BASE);
+    // Don't reset the baud rate out from under whoever is listening.
+    port.initialized = COM1_READY.load(Ordering::SeqCst);
+    port.config = COM1_CONFIG.read();
+    port
+}
@@ -0,0 +1,240 @@
+//! The 8253/8254 programmable interval timer, and our sense of time.
+//!
+//! By default we program the PIT to interrupt us `DEFAULT_HZ` times a
+//! second.  Boot with `timer_hz=N` to pick another rate, and with
+//! `tickless` to stop the periodic interrupt while we're idle: instead,
+//! `idle` programs a one-shot interrupt for the next deadline anybody has
+//! asked for with `request_wakeup`.  The PIT's 16-bit counter limits a
+//! single sleep to about 55ms, which is still a big improvement over a
+//! fast periodic tick when running under QEMU.
+//!
+//! We keep time by counting PIT input clocks, so uptime stays correct
+//! whatever rate we run at and however long we sleep.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_BOOL_INIT,
+                         ATOMIC_USIZE_INIT, Ordering};
+use core::usize;
+use cpuio::Port;
+use spin::Mutex;
+use x86;
+
+use arch::x86_64::multiboot;
+
+/// The frequency of the PIT's input clock.
+const PIT_HZ: usize = 1193182;
+
+/// The timer frequency we use unless the command line says otherwise.
+const DEFAULT_HZ: usize = 100;
+
+/// The largest count we can load into the PIT.  (Loading 0 means 65536,
+/// but we don't bother.)
+const MAX_COUNT: usize = 0xFFFF;
+
+/// PIT mode commands for channel 0, low byte then high byte.
+const MODE_ONE_SHOT: u8 = 0x30;
+const MODE_RATE_GENERATOR: u8 = 0x34;
+
+/// Latch the current count of channel 0 so we can read it.
+const LATCH_COUNT: u8 = 0x00;
+
+/// Total PIT input clocks which have elapsed as of the last timer
+/// interrupt.
+static ELAPSED_CLOCKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The count we use in periodic mode.  This lives outside `PIT` so that
+/// code running with interrupts enabled can read it without taking a lock
+/// which the timer interrupt also needs.
+static PERIOD: AtomicUsize = AtomicUsize::new(MAX_COUNT);
+
+/// The number of timer interrupts we've handled.
+static TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The earliest time, in microseconds of uptime, that somebody wants us
+/// to be awake.  `usize::MAX` means nobody has asked.
+static NEXT_WAKEUP_US: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Should we stop the periodic tick while idle?
+static TICKLESS: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The hardware timer and how we've currently programmed it.
+struct Pit {
+    command: Port<u8>,
+    channel0: Port<u8>,
+    /// If we're in one-shot mode, the count we loaded.
+    one_shot: Option<usize>,
+}
+
+impl Pit {
+    /// Load `count` into channel 0 using `mode`.
+    fn program(&mut self, mode: u8, count: usize) {
+        self.command.write(mode);
+        self.channel0.write(count as u8);
+        self.channel0.write((count >> 8) as u8);
+    }
+
+    /// Read the current count of channel 0.
+    fn read_count(&mut self) -> usize {
+        self.command.write(LATCH_COUNT);
+        let lo = self.channel0.read() as usize;
+        let hi = self.channel0.read() as usize;
+        hi << 8 | lo
+    }
+
+    /// Go back to interrupting us every `PERIOD` clocks.
+    fn start_periodic(&mut self) {
+        self.program(MODE_RATE_GENERATOR, period());
+        self.one_shot = None;
+    }
+
+    /// Interrupt us once, `count` clocks from now.
+    fn start_one_shot(&mut self, count: usize) {
+        self.program(MODE_ONE_SHOT, count);
+        self.one_shot = Some(count);
+    }
+}
+
+static PIT: Mutex<Pit> = Mutex::new(Pit {
+    command: unsafe { Port::new(0x43) },
+    channel0: unsafe { Port::new(0x40) },
+    one_shot: None,
+});
+
+/// Convert PIT input clocks to microseconds, without overflowing for a
+/// very long time.
+fn clocks_to_us(clocks: usize) -> usize {
+    clocks / PIT_HZ * 1000000 + clocks % PIT_HZ * 1000000 / PIT_HZ
+}
+
+/// Convert microseconds to PIT input clocks.
+fn us_to_clocks(us: usize) -> usize {
+    us / 1000000 * PIT_HZ + us % 1000000 * PIT_HZ / 1000000
+}
+
+/// Choose our timer frequency from the command line, and start the PIT.
+/// Call this before enabling interrupts.
+pub fn initialize() {
+    let info = multiboot::info();
+    let hz = match info.and_then(|i| i.option("timer_hz")) {
+        None => DEFAULT_HZ,
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(hz) if hz > PIT_HZ / MAX_COUNT && hz <= PIT_HZ => hz,
+            _ => {
+                println!("timer_hz: expected {} to {}, got {}",
+                         PIT_HZ / MAX_COUNT + 1, PIT_HZ, arg);
+                DEFAULT_HZ
+            }
+        },
+    };
+    TICKLESS.store(info.and_then(|i| i.option("tickless")).is_some(),
+                   Ordering::SeqCst);
+
+    PERIOD.store(PIT_HZ / hz, Ordering::SeqCst);
+    PIT.lock().start_periodic();
+}
+
+/// The count we use in periodic mode.
+fn period() -> usize {
+    PERIOD.load(Ordering::SeqCst)
+}
+
+/// The actual frequency of our periodic timer interrupt, in millihertz.
+pub fn frequency_millihertz() -> usize {
+    PIT_HZ * 1000 / period()
+}
+
+/// Are we stopping the periodic tick while idle?
+pub fn is_tickless() -> bool {
+    TICKLESS.load(Ordering::SeqCst)
+}
+
+/// The number of timer interrupts since we started the timer.  Don't use
+/// this to measure time, because the interval between ticks varies.
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Time since we started the timer, in microseconds, accurate to one
+/// timer interrupt.
+pub fn uptime_us() -> usize {
+    clocks_to_us(ELAPSED_CLOCKS.load(Ordering::SeqCst))
+}
+
+/// Time since we started the timer, in milliseconds.
+pub fn uptime_ms() -> usize {
+    uptime_us() / 1000
+}
+
+/// Ask to be woken up no later than `uptime_us() == at_us`.  Requests
+/// are forgotten once they're due, so periodic work should ask again
+/// each time it runs.
+pub fn request_wakeup(at_us: usize) {
+    let mut current = NEXT_WAKEUP_US.load(Ordering::SeqCst);
+    while at_us < current {
+        let previous = NEXT_WAKEUP_US.compare_and_swap(current, at_us,
+                                                      Ordering::SeqCst);
+        if previous == current { break; }
+        current = previous;
+    }
+}
+
+/// Called from our interrupt handler on every timer interrupt.
+pub fn handle_interrupt() {
+    let mut pit = PIT.lock();
+    let elapsed = match pit.one_shot {
+        Some(count) => {
+            // Our sleep is over, so start ticking again.
+            pit.start_periodic();
+            count
+        }
+        None => period(),
+    };
+    ELAPSED_CLOCKS.fetch_add(elapsed, Ordering::SeqCst);
+    TICKS.fetch_add(1, Ordering::Relaxed);
+
+    if uptime_us() >= NEXT_WAKEUP_US.load(Ordering::SeqCst) {
+        NEXT_WAKEUP_US.store(usize::MAX, Ordering::SeqCst);
+    }
+}
+
+/// Wait for the next interrupt.  Call this with interrupts enabled when
+/// there's nothing to do.  In tickless mode, we sleep until the next
+/// requested wakeup, or as long as the PIT allows.
+pub fn idle() {
+    unsafe { x86::irq::disable(); }
+
+    let now = uptime_us();
+    let wakeup = NEXT_WAKEUP_US.load(Ordering::SeqCst);
+    let count = if wakeup > now { us_to_clocks(wakeup - now) } else { 0 };
+
+    let mut pit = PIT.lock();
+    if !is_tickless() || count <= period() {
+        // We'd wake up before the next tick anyway.
+        drop(pit);
+        unsafe { asm!("sti; hlt" :::: "volatile"); }
+        return;
+    }
+
+    let count = if count > MAX_COUNT { MAX_COUNT } else { count };
+    pit.start_one_shot(count);
+    drop(pit);
+
+    // `sti` doesn't take effect until after the next instruction, so no
+    // interrupt can sneak in before we `hlt`.
+    unsafe {
+        asm!("sti; hlt" :::: "volatile");
+        x86::irq::disable();
+    }
+
+    // If something other than the timer woke us, account for the time we
+    // slept and resume the periodic tick.
+    let mut pit = PIT.lock();
+    if let Some(count) = pit.one_shot {
+        let remaining = pit.read_count();
+        let slept = if remaining <= count { count - remaining } else { 0 };
+        ELAPSED_CLOCKS.fetch_add(slept, Ordering::SeqCst);
+        pit.start_periodic();
+    }
+    drop(pit);
+
+    unsafe { x86::irq::enable(); }
+}
@@ -0,0 +1,286 @@
+//! A driver for the Bochs VBE "dispi" interface, which lets us switch the
+//! display into a high-resolution mode with a linear framebuffer.
+//!
+//! Bochs and QEMU's standard VGA (`-vga std`, PCI ID 1234:1111) implement
+//! this interface on I/O ports 0x1CE and 0x1CF.  QEMU's default Cirrus
+//! CL-GD5446 doesn't: it only offers graphics modes through its own
+//! registers, so on Cirrus we report that there's no VBE support and stay
+//! in text mode.
+//!
+//! We only switch modes when booted with the `vbe` option, after which
+//! the console moves to `fbterm`.  Before switching, we save the VGA
+//! text-mode registers and font, so that we can put them back if anything
+//! goes wrong, or if we panic.
+//!
+//! See http://wiki.osdev.org/Bochs_VBE_Extensions.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use cpuio::Port;
+use spin::Mutex;
+
+use arch::x86_64::multiboot;
+use arch::x86_64::paging;
+use arch::x86_64::pci::{DeviceMatch, Driver, FunctionInfo};
+use arch::x86_64::vga::{self, Color, Font, ModeRegisters, MODE_REGISTERS_INIT};
+use config;
+use sync::SeqLock;
+
+/// The mode we ask for.
+const WIDTH: usize = 1024;
+const HEIGHT: usize = 768;
+const BITS_PER_PIXEL: usize = 32;
+
+/// Registers of the dispi interface, selected by writing to the index
+/// port.
+const INDEX_ID: u16 = 0x0;
+const INDEX_XRES: u16 = 0x1;
+const INDEX_YRES: u16 = 0x2;
+const INDEX_BPP: u16 = 0x3;
+const INDEX_ENABLE: u16 = 0x4;
+const INDEX_VIRT_WIDTH: u16 = 0x6;
+const INDEX_X_OFFSET: u16 = 0x8;
+const INDEX_Y_OFFSET: u16 = 0x9;
+
+/// The range of interface versions we know about.  We need at least
+/// version 2 for 32-bit color and the linear framebuffer.
+const ID_MIN: u16 = 0xB0C2;
+const ID_MAX: u16 = 0xB0C5;
+
+/// Bits of `INDEX_ENABLE`.
+const ENABLED: u16 = 0x01;
+const LINEAR_FRAMEBUFFER: u16 = 0x40;
+
+/// The registers of the dispi interface.
+struct Dispi {
+    index: Port<u16>,
+    data: Port<u16>,
+}
+
+impl Dispi {
+    fn read(&mut self, index: u16) -> u16 {
+        self.index.write(index);
+        self.data.read()
+    }
+
+    fn write(&mut self, index: u16, value: u16) {
+        self.index.write(index);
+        self.data.write(value);
+    }
+}
+
+static DISPI: Mutex<Dispi> = Mutex::new(Dispi {
+    index: unsafe { Port::new(0x1CE) },
+    data: unsafe { Port::new(0x1CF) },
+});
+
+/// A linear framebuffer with 32-bit pixels, in `0x00RRGGBB` format.
+#[derive(Debug, Clone, Copy)]
+pub struct Framebuffer {
+    /// The virtual address of the top-left pixel.
+    address: usize,
+    width: usize,
+    height: usize,
+    /// The number of bytes from the start of one row to the next.
+    pitch: usize,
+}
+
+impl Framebuffer {
+    /// The width of the framebuffer, in pixels.
+    pub fn width(&self) -> usize { self.width }
+
+    /// The height of the framebuffer, in pixels.
+    pub fn height(&self) -> usize { self.height }
+
+    /// The number of bytes from the start of one row to the next.
+    pub fn pitch(&self) -> usize { self.pitch }
+
+    /// The address of the start of row `y`.
+    pub fn row(&self, y: usize) -> *mut u32 {
+        (self.address + y * self.pitch) as *mut u32
+    }
+
+    /// Set the pixel at `x`, `y` to `color`.  Pixels outside the
+    /// framebuffer are ignored.
+    pub fn put_pixel(&self, x: usize, y: usize, color: u32) {
+        if x < self.width && y < self.height {
+            unsafe { ptr::write_volatile(self.row(y).offset(x as isize), color); }
+        }
+    }
+
+    /// Fill a rectangle with `color`, clipping it to the framebuffer.
+    pub fn fill_rect(&self, x: usize, y: usize, width: usize, height: usize,
+                     color: u32) {
+        let x_end = if x + width < self.width { x + width } else { self.width };
+        let y_end = if y + height < self.height { y + height } else { self.height };
+        for row_y in y..y_end {
+            let row = self.row(row_y);
+            for col_x in x..x_end {
+                unsafe { ptr::write_volatile(row.offset(col_x as isize), color); }
+            }
+        }
+    }
+}
+
+/// Our framebuffer, once we've switched modes.  This is read every time
+/// anybody draws, and only written when we switch modes.
+static FRAMEBUFFER: SeqLock<Option<Framebuffer>> = SeqLock::new(None);
+
+/// The VGA text-mode font, saved before we switch modes, because the
+/// framebuffer overwrites the video memory where it lives.
+static TEXT_FONT: Mutex<Font> = Mutex::new([0; vga::FONT_SIZE]);
+
+/// The VGA text-mode registers, saved before we switch modes, because
+/// switching the dispi interface off doesn't restore them.
+static TEXT_MODE: Mutex<ModeRegisters> = Mutex::new(MODE_REGISTERS_INIT);
+
+/// Have we switched modes?  Unlike `FRAMEBUFFER`, we can check this
+/// without a lock.
+static FRAMEBUFFER_ACTIVE: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Our framebuffer, if we're in graphics mode.
+pub fn framebuffer() -> Option<Framebuffer> {
+    FRAMEBUFFER.read()
+}
+
+/// Switch into our graphics mode, with the framebuffer at physical
+/// address `phys`.
+unsafe fn set_mode(phys: usize) -> Result<Framebuffer, &'static str> {
+    let mut dispi = DISPI.lock();
+    let pitch = WIDTH * BITS_PER_PIXEL / 8;
+
+    dispi.write(INDEX_ENABLE, 0);
+    dispi.write(INDEX_XRES, WIDTH as u16);
+    dispi.write(INDEX_YRES, HEIGHT as u16);
+    dispi.write(INDEX_BPP, BITS_PER_PIXEL as u16);
+    dispi.write(INDEX_ENABLE, ENABLED | LINEAR_FRAMEBUFFER);
+
+    // The card quietly clamps modes it can't do, such as when it doesn't
+    // have enough video memory, so make sure we got what we asked for.
+    if dispi.read(INDEX_XRES) != WIDTH as u16 ||
+        dispi.read(INDEX_YRES) != HEIGHT as u16 ||
+        dispi.read(INDEX_BPP) != BITS_PER_PIXEL as u16 ||
+        dispi.read(INDEX_VIRT_WIDTH) != WIDTH as u16
+    {
+        leave_graphics(&mut dispi, &TEXT_MODE.lock(), &TEXT_FONT.lock());
+        return Err("display refused 1024x768x32");
+    }
+    dispi.write(INDEX_X_OFFSET, 0);
+    dispi.write(INDEX_Y_OFFSET, 0);
+
+    // Only map the framebuffer once we know we have one, so that we
+    // don't leave a mapping behind for a mode we couldn't use.
+    let address = match paging::map_mmio_large(phys, pitch * HEIGHT) {
+        Ok(address) => address,
+        Err(err) => {
+            leave_graphics(&mut dispi, &TEXT_MODE.lock(), &TEXT_FONT.lock());
+            return Err(err);
+        }
+    };
+
+    Ok(Framebuffer {
+        address: address,
+        width: WIDTH,
+        height: HEIGHT,
+        pitch: pitch,
+    })
+}
+
+/// Call `f` with the VGA text-mode font as it was before we switched
+/// modes, so that a framebuffer console can look the same.  Returns
+/// `None` if we're not in a graphics mode.
+pub fn with_text_font<R, F: FnOnce(&Font) -> R>(f: F) -> Option<R> {
+    if FRAMEBUFFER_ACTIVE.load(Ordering::SeqCst) {
+        Some(f(&TEXT_FONT.lock()))
+    } else {
+        None
+    }
+}
+
+/// Switch the dispi interface off, and put back the text-mode registers
+/// and font we saved before switching modes.  The framebuffer has
+/// overwritten the text buffer, so the caller should clear the screen.
+unsafe fn leave_graphics(dispi: &mut Dispi, mode: &ModeRegisters, font: &Font) {
+    dispi.write(INDEX_ENABLE, 0);
+    vga::restore_mode(mode);
+    vga::force_font(font);
+}
+
+/// Go back to VGA text mode, if we've left it, with a blank screen.
+/// Stop drawing on the framebuffer with `fbterm` first.
+pub fn restore_text_mode() {
+    if FRAMEBUFFER.read().is_none() {
+        return;
+    }
+    // Keep everybody off the text buffer while the font plane is mapped.
+    let mut screen = vga::SCREEN.lock();
+    unsafe {
+        leave_graphics(&mut DISPI.lock(), &TEXT_MODE.lock(), &TEXT_FONT.lock());
+    }
+    FRAMEBUFFER.write(None);
+    FRAMEBUFFER_ACTIVE.store(false, Ordering::SeqCst);
+    screen.clear(Color::Black);
+}
+
+/// Go back to VGA text mode without waiting for any locks, for our panic
+/// screen.  Harmless if we never left text mode.  If we panicked while
+/// somebody held the saved font or registers, we just switch the dispi
+/// interface off and hope for the best.
+pub unsafe fn force_text_mode() {
+    if !FRAMEBUFFER_ACTIVE.load(Ordering::SeqCst) {
+        return;
+    }
+    let mut dispi = Dispi {
+        index: Port::new(0x1CE),
+        data: Port::new(0x1CF),
+    };
+    match (TEXT_MODE.try_lock(), TEXT_FONT.try_lock()) {
+        (Some(mode), Some(font)) => leave_graphics(&mut dispi, &mode, &font),
+        _ => dispi.write(INDEX_ENABLE, 0),
+    }
+}
+
+/// Make sure the dispi interface is there, find the framebuffer and, if
+/// we were asked to, switch modes.
+fn probe(function: &FunctionInfo) -> Result<(), &'static str> {
+    let id = DISPI.lock().read(INDEX_ID);
+    if id < ID_MIN || id > ID_MAX {
+        return Err("no Bochs VBE interface (try QEMU's -vga std)");
+    }
+
+    // The framebuffer is the memory BAR 0.
+    let bar = function.bar(0);
+    if bar & 1 != 0 {
+        return Err("BAR 0 isn't a framebuffer");
+    }
+    let phys = (bar & !0xF) as usize;
+
+    let wanted = config::GRAPHICS &&
+        multiboot::info().and_then(|i| i.option("vbe")).is_some();
+    if !wanted || FRAMEBUFFER.read().is_some() {
+        return Ok(());
+    }
+    vga::get_font(&mut TEXT_FONT.lock());
+    *TEXT_MODE.lock() = vga::save_mode();
+    let framebuffer = try!(unsafe { set_mode(phys) });
+    framebuffer.fill_rect(0, 0, WIDTH, HEIGHT, 0);
+    FRAMEBUFFER.write(Some(framebuffer));
+    FRAMEBUFFER_ACTIVE.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Our PCI driver.
+pub static DRIVER: Driver = Driver {
+    name: "vbe",
+    matches: &[
+        // QEMU's standard VGA and Bochs.
+        DeviceMatch::Id { vendor: 0x1234, device: 0x1111 },
+        // QEMU's Cirrus, which we'll decline politely.
+        DeviceMatch::Id { vendor: 0x1013, device: 0x00b8 },
+    ],
+    probe: probe,
+    // Displays don't come and go, and there's nowhere to move the
+    // console to.
+    remove: None,
+    registers: None,
+};
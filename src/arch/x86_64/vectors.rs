@@ -0,0 +1,113 @@
+//! Who owns which interrupt vector.
+//!
+//! Vectors 0x00-0x1F are CPU exceptions, and we remap the PIC's IRQs to
+//! 0x20-0x2F.  Everything else is free for software interrupts, such as our
+//! system call gate, or for future interrupt sources like MSI or IPIs.
+//! Rather than pick numbers by hand and hope nobody else did, subsystems
+//! should `reserve` the vectors they hard-code, and `allocate` the rest.
+
+use spin::Mutex;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use arch::x86_64::multiboot;
+use util;
+
+/// The number of interrupt vectors.
+const VECTOR_COUNT: usize = 256;
+
+/// The first vector after the PIC's.  `allocate` hands out vectors from
+/// here up.
+pub const FIRST_FREE: u8 = 0x30;
+
+/// The system call vector we use unless `syscall=<vector>` is on the
+/// kernel command line.
+pub const DEFAULT_SYSCALL: u8 = 0x80;
+
+/// A vector which is in use.
+#[derive(Clone, Copy)]
+struct Owner {
+    name: &'static str,
+    /// Called by `dispatch` for vectors handed out by `allocate`.
+    handler: Option<fn(u8)>,
+}
+
+static OWNERS: Mutex<[Option<Owner>; VECTOR_COUNT]> =
+    Mutex::new([None; VECTOR_COUNT]);
+
+/// Our system call vector.
+static SYSCALL: AtomicUsize = AtomicUsize::new(DEFAULT_SYSCALL as usize);
+
+/// The vector for our system call gate.
+pub fn syscall() -> u8 {
+    SYSCALL.load(Ordering::Relaxed) as u8
+}
+
+/// Record that `name` uses `vector`, which was chosen by hand.
+pub fn reserve(vector: u8, name: &'static str) -> Result<(), &'static str> {
+    let mut owners = OWNERS.lock();
+    if owners[vector as usize].is_some() {
+        return Err("interrupt vector already in use");
+    }
+    owners[vector as usize] = Some(Owner { name: name, handler: None });
+    Ok(())
+}
+
+/// Find an unused vector for `name`, whose interrupts will be passed to
+/// `handler`.  Call this with interrupts disabled, because interrupt
+/// handlers look at our table.
+pub fn allocate(name: &'static str, handler: fn(u8)) -> Result<u8, &'static str> {
+    let mut owners = OWNERS.lock();
+    let vector = try!((FIRST_FREE as usize..VECTOR_COUNT)
+                      .find(|&v| owners[v].is_none())
+                      .ok_or("no free interrupt vectors"));
+    owners[vector] = Some(Owner { name: name, handler: Some(handler) });
+    Ok(vector as u8)
+}
+
+/// Give back a vector from `reserve` or `allocate`.
+pub fn free(vector: u8) {
+    OWNERS.lock()[vector as usize] = None;
+}
+
+/// Who owns `vector`, if anybody.
+pub fn owner(vector: u8) -> Option<&'static str> {
+    OWNERS.lock()[vector as usize].map(|owner| owner.name)
+}
+
+/// Pass an interrupt on `vector` to the handler it was allocated with.
+/// Returns false if it has none.
+pub fn dispatch(vector: u8) -> bool {
+    let handler = OWNERS.lock()[vector as usize].and_then(|owner| owner.handler);
+    match handler {
+        Some(handler) => { handler(vector); true }
+        None => false,
+    }
+}
+
+/// Reserve the exceptions, the PIC's IRQs and our system call gate.
+/// `irq_owners` names the users of particular PIC vectors.
+pub fn initialize(irq_owners: &[(u8, &'static str)]) {
+    for vector in 0x00..0x20 {
+        let _ = reserve(vector, "CPU exception");
+    }
+    for &(vector, name) in irq_owners {
+        let _ = reserve(vector, name);
+    }
+    for vector in 0x20..FIRST_FREE {
+        let _ = reserve(vector, "PIC IRQ");
+    }
+
+    let wanted = multiboot::info().and_then(|i| i.option("syscall"))
+        .and_then(util::parse_number);
+    if let Some(vector) = wanted {
+        if vector < FIRST_FREE as usize || vector >= VECTOR_COUNT {
+            println!("syscall: vector {:#x} is out of range; using {:#x}",
+                     vector, DEFAULT_SYSCALL);
+        } else {
+            SYSCALL.store(vector, Ordering::Relaxed);
+        }
+    }
+    if let Err(err) = reserve(syscall(), "syscall") {
+        println!("syscall: {}", err);
+    }
+}
@@ -1,12 +1,25 @@
 // The spin::Mutex + Uniq trick here is directly based on
 // http://blog.phil-opp.com/rust-os/printing-to-screen.html
 
-use core::fmt::{Write, Result};
-use core::ptr::Unique;
+use core::cmp::min;
+use core::fmt::{self, Write};
+use core::mem::size_of;
+use core::ptr::{self, Unique};
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use collections::vec::Vec;
+use collections::vec_deque::VecDeque;
 use spin::Mutex;
+use cpuio;
 
-const WIDTH: usize = 80;
-const HEIGHT: usize = 25;
+use arch::x86_64::paging;
+use cp437;
+
+/// The physical address of the text-mode buffer.
+pub const TEXT_BUFFER_ADDR: usize = 0xb8000;
+
+/// The size of our text-mode screen, in characters.
+pub const WIDTH: usize = 80;
+pub const HEIGHT: usize = 25;
 
 /// Standard VGA colors.
 #[derive(Copy, Clone)]
@@ -42,6 +55,12 @@ impl ColorScheme {
     pub const fn new(fore: Color, back: Color) -> Self {
         ColorScheme { value: (back as u8) << 4 | (fore as u8) }
     }
+
+    /// The foreground color, as a palette index from 0 to 15.
+    pub fn foreground(&self) -> u8 { self.value & 0xF }
+
+    /// The background color, as a palette index from 0 to 15.
+    pub fn background(&self) -> u8 { self.value >> 4 }
 }
 
 /// A colored VGA character.
@@ -52,29 +71,170 @@ pub struct Char {
     pub colors: ColorScheme,
 }
 
-type Buffer = [[Char; WIDTH]; HEIGHT];
+impl Char {
+    /// Create a new character.
+    pub const fn new(code: u8, colors: ColorScheme) -> Char {
+        Char { code: code, colors: colors }
+    }
+
+    /// The raw 16-bit value of this character, exactly as it's stored in
+    /// video memory.
+    fn to_u16(&self) -> u16 {
+        (self.colors.value as u16) << 8 | self.code as u16
+    }
+}
+
+/// A rectangular region of the screen, measured in characters.
+#[derive(Copy, Clone, Debug)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    /// Create a new rectangle with its top-left corner at `x`, `y`.
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Rect {
+        Rect { x: x, y: y, width: width, height: height }
+    }
+}
+
+type Line = [Char; WIDTH];
+type Buffer = [Line; HEIGHT];
+
+/// Lines which have scrolled off the top of the screen, plus what we need
+/// to let the user page back through them.  This lives on the heap, so
+/// it's only available once `enable_scrollback` has been called.
+struct Scrollback {
+    /// Old lines, oldest first.
+    history: VecDeque<Line>,
+    /// The maximum number of lines to keep in `history`.
+    limit: usize,
+    /// How many lines back from the live screen we're currently showing.
+    /// When this is zero, we draw directly to video memory.
+    offset: usize,
+    /// While `offset` is non-zero, normal output is drawn here instead of
+    /// to video memory, so that it doesn't overwrite what the user is
+    /// reading.
+    live: Vec<Line>,
+}
 
 /// A VGA screen, in character mode.
 pub struct Screen {
     colors: ColorScheme,
     x: usize,
     y: usize,
+    /// The number of rows at the top of the screen used for normal text
+    /// output.  Anything below this is left alone when we scroll.
+    text_height: usize,
     buffer: Unique<Buffer>,
+    scrollback: Option<Scrollback>,
 }
 
 impl Screen {
     /// Clear the screen to the specified color.
     pub fn clear(&mut self, color: Color) -> &mut Self {
+        self.scroll_to_live();
         let colors = ColorScheme::new(color, color);
-        let c = Char {
-            code: b' ',
-            colors: colors,
-        };
-        for y in 0..HEIGHT {
-            for x in 0..WIDTH {
-                self.buffer()[y][x] = c;
+        self.fill_region(Rect::new(0, 0, WIDTH, HEIGHT), b' ', colors);
+        self
+    }
+
+    /// Write `text` at the specified location using `colors`, without
+    /// moving the cursor or interpreting control characters.  Anything
+    /// which falls off the right edge of the screen is discarded.
+    pub fn write_str_at(&mut self, x: usize, y: usize, text: &str,
+                        colors: ColorScheme) {
+        let mut row = [Char::new(b' ', colors); WIDTH];
+        let count = min(text.chars().count(), WIDTH);
+        for (cell, c) in row.iter_mut().zip(text.chars()) {
+            cell.code = cp437::from_char(c);
+        }
+        self.write_cells(x, y, &row[..count]);
+    }
+
+    /// The size of the screen in characters, as `(width, height)`.  Use
+    /// this instead of `WIDTH` and `HEIGHT` when drawing, so that it keeps
+    /// working if we ever support other text modes.  They're still fine
+    /// as upper bounds, such as for the size of a line buffer.
+    pub fn size(&self) -> (usize, usize) {
+        (WIDTH, HEIGHT)
+    }
+
+    /// Draw `c` at the specified location, without moving the cursor.
+    /// Unlike our other drawing functions, which clip silently, this
+    /// returns an error if the location is off the screen.
+    pub fn put(&mut self, x: usize, y: usize, c: Char)
+        -> Result<(), &'static str>
+    {
+        let (width, height) = self.size();
+        if x >= width || y >= height {
+            return Err("position is off the screen");
+        }
+        self.write_cells(x, y, &[c]);
+        Ok(())
+    }
+
+    /// Fill `rect` with the character `code` using `colors`.  The
+    /// rectangle is clipped to the screen.
+    pub fn fill_region(&mut self, rect: Rect, code: u8, colors: ColorScheme) {
+        let row = [Char::new(code, colors); WIDTH];
+        let width = min(rect.width, WIDTH);
+        for y in rect.y..min(rect.y + rect.height, HEIGHT) {
+            self.write_cells(rect.x, y, &row[..width]);
+        }
+    }
+
+    /// Copy `cells` into row `y` starting at column `x`, clipping at the
+    /// right edge of the screen.  This is our fast path: we write four
+    /// characters at a time as a single volatile `u64`, instead of going
+    /// through `write_byte` one character at a time.
+    ///
+    /// This always writes straight to video memory, even while scrollback
+    /// is being shown, so it's best used outside the text area.
+    fn write_cells(&mut self, x: usize, y: usize, cells: &[Char]) {
+        if x >= WIDTH || y >= HEIGHT { return; }
+        let count = min(cells.len(), WIDTH - x);
+        let dest = &mut self.buffer()[y][x] as *mut Char as *mut u16;
+
+        unsafe {
+            // Write single characters until we're 8-byte aligned.
+            let mut i = 0;
+            while i < count && (dest.offset(i as isize) as usize) % 8 != 0 {
+                ptr::write_volatile(dest.offset(i as isize), cells[i].to_u16());
+                i += 1;
+            }
+
+            // Write four characters at a time.
+            while i + 4 <= count {
+                let word =
+                    (cells[i].to_u16() as u64)
+                    | (cells[i+1].to_u16() as u64) << 16
+                    | (cells[i+2].to_u16() as u64) << 32
+                    | (cells[i+3].to_u16() as u64) << 48;
+                ptr::write_volatile(dest.offset(i as isize) as *mut u64, word);
+                i += 4;
+            }
+
+            // Mop up whatever is left over.
+            while i < count {
+                ptr::write_volatile(dest.offset(i as isize), cells[i].to_u16());
+                i += 1;
             }
         }
+    }
+
+    /// Limit normal text output and scrolling to the top `height` rows of
+    /// the screen, leaving the rows below free for things like status
+    /// bars.
+    pub fn set_text_height(&mut self, height: usize) -> &mut Self {
+        assert!(0 < height && height <= HEIGHT);
+        self.scroll_to_live();
+        self.text_height = height;
+        if self.y >= height {
+            self.y = height - 1;
+        }
         self
     }
 
@@ -84,7 +244,7 @@ impl Screen {
         self
     }
 
-    /// Write a string to the screen.
+    /// Write raw CP437 bytes to the screen.  `write_str` takes UTF-8.
     pub fn write(&mut self, text: &[u8]) {
         for c in text {
             self.write_byte(*c);
@@ -96,20 +256,24 @@ impl Screen {
         if code == b'\n' {
             self.x = 0;
             self.y += 1;
+        } else if code == b'\x08' {
+            // Backspace just moves the cursor back; it doesn't erase.
+            if self.x > 0 { self.x -= 1; }
         } else {
             let c = Char {
                 code: code,
                 colors: self.colors,
             };
-            self.buffer()[self.y][self.x] = c;
+            let (x, y) = (self.x, self.y);
+            self.text_buffer()[y][x] = c;
             self.x += 1;
             if self.x >= WIDTH {
                 self.x = 0;
                 self.y += 1;
             }
         }
-        if self.y >= HEIGHT {
-            self.y = HEIGHT - 1;
+        if self.y >= self.text_height {
+            self.y = self.text_height - 1;
             self.scroll();
         }
     }
@@ -121,26 +285,125 @@ impl Screen {
             colors: self.colors,
         };
 
+        // Save the top line before we lose it.
+        let top = self.text_buffer()[0];
+        if let Some(ref mut scrollback) = self.scrollback {
+            if scrollback.history.len() >= scrollback.limit {
+                scrollback.history.pop_front();
+            } else if scrollback.offset > 0 {
+                // Keep the user's view pointed at the same text.
+                scrollback.offset += 1;
+            }
+            scrollback.history.push_back(top);
+        }
+
         // Move existing lines up one.
-        let buffer: &mut _ = self.buffer();
-        for y in 1..HEIGHT {
+        let height = self.text_height;
+        let buffer: &mut _ = self.text_buffer();
+        for y in 1..height {
             buffer[y-1] = buffer[y];
         }
 
         // Clear the last line.
         for x in 0..WIDTH {
-            buffer[HEIGHT-1][x] = clear;
+            buffer[height-1][x] = clear;
         }
     }
 
     fn buffer(&mut self) -> &mut Buffer {
         unsafe { self.buffer.get_mut() }
     }
+
+    /// The buffer which normal text output should go to.  This is video
+    /// memory, unless the user is looking at scrollback.
+    fn text_buffer(&mut self) -> &mut [Line] {
+        if let Some(ref mut scrollback) = self.scrollback {
+            if scrollback.offset > 0 {
+                return &mut scrollback.live[..];
+            }
+        }
+        unsafe { &mut self.buffer.get_mut()[..] }
+    }
+
+    /// Start remembering up to `lines` lines of output which scroll off
+    /// the top of the screen.  This allocates, so it can't be called until
+    /// the heap is up.
+    pub fn enable_scrollback(&mut self, lines: usize) {
+        self.scroll_to_live();
+        let blank = [Char::new(b' ', self.colors); WIDTH];
+        let mut live = Vec::with_capacity(HEIGHT);
+        for _ in 0..HEIGHT {
+            live.push(blank);
+        }
+        self.scrollback = Some(Scrollback {
+            history: VecDeque::with_capacity(lines),
+            limit: lines,
+            offset: 0,
+            live: live,
+        });
+    }
+
+    /// Show the previous page of scrollback.
+    pub fn scroll_back(&mut self) {
+        let page = self.text_height;
+        self.set_scroll_offset(|offset, max| min(offset + page, max));
+    }
+
+    /// Show the next page of scrollback, or the live screen if we're
+    /// already on the last page.
+    pub fn scroll_forward(&mut self) {
+        let page = self.text_height;
+        self.set_scroll_offset(|offset, _| offset.saturating_sub(page));
+    }
+
+    /// Return to the live screen, if we're showing scrollback.
+    pub fn scroll_to_live(&mut self) {
+        self.set_scroll_offset(|_, _| 0);
+    }
+
+    /// Pick a new scrollback offset using `f`, which is passed the current
+    /// offset and the maximum allowable offset, and redraw the text area.
+    fn set_scroll_offset<F>(&mut self, f: F)
+        where F: FnOnce(usize, usize) -> usize
+    {
+        let height = self.text_height;
+        let video: &mut Buffer = unsafe { self.buffer.get_mut() };
+        let scrollback = match self.scrollback {
+            Some(ref mut scrollback) => scrollback,
+            None => return,
+        };
+
+        let old = scrollback.offset;
+        let new = f(old, scrollback.history.len());
+        if new == old {
+            return;
+        }
+
+        // Save or restore the live screen as we leave or return to it.
+        if old == 0 {
+            scrollback.live[..height].copy_from_slice(&video[..height]);
+        }
+        scrollback.offset = new;
+
+        // Redraw the text area.  Conceptually, we're showing a window onto
+        // `history` followed by `live`, ending `new` lines from the bottom.
+        let start = scrollback.history.len() - new;
+        for y in 0..height {
+            let i = start + y;
+            video[y] = if i < scrollback.history.len() {
+                scrollback.history[i]
+            } else {
+                scrollback.live[i - scrollback.history.len()]
+            };
+        }
+    }
 }
 
 impl Write for Screen {
-    fn write_str(&mut self, s: &str) -> Result {
-        self.write(s.as_bytes());
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_byte(cp437::from_char(c));
+        }
         Ok(())
     }
 }
@@ -150,5 +413,348 @@ pub static SCREEN: Mutex<Screen> = Mutex::new(Screen {
     colors: ColorScheme::new(Color::White, Color::Black),
     x: 0,
     y: 0,
-    buffer: unsafe { Unique::new(0xb8000 as *mut _) },
+    text_height: HEIGHT,
+    buffer: unsafe { Unique::new(TEXT_BUFFER_ADDR as *mut _) },
+    scrollback: None,
 });
+
+/// Map the text buffer into the device window set up by `paging`, and
+/// switch `SCREEN` over to using it.  Until this is called, we rely on
+/// `boot.asm` having identity mapped `TEXT_BUFFER_ADDR`.
+pub unsafe fn map_text_buffer() -> Result<(), &'static str> {
+    let addr = try!(paging::map_mmio(TEXT_BUFFER_ADDR, size_of::<Buffer>()));
+    SCREEN.lock().buffer = Unique::new(addr as *mut _);
+    BUFFER_ADDR.store(addr, Ordering::SeqCst);
+    Ok(())
+}
+
+/// The address that `SCREEN` is using for the text buffer, or 0 if it's
+/// still using `TEXT_BUFFER_ADDR`.  We keep a copy outside the lock for
+/// `raw_screen`.
+static BUFFER_ADDR: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Create a second `Screen` which draws straight to the text buffer,
+/// bypassing the `SCREEN` lock.  This is only for panic handlers, which
+/// can't wait for a lock that may never be released.
+pub unsafe fn raw_screen() -> Screen {
+    let addr = match BUFFER_ADDR.load(Ordering::SeqCst) {
+        0 => TEXT_BUFFER_ADDR,
+        addr => addr,
+    };
+    Screen {
+        colors: ColorScheme::new(Color::White, Color::Black),
+        x: 0,
+        y: 0,
+        text_height: HEIGHT,
+        buffer: Unique::new(addr as *mut _),
+        scrollback: None,
+    }
+}
+
+
+//=========================================================================
+//  Text-mode fonts
+//
+//  The VGA stores its text-mode font in plane 2 of video memory, which is
+//  normally hidden behind the odd/even addressing used for character and
+//  attribute bytes.  To get at it, we temporarily reprogram the sequencer
+//  and graphics controller to expose plane 2 at 0xA0000, then put
+//  everything back the way we found it.  See
+//  http://wiki.osdev.org/VGA_Fonts for the gory details.
+
+/// Number of glyphs in a VGA font.
+pub const FONT_GLYPHS: usize = 256;
+
+/// Height of each glyph in our 8x16 fonts, in scanlines.
+pub const FONT_HEIGHT: usize = 16;
+
+/// The VGA reserves 32 bytes per glyph in plane 2, no matter how tall the
+/// glyphs actually are.
+const FONT_GLYPH_STRIDE: usize = 32;
+
+/// Where plane 2 appears once we've remapped video memory.
+const FONT_PLANE_BASE: usize = 0xA0000;
+
+/// A font for 8x16 text mode: 256 glyphs, one byte per scanline.
+pub type Font = [u8; FONT_SIZE];
+
+/// The size of a `Font`, in bytes.
+pub const FONT_SIZE: usize = FONT_GLYPHS * FONT_HEIGHT;
+
+/// An indexed VGA register set, where we write a register number to one
+/// port and then access the register itself through the next.
+struct IndexedRegisters {
+    index: cpuio::UnsafePort<u8>,
+    data: cpuio::UnsafePort<u8>,
+}
+
+impl IndexedRegisters {
+    const unsafe fn new(index: u16) -> IndexedRegisters {
+        IndexedRegisters {
+            index: cpuio::UnsafePort::new(index),
+            data: cpuio::UnsafePort::new(index + 1),
+        }
+    }
+
+    unsafe fn read(&mut self, register: u8) -> u8 {
+        self.index.write(register);
+        self.data.read()
+    }
+
+    unsafe fn write(&mut self, register: u8, value: u8) {
+        self.index.write(register);
+        self.data.write(value);
+    }
+}
+
+/// Sequencer register numbers we need.
+const SEQ_MAP_MASK: u8 = 0x02;
+const SEQ_MEMORY_MODE: u8 = 0x04;
+
+/// Graphics controller register numbers we need.
+const GC_READ_MAP_SELECT: u8 = 0x04;
+const GC_MODE: u8 = 0x05;
+const GC_MISC: u8 = 0x06;
+
+/// Registers saved while we have plane 2 mapped in.
+struct SavedPlaneState {
+    map_mask: u8,
+    memory_mode: u8,
+    read_map_select: u8,
+    mode: u8,
+    misc: u8,
+}
+
+/// Map font plane 2 at `FONT_PLANE_BASE`, returning the register values
+/// we need to restore afterwards.  Nothing may write to the text buffer
+/// until `unmap_font_plane` is called, which is why our callers hold the
+/// `SCREEN` lock.
+unsafe fn map_font_plane(seq: &mut IndexedRegisters, gc: &mut IndexedRegisters)
+    -> SavedPlaneState
+{
+    let saved = SavedPlaneState {
+        map_mask: seq.read(SEQ_MAP_MASK),
+        memory_mode: seq.read(SEQ_MEMORY_MODE),
+        read_map_select: gc.read(GC_READ_MAP_SELECT),
+        mode: gc.read(GC_MODE),
+        misc: gc.read(GC_MISC),
+    };
+
+    // Write only to plane 2, with sequential addressing.
+    seq.write(SEQ_MAP_MASK, 0x04);
+    seq.write(SEQ_MEMORY_MODE, 0x06);
+
+    // Read from plane 2, turn off odd/even mode, and map video memory at
+    // 0xA0000.
+    gc.write(GC_READ_MAP_SELECT, 0x02);
+    gc.write(GC_MODE, 0x00);
+    gc.write(GC_MISC, 0x04);
+
+    saved
+}
+
+/// Undo `map_font_plane`.
+unsafe fn unmap_font_plane(seq: &mut IndexedRegisters,
+                           gc: &mut IndexedRegisters,
+                           saved: SavedPlaneState)
+{
+    seq.write(SEQ_MAP_MASK, saved.map_mask);
+    seq.write(SEQ_MEMORY_MODE, saved.memory_mode);
+    gc.write(GC_READ_MAP_SELECT, saved.read_map_select);
+    gc.write(GC_MODE, saved.mode);
+    gc.write(GC_MISC, saved.misc);
+}
+
+/// Run `f` with the font plane mapped in, and with the screen locked so
+/// that nobody tries to print while text memory is unavailable.
+fn with_font_plane<F: FnOnce(*mut u8)>(f: F) {
+    let _screen = SCREEN.lock();
+    unsafe { with_font_plane_unlocked(f); }
+}
+
+/// Like `with_font_plane`, but without locking the screen, so our caller
+/// must make sure nobody else is drawing.
+unsafe fn with_font_plane_unlocked<F: FnOnce(*mut u8)>(f: F) {
+    let mut seq = IndexedRegisters::new(0x3C4);
+    let mut gc = IndexedRegisters::new(0x3CE);
+    let saved = map_font_plane(&mut seq, &mut gc);
+    f(FONT_PLANE_BASE as *mut u8);
+    unmap_font_plane(&mut seq, &mut gc, saved);
+}
+
+/// Read the currently installed text-mode font into `font`.  This is
+/// handy if you only want to replace a few glyphs.
+pub fn get_font(font: &mut Font) {
+    with_font_plane(|plane| {
+        for glyph in 0..FONT_GLYPHS {
+            for line in 0..FONT_HEIGHT {
+                let offset = glyph * FONT_GLYPH_STRIDE + line;
+                font[glyph * FONT_HEIGHT + line] = unsafe {
+                    ptr::read_volatile(plane.offset(offset as isize))
+                };
+            }
+        }
+    });
+}
+
+/// Install a new 8x16 text-mode font.  This takes effect immediately for
+/// everything on the screen, so it can be used to add custom glyphs (box
+/// drawing characters, a boot logo, etc.) in place of existing ones.
+pub fn set_font(font: &Font) {
+    with_font_plane(|plane| unsafe { write_font(plane, font) });
+}
+
+/// Install a font without locking the screen, for our panic screen.
+pub unsafe fn force_font(font: &Font) {
+    with_font_plane_unlocked(|plane| write_font(plane, font));
+}
+
+/// Copy `font` into the font plane, mapped at `plane`.
+unsafe fn write_font(plane: *mut u8, font: &Font) {
+    for glyph in 0..FONT_GLYPHS {
+        for line in 0..FONT_GLYPH_STRIDE {
+            // Pad the unused scanlines of each glyph with zeros.
+            let value = if line < FONT_HEIGHT {
+                font[glyph * FONT_HEIGHT + line]
+            } else {
+                0
+            };
+            let offset = glyph * FONT_GLYPH_STRIDE + line;
+            ptr::write_volatile(plane.offset(offset as isize), value);
+        }
+    }
+}
+
+
+//=========================================================================
+//  Saving and restoring text mode
+//
+//  Switching to a graphics mode, whether through VBE or otherwise,
+//  reprograms most of the VGA's registers, and switching back doesn't
+//  necessarily undo that.  So we save the whole register file while we're
+//  in text mode, and write it back afterwards.  See
+//  http://wiki.osdev.org/VGA_Hardware for what the registers do.
+
+/// The number of registers in each of the VGA's register sets.
+const SEQ_REGISTERS: usize = 5;
+const CRTC_REGISTERS: usize = 25;
+const GC_REGISTERS: usize = 9;
+const ATTR_REGISTERS: usize = 21;
+
+/// CRTC registers which control write-protection of CRTC registers 0-7.
+const CRTC_END_HORIZONTAL_BLANKING: u8 = 0x03;
+const CRTC_VERTICAL_RETRACE_END: u8 = 0x11;
+
+/// Attribute controller index bit which turns the display back on.
+const ATTR_PALETTE_ADDRESS_SOURCE: u8 = 0x20;
+
+/// The VGA registers which define a display mode.
+#[derive(Clone, Copy)]
+pub struct ModeRegisters {
+    misc: u8,
+    seq: [u8; SEQ_REGISTERS],
+    crtc: [u8; CRTC_REGISTERS],
+    gc: [u8; GC_REGISTERS],
+    attr: [u8; ATTR_REGISTERS],
+}
+
+/// Registers which `save_mode` hasn't filled in yet.
+pub const MODE_REGISTERS_INIT: ModeRegisters = ModeRegisters {
+    misc: 0,
+    seq: [0; SEQ_REGISTERS],
+    crtc: [0; CRTC_REGISTERS],
+    gc: [0; GC_REGISTERS],
+    attr: [0; ATTR_REGISTERS],
+};
+
+/// The ports we need to get at the registers that aren't in an
+/// `IndexedRegisters`.
+const MISC_OUTPUT_READ: u16 = 0x3CC;
+const MISC_OUTPUT_WRITE: u16 = 0x3C2;
+const ATTR_INDEX_AND_WRITE: u16 = 0x3C0;
+const ATTR_READ: u16 = 0x3C1;
+const INPUT_STATUS_1: u16 = 0x3DA;
+
+/// Reset the attribute controller so that the next write to
+/// `ATTR_INDEX_AND_WRITE` is an index.
+unsafe fn reset_attr_flip_flop() {
+    cpuio::UnsafePort::<u8>::new(INPUT_STATUS_1).read();
+}
+
+/// Save the registers for the current display mode, which should be our
+/// text mode.
+pub fn save_mode() -> ModeRegisters {
+    let mut saved = MODE_REGISTERS_INIT;
+    unsafe {
+        let mut seq = IndexedRegisters::new(0x3C4);
+        let mut crtc = IndexedRegisters::new(0x3D4);
+        let mut gc = IndexedRegisters::new(0x3CE);
+        let mut attr_index = cpuio::UnsafePort::<u8>::new(ATTR_INDEX_AND_WRITE);
+        let mut attr_read = cpuio::UnsafePort::<u8>::new(ATTR_READ);
+
+        saved.misc = cpuio::UnsafePort::<u8>::new(MISC_OUTPUT_READ).read();
+        for (i, value) in saved.seq.iter_mut().enumerate() {
+            *value = seq.read(i as u8);
+        }
+        for (i, value) in saved.crtc.iter_mut().enumerate() {
+            *value = crtc.read(i as u8);
+        }
+        for (i, value) in saved.gc.iter_mut().enumerate() {
+            *value = gc.read(i as u8);
+        }
+        for (i, value) in saved.attr.iter_mut().enumerate() {
+            reset_attr_flip_flop();
+            attr_index.write(i as u8);
+            *value = attr_read.read();
+        }
+
+        // Selecting attribute registers blanks the display until we set
+        // this bit again.
+        reset_attr_flip_flop();
+        attr_index.write(ATTR_PALETTE_ADDRESS_SOURCE);
+    }
+    saved
+}
+
+/// Put back the display mode saved by `save_mode`.  This doesn't touch
+/// video memory, so the caller may need to restore the font and clear the
+/// screen, too.  It doesn't take any locks.
+pub unsafe fn restore_mode(saved: &ModeRegisters) {
+    let mut seq = IndexedRegisters::new(0x3C4);
+    let mut crtc = IndexedRegisters::new(0x3D4);
+    let mut gc = IndexedRegisters::new(0x3CE);
+    let mut attr = cpuio::UnsafePort::<u8>::new(ATTR_INDEX_AND_WRITE);
+
+    cpuio::UnsafePort::<u8>::new(MISC_OUTPUT_WRITE).write(saved.misc);
+    for (i, &value) in saved.seq.iter().enumerate() {
+        seq.write(i as u8, value);
+    }
+
+    // Unlock CRTC registers 0-7 before writing them, and make sure our
+    // saved values don't lock them again halfway through.
+    let unlocked = crtc.read(CRTC_VERTICAL_RETRACE_END) & !0x80;
+    crtc.write(CRTC_VERTICAL_RETRACE_END, unlocked);
+    let end_blanking = crtc.read(CRTC_END_HORIZONTAL_BLANKING) | 0x80;
+    crtc.write(CRTC_END_HORIZONTAL_BLANKING, end_blanking);
+    for (i, &value) in saved.crtc.iter().enumerate() {
+        let value = match i as u8 {
+            CRTC_END_HORIZONTAL_BLANKING => value | 0x80,
+            CRTC_VERTICAL_RETRACE_END => value & !0x80,
+            _ => value,
+        };
+        crtc.write(i as u8, value);
+    }
+    crtc.write(CRTC_VERTICAL_RETRACE_END,
+               saved.crtc[CRTC_VERTICAL_RETRACE_END as usize]);
+
+    for (i, &value) in saved.gc.iter().enumerate() {
+        gc.write(i as u8, value);
+    }
+    for (i, &value) in saved.attr.iter().enumerate() {
+        reset_attr_flip_flop();
+        attr.write(i as u8);
+        attr.write(value);
+    }
+    reset_attr_flip_flop();
+    attr.write(ATTR_PALETTE_ADDRESS_SOURCE);
+}
@@ -0,0 +1,247 @@
+//! Legacy virtio over PCI, which is what QEMU offers unless told
+//! otherwise.
+//!
+//! A legacy virtio device has a block of I/O ports at BAR 0, which we use
+//! to negotiate features and to tell the device where its virtqueues are.
+//! A virtqueue is a ring of buffer descriptors in memory shared with the
+//! device.  We put buffers on the "available" ring, the device takes them,
+//! and it gives them back on the "used" ring when it's done.
+//!
+//! We poll the used rings rather than take interrupts, and we give each
+//! descriptor a fixed buffer of its own, which keeps things simple at the
+//! cost of some copying.
+//!
+//! See the "Legacy Interface" sections of the virtio 1.0 specification.
+
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use cpuio::Port;
+
+use arch::x86_64::pci::FunctionInfo;
+use dma::DmaVec;
+
+/// Registers in the legacy I/O port block.
+const DEVICE_FEATURES: u16 = 0x00;
+const GUEST_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0C;
+const QUEUE_SELECT: u16 = 0x0E;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+
+/// Where device-specific configuration starts, when MSI-X is off.
+pub const DEVICE_CONFIG: u16 = 0x14;
+
+/// Bits of `DEVICE_STATUS`.
+const ACKNOWLEDGE: u8 = 0x01;
+const DRIVER: u8 = 0x02;
+const DRIVER_OK: u8 = 0x04;
+const FAILED: u8 = 0x80;
+
+/// The legacy interface gives queue addresses as page numbers, and aligns
+/// the used ring to a page.
+const QUEUE_ALIGN: usize = 4096;
+
+/// Bits of a descriptor's `flags`.
+const DESC_WRITE: u16 = 0x02;
+
+/// The size of a descriptor.
+const DESC_SIZE: usize = 16;
+
+/// The legacy register block of a virtio device.
+pub struct Transport {
+    base: u16,
+}
+
+impl Transport {
+    /// Reset the device at `function` and announce that we have a driver
+    /// for it.
+    pub unsafe fn new(function: &FunctionInfo) -> Result<Transport, &'static str> {
+        let bar = function.bar(0);
+        if bar & 1 == 0 {
+            return Err("BAR 0 isn't an I/O port (is this a modern-only device?)");
+        }
+        function.enable_bus_master();
+
+        let transport = Transport { base: (bar & !0b11) as u16 };
+        transport.set_status(0);
+        transport.set_status(ACKNOWLEDGE);
+        transport.set_status(ACKNOWLEDGE | DRIVER);
+        Ok(transport)
+    }
+
+    unsafe fn port<T>(&self, offset: u16) -> Port<T> {
+        Port::new(self.base + offset)
+    }
+
+    fn set_status(&self, status: u8) {
+        unsafe { self.port::<u8>(DEVICE_STATUS).write(status); }
+    }
+
+    fn status(&self) -> u8 {
+        unsafe { self.port::<u8>(DEVICE_STATUS).read() }
+    }
+
+    /// The features the device offers.
+    pub fn device_features(&self) -> u32 {
+        unsafe { self.port::<u32>(DEVICE_FEATURES).read() }
+    }
+
+    /// Tell the device which of its features we'll use.
+    pub fn set_guest_features(&self, features: u32) {
+        unsafe { self.port::<u32>(GUEST_FEATURES).write(features); }
+    }
+
+    /// Read a byte of device-specific configuration.
+    pub fn config_u8(&self, offset: u16) -> u8 {
+        unsafe { self.port::<u8>(DEVICE_CONFIG + offset).read() }
+    }
+
+    /// Allocate queue number `index`, in whatever size the device wants,
+    /// and tell the device where it is.  Each of the first `buffers`
+    /// descriptors gets a buffer of `buffer_size` bytes.
+    pub fn setup_queue(&self, index: u16, buffers: u16, buffer_size: usize)
+                       -> Result<Virtqueue, &'static str>
+    {
+        let size = unsafe {
+            self.port::<u16>(QUEUE_SELECT).write(index);
+            self.port::<u16>(QUEUE_SIZE).read()
+        };
+        if size == 0 {
+            return Err("device has no such queue");
+        }
+        if unsafe { self.port::<u32>(QUEUE_ADDRESS).read() } != 0 {
+            return Err("queue already in use");
+        }
+        let buffers = if buffers > size { size } else { buffers };
+
+        let (avail, used, total) = queue_layout(size as usize);
+        let memory = try!(DmaVec::zeroed(total, QUEUE_ALIGN));
+        let data = try!(DmaVec::zeroed(buffers as usize * buffer_size, 8));
+        unsafe {
+            self.port::<u32>(QUEUE_ADDRESS)
+                .write((memory.physical_addr() / QUEUE_ALIGN) as u32);
+        }
+        Ok(Virtqueue {
+            notify: unsafe { self.port(QUEUE_NOTIFY) },
+            index: index,
+            size: size,
+            avail_offset: avail,
+            used_offset: used,
+            memory: memory,
+            data: data,
+            buffer_size: buffer_size,
+            avail_idx: 0,
+            last_used: 0,
+        })
+    }
+
+    /// Tell the device we're ready, and check that it agrees.
+    pub fn driver_ok(&self) -> Result<(), &'static str> {
+        self.set_status(ACKNOWLEDGE | DRIVER | DRIVER_OK);
+        if self.status() & FAILED != 0 {
+            return Err("device rejected our setup");
+        }
+        Ok(())
+    }
+
+    /// Reset the device, so that it forgets our queues.  Do this before
+    /// dropping any `Virtqueue` the device knows about.
+    pub fn reset(&self) {
+        self.set_status(0);
+    }
+}
+
+/// Where the available and used rings go in a queue of `size`
+/// descriptors, and the total size, in bytes.
+fn queue_layout(size: usize) -> (usize, usize, usize) {
+    let align = |n: usize| (n + QUEUE_ALIGN - 1) & !(QUEUE_ALIGN - 1);
+    let avail = size * DESC_SIZE;
+    // flags, idx, ring[size], used_event.
+    let used = align(avail + 2 * (3 + size));
+    // flags, idx, ring[size] of (id: u32, len: u32), avail_event.
+    let total = align(used + 6 + 8 * size);
+    (avail, used, total)
+}
+
+/// A queue of buffers shared with a device.
+pub struct Virtqueue {
+    notify: Port<u16>,
+    index: u16,
+    /// The number of descriptors.
+    size: u16,
+    /// Offsets of the rings in `memory`.
+    avail_offset: usize,
+    used_offset: usize,
+    /// The descriptors and rings.
+    memory: DmaVec<u8>,
+    /// Our buffers, one per descriptor we use.
+    data: DmaVec<u8>,
+    buffer_size: usize,
+    /// Our copy of the available ring's index.
+    avail_idx: u16,
+    /// The used ring index we've read up to.
+    last_used: u16,
+}
+
+impl Virtqueue {
+    /// The number of descriptors which have buffers.
+    pub fn buffers(&self) -> u16 {
+        (self.data.len() / self.buffer_size) as u16
+    }
+
+    /// The buffer belonging to descriptor `desc`.
+    pub fn buffer(&mut self, desc: u16) -> &mut [u8] {
+        let start = desc as usize * self.buffer_size;
+        &mut self.data[start..start + self.buffer_size]
+    }
+
+    unsafe fn write<T>(&mut self, offset: usize, value: T) {
+        ptr::write_volatile(self.memory.as_mut_ptr().offset(offset as isize) as *mut T,
+                            value);
+    }
+
+    unsafe fn read<T>(&self, offset: usize) -> T {
+        ptr::read_volatile(self.memory.as_ptr().offset(offset as isize) as *const T)
+    }
+
+    /// Hand the first `len` bytes of descriptor `desc`'s buffer to the
+    /// device, for it to read or, if `device_writes`, to fill in.
+    pub fn submit(&mut self, desc: u16, len: usize, device_writes: bool) {
+        assert!(desc < self.buffers() && len <= self.buffer_size);
+        let addr = self.data.physical_addr() + desc as usize * self.buffer_size;
+        let d = desc as usize * DESC_SIZE;
+        let slot = self.avail_offset + 4 + 2 * (self.avail_idx % self.size) as usize;
+        let avail = self.avail_offset;
+        unsafe {
+            self.write::<u64>(d, addr as u64);
+            self.write::<u32>(d + 8, len as u32);
+            self.write::<u16>(d + 12, if device_writes { DESC_WRITE } else { 0 });
+            self.write::<u16>(d + 14, 0);
+            self.write::<u16>(slot, desc);
+            // The device mustn't see the new index before the entry.
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            self.write::<u16>(avail + 2, self.avail_idx);
+            fence(Ordering::SeqCst);
+            self.notify.write(self.index);
+        }
+    }
+
+    /// Take the next descriptor the device has finished with, and the
+    /// number of bytes it wrote, if any.
+    pub fn pop_used(&mut self) -> Option<(u16, usize)> {
+        let used = self.used_offset;
+        let idx = unsafe { self.read::<u16>(used + 2) };
+        if idx == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let elem = used + 4 + 8 * (self.last_used % self.size) as usize;
+        let (id, len) = unsafe {
+            (self.read::<u32>(elem), self.read::<u32>(elem + 4))
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+        Some((id as u16, len as usize))
+    }
+}
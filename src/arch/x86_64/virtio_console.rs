@@ -0,0 +1,185 @@
+//! A driver for virtio consoles, which gives us a faster channel to the
+//! host than the emulated 16550 UART.  With QEMU, try:
+//!
+//! ```sh
+//! qemu-system-x86_64 ... -device virtio-serial \
+//!     -chardev stdio,id=vc0 -device virtconsole,chardev=vc0
+//! ```
+//!
+//! Once we've found one, console output goes to it as well as everywhere
+//! else, and we read console input from it as well as from COM1.  We don't
+//! negotiate multiple ports, so we only talk to port 0, which is where
+//! QEMU puts the first `virtconsole`.
+
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use spin::Mutex;
+
+use arch::x86_64::pci::{DeviceMatch, Driver, FunctionInfo};
+use arch::x86_64::virtio::{Transport, Virtqueue};
+
+/// Port 0's queues.
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+/// How many receive buffers we keep posted, and how big they are.
+const RECEIVE_BUFFERS: u16 = 16;
+const RECEIVE_BUFFER_SIZE: usize = 64;
+
+/// We send one buffer at a time, and wait for the host to take it.
+const TRANSMIT_BUFFER_SIZE: usize = 1024;
+
+/// How many times we poll for the host to take a buffer before giving up.
+/// If the host isn't listening, we drop output rather than hang.
+const TRANSMIT_POLLS: usize = 1_000_000;
+
+struct VirtioConsole {
+    transport: Transport,
+    receive: Virtqueue,
+    transmit: Virtqueue,
+    /// A filled receive buffer we're part way through, as `(descriptor,
+    /// position, length)`.
+    pending: Option<(u16, usize, usize)>,
+    /// Does the host still have our transmit buffer?
+    transmitting: bool,
+}
+
+impl VirtioConsole {
+    /// Send `bytes`, a buffer at a time.
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(TRANSMIT_BUFFER_SIZE) {
+            if !self.wait_for_transmit() {
+                return;
+            }
+            self.transmit.buffer(0)[..chunk.len()].copy_from_slice(chunk);
+            self.transmit.submit(0, chunk.len(), false);
+            self.transmitting = true;
+        }
+        self.wait_for_transmit();
+    }
+
+    /// Wait for the host to give back our transmit buffer.  Returns false
+    /// if it doesn't.
+    fn wait_for_transmit(&mut self) -> bool {
+        for _ in 0..TRANSMIT_POLLS {
+            if !self.transmitting {
+                return true;
+            }
+            if self.transmit.pop_used().is_some() {
+                self.transmitting = false;
+            }
+        }
+        !self.transmitting
+    }
+
+    /// Read a byte of input, if the host has sent us any.
+    fn read_byte(&mut self) -> Option<u8> {
+        while self.pending.is_none() {
+            match self.receive.pop_used() {
+                // Give back empty buffers straight away.
+                Some((desc, 0)) => self.receive.submit(desc, RECEIVE_BUFFER_SIZE, true),
+                Some((desc, len)) => self.pending = Some((desc, 0, len)),
+                None => return None,
+            }
+        }
+        let (desc, pos, len) = match self.pending {
+            Some(pending) => pending,
+            None => return None,
+        };
+        let byte = self.receive.buffer(desc)[pos];
+        if pos + 1 < len {
+            self.pending = Some((desc, pos + 1, len));
+        } else {
+            // Give the buffer back for more input.
+            self.pending = None;
+            self.receive.submit(desc, RECEIVE_BUFFER_SIZE, true);
+        }
+        Some(byte)
+    }
+}
+
+static CONSOLE: Mutex<Option<VirtioConsole>> = Mutex::new(None);
+
+/// Have we found a console?  This lets `write` skip taking a lock.
+static ACTIVE: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Send `s` to our virtio console, if we have one.
+pub fn write(s: &str) {
+    if ACTIVE.load(Ordering::Relaxed) {
+        if let Some(ref mut console) = *CONSOLE.lock() {
+            console.write_bytes(s.as_bytes());
+        }
+    }
+}
+
+/// Read a byte from our virtio console, if we have one and there's input
+/// waiting.
+pub fn read_byte() -> Option<u8> {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return None;
+    }
+    CONSOLE.lock().as_mut().and_then(|console| console.read_byte())
+}
+
+/// Set up our queues, and post our receive buffers.  If anything goes
+/// wrong, we reset the device before dropping the queues, so that it
+/// doesn't write to memory we've freed.
+fn start(transport: &Transport) -> Result<(Virtqueue, Virtqueue), &'static str> {
+    // We don't want any optional features, including multiple ports.
+    transport.set_guest_features(0);
+    let receive = transport.setup_queue(RECEIVE_QUEUE, RECEIVE_BUFFERS,
+                                        RECEIVE_BUFFER_SIZE);
+    let transmit = transport.setup_queue(TRANSMIT_QUEUE, 1, TRANSMIT_BUFFER_SIZE);
+    let (mut receive, transmit) = match (receive, transmit) {
+        (Ok(receive), Ok(transmit)) => (receive, transmit),
+        (Err(err), _) | (_, Err(err)) => {
+            transport.reset();
+            return Err(err);
+        }
+    };
+    for desc in 0..receive.buffers() {
+        receive.submit(desc, RECEIVE_BUFFER_SIZE, true);
+    }
+    if let Err(err) = transport.driver_ok() {
+        transport.reset();
+        return Err(err);
+    }
+    Ok((receive, transmit))
+}
+
+fn probe(function: &FunctionInfo) -> Result<(), &'static str> {
+    if CONSOLE.lock().is_some() {
+        return Err("we only support one virtio console");
+    }
+    let transport = try!(unsafe { Transport::new(function) });
+    let (receive, transmit) = try!(start(&transport));
+    *CONSOLE.lock() = Some(VirtioConsole {
+        transport: transport,
+        receive: receive,
+        transmit: transmit,
+        pending: None,
+        transmitting: false,
+    });
+    ACTIVE.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Stop using our console, and make the device forget our queues before
+/// we free them.
+fn remove(_function: &FunctionInfo) {
+    ACTIVE.store(false, Ordering::SeqCst);
+    if let Some(console) = CONSOLE.lock().take() {
+        console.transport.reset();
+    }
+}
+
+/// Our PCI driver.
+pub static DRIVER: Driver = Driver {
+    name: "virtio-console",
+    matches: &[
+        // The legacy (transitional) virtio console.
+        DeviceMatch::Id { vendor: 0x1af4, device: 0x1003 },
+    ],
+    probe: probe,
+    remove: Some(remove),
+    registers: None,
+};
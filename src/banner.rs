@@ -0,0 +1,74 @@
+//! The summary we print at the end of kernel initialization.  Everything
+//! we've learned about the machine goes here, in a fixed order, so that
+//! boot logs from different runs are easy to compare.
+
+use arch::{cpu, kernel_layout, mem, multiboot, pci, reset, timer};
+use build_info;
+use heap;
+
+/// Print our boot summary.
+pub fn print() {
+    println!("==== toyos boot summary ====");
+    println!("Build:     {}", build_info::Summary);
+    println!("Reset:     {}", reset::cause().description());
+
+    let cpu = cpu::CpuInfo::read();
+    println!("CPU:       {} family {} model {} stepping {}",
+             cpu.vendor(), cpu.family, cpu.model, cpu.stepping);
+    if !cpu.brand().is_empty() {
+        println!("           {}", cpu.brand());
+    }
+    println!("Features:  {}", cpu.features());
+    println!("Mem ops:   {}", mem::description());
+
+    print_memory();
+
+    let kernel = kernel_layout::kernel();
+    println!("Kernel:    {} KB at 0x{:x}-0x{:x}",
+             kernel.size() / 1024, kernel.start, kernel.end);
+    let (heap_bottom, heap_top) = heap::bounds();
+    println!("Heap:      {} KB at 0x{:x}",
+             (heap_top - heap_bottom) / 1024, heap_bottom);
+    let (dma_free, _) = heap::dma_free_summary();
+    println!("           {} KB for ISA DMA below 16MB", dma_free / 1024);
+
+    println!("PCI:       {} functions", pci::functions().count());
+    for function in pci::functions() {
+        match pci::bound_driver(function.address()) {
+            Some(driver) => println!("           {} [{}]", function, driver),
+            None => println!("           {}", function),
+        }
+    }
+
+    let millihertz = timer::frequency_millihertz();
+    println!("Timer:     8253/8254 PIT via 8259 PIC (IRQ 0), {}.{:03} Hz{}",
+             millihertz / 1000, millihertz % 1000,
+             if timer::is_tickless() { ", tickless when idle" } else { "" });
+    println!("Consoles:  VGA text 80x25, COM1 serial");
+    println!("============================");
+}
+
+/// Print a summary of the boot loader's memory map.
+fn print_memory() {
+    let info = match multiboot::info() {
+        Some(info) => info,
+        None => {
+            println!("Memory:    (no multiboot information)");
+            return;
+        }
+    };
+
+    if let Some((lower, upper)) = info.basic_memory() {
+        println!("Memory:    {} KB lower, {} KB upper", lower, upper);
+    }
+    if let Some(areas) = info.memory_areas() {
+        let mut regions = 0;
+        let mut available = 0;
+        for area in areas.filter(|a| a.is_available()) {
+            regions += 1;
+            available += area.length;
+        }
+        println!("           {} MB available in {} regions",
+                 available / (1024 * 1024), regions);
+    }
+}
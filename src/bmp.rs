@@ -0,0 +1,86 @@
+//! Just enough of the BMP file format to display uncompressed 24-bit and
+//! 32-bit images.
+//!
+//! A BMP file starts with a 14-byte file header, which tells us where the
+//! pixels are, followed by a `BITMAPINFOHEADER` (or a later, longer
+//! version of it) describing them.  Rows are padded to a multiple of 4
+//! bytes, and are stored bottom-up unless the height is negative.
+
+/// An uncompressed image.
+pub struct Image {
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+    /// The number of bytes from one row to the next, including padding.
+    stride: usize,
+    top_down: bool,
+    data: &'static [u8],
+}
+
+/// BMP compression code for uncompressed pixels.
+const COMPRESSION_NONE: u32 = 0;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+/// Parse a BMP file.
+pub fn parse(bytes: &'static [u8]) -> Result<Image, &'static str> {
+    if bytes.len() < 54 || &bytes[0..2] != b"BM" {
+        return Err("not a BMP file");
+    }
+    let pixels = read_u32(bytes, 10) as usize;
+    if read_u32(bytes, 14) < 40 {
+        return Err("BMP file is too old");
+    }
+    let width = read_u32(bytes, 18) as i32;
+    let height = read_u32(bytes, 22) as i32;
+    let bits_per_pixel = read_u16(bytes, 28);
+    if read_u32(bytes, 30) != COMPRESSION_NONE {
+        return Err("BMP file is compressed");
+    }
+    if bits_per_pixel != 24 && bits_per_pixel != 32 {
+        return Err("only 24-bit and 32-bit BMP files are supported");
+    }
+    if width <= 0 || height == 0 {
+        return Err("BMP file is empty");
+    }
+
+    let width = width as usize;
+    let top_down = height < 0;
+    let height = (height as i64).abs() as usize;
+    let bytes_per_pixel = bits_per_pixel as usize / 8;
+    let stride = (width * bytes_per_pixel + 3) & !3;
+    if pixels > bytes.len() || bytes.len() - pixels < stride * height {
+        return Err("truncated BMP file");
+    }
+
+    Ok(Image {
+        width: width,
+        height: height,
+        bytes_per_pixel: bytes_per_pixel,
+        stride: stride,
+        top_down: top_down,
+        data: &bytes[pixels..pixels + stride * height],
+    })
+}
+
+impl Image {
+    pub fn width(&self) -> usize { self.width }
+    pub fn height(&self) -> usize { self.height }
+
+    /// The color of the pixel at `x`, `y`, counting from the top left, in
+    /// `0x00RRGGBB` format.
+    pub fn pixel(&self, x: usize, y: usize) -> u32 {
+        let row = if self.top_down { y } else { self.height - 1 - y };
+        let offset = row * self.stride + x * self.bytes_per_pixel;
+        let blue = self.data[offset] as u32;
+        let green = self.data[offset + 1] as u32;
+        let red = self.data[offset + 2] as u32;
+        red << 16 | green << 8 | blue
+    }
+}
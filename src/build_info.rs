@@ -0,0 +1,33 @@
+//! What we were built from, and how.  The constants here are generated by
+//! `build.rs`.
+
+use core::fmt;
+
+use integrity;
+
+include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+
+/// Displays a one-line summary of the build, for banners and bug reports.
+pub struct Summary;
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "toyos {} ({}, built {})", VERSION, COMMIT, TIMESTAMP)
+    }
+}
+
+/// Print everything we know about this build.
+pub fn print() {
+    println!("{}", Summary);
+    println!("Compiler:  {}", RUSTC);
+    println!("Text CRC:  {}", integrity::Summary);
+    if FEATURES.is_empty() {
+        println!("Features:  (none)");
+    } else {
+        print!("Features: ");
+        for feature in FEATURES {
+            print!(" {}", feature);
+        }
+        println!("");
+    }
+}
@@ -0,0 +1,24 @@
+//! Which optional subsystems this kernel was built with.
+//!
+//! Each of these mirrors a cargo feature in `Cargo.toml`.  We use
+//! constants instead of `#[cfg]` so that every subsystem still gets
+//! compiled and type-checked in every configuration, and the optimizer
+//! throws away whatever a minimal build doesn't use.  Build a minimal
+//! kernel with `make features=`.
+
+/// Switch to a framebuffer console when booted with `vbe`.
+pub const GRAPHICS: bool = cfg!(feature = "graphics");
+
+/// Run the interactive shell on the keyboard and serial console.
+pub const SHELL: bool = cfg!(feature = "shell");
+
+/// Drive the Sound Blaster 16.
+pub const SOUND: bool = cfg!(feature = "sound");
+
+/// Allow boot-time self tests, such as `memtest`.
+pub const SELFTESTS: bool = cfg!(feature = "selftests");
+
+/// Start a serial console before anything else, and report each step of
+/// boot on it.  `earlyserial` on the kernel command line does the same, and
+/// `earlyserial=off` turns it off.
+pub const EARLY_SERIAL: bool = cfg!(feature = "early-serial");
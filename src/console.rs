@@ -1,18 +1,178 @@
-//! A wrapper around both our VGA console and our serial console.
+//! A wrapper around both our screen console and our serial console.  The
+//! screen console is VGA text mode, or `fbterm` in graphics mode.
 
-use core::fmt;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
 use spin::Mutex;
-use arch::{vga, serial};
+use arch::{keyboard, vga, serial, virtio_console};
+use arch::keyboard::{Key, KeyEvent, KeyState};
+use fbterm;
+use klog;
 
 pub struct Console;
 
+/// While this is set, we only write to the kernel log.
+static QUIET: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Stop (or resume) showing output on our console outputs.  Output still
+/// goes to the kernel log.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::SeqCst);
+}
+
+impl Console {
+    /// Output a string to each of our console outputs, without recording
+    /// it in the kernel log.
+    fn write_outputs(&mut self, s: &str) -> fmt::Result {
+        if QUIET.load(Ordering::SeqCst) { return Ok(()); }
+        match *fbterm::TERMINAL.lock() {
+            Some(ref mut terminal) => try!(terminal.write_str(s)),
+            None => try!(vga::SCREEN.lock().write_str(s)),
+        }
+        try!(serial::COM1.lock().write_str(s));
+        virtio_console::write(s);
+        Ok(())
+    }
+}
+
 impl fmt::Write for Console {
-    /// Output a string to each of our console outputs.
+    /// Output a string to each of our console outputs, and to the kernel
+    /// log.
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        try!(vga::SCREEN.lock().write_str(s));
-        serial::COM1.lock().write_str(s)
+        klog::write(s);
+        self.write_outputs(s)
     }
 }
 
 pub static CONSOLE: Mutex<Console> = Mutex::new(Console);
 
+/// Print formatted text without adding it to the kernel log.  This is for
+/// replaying the log itself.
+pub fn print_unlogged(args: fmt::Arguments) {
+    struct Unlogged<'a>(&'a mut Console);
+
+    impl<'a> fmt::Write for Unlogged<'a> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0.write_outputs(s)
+        }
+    }
+
+    let mut console = CONSOLE.lock();
+    let _ = fmt::Write::write_fmt(&mut Unlogged(&mut console), args);
+}
+
+
+/// Something typed or pasted at our serial console.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Input {
+    Char(char),
+    /// The terminal is about to send pasted text.
+    PasteStart,
+    /// The pasted text is over.
+    PasteEnd,
+}
+
+/// What a terminal in bracketed paste mode sends around pasted text.
+const PASTE_START: &'static [u8] = b"\x1B[200~";
+const PASTE_END: &'static [u8] = b"\x1B[201~";
+
+/// Recognizes bracketed paste markers in serial input.  Bytes which might
+/// be the start of a marker are held back until we know; if they turn
+/// out not to be, we hand them out one at a time.
+struct InputDecoder {
+    held: [u8; 6],
+    len: usize,
+    /// When flushing, the next held byte to return.
+    flushing: Option<usize>,
+}
+
+static DECODER: Mutex<InputDecoder> = Mutex::new(InputDecoder {
+    held: [0; 6],
+    len: 0,
+    flushing: None,
+});
+
+impl InputDecoder {
+    fn next(&mut self) -> Option<Input> {
+        loop {
+            // Hand out any bytes which turned out not to be a marker.
+            if let Some(pos) = self.flushing {
+                if pos < self.len {
+                    self.flushing = Some(pos + 1);
+                    return Some(Input::Char(to_char(self.held[pos])));
+                }
+                self.flushing = None;
+                self.len = 0;
+            }
+
+            let from_serial = serial::read_received();
+            let b = match from_serial.or_else(virtio_console::read_byte) {
+                Some(b) => b,
+                None => return None,
+            };
+            self.held[self.len] = b;
+            self.len += 1;
+
+            let held = &self.held[..self.len];
+            if held == PASTE_START || held == PASTE_END {
+                self.len = 0;
+                return Some(if held == PASTE_START {
+                    Input::PasteStart
+                } else {
+                    Input::PasteEnd
+                });
+            } else if !PASTE_START.starts_with(held)
+                && !PASTE_END.starts_with(held)
+            {
+                self.flushing = Some(0);
+            }
+        }
+    }
+}
+
+/// Convert a byte of serial input to a character.
+fn to_char(b: u8) -> char {
+    match b {
+        // Most terminals send DEL when you press backspace.
+        0x7F => '\x08',
+        _ => b as char,
+    }
+}
+
+/// Ask the terminal on our serial port to mark pasted text, so that we
+/// can tell it apart from typing.  Terminals which don't support this
+/// will ignore the request.
+pub fn enable_bracketed_paste() {
+    let _ = serial::COM1.lock().write_str("\x1B[?2004h");
+}
+
+/// Check our console inputs for something to do: the keyboard, the serial
+/// port and any virtio console.  Keys which scroll the screen are handled
+/// here.
+pub fn read_input() -> Option<Input> {
+    read_keyboard().or_else(|| DECODER.lock().next())
+}
+
+/// Read the next character typed on the keyboard.
+fn read_keyboard() -> Option<Input> {
+    loop {
+        // The shell only cares about presses and repeats, so ignore
+        // releases if somebody has asked for them.
+        let key = match keyboard::read_key() {
+            Some(KeyEvent { state: KeyState::Released, .. }) => continue,
+            Some(event) => event.key,
+            None => return None,
+        };
+        match key {
+            Key::Char(input) => {
+                // Typing jumps back to the live screen, like most
+                // terminals.
+                vga::SCREEN.lock().scroll_to_live();
+                return Some(Input::Char(input));
+            }
+            Key::PageUp { shift: true } => vga::SCREEN.lock().scroll_back(),
+            Key::PageDown { shift: true } => vga::SCREEN.lock().scroll_forward(),
+            _ => {}
+        }
+    }
+}
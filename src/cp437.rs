@@ -0,0 +1,34 @@
+//! Code page 437, the character set built into VGA text mode, which is
+//! also what our framebuffer fonts normally contain.  Rust strings are
+//! UTF-8, so our screen consoles use this to pick a glyph for each `char`.
+
+/// The characters for bytes 0x80 through 0xFF, in order.
+const UPPER_HALF: &'static str =
+    "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»\
+     ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀\
+     αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{A0}";
+
+/// Characters which aren't in CP437, but which have a close enough
+/// substitute that it's better than '?'.
+const SUBSTITUTES: &'static [(char, u8)] = &[
+    ('‘', b'\''), ('’', b'\''), ('“', b'"'), ('”', b'"'),
+    ('–', b'-'), ('—', b'-'), ('−', b'-'), ('•', 0xF9),
+    ('β', 0xE1), ('\u{2126}', 0xEA), ('μ', 0xE6), ('∈', 0xEE),
+    ('━', 0xC4), ('┃', 0xB3), ('┏', 0xDA), ('┓', 0xBF),
+    ('┗', 0xC0), ('┛', 0xD9),
+];
+
+/// The CP437 byte to draw for `c`, or '?' if there's nothing suitable.
+/// ASCII, including control characters, passes through unchanged.
+pub fn from_char(c: char) -> u8 {
+    if (c as u32) < 0x80 {
+        return c as u8;
+    }
+    if let Some(i) = UPPER_HALF.chars().position(|u| u == c) {
+        return 0x80 + i as u8;
+    }
+    SUBSTITUTES.iter()
+        .find(|&&(from, _)| from == c)
+        .map(|&(_, to)| to)
+        .unwrap_or(b'?')
+}
@@ -0,0 +1,124 @@
+//! Machine-readable crash dumps, for post-mortem analysis after the VM is
+//! gone.
+//!
+//! When booted with the `crashdump` option, the panic screen follows its
+//! report with a second copy on the serial port, base64 encoded between
+//! `BEGIN` and `END` lines.  Terminal emulators and log collectors can
+//! mangle control characters and long lines, but they leave base64 alone,
+//! so a script can reliably fish the dump out of a serial log:
+//!
+//! ```sh
+//! sed -n '/^-----BEGIN TOYOS CRASH DUMP/,/^-----END/p' serial.log |
+//!     grep -v '^-----' | base64 -d
+//! ```
+//!
+//! The decoded dump is the panic report, followed by the last `LOG_LINES`
+//! lines of the kernel log.  Like the rest of the panic path, we don't
+//! allocate or wait for locks.
+
+use core::fmt::{self, Write};
+
+use arch::multiboot;
+use klog;
+
+/// How many lines of the kernel log we include.
+const LOG_LINES: usize = 50;
+
+/// The width of each line of base64.
+const LINE_WIDTH: usize = 76;
+
+const BASE64: &'static [u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Were we asked to write crash dumps?
+pub fn wanted() -> bool {
+    multiboot::info().and_then(|i| i.option("crashdump")).is_some()
+}
+
+/// Base64-encodes everything written to it, and writes it to `out`.
+pub struct Dump<'a, W: Write + 'a> {
+    out: &'a mut W,
+    /// Bytes we haven't encoded yet, because we need 3 at a time.
+    pending: [u8; 3],
+    pending_len: usize,
+    /// The number of characters on the current output line.
+    column: usize,
+    /// The first error from `out`, if any.
+    result: fmt::Result,
+}
+
+impl<'a, W: Write> Dump<'a, W> {
+    /// Start a dump on `out`.
+    pub fn begin(out: &'a mut W) -> Dump<'a, W> {
+        let result = out.write_str("\n-----BEGIN TOYOS CRASH DUMP-----\n");
+        Dump {
+            out: out,
+            pending: [0; 3],
+            pending_len: 0,
+            column: 0,
+            result: result,
+        }
+    }
+
+    /// Encode `bytes`.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.pending[self.pending_len] = byte;
+            self.pending_len += 1;
+            if self.pending_len == 3 {
+                self.flush_group();
+            }
+        }
+    }
+
+    /// Encode the last lines of the kernel log.
+    pub fn write_log(&mut self) -> fmt::Result {
+        try!(self.write_str("\nLog:\n"));
+        if !klog::try_tail(LOG_LINES, |text| self.write_bytes(text)) {
+            try!(self.write_str("(locked)\n"));
+        }
+        Ok(())
+    }
+
+    /// Output `pending` as 4 characters, with `=` for any missing bytes.
+    fn flush_group(&mut self) {
+        let p = self.pending;
+        let n = (p[0] as usize) << 16 | (p[1] as usize) << 8 | p[2] as usize;
+        let mut chars = [b'='; 4];
+        for i in 0..self.pending_len + 1 {
+            chars[i] = BASE64[(n >> (18 - 6 * i)) & 0x3F];
+        }
+        // Our alphabet is all ASCII.
+        let text = unsafe { ::core::str::from_utf8_unchecked(&chars) };
+        if self.result.is_ok() {
+            self.result = self.out.write_str(text);
+        }
+        self.pending = [0; 3];
+        self.pending_len = 0;
+
+        self.column += 4;
+        if self.column >= LINE_WIDTH && self.result.is_ok() {
+            self.result = self.out.write_str("\n");
+            self.column = 0;
+        }
+    }
+
+    /// Encode anything left over, and write our `END` line.
+    pub fn finish(mut self) -> fmt::Result {
+        if self.pending_len > 0 {
+            self.flush_group();
+        }
+        try!(self.result);
+        if self.column > 0 {
+            try!(self.out.write_str("\n"));
+        }
+        self.out.write_str("-----END TOYOS CRASH DUMP-----\n")
+    }
+}
+
+impl<'a, W: Write> Write for Dump<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        self.result
+    }
+}
@@ -0,0 +1,165 @@
+//! Owning pointers to memory which devices can access directly.
+//!
+//! `DmaBox<T>` and `DmaVec<T>` allocate from our DMA heap, which lies
+//! below 16MB and is identity mapped, so `physical_addr` is what a device
+//! needs.  The memory never moves, so it's safe to hand the address to a
+//! device for as long as the box is alive.
+//!
+//! Devices write to this memory behind the compiler's back, so we only
+//! allow `Pod` types, where any bit pattern is a valid value.  To catch
+//! devices (or drivers) which write past the end of a buffer, we put a
+//! canary after each allocation and check it when the buffer is dropped.
+//! Freed buffers are poisoned, so that a device still writing to one is
+//! easier to spot.
+
+use alloc_buddy_simple::{allocate_zeroed_from, deallocate_to};
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::slice;
+
+use heap;
+
+/// "Plain old data": types which can hold any bit pattern, and which can
+/// therefore be safely shared with hardware.  Implement this for
+/// `#[repr(C)]` structs made entirely of `Pod` fields.
+pub unsafe trait Pod: Copy {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for i8 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+
+/// What we store just past the end of each buffer.
+const CANARY: u64 = 0xD0D0_CAFE_F00D_D0D0;
+
+/// What we fill buffers with when we free them.
+const POISON: u8 = 0xDB;
+
+/// A DMA allocation of `size` bytes plus our canary.
+struct Allocation {
+    ptr: *mut u8,
+    size: usize,
+    align: usize,
+}
+
+impl Allocation {
+    /// Allocate `size` zeroed bytes with the specified alignment.
+    fn new(size: usize, align: usize) -> Result<Allocation, &'static str> {
+        let align = if align < align_of::<u64>() { align_of::<u64>() } else { align };
+        // Round up so our canary is aligned.
+        let padded = (size + size_of::<u64>() - 1) & !(size_of::<u64>() - 1);
+        let ptr = unsafe {
+            allocate_zeroed_from(heap::dma_heap(), padded + size_of::<u64>(), align)
+        };
+        if ptr.is_null() {
+            return Err("out of DMA memory");
+        }
+        unsafe {
+            ptr::write(ptr.offset(padded as isize) as *mut u64, CANARY);
+        }
+        Ok(Allocation { ptr: ptr, size: padded, align: align })
+    }
+
+    /// The physical address of our memory.
+    fn physical_addr(&self) -> usize {
+        // The DMA heap is identity mapped.
+        self.ptr as usize
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        unsafe {
+            let canary = ptr::read(self.ptr.offset(self.size as isize) as *const u64);
+            if canary != CANARY {
+                panic!("DMA buffer at 0x{:x} overrun (canary {:016x})",
+                       self.ptr as usize, canary);
+            }
+            ptr::write_bytes(self.ptr, POISON, self.size + size_of::<u64>());
+            deallocate_to(heap::dma_heap(), self.ptr,
+                          self.size + size_of::<u64>(), self.align);
+        }
+    }
+}
+
+/// A single value in DMA memory.
+pub struct DmaBox<T: Pod> {
+    allocation: Allocation,
+    _marker: PhantomData<T>,
+}
+
+// We own our memory outright, so we can be sent wherever `T` can.
+unsafe impl<T: Pod + Send> Send for DmaBox<T> {}
+
+impl<T: Pod> DmaBox<T> {
+    /// Move `value` into DMA memory.
+    pub fn new(value: T) -> Result<DmaBox<T>, &'static str> {
+        let allocation = try!(Allocation::new(size_of::<T>(), align_of::<T>()));
+        unsafe { ptr::write(allocation.ptr as *mut T, value); }
+        Ok(DmaBox { allocation: allocation, _marker: PhantomData })
+    }
+
+    /// The physical address of our value, for handing to a device.
+    pub fn physical_addr(&self) -> usize {
+        self.allocation.physical_addr()
+    }
+}
+
+impl<T: Pod> Deref for DmaBox<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*(self.allocation.ptr as *const T) }
+    }
+}
+
+impl<T: Pod> DerefMut for DmaBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *(self.allocation.ptr as *mut T) }
+    }
+}
+
+/// A fixed-length array in DMA memory.  Unlike `Vec`, this can't grow,
+/// because growing would move it out from under the device.
+pub struct DmaVec<T: Pod> {
+    allocation: Allocation,
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Pod + Send> Send for DmaVec<T> {}
+
+impl<T: Pod> DmaVec<T> {
+    /// Allocate `len` zeroed elements, aligned to at least `align` bytes.
+    /// Use a large alignment to keep a buffer from crossing a boundary,
+    /// such as the 64KB boundaries that ISA DMA can't cross.
+    pub fn zeroed(len: usize, align: usize) -> Result<DmaVec<T>, &'static str> {
+        let align = if align < align_of::<T>() { align_of::<T>() } else { align };
+        let allocation = try!(Allocation::new(len * size_of::<T>(), align));
+        Ok(DmaVec { allocation: allocation, len: len, _marker: PhantomData })
+    }
+
+    /// The physical address of our first element, for handing to a
+    /// device.
+    pub fn physical_addr(&self) -> usize {
+        self.allocation.physical_addr()
+    }
+}
+
+impl<T: Pod> Deref for DmaVec<T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.allocation.ptr as *const T, self.len) }
+    }
+}
+
+impl<T: Pod> DerefMut for DmaVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.allocation.ptr as *mut T, self.len) }
+    }
+}
@@ -0,0 +1,353 @@
+//! A text console on the graphics framebuffer, for when `vbe` has taken us
+//! out of VGA text mode.
+//!
+//! We keep a grid of characters, just like VGA text memory, and draw
+//! glyphs from a bitmap font into the framebuffer.  The font comes from a
+//! PSF file loaded as the `font` multiboot module if there is one, or else
+//! the VGA text-mode font saved by `vbe`.  To keep things fast, we:
+//!
+//! - cache recently drawn glyphs, already expanded to pixels in their
+//!   colors,
+//! - only redraw the cells which have changed since our last flush, and
+//! - scroll by moving framebuffer memory instead of redrawing everything.
+//!
+//! Control characters are handled the same way as on the VGA console.
+
+use collections::vec::Vec;
+use core::fmt;
+use core::ptr;
+use spin::Mutex;
+
+use arch::multiboot;
+use arch::vbe::{self, Framebuffer};
+use arch::vga::{self, Char, Color, ColorScheme, Rect};
+use cp437;
+
+/// The standard VGA palette, in `0x00RRGGBB` format.
+const PALETTE: [u32; 16] = [
+    0x000000, 0x0000AA, 0x00AA00, 0x00AAAA,
+    0xAA0000, 0xAA00AA, 0xAA5500, 0xAAAAAA,
+    0x555555, 0x5555FF, 0x55FF55, 0x55FFFF,
+    0xFF5555, 0xFF55FF, 0xFFFF55, 0xFFFFFF,
+];
+
+/// The number of glyphs we keep in our cache.
+const CACHE_SLOTS: usize = 64;
+
+/// A cache key which never matches a real glyph.
+const NO_KEY: u32 = !0;
+
+/// Magic numbers for the two versions of the PSF font format.
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+
+/// PSF1 mode bit for fonts with 512 glyphs.
+const PSF1_MODE_512: u8 = 0x01;
+
+/// A bitmap font, with each scanline padded to a whole number of bytes.
+struct Glyphs {
+    width: usize,
+    height: usize,
+    count: usize,
+    /// The bytes in each scanline.
+    row_bytes: usize,
+    data: Vec<u8>,
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes[offset] as u32 | (bytes[offset + 1] as u32) << 8 |
+        (bytes[offset + 2] as u32) << 16 | (bytes[offset + 3] as u32) << 24
+}
+
+impl Glyphs {
+    /// Load a font in either version of the PSF format used by the Linux
+    /// console.
+    fn parse_psf(bytes: &[u8]) -> Result<Glyphs, &'static str> {
+        let (header_size, count, width, height) =
+            if bytes.len() >= 4 && &bytes[0..2] == &PSF1_MAGIC[..] {
+                let count = if bytes[2] & PSF1_MODE_512 != 0 { 512 } else { 256 };
+                (4, count, 8, bytes[3] as usize)
+            } else if bytes.len() >= 32 && &bytes[0..4] == &PSF2_MAGIC[..] {
+                (read_u32(bytes, 8) as usize, read_u32(bytes, 16) as usize,
+                 read_u32(bytes, 28) as usize, read_u32(bytes, 24) as usize)
+            } else {
+                return Err("not a PSF font");
+            };
+        if width == 0 || height == 0 || count == 0 {
+            return Err("PSF font is empty");
+        }
+
+        let row_bytes = (width + 7) / 8;
+        let size = count * height * row_bytes;
+        if header_size > bytes.len() || bytes.len() - header_size < size {
+            return Err("truncated PSF font");
+        }
+        let mut data = Vec::with_capacity(size);
+        data.extend_from_slice(&bytes[header_size..header_size + size]);
+        Ok(Glyphs {
+            width: width,
+            height: height,
+            count: count,
+            row_bytes: row_bytes,
+            data: data,
+        })
+    }
+
+    /// Use a VGA text-mode font.
+    fn from_vga(font: &vga::Font) -> Glyphs {
+        let mut data = Vec::with_capacity(font.len());
+        data.extend_from_slice(&font[..]);
+        Glyphs {
+            width: 8,
+            height: vga::FONT_HEIGHT,
+            count: vga::FONT_GLYPHS,
+            row_bytes: 1,
+            data: data,
+        }
+    }
+
+    /// Is pixel `x`, `y` of glyph `code` set?
+    fn is_set(&self, code: usize, x: usize, y: usize) -> bool {
+        let offset = (code * self.height + y) * self.row_bytes + x / 8;
+        self.data[offset] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+/// A glyph expanded to pixels in a particular pair of colors.
+struct CachedGlyph {
+    /// The character and colors, as returned by `cache_key`, or `NO_KEY`.
+    key: u32,
+    pixels: Vec<u32>,
+}
+
+/// The key we cache `c` under.
+fn cache_key(c: Char) -> u32 {
+    (c.colors.background() as u32) << 12 | (c.colors.foreground() as u32) << 8 |
+        c.code as u32
+}
+
+/// A text terminal drawn on a framebuffer.
+pub struct Terminal {
+    framebuffer: Framebuffer,
+    glyphs: Glyphs,
+    /// Our size in characters.
+    columns: usize,
+    rows: usize,
+    /// What should be on the screen, one row after another.
+    cells: Vec<Char>,
+    x: usize,
+    y: usize,
+    colors: ColorScheme,
+    /// The cells which have changed since we last drew them.
+    dirty: Option<Rect>,
+    cache: Vec<CachedGlyph>,
+}
+
+impl Terminal {
+    fn new(framebuffer: Framebuffer, glyphs: Glyphs) -> Terminal {
+        let columns = framebuffer.width() / glyphs.width;
+        let rows = framebuffer.height() / glyphs.height;
+        let colors = ColorScheme::new(Color::White, Color::Black);
+        let mut cells = Vec::with_capacity(columns * rows);
+        for _ in 0..columns * rows {
+            cells.push(Char::new(b' ', colors));
+        }
+        let mut cache = Vec::with_capacity(CACHE_SLOTS);
+        for _ in 0..CACHE_SLOTS {
+            cache.push(CachedGlyph { key: NO_KEY, pixels: Vec::new() });
+        }
+        let mut terminal = Terminal {
+            framebuffer: framebuffer,
+            glyphs: glyphs,
+            columns: columns,
+            rows: rows,
+            cells: cells,
+            x: 0,
+            y: 0,
+            colors: colors,
+            dirty: None,
+            cache: cache,
+        };
+        terminal.clear();
+        terminal
+    }
+
+    /// Our size in characters, as `(columns, rows)`.
+    pub fn size(&self) -> (usize, usize) {
+        (self.columns, self.rows)
+    }
+
+    /// Clear the screen using the current background color, and move the
+    /// cursor to the top left.
+    pub fn clear(&mut self) {
+        let blank = Char::new(b' ', self.colors);
+        for cell in self.cells.iter_mut() {
+            *cell = blank;
+        }
+        let fb = self.framebuffer;
+        fb.fill_rect(0, 0, fb.width(), fb.height(),
+                     PALETTE[self.colors.background() as usize]);
+        self.dirty = None;
+        self.x = 0;
+        self.y = 0;
+    }
+
+    /// Write a single character.
+    pub fn write_byte(&mut self, code: u8) {
+        if code == b'\n' {
+            self.x = 0;
+            self.y += 1;
+        } else if code == b'\x08' {
+            // Backspace just moves the cursor back; it doesn't erase.
+            if self.x > 0 { self.x -= 1; }
+        } else {
+            let (x, y) = (self.x, self.y);
+            self.cells[y * self.columns + x] = Char::new(code, self.colors);
+            self.mark_dirty(x, y);
+            self.x += 1;
+            if self.x >= self.columns {
+                self.x = 0;
+                self.y += 1;
+            }
+        }
+        if self.y >= self.rows {
+            self.y = self.rows - 1;
+            self.scroll();
+        }
+    }
+
+    /// Remember that the cell at `x`, `y` needs to be redrawn.
+    fn mark_dirty(&mut self, x: usize, y: usize) {
+        self.dirty = Some(match self.dirty {
+            None => Rect::new(x, y, 1, 1),
+            Some(r) => {
+                let left = if x < r.x { x } else { r.x };
+                let top = if y < r.y { y } else { r.y };
+                let right = if x + 1 > r.x + r.width { x + 1 } else { r.x + r.width };
+                let bottom = if y + 1 > r.y + r.height { y + 1 } else { r.y + r.height };
+                Rect::new(left, top, right - left, bottom - top)
+            }
+        });
+    }
+
+    /// Draw every cell which has changed since the last flush.
+    pub fn flush(&mut self) {
+        if let Some(r) = self.dirty.take() {
+            for y in r.y..r.y + r.height {
+                for x in r.x..r.x + r.width {
+                    self.draw_cell(x, y);
+                }
+            }
+        }
+    }
+
+    /// Draw the cell at `x`, `y` into the framebuffer.
+    fn draw_cell(&mut self, x: usize, y: usize) {
+        let c = self.cells[y * self.columns + x];
+        let key = cache_key(c);
+        let slot = key as usize % CACHE_SLOTS;
+        if self.cache[slot].key != key {
+            self.render_glyph(slot, c);
+        }
+
+        let (width, height) = (self.glyphs.width, self.glyphs.height);
+        let pixels = &self.cache[slot].pixels;
+        for row in 0..height {
+            let dest = self.framebuffer.row(y * height + row);
+            unsafe {
+                ptr::copy_nonoverlapping(pixels[row * width..].as_ptr(),
+                                         dest.offset((x * width) as isize),
+                                         width);
+            }
+        }
+    }
+
+    /// Expand `c` into pixels, and store it in cache slot `slot`.
+    fn render_glyph(&mut self, slot: usize, c: Char) {
+        let fore = PALETTE[c.colors.foreground() as usize];
+        let back = PALETTE[c.colors.background() as usize];
+        // Show glyphs our font doesn't have as '?'.
+        let code = if (c.code as usize) < self.glyphs.count {
+            c.code as usize
+        } else {
+            b'?' as usize
+        };
+
+        let glyphs = &self.glyphs;
+        let entry = &mut self.cache[slot];
+        entry.key = cache_key(c);
+        entry.pixels.clear();
+        for y in 0..glyphs.height {
+            for x in 0..glyphs.width {
+                entry.pixels.push(if glyphs.is_set(code, x, y) { fore } else { back });
+            }
+        }
+    }
+
+    /// Move everything up one line, and clear the bottom line.
+    fn scroll(&mut self) {
+        // Draw any pending changes first, so that what we move is correct.
+        self.flush();
+
+        let fb = self.framebuffer;
+        let line_bytes = self.glyphs.height * fb.pitch();
+        unsafe {
+            ptr::copy(fb.row(self.glyphs.height) as *const u8,
+                      fb.row(0) as *mut u8,
+                      (self.rows - 1) * line_bytes);
+        }
+        fb.fill_rect(0, (self.rows - 1) * self.glyphs.height,
+                     fb.width(), self.glyphs.height,
+                     PALETTE[self.colors.background() as usize]);
+
+        let columns = self.columns;
+        for i in columns..self.cells.len() {
+            self.cells[i - columns] = self.cells[i];
+        }
+        let blank = Char::new(b' ', self.colors);
+        let len = self.cells.len();
+        for cell in self.cells[len - columns..].iter_mut() {
+            *cell = blank;
+        }
+    }
+}
+
+impl fmt::Write for Terminal {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_byte(cp437::from_char(c));
+        }
+        self.flush();
+        Ok(())
+    }
+}
+
+/// Our framebuffer terminal, if we're in a graphics mode.
+pub static TERMINAL: Mutex<Option<Terminal>> = Mutex::new(None);
+
+/// If the display driver put us in a graphics mode, start drawing our
+/// console on the framebuffer.  Requires the heap.
+pub fn initialize() {
+    let framebuffer = match vbe::framebuffer() {
+        Some(framebuffer) => framebuffer,
+        None => return,
+    };
+    let module = multiboot::info().and_then(|i| i.module("font"));
+    let glyphs = match module.map(|m| Glyphs::parse_psf(m.data)) {
+        Some(Ok(glyphs)) => glyphs,
+        other => {
+            if let Some(Err(err)) = other {
+                println!("fbterm: {}; using the VGA font", err);
+            }
+            match vbe::with_text_font(Glyphs::from_vga) {
+                Some(glyphs) => glyphs,
+                None => return,
+            }
+        }
+    };
+
+    *TERMINAL.lock() = Some(Terminal::new(framebuffer, glyphs));
+    let (columns, rows) = TERMINAL.lock().as_ref().unwrap().size();
+    println!("Console: {}x{} framebuffer, {}x{} characters",
+             framebuffer.width(), framebuffer.height(), columns, rows);
+}
@@ -1,37 +1,327 @@
-//! Configuration of our system allocator.
-//!
-//! There's a good chance that `HEAP_BOTTOM` and `HEAP_TOP` stuff involves
-//! undefined behavior and thus nasal demons as far as `rustc` is
-//! concerned.
+//! Configuration of our system allocator.  The memory comes from the
+//! heap regions that `boot.asm` reserves; see `kernel_layout`.
 
-use alloc_buddy_simple::{FreeBlock, initialize_allocator};
+use core::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_BOOL_INIT,
+                        ATOMIC_USIZE_INIT, Ordering};
+use alloc_buddy_simple::{initialize_allocator, free_blocks_per_order,
+                         try_free_blocks_per_order, allocation_stats};
+use alloc_buddy_simple::{add_heap, free_blocks_per_order_in, AllocStats,
+                         HeapId, scrub, zeroed_allocations_in};
+use alloc_buddy_simple::{set_watermarks, set_watermark_handler, usage_in,
+                         GENERAL_HEAP, MAX_WATERMARKS};
+use spin::Mutex;
+use alloc_buddy_simple::MAX_ORDERS;
+pub use alloc_buddy_simple::MIN_BLOCK_SIZE;
+#[cfg(feature = "alloc-timing")]
+use alloc_buddy_simple::{set_cycle_counter, timing_in, OrderTiming};
 
-extern {
-    /// The bottom of our heap.  Declared in `boot.asm` so that we can
-    /// easily specify alignment constraints.  We declare this as a single
-    /// variable of type `u8`, because that's how we get it to link, but we
-    /// only want to take the address of it.
-    static mut HEAP_BOTTOM: u8;
+#[cfg(feature = "alloc-timing")]
+use arch::cpu;
+use arch::interrupts;
+use arch::kernel_layout;
+use arch::multiboot;
+use config;
+use memtest;
+use util;
 
-    /// The top of our heap.  This is actually "one beyond" the heap space,
-    /// so storing things here would be Very Bad.  Even just declaring this
-    /// probably invokes undefined behavior, but our fingers are crossed.
-    static mut HEAP_TOP: u8;
+/// ISA DMA can only reach the first 16MB of physical memory.
+const ISA_DMA_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Our heap for ISA DMA buffers, once it's set up.
+static DMA_HEAP: Mutex<Option<HeapId>> = Mutex::new(None);
+
+/// The address range we actually gave to the allocator.  This may be
+/// smaller than `kernel_layout::heap()` if `memtest` found bad memory.
+static BOTTOM: AtomicUsize = ATOMIC_USIZE_INIT;
+static TOP: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The smallest heap we're willing to run with after quarantining bad
+/// memory.
+const MIN_HEAP_SIZE: usize = 256 * 1024;
+
+/// Should we zero free memory while we're idle?  Set by booting with
+/// `heapscrub`.
+static SCRUB: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// How much memory we zero at a time.  We hold the heap lock with
+/// interrupts off while we do it, so this bounds the delay we add.
+const SCRUB_BUDGET: usize = 4096;
+
+/// The address range covered by our heap.
+pub fn bounds() -> (usize, usize) {
+    (BOTTOM.load(Ordering::SeqCst), TOP.load(Ordering::SeqCst))
+}
+
+/// Pair each entry of `counts` with the corresponding block size.
+fn with_sizes(counts: [usize; MAX_ORDERS]) -> [(usize, usize); MAX_ORDERS] {
+    let mut result = [(0, 0); MAX_ORDERS];
+    for (order, &count) in counts.iter().enumerate() {
+        result[order] = (MIN_BLOCK_SIZE << order, count);
+    }
+    result
+}
+
+/// Total the free bytes in `blocks`, and find the largest free block.
+fn summarize(blocks: [(usize, usize); MAX_ORDERS]) -> (usize, usize) {
+    blocks.iter().fold((0, 0), |(total, largest), &(size, count)| {
+        (total + size * count, if count > 0 { size } else { largest })
+    })
+}
+
+/// The number of free blocks of each size in the heap, as `(size,
+/// count)` pairs from smallest to largest.  Sizes with no free blocks are
+/// included.
+pub fn free_blocks() -> [(usize, usize); MAX_ORDERS] {
+    with_sizes(free_blocks_per_order())
+}
+
+/// The total number of free bytes in the heap, and the size of the
+/// largest free block.  The gap between the two is a rough measure of
+/// fragmentation.
+pub fn free_summary() -> (usize, usize) {
+    summarize(free_blocks())
+}
+
+/// Like `free_summary`, but for our ISA DMA heap.
+pub fn dma_free_summary() -> (usize, usize) {
+    summarize(with_sizes(free_blocks_per_order_in(dma_heap())))
+}
+
+/// Like `free_summary`, but safe to call while panicking: returns `None`
+/// if the heap is locked.
+pub fn try_free_summary() -> Option<(usize, usize)> {
+    try_free_blocks_per_order().map(|counts| summarize(with_sizes(counts)))
+}
+
+/// The number of bytes allocated from the heap, and its size.
+pub fn usage() -> (usize, usize) {
+    usage_in(GENERAL_HEAP)
+}
+
+/// Statistics about every allocation made since boot.
+pub fn stats() -> AllocStats {
+    allocation_stats().expect("heap not initialized")
 }
 
-/// An array of free lists which we pass to the system allocator at system
-/// startup time.
-static mut FREE_LISTS: [*mut FreeBlock; 19] = [0 as *mut _; 19];
+/// The heap usage levels we warn about, in percent, unless the kernel
+/// command line says otherwise with `heapwatermarks=<percent>,...`.
+const DEFAULT_WATERMARKS: [usize; 2] = [75, 90];
+
+/// The watermarks we're using, in percent.
+static WATERMARKS: Mutex<([usize; MAX_WATERMARKS], usize)> =
+    Mutex::new(([0; MAX_WATERMARKS], 0));
+
+/// How many watermarks the general heap is above, according to the
+/// allocator, and according to the last `check_watermarks`.
+static WATERMARK_LEVEL: AtomicUsize = ATOMIC_USIZE_INIT;
+static REPORTED_LEVEL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The most subsystems which can ask to hear about heap pressure.
+const MAX_PRESSURE_HANDLERS: usize = 8;
+
+/// Who wants to hear about heap pressure.
+static PRESSURE_HANDLERS: Mutex<[Option<fn(usize)>; MAX_PRESSURE_HANDLERS]> =
+    Mutex::new([None; MAX_PRESSURE_HANDLERS]);
+
+/// Ask for `handler` to be called whenever heap usage crosses one of our
+/// watermarks.  It gets the highest watermark we're now above, in
+/// percent, or 0 if we're below them all.  Subsystems with caches should
+/// shrink them when this goes up.  Handlers run from the main loop, so
+/// they may allocate and free memory.
+pub fn on_pressure(handler: fn(usize)) -> Result<(), &'static str> {
+    let mut handlers = PRESSURE_HANDLERS.lock();
+    let slot = try!(handlers.iter_mut().find(|h| h.is_none())
+                    .ok_or("too many heap pressure handlers"));
+    *slot = Some(handler);
+    Ok(())
+}
+
+/// Called by the allocator, possibly from an interrupt handler, so we
+/// only take note.
+fn watermark_crossed(id: HeapId, _old: usize, new: usize) {
+    if id == GENERAL_HEAP {
+        WATERMARK_LEVEL.store(new, Ordering::SeqCst);
+    }
+}
+
+/// The percentage for watermark `level`, where 0 means below them all.
+fn level_percent(level: usize) -> usize {
+    let watermarks = WATERMARKS.lock();
+    if level == 0 { 0 } else { watermarks.0[level - 1] }
+}
+
+/// Report any watermarks we've crossed since last time, and tell our
+/// pressure handlers.  Call this regularly from the main loop.
+pub fn check_watermarks() {
+    let level = WATERMARK_LEVEL.load(Ordering::SeqCst);
+    if REPORTED_LEVEL.swap(level, Ordering::SeqCst) == level {
+        return;
+    }
+    let (in_use, size) = usage();
+    let percent = level_percent(level);
+    if level == 0 {
+        println!("heap: usage back below warning levels ({} of {} bytes)",
+                 in_use, size);
+    } else {
+        println!("WARNING: heap usage over {}% ({} of {} bytes)",
+                 percent, in_use, size);
+    }
+    let handlers = *PRESSURE_HANDLERS.lock();
+    for handler in handlers.iter().filter_map(|h| *h) {
+        handler(percent);
+    }
+}
+
+/// Parse `heapwatermarks=<percent>,...` from the kernel command line, or
+/// use our defaults.
+fn watermark_percents() -> ([usize; MAX_WATERMARKS], usize) {
+    let mut percents = [0; MAX_WATERMARKS];
+    let option = multiboot::info().and_then(|i| i.option("heapwatermarks"));
+    if let Some(option) = option {
+        let mut count = 0;
+        for part in option.split(',').filter(|p| !p.is_empty()) {
+            match util::parse_number(part) {
+                Some(p) if p > 0 && p <= 100 && count < MAX_WATERMARKS => {
+                    percents[count] = p;
+                    count += 1;
+                }
+                _ => {
+                    println!("heapwatermarks: ignoring {:?}", part);
+                }
+            }
+        }
+        return (percents, count);
+    }
+    percents[..DEFAULT_WATERMARKS.len()].copy_from_slice(&DEFAULT_WATERMARKS);
+    (percents, DEFAULT_WATERMARKS.len())
+}
+
+/// Zero a little free memory, if we were asked to, so that allocating
+/// zeroed DMA buffers is cheap later.  Call this when there's nothing else
+/// to do.  Returns false if there was no work, so that the caller can go
+/// to sleep.
+pub fn scrub_step() -> bool {
+    if !SCRUB.load(Ordering::Relaxed) {
+        return false;
+    }
+    // Interrupt handlers allocate, so they mustn't find the heap locked.
+    interrupts::without_interrupts(|| scrub(SCRUB_BUDGET) > 0)
+}
+
+/// How many zeroed DMA allocations we've made, and how many of those
+/// found memory we'd already scrubbed.
+pub fn dma_zeroed_allocations() -> (usize, usize) {
+    zeroed_allocations_in(dma_heap())
+}
+
+/// Run a memory test over the heap if the kernel command line asks for
+/// one, and return the largest part of the heap that passed.
+unsafe fn test_memory(bottom: usize, size: usize) -> (usize, usize) {
+    let wanted = config::SELFTESTS && multiboot::info()
+        .and_then(|i| i.option("memtest"))
+        .is_some();
+    if !wanted {
+        return (bottom, size);
+    }
+
+    println!("Testing heap memory...");
+    let report = memtest::test(bottom, bottom + size);
+    for frame in report.bad_frames() {
+        println!("  bad frame at 0x{:x}", frame);
+    }
+    if report.overflowed {
+        println!("  (and more; giving up on this memory)");
+    }
+    println!("  {} frames tested, {} bad", report.tested,
+             report.bad_frames().len());
+
+    let (bottom, size) = report.largest_clean_block(bottom, size, MIN_HEAP_SIZE)
+        .expect("not enough good memory for a heap");
+    if report.bad_frames().len() > 0 {
+        println!("  using 0x{:x}-0x{:x} for the heap", bottom, bottom + size);
+    }
+    (bottom, size)
+}
 
 /// Initialze our system heap.  Once this is done, it's theoretically safe
 /// to use functions in libcollection that allocate memory.
 pub unsafe fn initialize() {
-    // Convert our fake variables into the pointers we wanted in the first
-    // place.  Again, there may be some risk of undefined behavior here.
-    let heap_bottom_ptr = &mut HEAP_BOTTOM as *mut _;
-    let heap_top_ptr = &mut HEAP_TOP as *mut _;
-
-    // Initialize our main allocator library.
-    let heap_size = heap_top_ptr as usize - heap_bottom_ptr as usize;
-    initialize_allocator(heap_bottom_ptr, heap_size, &mut FREE_LISTS);
+    // Initialize our main allocator library, leaving out any bad memory.
+    let heap = kernel_layout::heap();
+    let (bottom, size) = test_memory(heap.start, heap.size());
+    BOTTOM.store(bottom, Ordering::SeqCst);
+    TOP.store(bottom + size, Ordering::SeqCst);
+    initialize_allocator(bottom as *mut u8, size);
+
+    // Set up our ISA DMA heap.  We identity map memory, so these addresses
+    // are also physical addresses.
+    let dma_heap = kernel_layout::dma_heap();
+    let dma = add_heap("dma", dma_heap.start as *mut u8, dma_heap.size(),
+                       ISA_DMA_LIMIT)
+        .expect("could not create ISA DMA heap");
+    *DMA_HEAP.lock() = Some(dma);
+
+    let scrub = multiboot::info().and_then(|i| i.option("heapscrub")).is_some();
+    SCRUB.store(scrub, Ordering::Relaxed);
+
+    // Warn before we run out of memory, rather than after.
+    let (percents, count) = watermark_percents();
+    let mut levels = [0; MAX_WATERMARKS];
+    for i in 0..count {
+        levels[i] = size / 100 * percents[i];
+    }
+    set_watermark_handler(watermark_crossed);
+    match set_watermarks(GENERAL_HEAP, &levels[..count]) {
+        Ok(()) => *WATERMARKS.lock() = (percents, count),
+        Err(err) => println!("heapwatermarks: {}", err),
+    }
+
+    start_timing();
+}
+
+/// Time heap operations with the TSC.
+#[cfg(feature = "alloc-timing")]
+fn start_timing() {
+    set_cycle_counter(cpu::rdtsc);
+}
+
+#[cfg(not(feature = "alloc-timing"))]
+fn start_timing() {}
+
+/// Print one line of `print_timing`.
+#[cfg(feature = "alloc-timing")]
+fn print_order_timing(size: usize, operation: &str, timing: &OrderTiming) {
+    if let (Some(min), Some(avg), Some(p99), Some(max)) =
+        (timing.min(), timing.average(), timing.percentile(99), timing.max())
+    {
+        println!("{:>10} {:>7} {:>8} {:>8} {:>8} {:>8} {:>8}",
+                 size, operation, timing.count(), min, avg, p99, max);
+    }
+}
+
+/// Show how many cycles allocating and deallocating each size of block
+/// has taken on our main heap.
+#[cfg(feature = "alloc-timing")]
+pub fn print_timing() {
+    println!("{:>10} {:>7} {:>8} {:>8} {:>8} {:>8} {:>8}",
+             "block size", "op", "count", "min", "avg", "p99", "max");
+    for order in 0..MAX_ORDERS {
+        let (allocate, deallocate) = timing_in(GENERAL_HEAP, order);
+        let size = MIN_BLOCK_SIZE << order;
+        print_order_timing(size, "alloc", &allocate);
+        print_order_timing(size, "free", &deallocate);
+    }
+    println!("(times in TSC cycles)");
+}
+
+#[cfg(not(feature = "alloc-timing"))]
+pub fn print_timing() {
+    println!("Build with the alloc-timing feature to time heap operations.");
+}
+
+/// The heap to use for ISA DMA buffers, which must lie below 16MB.  Most
+/// code should use `dma::DmaBox` or `dma::DmaVec` instead of allocating
+/// from it directly.
+pub fn dma_heap() -> HeapId {
+    DMA_HEAP.lock().expect("heap not initialized")
 }
@@ -0,0 +1,66 @@
+//! A boot-time check that our code is what we linked.
+//!
+//! After linking, the Makefile computes the CRC32 of `.text` and stores it
+//! in `EXPECTED_CRC`.  At boot, we compute it again.  If they differ,
+//! something corrupted the kernel on its way into memory, such as bad RAM
+//! or a botched copy by the boot loader, and it's better to say so now
+//! than to chase impossible bugs later.
+
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+use arch::kernel_layout;
+use hash::crc32;
+
+/// The CRC32 of `.text`, as computed by the Makefile, or 0 if we were
+/// linked some other way.
+#[link_section = ".checksum"]
+#[no_mangle]
+pub static EXPECTED_CRC: u32 = 0;
+
+/// The CRC32 we computed at boot.
+static ACTUAL_CRC: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The CRC32 the Makefile recorded, if any.
+fn expected() -> Option<u32> {
+    // The Makefile patches this after the compiler has seen it, so the
+    // compiler mustn't assume it's still 0.
+    match unsafe { ptr::read_volatile(&EXPECTED_CRC) } {
+        0 => None,
+        crc => Some(crc),
+    }
+}
+
+/// Does our code match what we linked?  Returns true if we don't know.
+pub fn is_intact() -> bool {
+    match expected() {
+        Some(crc) => ACTUAL_CRC.load(Ordering::Relaxed) as u32 == crc,
+        None => true,
+    }
+}
+
+/// Check the CRC32 of `.text`, and complain loudly if it's wrong.
+pub fn check() {
+    let crc = crc32(unsafe { kernel_layout::text().as_slice() });
+    ACTUAL_CRC.store(crc as usize, Ordering::Relaxed);
+    if !is_intact() {
+        println!("WARNING: kernel .text CRC32 is {}", Summary);
+        println!("Our code is corrupt: suspect bad RAM or a damaged kernel \
+                  image.");
+    }
+}
+
+/// Displays the result of our check, for `version` and the like.
+pub struct Summary;
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let actual = ACTUAL_CRC.load(Ordering::Relaxed) as u32;
+        match expected() {
+            Some(crc) if crc == actual => write!(f, "{:08x} (ok)", actual),
+            Some(crc) => write!(f, "{:08x} (expected {:08x})", actual, crc),
+            None => write!(f, "{:08x} (not recorded at build time)", actual),
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Non-fatal kernel assertions, for flagging suspicious states during
+//! bring-up without killing the run.
+//!
+//! When a `kassert!` fails, we execute `int3` with a pointer to the
+//! assertion in `rax` and a magic number in `rdx`.  Our breakpoint
+//! handler recognizes these, prints the message and location on COM1, and
+//! asks there whether to continue or halt.  Boot with
+//! `kassert=continue` or `kassert=halt` to decide ahead of time.  Going
+//! through `int3` means that a debugger attached to QEMU sees every
+//! failed assertion, too.
+
+use core::fmt::{self, Write};
+
+use arch::{multiboot, serial};
+
+/// What we put in `rdx` to say that `rax` points to an `Assertion`.
+const MAGIC: u64 = 0x6B61_7373_6572_7421;
+
+/// A failed assertion.  Only `pub` because `kassert!` needs it.
+pub struct Assertion<'a> {
+    pub file: &'static str,
+    pub line: u32,
+    pub message: fmt::Arguments<'a>,
+}
+
+/// Report a failed assertion via our breakpoint handler.  Returns if the
+/// user chooses to continue.
+pub fn fail(assertion: &Assertion) {
+    unsafe {
+        asm!("int3"
+             :: "{rax}"(assertion as *const Assertion as u64), "{rdx}"(MAGIC)
+             : "memory"
+             : "volatile");
+    }
+}
+
+/// Called from our breakpoint handler with the values of `rax` and `rdx`
+/// at the breakpoint.  Returns `false` if this isn't a `kassert!`.
+/// Otherwise, panics or returns `true` if we should carry on.
+pub fn handle_breakpoint(rax: u64, rdx: u64) -> bool {
+    if rdx != MAGIC || rax == 0 {
+        return false;
+    }
+    let assertion = unsafe { &*(rax as *const Assertion) };
+    with_com1(|port| {
+        let _ = write!(port, "kassert failed at {}:{}: {}\n",
+                       assertion.file, assertion.line, assertion.message);
+    });
+
+    let choice = multiboot::info().and_then(|i| i.option("kassert"));
+    let halt = match choice {
+        Some("continue") => false,
+        Some("halt") => true,
+        _ => ask_whether_to_halt(),
+    };
+    if halt {
+        panic!("kassert failed: {}", assertion.message);
+    }
+    true
+}
+
+/// Run `f` with COM1.  The code we interrupted may hold the `COM1` lock,
+/// or the console locks, so we never wait: if `COM1` is taken, we use a raw
+/// handle the way the panic screen does.
+fn with_com1<R, F: FnOnce(&mut serial::ComPort) -> R>(f: F) -> R {
+    match serial::COM1.try_lock() {
+        Some(mut port) => f(&mut port),
+        None => f(&mut unsafe { serial::raw_com1() }),
+    }
+}
+
+/// Ask on the serial console whether to halt.  We're in an interrupt
+/// handler, so we poll.
+fn ask_whether_to_halt() -> bool {
+    with_com1(|port| {
+        let _ = port.write_str("Continue or halt? [c/h]\n");
+        loop {
+            match port.read_byte() {
+                Some(b'c') | Some(b'C') => return false,
+                Some(b'h') | Some(b'H') => return true,
+                _ => {}
+            }
+        }
+    })
+}
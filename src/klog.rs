@@ -0,0 +1,108 @@
+//! The kernel log: a copy of everything we've printed to the console,
+//! kept in a fixed-size ring buffer so that it's available from the very
+//! start of boot, and so that output which has scrolled away isn't lost.
+//!
+//! We store raw text, and number lines as we go.  When the buffer fills
+//! up, we throw away the oldest line.
+
+use collections::vec::Vec;
+use spin::Mutex;
+
+/// The size of our log buffer, in bytes.
+pub const LOG_SIZE: usize = 16 * 1024;
+
+struct Log {
+    /// Our text, stored as a ring buffer.
+    buffer: [u8; LOG_SIZE],
+    /// The index of our oldest byte.
+    start: usize,
+    /// The number of bytes in use.
+    len: usize,
+    /// The sequence number of the oldest line still in the buffer.
+    first_seq: usize,
+}
+
+impl Log {
+    /// Append a byte, making room if necessary.
+    fn push(&mut self, byte: u8) {
+        if self.len == LOG_SIZE {
+            self.drop_oldest_line();
+        }
+        self.buffer[(self.start + self.len) % LOG_SIZE] = byte;
+        self.len += 1;
+    }
+
+    /// Throw away everything up to and including the first newline.
+    fn drop_oldest_line(&mut self) {
+        while self.len > 0 {
+            let byte = self.buffer[self.start];
+            self.start = (self.start + 1) % LOG_SIZE;
+            self.len -= 1;
+            if byte == b'\n' {
+                break;
+            }
+        }
+        self.first_seq += 1;
+    }
+}
+
+static LOG: Mutex<Log> = Mutex::new(Log {
+    buffer: [0; LOG_SIZE],
+    start: 0,
+    len: 0,
+    first_seq: 0,
+});
+
+/// Append `text` to the log.
+pub fn write(text: &str) {
+    let mut log = LOG.lock();
+    for &byte in text.as_bytes() {
+        log.push(byte);
+    }
+}
+
+/// Copy out the contents of the log, along with the sequence number of the
+/// first line.  We copy so that callers can print the log without holding
+/// our lock (since printing writes to the log).
+pub fn contents() -> (usize, Vec<u8>) {
+    let log = LOG.lock();
+    let mut text = Vec::with_capacity(log.len);
+    for i in 0..log.len {
+        text.push(log.buffer[(log.start + i) % LOG_SIZE]);
+    }
+    (log.first_seq, text)
+}
+
+/// Call `f` with the last `lines` lines of the log, in one or two pieces,
+/// without allocating.  This is for crash reports, so if somebody holds our
+/// lock (possibly because we panicked while logging), we give up and
+/// return false.
+pub fn try_tail<F: FnMut(&[u8])>(lines: usize, mut f: F) -> bool {
+    let log = match LOG.try_lock() {
+        Some(log) => log,
+        None => return false,
+    };
+
+    // Walk backwards to the start of the line we want, ignoring any
+    // newline at the very end.
+    let mut skip = log.len;
+    let mut seen = 0;
+    while skip > 0 {
+        let byte = log.buffer[(log.start + skip - 1) % LOG_SIZE];
+        if byte == b'\n' && skip != log.len {
+            seen += 1;
+            if seen == lines { break; }
+        }
+        skip -= 1;
+    }
+
+    let first = (log.start + skip) % LOG_SIZE;
+    let len = log.len - skip;
+    if first + len <= LOG_SIZE {
+        f(&log.buffer[first..first + len]);
+    } else {
+        f(&log.buffer[first..]);
+        f(&log.buffer[..first + len - LOG_SIZE]);
+    }
+    true
+}
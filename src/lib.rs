@@ -1,12 +1,14 @@
-#![feature(asm, const_fn, lang_items, unique, collections)]
+#![feature(alloc, asm, const_fn, unique, collections)]
 #![no_std]
 
+extern crate alloc;
 extern crate collections;
 
 extern crate alloc_buddy_simple;
 extern crate cpuio;
+extern crate lang_items_toyos;
 extern crate pic8259_simple;
-extern crate rlibc;
+extern crate spsc_ring;
 extern crate spin;
 
 #[macro_use(int)]
@@ -16,30 +18,106 @@ use core::fmt::Write;
 
 // These need to be visible to the linker, so we need to export them.
 pub use arch::interrupts::rust_interrupt_handler;
-pub use runtime_glue::*;
+pub use lang_items_toyos::_Unwind_Resume;
 
 #[macro_use]
 mod macros;
-mod runtime_glue;
 mod heap;
+mod kassert;
+mod memtest;
 mod arch;
+mod config;
 mod console;
+mod cp437;
+mod crash_dump;
+mod dma;
+mod fbterm;
+mod hash;
+mod integrity;
+mod klog;
+mod map;
+mod panic_screen;
+mod ratelimit;
+mod regs;
+mod banner;
+mod bmp;
+mod build_info;
+mod shell;
+mod splash;
+mod status_bar;
+mod sync;
+mod util;
+mod wav;
 
 
+/// Apply `earlyserial`, `earlyserial=<baud>` or `earlyserial=off` from the
+/// kernel command line.
+fn early_serial_options() {
+    match arch::multiboot::info().and_then(|i| i.option("earlyserial")) {
+        None => {}
+        Some("off") => arch::serial::early_disable(),
+        Some("") => { let _ = arch::serial::early_initialize(None); }
+        Some(baud) => {
+            let baud = util::parse_number(baud).map(|b| b as u32);
+            let _ = arch::serial::early_initialize(baud);
+        }
+    }
+}
+
 #[no_mangle]
-pub extern "C" fn rust_main() {
+pub extern "C" fn rust_main(multiboot_info: usize) {
     use arch::vga::{SCREEN, ColorScheme};
     use arch::vga::Color::*;
 
+    // Get a serial console going before we touch anything else, so that we
+    // can see how far we got if something goes wrong.
+    if config::EARLY_SERIAL {
+        let _ = arch::serial::early_initialize(None);
+    }
+    unsafe { arch::multiboot::initialize(multiboot_info); }
+    early_serial_options();
+    early_println!("boot: cpu features");
+
+    // Use the fastest memcpy and friends this CPU supports.
+    arch::mem::initialize();
+
+    // Show our panic screen if anything goes wrong from here on.
+    lang_items_toyos::set_panic_hook(panic_screen::show);
+    lang_items_toyos::install_oom_handler();
+
     SCREEN.lock()
           .clear(DarkGrey)
           .set_colors(ColorScheme::new(Yellow, DarkGrey));
     println!("Hello, world!");
 
+    // Make sure our code arrived intact before we run much of it.
+    early_println!("boot: integrity check");
+    integrity::check();
+    // Pick a random key for hash tables.
+    hash::initialize();
+
     unsafe {
+        arch::reset::initialize();
+        #[cfg(feature = "trace-io")]
+        arch::x86_64::io_trace::initialize();
+        early_println!("boot: timer");
+        arch::timer::initialize();
+        arch::latency::initialize();
+        arch::keyboard::initialize();
+        early_println!("boot: interrupts");
         arch::interrupts::initialize();
+        arch::serial::enable_receive_interrupts();
+        early_println!("boot: paging");
+        arch::paging::initialize();
+        arch::vga::map_text_buffer().expect("could not map VGA text buffer");
+        early_println!("boot: heap");
         heap::initialize();
     }
+    early_println!("boot: heap ready");
+
+    // Now that we have a heap, keep a few screenfuls of history around for
+    // Shift+PageUp.
+    SCREEN.lock().enable_scrollback(8 * arch::vga::HEIGHT);
 
     let mut vec = collections::vec::Vec::<u8>::new();
     vec.push(1);
@@ -47,12 +125,51 @@ pub extern "C" fn rust_main() {
     vec.push(3);
     println!("Hey, I made a vector in kernel space! {:?}", vec);
 
-    println!("Scanning PCI bus...");
-    for function in arch::pci::functions() {
-        println!("{}", function);
-    }
+    early_println!("boot: PCI drivers");
+    arch::pci::bind_drivers();
+    // If the display driver switched to a graphics mode, show our splash
+    // screen while we finish booting.
+    splash::show();
+    banner::print();
+    splash::progress(1, 3);
 
+    early_println!("boot: done");
     println!("Running.");
+    // The shell may run a startup script.  Like any other shell command,
+    // it runs with interrupts on, so the clock keeps ticking and input
+    // typed meanwhile is queued for the shell.
+    if config::SHELL {
+        shell::initialize();
+    }
+    splash::progress(2, 3);
+    status_bar::initialize();
+    splash::progress(3, 3);
+    splash::finish();
+    // From here on, if we're in a graphics mode, the console is drawn on
+    // the framebuffer.
+    fbterm::initialize();
 
-    loop {}
+    // Feed keyboard and serial input to our shell.  The keyboard and COM1
+    // interrupt handlers queue their input for us, and we also poll the
+    // serial port, so we only sleep once we've drained it all, and (when
+    // booted with `heapscrub`) once we've zeroed all our free memory.
+    loop {
+        // Read input with interrupts off, so that the COM1 interrupt
+        // handler can't queue a byte between us checking its queue and
+        // polling the UART.  But run the shell with them on, so that a
+        // slow command doesn't stop the clock or drop keystrokes.
+        let input = arch::interrupts::without_interrupts(console::read_input);
+        let got_input = input.is_some();
+        if let Some(input) = input {
+            if config::SHELL { shell::handle_input(input); }
+        }
+        // Pressure handlers may allocate, so keep interrupt handlers away
+        // from the heap lock.
+        arch::interrupts::without_interrupts(heap::check_watermarks);
+        arch::serial::report_modem_changes();
+        arch::interrupts::report_unknown_interrupts();
+        if !got_input && !heap::scrub_step() {
+            arch::timer::idle();
+        }
+    }
 }
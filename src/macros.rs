@@ -18,3 +18,58 @@ macro_rules! println {
     ($fmt:expr) => (print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
 }
+
+/// Print a line straight to COM1, if the early serial console is on.  This
+/// works before we have a heap or interrupts, and it doesn't depend on
+/// anything `println!` uses, so it's for tracking down boot failures.
+macro_rules! early_println {
+    ($($arg:tt)*) => ($crate::arch::serial::early_print(format_args!($($arg)*)));
+}
+
+/// Check that `cond` is true, and if it isn't, report it and let the user
+/// decide whether to continue.  See `kassert.rs`.
+///
+/// ```ignore
+/// kassert!(count < limit, "count {} over limit {}", count, limit);
+/// ```
+macro_rules! kassert {
+    ($cond:expr) => (
+        kassert!($cond, "{}", concat!("assertion failed: ", stringify!($cond)))
+    );
+    ($cond:expr, $($arg:tt)+) => ({
+        if !$cond {
+            $crate::kassert::fail(&$crate::kassert::Assertion {
+                file: file!(),
+                line: line!(),
+                message: format_args!($($arg)+),
+            });
+        }
+    });
+}
+
+/// Print a line to our console, at most `max` times every `ms`
+/// milliseconds from this call site.  Identical consecutive messages are folded
+/// into a "last message repeated" line.
+///
+/// ```ignore
+/// log_rate_limited!(5, 1000, "spurious interrupt on IRQ {}", irq);
+/// ```
+macro_rules! log_rate_limited {
+    ($max:expr, $ms:expr, $($arg:tt)*) => ({
+        use $crate::ratelimit::{RateLimit, Verdict};
+        static LIMIT: ::spin::Mutex<RateLimit> =
+            ::spin::Mutex::new(RateLimit::new());
+        let now = $crate::arch::timer::uptime_ms();
+        let verdict = LIMIT.lock().check($max, $ms, now,
+                                         format_args!($($arg)*));
+        if let Verdict::Print { repeated, suppressed } = verdict {
+            if repeated > 0 {
+                println!("(last message repeated {} times)", repeated);
+            }
+            if suppressed > 0 {
+                println!("({} messages suppressed)", suppressed);
+            }
+            println!($($arg)*);
+        }
+    });
+}
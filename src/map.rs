@@ -0,0 +1,239 @@
+//! Maps for kernel subsystems, so that nobody needs to search a `Vec`.
+//!
+//! `HashMap` uses open addressing with linear probing, which keeps each
+//! entry in a single flat table on our heap.  By default it hashes with
+//! `hash::SipHasher13` and our boot key, so keys from outside can't all be
+//! made to collide.  When keys need to stay sorted, or are strings, use
+//! `BTreeMap` or `StringMap` from `liballoc`'s collections.
+//!
+//! None of these maps do any locking, so shared maps need a lock around
+//! them, such as a `sync::RwSpinlock`.  Looking things up never allocates,
+//! so it's fine in interrupt handlers, if the lock allows it.  Inserting and
+//! removing may allocate or free memory, which takes the heap lock.  So
+//! don't change a map from an interrupt handler if the interrupted code
+//! might be using the heap.
+
+use collections::string::String;
+use collections::vec::Vec;
+use core::hash::{BuildHasher, Hash, Hasher};
+use core::mem;
+use core::slice;
+
+use hash::RandomState;
+
+pub use collections::btree_map::BTreeMap;
+
+/// A map from names to values, kept sorted by name.
+pub type StringMap<V> = BTreeMap<String, V>;
+
+/// The smallest table we allocate.
+const MIN_SLOTS: usize = 8;
+
+/// A hash map using open addressing.  We keep the table no more than 3/4
+/// full, so that probe sequences stay short.
+pub struct HashMap<K, V, S = RandomState> {
+    /// Our table.  Its length is 0 or a power of two.
+    slots: Vec<Option<(K, V)>>,
+    len: usize,
+    hash_builder: S,
+}
+
+impl<K: Hash + Eq, V> HashMap<K, V, RandomState> {
+    /// Create an empty map.  This doesn't allocate until the first insert.
+    pub fn new() -> HashMap<K, V, RandomState> {
+        HashMap::with_hasher(RandomState)
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> HashMap<K, V, S> {
+    /// Create an empty map which hashes keys with `hash_builder`.
+    pub fn with_hasher(hash_builder: S) -> HashMap<K, V, S> {
+        HashMap { slots: Vec::new(), len: 0, hash_builder: hash_builder }
+    }
+
+    /// The number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The slot where a search for `key` starts.
+    fn home(&self, key: &K) -> usize {
+        let mut hasher = self.hash_builder.build_hasher();
+        key.hash(&mut hasher);
+        hasher.finish() as usize & (self.slots.len() - 1)
+    }
+
+    /// The index of the slot holding `key`, if any.
+    fn find(&self, key: &K) -> Option<usize> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        let mask = self.slots.len() - 1;
+        let mut i = self.home(key);
+        // We always have empty slots, so this ends.
+        loop {
+            match self.slots[i] {
+                None => return None,
+                Some((ref k, _)) if k == key => return Some(i),
+                Some(_) => i = (i + 1) & mask,
+            }
+        }
+    }
+
+    /// Put an entry in the first free slot after its home.  The key must
+    /// not already be present, and there must be room.
+    fn insert_new(&mut self, key: K, value: V) {
+        let mask = self.slots.len() - 1;
+        let mut i = self.home(&key);
+        while self.slots[i].is_some() {
+            i = (i + 1) & mask;
+        }
+        self.slots[i] = Some((key, value));
+        self.len += 1;
+    }
+
+    /// Move everything into a table with `count` slots.
+    fn resize(&mut self, count: usize) {
+        let mut slots = Vec::with_capacity(count);
+        for _ in 0..count {
+            slots.push(None);
+        }
+        let old = mem::replace(&mut self.slots, slots);
+        self.len = 0;
+        for (key, value) in old.into_iter().filter_map(|slot| slot) {
+            self.insert_new(key, value);
+        }
+    }
+
+    /// Insert a value, returning the old value for `key` if there was one.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(i) = self.find(&key) {
+            let entry = self.slots[i].as_mut().unwrap();
+            return Some(mem::replace(&mut entry.1, value));
+        }
+        if (self.len + 1) * 4 > self.slots.len() * 3 {
+            let count = if self.slots.is_empty() {
+                MIN_SLOTS
+            } else {
+                self.slots.len() * 2
+            };
+            self.resize(count);
+        }
+        self.insert_new(key, value);
+        None
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.find(key).map(move |i| &self.slots[i].as_ref().unwrap().1)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        match self.find(key) {
+            Some(i) => Some(&mut self.slots[i].as_mut().unwrap().1),
+            None => None,
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.find(key).is_some()
+    }
+
+    /// Remove `key`, returning its value if it was present.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let mut hole = match self.find(key) {
+            Some(i) => i,
+            None => return None,
+        };
+        let (_, value) = self.slots[hole].take().unwrap();
+        self.len -= 1;
+
+        // Linear probing can't leave holes in the middle of a run of
+        // entries, or searches would stop early.  So move later entries
+        // back into the hole, unless that would put them before their
+        // home slot.
+        let mask = self.slots.len() - 1;
+        let mut i = (hole + 1) & mask;
+        loop {
+            let home = match self.slots[i] {
+                None => break,
+                Some((ref k, _)) => self.home(k),
+            };
+            if (i.wrapping_sub(home) & mask) >= (i.wrapping_sub(hole) & mask) {
+                self.slots[hole] = self.slots[i].take();
+                hole = i;
+            }
+            i = (i + 1) & mask;
+        }
+        Some(value)
+    }
+
+    /// Remove every entry for which `f` returns false.
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&mut self, mut f: F) {
+        let count = self.slots.len();
+        let old = mem::replace(&mut self.slots, Vec::new());
+        self.len = 0;
+        let mut kept = Vec::new();
+        for (key, mut value) in old.into_iter().filter_map(|slot| slot) {
+            if f(&key, &mut value) {
+                kept.push((key, value));
+            }
+        }
+        self.resize(count);
+        for (key, value) in kept {
+            self.insert_new(key, value);
+        }
+    }
+
+    /// Remove every entry, keeping our table.
+    pub fn clear(&mut self) {
+        for slot in self.slots.iter_mut() {
+            *slot = None;
+        }
+        self.len = 0;
+    }
+
+    /// Iterate over our entries, in no particular order.
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter { slots: self.slots.iter() }
+    }
+
+    /// Iterate over our values, in no particular order.
+    pub fn values<'a>(&'a self) -> Values<'a, K, V> {
+        Values { iter: self.iter() }
+    }
+}
+
+/// An iterator over the entries of a `HashMap`.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    slots: slice::Iter<'a, Option<(K, V)>>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        for slot in &mut self.slots {
+            if let Some((ref key, ref value)) = *slot {
+                return Some((key, value));
+            }
+        }
+        None
+    }
+}
+
+/// An iterator over the values of a `HashMap`.
+pub struct Values<'a, K: 'a, V: 'a> {
+    iter: Iter<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.iter.next().map(|(_, value)| value)
+    }
+}
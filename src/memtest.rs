@@ -0,0 +1,115 @@
+//! An optional boot-time memory test, enabled by passing `memtest` on the
+//! kernel command line.
+//!
+//! We only test memory that we're about to hand to an allocator, which at
+//! the moment means the heap.  Each 4K frame gets the classic treatment:
+//! fill it with `0x55`, then `0xAA`, checking each time, and then store
+//! every word's own address in it and check that.  This won't catch
+//! everything a real memory tester would, but it will catch stuck bits and
+//! crossed address lines, which are the usual failures on old hardware.
+
+use core::ptr;
+
+use arch::paging::PAGE_SIZE;
+
+/// The most bad frames we'll remember.  If we find more than this, the
+/// machine has bigger problems.
+pub const MAX_BAD_FRAMES: usize = 64;
+
+/// The results of a memory test.
+pub struct Report {
+    /// The number of frames we tested.
+    pub tested: usize,
+    /// The addresses of bad frames, in increasing order.
+    bad: [usize; MAX_BAD_FRAMES],
+    /// The number of entries of `bad` which are used.
+    bad_count: usize,
+    /// Did we find more bad frames than we could record?
+    pub overflowed: bool,
+}
+
+impl Report {
+    /// The bad frames we found.
+    pub fn bad_frames(&self) -> &[usize] {
+        &self.bad[..self.bad_count]
+    }
+
+    /// Does `start..end` contain any bad frames?
+    fn is_clean(&self, start: usize, end: usize) -> bool {
+        !self.overflowed &&
+            !self.bad_frames().iter().any(|&f| start <= f && f < end)
+    }
+
+    /// Find the largest block inside `base..base+size` that we can get by
+    /// repeatedly halving the block, and that contains no bad frames.
+    /// `size` must be a power of 2.  Returns `None` if we can't find a
+    /// clean block at least `min_size` bytes long.
+    pub fn largest_clean_block(&self, base: usize, size: usize,
+                               min_size: usize)
+        -> Option<(usize, usize)>
+    {
+        if size < min_size {
+            None
+        } else if self.is_clean(base, base + size) {
+            Some((base, size))
+        } else {
+            let half = size / 2;
+            let lower = self.largest_clean_block(base, half, min_size);
+            let upper = self.largest_clean_block(base + half, half, min_size);
+            match (lower, upper) {
+                (Some(l), Some(u)) => Some(if u.1 > l.1 { u } else { l }),
+                (l, u) => l.or(u),
+            }
+        }
+    }
+}
+
+/// Fill the frame at `frame` with `pattern` and check that it reads back.
+unsafe fn check_fill(frame: usize, pattern: u8) -> bool {
+    let words = frame as *mut u64;
+    let value = pattern as u64 * 0x0101_0101_0101_0101;
+    for i in 0..PAGE_SIZE / 8 {
+        ptr::write_volatile(words.offset(i as isize), value);
+    }
+    (0..PAGE_SIZE / 8).all(|i| ptr::read_volatile(words.offset(i as isize)) == value)
+}
+
+/// Store each word's address in itself, and check that it reads back.
+unsafe fn check_own_address(frame: usize) -> bool {
+    let words = frame as *mut u64;
+    for i in 0..PAGE_SIZE / 8 {
+        let word = words.offset(i as isize);
+        ptr::write_volatile(word, word as u64);
+    }
+    (0..PAGE_SIZE / 8).all(|i| {
+        let word = words.offset(i as isize);
+        ptr::read_volatile(word) == word as u64
+    })
+}
+
+/// Test every frame in `start..end`, which must be page-aligned and not in
+/// use for anything else.  This destroys the contents of that memory.
+pub unsafe fn test(start: usize, end: usize) -> Report {
+    let mut report = Report {
+        tested: 0,
+        bad: [0; MAX_BAD_FRAMES],
+        bad_count: 0,
+        overflowed: false,
+    };
+    let mut frame = start;
+    while frame < end {
+        let good = check_fill(frame, 0x55) && check_fill(frame, 0xAA) &&
+            check_own_address(frame);
+        if !good {
+            if report.bad_count < MAX_BAD_FRAMES {
+                report.bad[report.bad_count] = frame;
+                report.bad_count += 1;
+            } else {
+                report.overflowed = true;
+            }
+        }
+        report.tested += 1;
+        frame += PAGE_SIZE;
+    }
+    report
+}
@@ -0,0 +1,115 @@
+//! A full-screen report for kernel panics, mirrored to the serial port.
+//!
+//! By the time we get here, anything could be broken, including whoever
+//! holds the console locks.  So we turn off interrupts and draw using our
+//! own private handles to the VGA text buffer and COM1, and we're careful
+//! not to allocate.  If we've switched into a graphics mode, we switch
+//! back to text mode first.  If asked to, we finish with a crash dump on
+//! the serial port; see `crash_dump`.
+
+use core::fmt::{self, Write};
+use x86;
+
+use arch::{backtrace, interrupts, serial, timer, vbe, vga};
+use arch::vga::{ColorScheme, Rect, Screen};
+use arch::vga::Color::*;
+use build_info;
+use crash_dump;
+use heap;
+
+/// Our normal text colors.
+const COLORS: ColorScheme = ColorScheme::new(White, Blue);
+
+/// Colors for the title bar.
+const TITLE_COLORS: ColorScheme = ColorScheme::new(Blue, LightGrey);
+
+/// Writes to both our raw screen and our raw serial port.
+struct PanicWriter {
+    screen: Screen,
+    serial: serial::ComPort,
+}
+
+impl Write for PanicWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Don't let a broken serial port stop us from drawing the screen.
+        let _ = self.serial.write_str(s);
+        self.screen.write_str(s)
+    }
+}
+
+/// Draw the whole report.
+fn write_report<W: Write>(w: &mut W, msg: fmt::Arguments, file: &str,
+                line: u32) -> fmt::Result {
+    try!(write!(w, "\n\npanicked at {}:{}:\n  {}\n\n", file, line, msg));
+    try!(write!(w, "{}\n\n", build_info::Summary));
+
+    if let Some(ctx) = interrupts::exception_context() {
+        try!(w.write_str("Registers at exception:\n"));
+        try!(ctx.write_registers(w));
+    }
+    let (cr0, cr2, cr3, cr4) = unsafe {
+        (x86::controlregs::cr0(), x86::controlregs::cr2(),
+         x86::controlregs::cr3(), x86::controlregs::cr4())
+    };
+    try!(write!(w, "   cr0={:016x}     cr2={:016x}     cr3={:016x}\n   cr4={:016x}\n\n",
+                cr0, cr2, cr3, cr4));
+
+    try!(w.write_str("Backtrace:"));
+    let mut count = 0;
+    let mut result = Ok(());
+    backtrace::walk(|addr| {
+        // Fit several frames on each line of the screen.
+        let sep = if count % 4 == 0 { "\n  " } else { "  " };
+        if result.is_ok() {
+            result = write!(w, "{}{:016x}", sep, addr);
+        }
+        count += 1;
+    });
+    try!(result);
+    if count == 0 {
+        try!(w.write_str(" (none)"));
+    }
+    try!(w.write_str("\n\n"));
+
+    match heap::try_free_summary() {
+        Some((free, largest)) =>
+            try!(write!(w, "Heap: {}K free, largest block {}K\n",
+                        free / 1024, largest / 1024)),
+        None => try!(w.write_str("Heap: locked (did we panic in the allocator?)\n")),
+    }
+    let seconds = timer::uptime_ms() / 1000;
+    write!(w, "Uptime: {}:{:02}:{:02}, {} interrupts\n",
+           seconds / 3600, seconds / 60 % 60, seconds % 60,
+           interrupts::interrupt_count())
+}
+
+/// Show our panic screen, and halt.
+pub fn show(msg: fmt::Arguments, file: &str, line: u32) -> ! {
+    unsafe {
+        x86::irq::disable();
+        vbe::force_text_mode();
+    }
+
+    let mut screen = unsafe { vga::raw_screen() };
+    screen.clear(Blue).set_colors(COLORS);
+    let (width, _) = screen.size();
+    screen.fill_region(Rect::new(0, 0, width, 1), b' ', TITLE_COLORS);
+    screen.write_str_at((width - 18) / 2, 0, "toyos kernel panic", TITLE_COLORS);
+
+    let mut w = PanicWriter {
+        screen: screen,
+        serial: unsafe { serial::raw_com1() },
+    };
+    let _ = write_report(&mut w, msg, file, line);
+
+    if crash_dump::wanted() {
+        let mut dump = crash_dump::Dump::begin(&mut w.serial);
+        let _ = write_report(&mut dump, msg, file, line)
+            .and_then(|_| dump.write_log());
+        let _ = dump.finish();
+    }
+
+    loop {
+        unsafe { asm!("hlt" :::: "volatile"); }
+    }
+}
@@ -0,0 +1,84 @@
+//! Support for `log_rate_limited!`, which keeps a chattering device or a
+//! polling loop from flooding the console.
+//!
+//! Each call site gets its own `RateLimit`.  We allow at most `max`
+//! messages per window of `window` milliseconds, and we fold identical
+//! consecutive messages into a single "repeated" line.  A message which
+//! keeps repeating is still printed once per window.  To avoid
+//! allocating, we compare messages by hashing their formatted text.
+
+use core::fmt::{self, Write};
+use core::hash::Hasher;
+
+use hash::Fnv1a;
+
+/// Hash the text produced by `args`.
+fn hash(args: fmt::Arguments) -> u64 {
+    let mut hasher = Fnv1a::new();
+    let _ = hasher.write_fmt(args);
+    hasher.finish()
+}
+
+/// What we should do with a message.
+pub enum Verdict {
+    /// Print it.  But first, report how many identical copies of the
+    /// previous message we swallowed, and how many messages we dropped
+    /// because we were over our limit.
+    Print { repeated: usize, suppressed: usize },
+    /// Don't print it.
+    Drop,
+}
+
+/// The rate-limiting state for a single call site.
+pub struct RateLimit {
+    window_start: usize,
+    count: usize,
+    suppressed: usize,
+    last_hash: u64,
+    repeated: usize,
+}
+
+impl RateLimit {
+    pub const fn new() -> RateLimit {
+        RateLimit {
+            window_start: 0,
+            count: 0,
+            suppressed: 0,
+            last_hash: 0,
+            repeated: 0,
+        }
+    }
+
+    /// Decide what to do with a message, given that it's now `now`
+    /// milliseconds since boot.
+    pub fn check(&mut self, max: usize, window: usize, now: usize,
+                 args: fmt::Arguments) -> Verdict {
+        if now.wrapping_sub(self.window_start) >= window {
+            self.window_start = now;
+            self.count = 0;
+        }
+
+        // Only fold repeats within a window.  Otherwise, a message which
+        // never changes would never be printed again, and we'd never
+        // report how many times it repeated.
+        let hash = hash(args);
+        if self.count > 0 && hash == self.last_hash {
+            self.repeated += 1;
+            return Verdict::Drop;
+        }
+        if self.count >= max {
+            self.suppressed += 1;
+            return Verdict::Drop;
+        }
+
+        let verdict = Verdict::Print {
+            repeated: self.repeated,
+            suppressed: self.suppressed,
+        };
+        self.count += 1;
+        self.last_hash = hash;
+        self.repeated = 0;
+        self.suppressed = 0;
+        verdict
+    }
+}
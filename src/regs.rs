@@ -0,0 +1,92 @@
+//! Register descriptions, so that the shell can show a device's registers
+//! by name, with their bit fields decoded, instead of leaving us to
+//! cross-reference datasheets by hand.
+
+use cpuio;
+
+/// A named bit field within a register.
+pub struct Field {
+    pub name: &'static str,
+    /// The lowest bit of the field.
+    pub shift: u8,
+    /// The width of the field, in bits.
+    pub width: u8,
+}
+
+/// A single device register.
+pub struct Register {
+    pub name: &'static str,
+    /// The offset of this register from the device's base address.
+    pub offset: u16,
+    /// The size of this register in bytes: 1, 2 or 4.
+    pub size: u8,
+    /// Does reading this register change the device's state?  (Popping a
+    /// FIFO, clearing interrupt flags, etc.)  We only read these when
+    /// explicitly asked to.
+    pub read_has_side_effects: bool,
+    pub fields: &'static [Field],
+}
+
+/// A description of all the registers of some kind of device.
+pub struct RegisterMap {
+    pub name: &'static str,
+    pub registers: &'static [Register],
+}
+
+/// Where a device's registers live.
+#[derive(Debug, Clone, Copy)]
+pub enum Base {
+    /// I/O ports, starting at the given port.
+    Port(u16),
+    /// Mapped memory, starting at the given virtual address.
+    Memory(usize),
+}
+
+impl Register {
+    /// Read this register.
+    unsafe fn read(&self, base: Base) -> u32 {
+        match base {
+            Base::Port(port) => {
+                let port = port + self.offset;
+                match self.size {
+                    1 => cpuio::inb(port) as u32,
+                    2 => cpuio::inw(port) as u32,
+                    _ => cpuio::inl(port),
+                }
+            }
+            Base::Memory(addr) => {
+                let addr = addr + self.offset as usize;
+                match self.size {
+                    1 => *(addr as *const u8) as u32,
+                    2 => *(addr as *const u16) as u32,
+                    _ => *(addr as *const u32),
+                }
+            }
+        }
+    }
+}
+
+/// Print every register in `map`.  Registers whose reads have side
+/// effects are skipped unless `include_side_effects` is set.
+pub unsafe fn dump(map: &RegisterMap, base: Base, include_side_effects: bool) {
+    println!("{} at {:?}:", map.name, base);
+    for register in map.registers {
+        if register.read_has_side_effects && !include_side_effects {
+            println!("  {:8} (not read: has side effects)", register.name);
+            continue;
+        }
+        let value = register.read(base);
+        print!("  {:8} 0x{:0width$x} ", register.name, value,
+               width = register.size as usize * 2);
+        for field in register.fields {
+            let mask = (1u64 << field.width) - 1;
+            let field_value = (value as u64 >> field.shift) & mask;
+            if field.width == 1 {
+                print!(" {}={}", field.name, field_value);
+            } else {
+                print!(" {}=0x{:x}", field.name, field_value);
+            }
+        }
+        println!("");
+    }
+}
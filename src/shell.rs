@@ -0,0 +1,704 @@
+//! A tiny interactive command shell.
+//!
+//! Input arrives one character at a time from the console, via the main
+//! loop, and we run each command as soon as we see a carriage return.
+//! This is all very primitive, but it's enough to poke at the hardware.
+
+use collections::string::String;
+use collections::vec::Vec;
+use core::ptr;
+use core::str;
+use spin::Mutex;
+use cpuio;
+
+use arch::{acpi, cpu, kernel_layout, keyboard, keymap, latency, multiboot,
+           pci, reset, sb16, serial, vectors};
+use build_info;
+use console::{self, Input};
+use heap;
+use klog;
+use regs;
+use util;
+use wav;
+
+/// A shell command handler, which receives any arguments after the
+/// command name.
+type Handler = fn(&mut Shell, &[&str]);
+
+/// A command we know how to run.
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    handler: Handler,
+}
+
+/// All of our available commands.
+static COMMANDS: &'static [Command] = &[
+    Command { name: "help", usage: "help", handler: cmd_help },
+    Command { name: "dmesg", usage: "dmesg", handler: cmd_dmesg },
+    Command { name: "dangerous", usage: "dangerous [on|off]",
+              handler: cmd_dangerous },
+    Command { name: "mem", usage: "mem free | mem stats [-v] | mem layout | mem read <addr> <len> | mem write <addr> <bytes>...",
+              handler: cmd_mem },
+    Command { name: "io", usage: "io in{b,w,l} <port> | io out{b,w,l} <port> <value>",
+              handler: cmd_io },
+    Command { name: "regs", usage: "regs [-f] com1 | regs [-f] <bus> <device> <function>",
+              handler: cmd_regs },
+    Command { name: "reboot", usage: "reboot", handler: cmd_reboot },
+    Command { name: "shutdown", usage: "shutdown", handler: cmd_shutdown },
+    Command { name: "play", usage: "play <module>", handler: cmd_play },
+    Command { name: "version", usage: "version", handler: cmd_version },
+    Command { name: "cpustat", usage: "cpustat", handler: cmd_cpustat },
+    Command { name: "latency", usage: "latency [reset]", handler: cmd_latency },
+    Command { name: "vectors", usage: "vectors", handler: cmd_vectors },
+    Command { name: "keymap", usage: "keymap [<name>]", handler: cmd_keymap },
+    Command { name: "pci", usage: "pci [-t|-m|rescan] | pci [power|reset] <bus> <device> <function>",
+              handler: cmd_pci },
+    Command { name: "serial", usage: "serial | serial config [baud=<n>] [bits=<5-8>] [parity=n|o|e|m|s] [stop=1|2] [flow=rts|none] | serial irq on|off",
+              handler: cmd_serial },
+];
+
+/// Our shell state.
+pub struct Shell {
+    /// The line we're currently reading.
+    line: String,
+    /// Are commands which can crash the machine allowed?
+    dangerous: bool,
+    /// If the terminal is in the middle of sending us pasted text, what
+    /// we've received so far.
+    paste: Option<String>,
+}
+
+impl Shell {
+    fn new() -> Shell {
+        Shell { line: String::new(), dangerous: false, paste: None }
+    }
+
+    /// Process input from the serial console.
+    fn handle_input(&mut self, input: Input) {
+        match (input, self.paste.is_some()) {
+            (Input::PasteStart, _) => self.paste = Some(String::new()),
+            (Input::PasteEnd, true) => {
+                let text = self.paste.take().unwrap();
+                self.handle_paste(&text);
+            }
+            (Input::PasteEnd, false) => {}
+            (Input::Char(c), true) => self.paste.as_mut().unwrap().push(c),
+            (Input::Char(c), false) => self.handle_char(c),
+        }
+    }
+
+    /// Process pasted text.  We take it literally: backspaces and other
+    /// control characters aren't editing commands, and we echo each line
+    /// once instead of a character at a time.  Every complete line is
+    /// run, and anything after the last newline is left for the user to
+    /// finish.
+    fn handle_paste(&mut self, text: &str) {
+        let mut lines = text.split(|c| c == '\r' || c == '\n').peekable();
+        while let Some(part) = lines.next() {
+            // `split` gives us an empty string between `\r` and `\n`.
+            if part.is_empty() && lines.peek().is_some() { continue; }
+            let printable: String = part.chars()
+                .filter(|&c| c == '\t' || !c.is_control())
+                .collect();
+            self.line.push_str(&printable);
+            print!("{}", printable);
+            if lines.peek().is_some() {
+                self.handle_char('\r');
+            }
+        }
+    }
+
+    /// Process a single character of input.
+    fn handle_char(&mut self, c: char) {
+        match c {
+            '\r' | '\n' => {
+                println!("");
+                let line = self.line.clone();
+                self.line.clear();
+                self.run(&line);
+                self.prompt();
+            }
+            '\x08' => {
+                if self.line.pop().is_some() {
+                    // Back up, erase the character, and back up again.
+                    print!("\x08 \x08");
+                }
+            }
+            _ => {
+                self.line.push(c);
+                print!("{}", c);
+            }
+        }
+    }
+
+    /// Print our command prompt.
+    fn prompt(&self) {
+        print!("> ");
+    }
+
+    /// Run a single command line.
+    fn run(&mut self, line: &str) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() { return; }
+
+        match COMMANDS.iter().find(|c| c.name == words[0]) {
+            Some(command) => (command.handler)(self, &words[1..]),
+            None => println!("{}: unknown command (try `help`)", words[0]),
+        }
+    }
+
+    /// Run each line of `script` as a command.  Blank lines and lines
+    /// starting with `#` are skipped.  Commands are echoed as if typed,
+    /// unless they start with `@quiet`, in which case neither the command
+    /// nor its output is shown on the console.
+    fn run_script(&mut self, script: &str) {
+        for line in script.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            if line.starts_with("@quiet") {
+                console::set_quiet(true);
+                self.run(&line["@quiet".len()..]);
+                console::set_quiet(false);
+            } else {
+                println!("{}", line);
+                self.run(line);
+                self.prompt();
+            }
+        }
+    }
+
+    /// Check whether dangerous commands are enabled, and complain if not.
+    fn check_dangerous(&self) -> bool {
+        if !self.dangerous {
+            println!("This command can crash the machine; run `dangerous on` first.");
+        }
+        self.dangerous
+    }
+}
+
+/// Parse a number, and print an error if it doesn't work.
+fn parse_arg(s: &str) -> Option<usize> {
+    let result = util::parse_number(s);
+    if result.is_none() {
+        println!("Invalid number: {}", s);
+    }
+    result
+}
+
+fn cmd_help(_shell: &mut Shell, _args: &[&str]) {
+    println!("Commands:");
+    for command in COMMANDS {
+        println!("  {}", command.usage);
+    }
+}
+
+fn cmd_dangerous(shell: &mut Shell, args: &[&str]) {
+    match args.get(0) {
+        None => {}
+        Some(&"on") => shell.dangerous = true,
+        Some(&"off") => shell.dangerous = false,
+        _ => { println!("usage: dangerous [on|off]"); return; }
+    }
+    println!("Dangerous commands are {}.",
+             if shell.dangerous { "enabled" } else { "disabled" });
+}
+
+fn cmd_dmesg(_shell: &mut Shell, _args: &[&str]) {
+    let (first_seq, text) = klog::contents();
+    let text = String::from_utf8_lossy(&text);
+    for (i, line) in text.lines().enumerate() {
+        console::print_unlogged(format_args!("[{:5}] {}\n", first_seq + i, line));
+    }
+}
+
+fn cmd_mem(shell: &mut Shell, args: &[&str]) {
+    if args.get(0) == Some(&"free") {
+        mem_free();
+        return;
+    }
+    if args.get(0) == Some(&"stats") {
+        mem_stats();
+        if args.get(1) == Some(&"-v") {
+            heap::print_timing();
+        }
+        return;
+    }
+    if args.get(0) == Some(&"layout") {
+        mem_layout();
+        return;
+    }
+    if args.len() < 3 {
+        println!("usage: mem free | mem stats [-v] | mem layout | mem read <addr> <len> | mem write <addr> <bytes>...");
+        return;
+    }
+    if !shell.check_dangerous() { return; }
+    let addr = match parse_arg(args[1]) { Some(a) => a, None => return };
+
+    match args[0] {
+        "read" => {
+            let len = match parse_arg(args[2]) { Some(l) => l, None => return };
+            unsafe { util::hexdump(addr, len); }
+        }
+        "write" => {
+            let mut bytes = Vec::new();
+            for arg in &args[2..] {
+                match parse_arg(arg) {
+                    Some(b) if b <= 0xFF => bytes.push(b as u8),
+                    Some(_) => { println!("Not a byte: {}", arg); return; }
+                    None => return,
+                }
+            }
+            for (i, &b) in bytes.iter().enumerate() {
+                unsafe { ptr::write_volatile((addr + i) as *mut u8, b); }
+            }
+            println!("Wrote {} bytes at 0x{:x}.", bytes.len(), addr);
+        }
+        other => println!("mem: unknown operation: {}", other),
+    }
+}
+
+/// Show where the pieces of the kernel image live.
+fn mem_layout() {
+    for region in kernel_layout::regions().iter() {
+        println!("{}", region);
+    }
+    println!("{}", kernel_layout::kernel());
+}
+
+/// Show the heap's free blocks by size.
+fn mem_free() {
+    let (total, largest) = heap::free_summary();
+    let (bottom, top) = heap::bounds();
+    println!("{:>10} {:>8}", "block size", "free");
+    for &(size, count) in heap::free_blocks().iter() {
+        if size > top - bottom { break; }
+        println!("{:>10} {:>8}", size, count);
+    }
+    println!("{} bytes free, largest block {} bytes", total, largest);
+}
+
+/// Show what sizes of allocation we've seen, how much rounding them up
+/// has cost us, and whether a different minimum block size would help.
+fn mem_stats() {
+    let stats = heap::stats();
+    println!("{:>10} {:>8}", "request <=", "count");
+    for (class, &count) in stats.requests_by_size_class().iter().enumerate() {
+        if count > 0 { println!("{:>10} {:>8}", 1usize << class, count); }
+    }
+    println!("{:>10} {:>8}", "block size", "granted");
+    for (order, &count) in stats.granted_by_order().iter().enumerate() {
+        if count > 0 {
+            println!("{:>10} {:>8}", heap::MIN_BLOCK_SIZE << order, count);
+        }
+    }
+    println!("{} allocations, {} bytes requested, {} bytes granted",
+             stats.total_requests(), stats.total_requested_bytes(),
+             stats.total_granted_bytes());
+    println!("{} bytes lost to rounding", stats.internal_fragmentation());
+    let (in_use, size) = heap::usage();
+    println!("{} of {} bytes in use", in_use, size);
+    let (zeroed, prezeroed) = heap::dma_zeroed_allocations();
+    println!("{} zeroed DMA allocations, {} already scrubbed", zeroed, prezeroed);
+    let suggested = stats.suggest_min_block_size(16, 4096);
+    if suggested == heap::MIN_BLOCK_SIZE {
+        println!("MIN_BLOCK_SIZE {} looks right for this workload",
+                 heap::MIN_BLOCK_SIZE);
+    } else {
+        println!("suggest MIN_BLOCK_SIZE {} (now {}): est. {} bytes lost instead of {}",
+                 suggested, heap::MIN_BLOCK_SIZE,
+                 stats.estimated_waste(suggested),
+                 stats.estimated_waste(heap::MIN_BLOCK_SIZE));
+    }
+}
+
+fn cmd_io(shell: &mut Shell, args: &[&str]) {
+    if args.len() < 2 {
+        println!("usage: io in{{b,w,l}} <port> | io out{{b,w,l}} <port> <value>");
+        return;
+    }
+    if !shell.check_dangerous() { return; }
+    let port = match parse_arg(args[1]) {
+        Some(p) if p <= 0xFFFF => p as u16,
+        Some(_) => { println!("Not a port: {}", args[1]); return; }
+        None => return,
+    };
+
+    unsafe {
+        match (args[0], args.get(2)) {
+            ("inb", None) => println!("0x{:02x}", cpuio::inb(port)),
+            ("inw", None) => println!("0x{:04x}", cpuio::inw(port)),
+            ("inl", None) => println!("0x{:08x}", cpuio::inl(port)),
+            ("outb", Some(v)) => {
+                if let Some(v) = parse_arg(v) { cpuio::outb(v as u8, port); }
+            }
+            ("outw", Some(v)) => {
+                if let Some(v) = parse_arg(v) { cpuio::outw(v as u16, port); }
+            }
+            ("outl", Some(v)) => {
+                if let Some(v) = parse_arg(v) { cpuio::outl(v as u32, port); }
+            }
+            _ => println!("io: bad arguments"),
+        }
+    }
+}
+
+/// Parse a PCI bus, device and function from `args`.
+fn parse_pci_address(args: &[&str]) -> Option<(u8, u8, u8)> {
+    let mut ids = [0u8; 3];
+    for (id, arg) in ids.iter_mut().zip(args) {
+        match parse_arg(arg) {
+            Some(n) if n <= 0xFF => *id = n as u8,
+            Some(_) => { println!("Out of range: {}", arg); return None; }
+            None => return None,
+        }
+    }
+    Some((ids[0], ids[1], ids[2]))
+}
+
+fn cmd_pci(shell: &mut Shell, args: &[&str]) {
+    match args.len() {
+        0 => {
+            for function in pci::functions() {
+                match pci::bound_driver(function.address()) {
+                    Some(driver) => println!("{}  driver: {}", function, driver),
+                    None => println!("{}", function),
+                }
+            }
+        }
+        3 => {
+            if let Some((bus, device, function)) = parse_pci_address(args) {
+                util::hexdump_slice(&pci::config_space(bus, device, function));
+            }
+        }
+        4 if args[0] == "power" => {
+            let address = match parse_pci_address(&args[1..]) {
+                Some(address) => address,
+                None => return,
+            };
+            match pci::find_function(address) {
+                Some(function) => match function.power_state() {
+                    Some(state) => println!("{:?}", state),
+                    None => println!("No power management support."),
+                },
+                None => println!("No such function."),
+            }
+        }
+        4 if args[0] == "reset" => {
+            if !shell.check_dangerous() { return; }
+            let address = match parse_pci_address(&args[1..]) {
+                Some(address) => address,
+                None => return,
+            };
+            match unsafe { pci::reset(address) } {
+                Ok(()) => println!("Reset."),
+                Err(err) => println!("pci: {}", err),
+            }
+        }
+        1 if args[0] == "-t" => pci_tree(),
+        1 if args[0] == "rescan" => {
+            match pci::rescan() {
+                Ok((added, removed)) =>
+                    println!("{} added, {} removed", added, removed),
+                Err(err) => println!("pci: {}", err),
+            }
+        }
+        1 if args[0] == "-m" => {
+            for function in pci::functions() {
+                pci_machine_readable(&function);
+            }
+        }
+        _ => println!("usage: pci [-t|-m|rescan] | pci [power|reset] <bus> <device> <function>"),
+    }
+}
+
+/// Print one function in the same format as `lspci -mn` on the host, so
+/// that scripts can compare the two.
+fn pci_machine_readable(function: &pci::FunctionInfo) {
+    let (bus, device, func) = function.address();
+    print!("{:02x}:{:02x}.{:x} \"{:02x}{:02x}\" \"{:04x}\" \"{:04x}\" -r{:02x}",
+           bus, device, func, function.class(), function.subclass(),
+           function.vendor_id(), function.device_id(),
+           function.revision_id());
+    if function.prog_if() != 0 {
+        print!(" -p{:02x}", function.prog_if());
+    }
+    match function.subsystem() {
+        Some((vendor, device)) => println!(" \"{:04x}\" \"{:04x}\"", vendor, device),
+        None => println!(" \"\" \"\""),
+    }
+}
+
+/// Print our PCI functions as a tree, with the functions behind each
+/// bridge indented under it, in the spirit of `lspci -t`.
+fn pci_tree() {
+    let functions: Vec<pci::FunctionInfo> = pci::functions().collect();
+    let mut visited = [false; 256];
+    let mut prefix = String::new();
+    pci_tree_bus(&functions, 0, &mut visited, &mut prefix);
+
+    // Anything we couldn't reach through a bridge from bus 0 is behind
+    // another host bridge.
+    for function in &functions {
+        let bus = function.address().0;
+        if !visited[bus as usize] {
+            pci_tree_bus(&functions, bus, &mut visited, &mut prefix);
+        }
+    }
+}
+
+/// Print the functions on `bus`, and recursively, the buses behind any
+/// bridges there.
+fn pci_tree_bus(functions: &[pci::FunctionInfo], bus: u8,
+                visited: &mut [bool; 256], prefix: &mut String) {
+    visited[bus as usize] = true;
+    println!("{}[{:02x}]", prefix, bus);
+    let on_bus: Vec<&pci::FunctionInfo> =
+        functions.iter().filter(|f| f.address().0 == bus).collect();
+    for (i, function) in on_bus.iter().enumerate() {
+        let last = i + 1 == on_bus.len();
+        let (_, device, func) = function.address();
+        print!("{}{}{:02x}.{:x}  {:04x}:{:04x} {:?} {:02x}", prefix,
+               if last { "\\-" } else { "+-" }, device, func,
+               function.vendor_id(), function.device_id(),
+               function.class_code(), function.subclass());
+        match pci::bound_driver(function.address()) {
+            Some(driver) => println!("  [{}]", driver),
+            None => println!(""),
+        }
+
+        // A misconfigured bridge could point back at a bus we've already
+        // printed, so don't follow it around in circles.
+        if let Some(secondary) = function.secondary_bus() {
+            if !visited[secondary as usize] {
+                let len = prefix.len();
+                prefix.push_str(if last { "   " } else { "|  " });
+                pci_tree_bus(functions, secondary, visited, prefix);
+                prefix.truncate(len);
+            }
+        }
+    }
+}
+
+fn cmd_regs(shell: &mut Shell, args: &[&str]) {
+    // `-f` forces us to read registers where that has side effects.
+    let force = args.get(0) == Some(&"-f");
+    let args = if force { &args[1..] } else { args };
+    if force && !shell.check_dangerous() { return; }
+
+    let found = match args.len() {
+        1 if args[0] == "com1" =>
+            Ok((&serial::REGISTERS, regs::Base::Port(serial::COM1_BASE))),
+        3 => match parse_pci_address(args) {
+            Some(address) => pci::bound_registers(address),
+            None => return,
+        },
+        _ => {
+            println!("usage: regs [-f] com1 | regs [-f] <bus> <device> <function>");
+            return;
+        }
+    };
+    match found {
+        Ok((map, base)) => unsafe { regs::dump(map, base, force) },
+        Err(err) => println!("regs: {}", err),
+    }
+}
+
+/// Show or change how COM1 is set up.
+fn cmd_serial(shell: &mut Shell, args: &[&str]) {
+    match args.get(0) {
+        None => {
+            let (config, lines) = {
+                let mut com1 = serial::COM1.lock();
+                (com1.config(), com1.modem_lines())
+            };
+            let (changes, timeouts) = serial::stats();
+            println!("COM1: {}", config);
+            println!("Modem lines: {}", lines);
+            println!("Modem status interrupts: {}, CTS timeouts: {}",
+                     changes, timeouts);
+        }
+        Some(&"config") if args.len() > 1 => {
+            // Changing the line settings can cut off a serial console.
+            if !shell.check_dangerous() { return; }
+            let mut config = serial::COM1.lock().config();
+            for arg in &args[1..] {
+                if let Err(err) = parse_serial_setting(&mut config, arg) {
+                    println!("serial: {}: {}", arg, err);
+                    return;
+                }
+            }
+            let result = serial::COM1.lock().configure(config);
+            match result {
+                Ok(()) => println!("COM1: {}", config),
+                Err(err) => println!("serial: {}", err),
+            }
+        }
+        Some(&"irq") if args.len() == 2 && (args[1] == "on" || args[1] == "off") =>
+            serial::COM1.lock().set_modem_interrupts(args[1] == "on"),
+        _ => println!("usage: serial | serial config [baud=<n>] [bits=<5-8>] [parity=n|o|e|m|s] [stop=1|2] [flow=rts|none] | serial irq on|off"),
+    }
+}
+
+/// Apply a `name=value` setting from `serial config` to `config`.  We
+/// leave checking the values to `ComPort::configure`.
+fn parse_serial_setting(config: &mut serial::Config, setting: &str)
+                        -> Result<(), &'static str> {
+    let mut parts = setting.splitn(2, '=');
+    let name = parts.next().unwrap();
+    let value = try!(parts.next().ok_or("expected name=value"));
+    match name {
+        "baud" => config.baud = try!(parse_setting(value, 0xFFFF_FFFF)) as u32,
+        "bits" => config.data_bits = try!(parse_setting(value, 0xFF)) as u8,
+        "stop" => config.stop_bits = try!(parse_setting(value, 0xFF)) as u8,
+        "parity" => config.parity = match value {
+            "n" | "none" => serial::Parity::None,
+            "o" | "odd" => serial::Parity::Odd,
+            "e" | "even" => serial::Parity::Even,
+            "m" | "mark" => serial::Parity::Mark,
+            "s" | "space" => serial::Parity::Space,
+            _ => return Err("unknown parity"),
+        },
+        "flow" => config.flow_control = match value {
+            "rts" | "rtscts" => true,
+            "none" => false,
+            _ => return Err("unknown flow control"),
+        },
+        _ => return Err("unknown setting"),
+    }
+    Ok(())
+}
+
+/// Parse a numeric setting no larger than `max`.
+fn parse_setting(value: &str, max: usize) -> Result<usize, &'static str> {
+    match util::parse_number(value) {
+        Some(n) if n <= max => Ok(n),
+        Some(_) => Err("out of range"),
+        None => Err("not a number"),
+    }
+}
+
+fn cmd_reboot(_shell: &mut Shell, _args: &[&str]) {
+    println!("Rebooting...");
+    reset::reboot();
+}
+
+fn cmd_shutdown(_shell: &mut Shell, _args: &[&str]) {
+    println!("Powering off...");
+    if let Err(err) = acpi::power_off() {
+        println!("shutdown: {}", err);
+    }
+}
+
+/// Play a WAV file loaded by GRUB as a multiboot module.
+fn cmd_play(_shell: &mut Shell, args: &[&str]) {
+    if args.len() != 1 {
+        println!("usage: play <module>");
+        return;
+    }
+    let module = match multiboot::info().and_then(|info| info.module(args[0])) {
+        Some(module) => module,
+        None => { println!("play: no module named {}", args[0]); return; }
+    };
+    let result = wav::parse(module.data).and_then(|sound| sb16::play(sound));
+    if let Err(err) = result {
+        println!("play: {}", err);
+    }
+}
+
+/// Show who owns which interrupt vectors, grouping runs with the same
+/// owner.
+fn cmd_vectors(_shell: &mut Shell, _args: &[&str]) {
+    let mut start = 0;
+    while start < 256 {
+        let owner = vectors::owner(start as u8);
+        let mut end = start + 1;
+        while end < 256 && vectors::owner(end as u8) == owner {
+            end += 1;
+        }
+        if let Some(owner) = owner {
+            if end - start == 1 {
+                println!("{:#04x}       {}", start, owner);
+            } else {
+                println!("{:#04x}-{:#04x}  {}", start, end - 1, owner);
+            }
+        }
+        start = end;
+    }
+}
+
+/// Show what the CPU's own sensors say, where it has them.
+fn cmd_cpustat(_shell: &mut Shell, _args: &[&str]) {
+    match cpu::temperature() {
+        Some(celsius) => println!("Temperature: {}C", celsius),
+        None => println!("Temperature: no sensor"),
+    }
+    match (cpu::effective_frequency_percent(), cpu::base_frequency_mhz()) {
+        (Some(percent), Some(mhz)) =>
+            println!("Frequency:   {}% of {} MHz (about {} MHz)",
+                     percent, mhz, percent * mhz as u64 / 100),
+        (Some(percent), None) =>
+            println!("Frequency:   {}% of base", percent),
+        (None, _) => println!("Frequency:   no APERF/MPERF"),
+    }
+}
+
+/// Show how long we've run with interrupts disabled.
+fn cmd_latency(_shell: &mut Shell, args: &[&str]) {
+    match args.get(0) {
+        None => print!("{}", latency::Report),
+        Some(&"reset") => latency::reset(),
+        _ => println!("usage: latency [reset]"),
+    }
+}
+
+fn cmd_keymap(_shell: &mut Shell, args: &[&str]) {
+    match args.get(0) {
+        None => {
+            print!("Keymap: {} (available:", keyboard::keymap_name());
+            for keymap in keymap::KEYMAPS.iter() {
+                print!(" {}", keymap.name);
+            }
+            println!(")");
+        }
+        Some(name) => {
+            if let Err(err) = keyboard::set_keymap(name) {
+                println!("keymap: {}", err);
+            }
+        }
+    }
+}
+
+fn cmd_version(_shell: &mut Shell, _args: &[&str]) {
+    build_info::print();
+}
+
+/// Our global shell, or `None` if it hasn't been started yet.  We can't
+/// create it at compile time, because it needs the heap.
+static SHELL: Mutex<Option<Shell>> = Mutex::new(None);
+
+/// Start the shell and print a prompt.  The heap must be initialized
+/// first.
+pub fn initialize() {
+    let mut shell = SHELL.lock();
+    *shell = Some(Shell::new());
+    console::enable_bracketed_paste();
+    shell.as_ref().unwrap().prompt();
+
+    // Run our startup script, if GRUB loaded one.
+    let rc = multiboot::info().and_then(|info| info.module("rc"));
+    if let Some(rc) = rc {
+        match str::from_utf8(rc.data) {
+            Ok(script) => shell.as_mut().unwrap().run_script(script),
+            Err(_) => println!("rc: not valid UTF-8"),
+        }
+    }
+}
+
+/// Feed console input to the shell.  Input is ignored until `initialize`
+/// has been called.
+pub fn handle_input(input: Input) {
+    if let Some(ref mut shell) = *SHELL.lock() {
+        shell.handle_input(input);
+    }
+}
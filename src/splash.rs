@@ -0,0 +1,102 @@
+//! A boot splash screen, for when we're running on a framebuffer.
+//!
+//! If the display driver has switched to a graphics mode and GRUB loaded a
+//! BMP file as the `splash` module, we draw it in the middle of the screen
+//! with a progress bar underneath, which `rust_main` advances as it works
+//! through the rest of initialization.  To try it, copy an image to
+//! `build/isofiles/boot/splash.bmp` and add `module2 /boot/splash.bmp
+//! splash` to `grub.cfg`.
+
+use spin::Mutex;
+
+use arch::multiboot;
+use arch::vbe::{self, Framebuffer};
+use bmp;
+
+/// The size of our progress bar, and how far it is below the image.
+const BAR_WIDTH: usize = 256;
+const BAR_HEIGHT: usize = 8;
+const BAR_GAP: usize = 16;
+
+/// Colors, in `0x00RRGGBB` format.
+const BACKGROUND: u32 = 0x000000;
+const BAR_EMPTY: u32 = 0x404040;
+const BAR_FULL: u32 = 0xC0C0C0;
+
+/// Where we drew our progress bar, if we're showing a splash screen.
+struct Bar {
+    framebuffer: Framebuffer,
+    x: usize,
+    y: usize,
+}
+
+static BAR: Mutex<Option<Bar>> = Mutex::new(None);
+
+/// Draw `image` on `framebuffer`, centered, clipping it if it's too big.
+fn draw(framebuffer: &Framebuffer, image: &bmp::Image) {
+    let width = if image.width() < framebuffer.width() {
+        image.width()
+    } else {
+        framebuffer.width()
+    };
+    let height = if image.height() < framebuffer.height() {
+        image.height()
+    } else {
+        framebuffer.height()
+    };
+    let left = (framebuffer.width() - width) / 2;
+    let top = (framebuffer.height() - height) / 2;
+    for y in 0..height {
+        for x in 0..width {
+            framebuffer.put_pixel(left + x, top + y, image.pixel(x, y));
+        }
+    }
+}
+
+/// Show our splash screen, if we're in a graphics mode and have an image.
+pub fn show() {
+    let framebuffer = match vbe::framebuffer() {
+        Some(framebuffer) => framebuffer,
+        None => return,
+    };
+    let module = match multiboot::info().and_then(|i| i.module("splash")) {
+        Some(module) => module,
+        None => return,
+    };
+    let image = match bmp::parse(module.data) {
+        Ok(image) => image,
+        Err(err) => {
+            println!("splash: {}", err);
+            return;
+        }
+    };
+
+    framebuffer.fill_rect(0, 0, framebuffer.width(), framebuffer.height(),
+                          BACKGROUND);
+    draw(&framebuffer, &image);
+
+    // Put the bar under the image, unless the image fills the screen.
+    let image_bottom = (framebuffer.height() + image.height()) / 2;
+    let y = if image_bottom + BAR_GAP + BAR_HEIGHT <= framebuffer.height() {
+        image_bottom + BAR_GAP
+    } else {
+        framebuffer.height() - BAR_GAP - BAR_HEIGHT
+    };
+    let x = (framebuffer.width() - BAR_WIDTH) / 2;
+    framebuffer.fill_rect(x, y, BAR_WIDTH, BAR_HEIGHT, BAR_EMPTY);
+    *BAR.lock() = Some(Bar { framebuffer: framebuffer, x: x, y: y });
+}
+
+/// Record that we've finished `done` of our `total` initialization steps.
+/// Does nothing if we're not showing a splash screen.
+pub fn progress(done: usize, total: usize) {
+    if let Some(ref bar) = *BAR.lock() {
+        let filled = BAR_WIDTH * done / total;
+        bar.framebuffer.fill_rect(bar.x, bar.y, filled, BAR_HEIGHT, BAR_FULL);
+    }
+}
+
+/// We're done booting, so stop updating the progress bar.
+pub fn finish() {
+    *BAR.lock() = None;
+}
@@ -0,0 +1,137 @@
+//! A status bar on the bottom row of the VGA screen, refreshed from the
+//! timer interrupt.
+//!
+//! We draw from interrupt context, so we must never allocate here, and we
+//! never wait for a lock that the code we interrupted might hold.  If the
+//! screen or the heap is busy, we skip this refresh and try again on the
+//! next tick.  We only start drawing once `initialize` is called at the
+//! end of boot, and only in text mode: in a graphics mode, `fbterm` owns
+//! the display, and the VGA text buffer isn't on the screen at all.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use spin::Mutex;
+
+use arch::{interrupts, timer, vbe};
+use heap;
+use arch::vga::{SCREEN, ColorScheme, Rect, WIDTH};
+use arch::vga::Color::*;
+
+/// How often we redraw the status bar, in milliseconds.
+const REFRESH_MS: usize = 500;
+
+/// The virtual terminal we're showing, and the number of tasks.  We only
+/// have one console and one thread of execution, so these are constant
+/// until we grow virtual terminals and a scheduler.
+const CURRENT_VT: usize = 1;
+const TASK_COUNT: usize = 1;
+
+/// Colors for the status bar.
+const COLORS: ColorScheme = ColorScheme::new(Black, LightGrey);
+
+/// Have we been initialized yet?
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// What we need to remember between refreshes.
+struct State {
+    last_ms: usize,
+    last_interrupts: usize,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    last_ms: 0,
+    last_interrupts: 0,
+});
+
+/// A fixed-size line of text that we can format into without using the
+/// heap.  Anything past `WIDTH` characters is silently dropped.
+struct Line {
+    text: [u8; WIDTH],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Line {
+        Line { text: [b' '; WIDTH], len: 0 }
+    }
+
+    fn as_str(&self) -> &str {
+        // We only ever copy in whole `str` values, but we may have cut one
+        // in half at the end of the line.
+        let mut len = self.len;
+        while ::core::str::from_utf8(&self.text[..len]).is_err() {
+            len -= 1;
+        }
+        ::core::str::from_utf8(&self.text[..len]).unwrap()
+    }
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            if self.len < WIDTH {
+                self.text[self.len] = b;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reserve the bottom row of the screen for our status bar, and start
+/// updating it.  Does nothing in a graphics mode.
+pub fn initialize() {
+    if vbe::framebuffer().is_some() { return; }
+    let mut screen = SCREEN.lock();
+    let (_, height) = screen.size();
+    screen.set_text_height(height - 1);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Called on every timer interrupt.
+pub fn tick() {
+    if !ENABLED.load(Ordering::SeqCst) { return; }
+    let now = timer::uptime_ms();
+    let mut state = STATE.lock();
+    if now - state.last_ms >= REFRESH_MS {
+        // If we couldn't draw, try again on the next tick.
+        if !redraw(&mut state, now) {
+            return;
+        }
+    }
+    // Make sure a tickless timer still wakes us up for our next refresh.
+    timer::request_wakeup((state.last_ms + REFRESH_MS) * 1000);
+}
+
+/// Redraw the status bar.  Returns false, without drawing anything, if
+/// the heap or the screen is locked by the code we interrupted.
+fn redraw(state: &mut State, now: usize) -> bool {
+    let (heap_free, heap_largest) = match heap::try_free_summary() {
+        Some(summary) => summary,
+        None => return false,
+    };
+    let mut screen = match SCREEN.try_lock() {
+        Some(screen) => screen,
+        None => return false,
+    };
+
+    // Calculate our interrupt rate since the last update.
+    let interrupts = interrupts::interrupt_count();
+    let elapsed_ms = now - state.last_ms;
+    let rate = (interrupts - state.last_interrupts) * 1000 / elapsed_ms;
+    state.last_ms = now;
+    state.last_interrupts = interrupts;
+
+    let seconds = now / 1000;
+    let mut line = Line::new();
+    let _ = write!(line, " vt{} | up {}:{:02}:{:02} | {} task | {} irq/s",
+                   CURRENT_VT, seconds / 3600, seconds / 60 % 60, seconds % 60,
+                   TASK_COUNT, rate);
+    let _ = write!(line, " | heap {}K free, largest {}K",
+                   heap_free / 1024, heap_largest / 1024);
+
+    let (width, height) = screen.size();
+    screen.fill_region(Rect::new(0, height - 1, width, 1), b' ', COLORS);
+    screen.write_str_at(0, height - 1, line.as_str(), COLORS);
+    true
+}
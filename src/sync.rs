@@ -0,0 +1,181 @@
+//! Synchronization and shared ownership.
+//!
+//! `spin::Mutex` makes readers wait for each other.  `RwSpinlock` lets any
+//! number of readers in at once, and `SeqLock` goes further: readers never
+//! write to shared memory at all, and just retry if a writer got in their
+//! way.  Like `spin::Mutex`, neither of these disables interrupts, so
+//! don't take a lock from an interrupt handler if the code it interrupted
+//! might hold it for writing.
+//!
+//! For shared ownership, we use the real `Arc` and `Weak` from `liballoc`,
+//! which we already build for our target, and which allocate from our
+//! heap.  Dropping the last `Arc` frees memory, which takes the heap lock,
+//! so an interrupt handler should never drop the last reference to
+//! anything: hand it back to ordinary kernel code instead.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use core::usize;
+
+pub use alloc::arc::{Arc, Weak};
+
+/// The value of `RwSpinlock::state` while a writer holds the lock.
+const WRITER: usize = usize::MAX;
+
+/// A spinlock which allows many readers or one writer.  Writers wait until
+/// there are no readers, so a steady stream of readers can starve them.
+pub struct RwSpinlock<T> {
+    /// The number of readers, or `WRITER`.
+    state: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwSpinlock<T> {}
+unsafe impl<T: Send> Send for RwSpinlock<T> {}
+
+/// Shared access to the data in a `RwSpinlock`.
+pub struct ReadGuard<'a, T: 'a> {
+    lock: &'a RwSpinlock<T>,
+}
+
+/// Exclusive access to the data in a `RwSpinlock`.
+pub struct WriteGuard<'a, T: 'a> {
+    lock: &'a RwSpinlock<T>,
+}
+
+impl<T> RwSpinlock<T> {
+    pub const fn new(data: T) -> RwSpinlock<T> {
+        RwSpinlock {
+            state: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Wait until there's no writer, and lock for reading.
+    pub fn read(&self) -> ReadGuard<T> {
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            // Leave room for `WRITER`, however unlikely that many readers
+            // might be.
+            if state < WRITER - 1 &&
+                self.state.compare_and_swap(state, state + 1,
+                                            Ordering::Acquire) == state
+            {
+                return ReadGuard { lock: self };
+            }
+        }
+    }
+
+    /// Wait until nobody else holds the lock, and lock for writing.
+    pub fn write(&self) -> WriteGuard<T> {
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+        }
+    }
+
+    /// Lock for writing if nobody else holds the lock.
+    pub fn try_write(&self) -> Option<WriteGuard<T>> {
+        if self.state.compare_and_swap(0, WRITER, Ordering::Acquire) == 0 {
+            Some(WriteGuard { lock: self })
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, T> Deref for ReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for ReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for WriteGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for WriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for WriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+    }
+}
+
+/// A sequence lock, for small `Copy` values.
+///
+/// The sequence number is odd while a write is in progress, and goes up
+/// by 2 with each completed write.  A reader copies the value out, and
+/// then checks that the sequence number was even and didn't change while
+/// it was copying.  If it did, the copy may be torn, so we try again.
+///
+/// Because of this, readers can't see a writer they interrupted finish,
+/// and will spin forever.  Never read a `SeqLock` from an interrupt
+/// handler if the interrupted code might be writing it.
+pub struct SeqLock<T: Copy> {
+    sequence: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(data: T) -> SeqLock<T> {
+        SeqLock {
+            sequence: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Get a consistent copy of our value.
+    pub fn read(&self) -> T {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before & 1 != 0 {
+                continue;
+            }
+            // This may race with a writer, which is why we use a volatile
+            // read and check the sequence number afterwards.
+            let value = unsafe { ptr::read_volatile(self.data.get()) };
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == before {
+                return value;
+            }
+        }
+    }
+
+    /// Replace our value.  Writers wait for each other.
+    pub fn write(&self, value: T) {
+        let mut sequence;
+        loop {
+            sequence = self.sequence.load(Ordering::Relaxed);
+            if sequence & 1 == 0 &&
+                self.sequence.compare_and_swap(sequence, sequence + 1,
+                                               Ordering::Acquire) == sequence
+            {
+                break;
+            }
+        }
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(self.data.get(), value); }
+        self.sequence.store(sequence.wrapping_add(2), Ordering::Release);
+    }
+}
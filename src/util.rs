@@ -0,0 +1,64 @@
+//! Small utilities shared across the kernel.
+
+use core::cmp::min;
+use core::ptr;
+
+/// How many bytes we show on each line of a hexdump.
+const HEXDUMP_WIDTH: usize = 16;
+
+/// Print `len` bytes in the classic offset/hex/ASCII layout, labeling each
+/// line starting with `label`.  `read` fetches the byte at a given offset.
+fn hexdump_with<F: Fn(usize) -> u8>(label: usize, len: usize, read: F) {
+    let mut line_start = 0;
+    while line_start < len {
+        let line_end = min(line_start + HEXDUMP_WIDTH, len);
+        let mut bytes = [0u8; HEXDUMP_WIDTH];
+        for i in line_start..line_end {
+            bytes[i - line_start] = read(i);
+        }
+        let bytes = &bytes[..line_end - line_start];
+
+        // Offset and hex columns, with an extra gap in the middle.
+        print!("{:08x} ", label + line_start);
+        for i in 0..HEXDUMP_WIDTH {
+            if i == HEXDUMP_WIDTH / 2 { print!(" "); }
+            match bytes.get(i) {
+                Some(b) => print!(" {:02x}", b),
+                None => print!("   "),
+            }
+        }
+
+        // ASCII column, with non-printable characters shown as dots.
+        print!("  |");
+        for &b in bytes {
+            let c = if 0x20 <= b && b < 0x7F { b as char } else { '.' };
+            print!("{}", c);
+        }
+        println!("|");
+
+        line_start += HEXDUMP_WIDTH;
+    }
+}
+
+/// Hexdump `len` bytes of memory starting at `addr`, labeled with their
+/// actual addresses.  Each byte is read using a volatile load.  This is
+/// unsafe because `addr` may point anywhere.
+pub unsafe fn hexdump(addr: usize, len: usize) {
+    hexdump_with(addr, len, |i| ptr::read_volatile((addr + i) as *const u8));
+}
+
+/// Hexdump a slice, labeled with offsets from the start of the slice.
+pub fn hexdump_slice(data: &[u8]) {
+    hexdump_with(0, data.len(), |i| data[i]);
+}
+
+/// Parse a number, either in decimal or in hexadecimal with a leading
+/// `0x`.
+pub fn parse_number(s: &str) -> Option<usize> {
+    let result = if s.starts_with("0x") {
+        usize::from_str_radix(&s[2..], 16)
+    } else {
+        usize::from_str_radix(s, 10)
+    };
+    result.ok()
+}
@@ -0,0 +1,65 @@
+//! Just enough of the WAV file format to find uncompressed PCM data.
+//!
+//! A WAV file is a RIFF container holding a `fmt ` chunk, which describes
+//! the samples, and a `data` chunk, which holds them.  We skip any other
+//! chunks.
+
+/// Uncompressed PCM audio.
+pub struct Sound {
+    pub rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+    pub data: &'static [u8],
+}
+
+/// WAV format code for uncompressed PCM.
+const FORMAT_PCM: u16 = 1;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+/// Parse a WAV file.
+pub fn parse(bytes: &'static [u8]) -> Result<Sound, &'static str> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err("not a WAV file");
+    }
+
+    let mut format = None;
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let id = &bytes[offset..offset + 4];
+        let size = read_u32(bytes, offset + 4) as usize;
+        let body = offset + 8;
+        if body + size > bytes.len() {
+            return Err("truncated WAV file");
+        }
+
+        if id == b"fmt " {
+            if size < 16 { return Err("bad WAV format chunk"); }
+            if read_u16(bytes, body) != FORMAT_PCM {
+                return Err("WAV file is compressed");
+            }
+            format = Some((read_u16(bytes, body + 2),
+                           read_u32(bytes, body + 4),
+                           read_u16(bytes, body + 14)));
+        } else if id == b"data" {
+            let (channels, rate, bits_per_sample) = try!(format.ok_or(
+                "WAV data before format"));
+            return Ok(Sound {
+                rate: rate,
+                channels: channels,
+                bits_per_sample: bits_per_sample,
+                data: &bytes[body..body + size],
+            });
+        }
+
+        // Chunks are padded to an even length.
+        offset = body + size + (size & 1);
+    }
+    Err("WAV file has no data")
+}